@@ -0,0 +1,187 @@
+use super::*;
+
+/// The bech32 alphabet, indexed by 5 bit group.
+pub const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// What the checksum of a bech32 (BIP 173) string works out to. Bech32m (BIP 350), used for
+/// segwit versions above 0, uses `BECH32M_CONST` instead.
+pub const BECH32_CONST: u32 = 1;
+pub const BECH32M_CONST: u32 = 0x2bc8_30a3;
+
+const BECH32_GENERATOR: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+const BECH32_CHECKSUM_GROUPS: usize = 6;
+
+pub fn char_to_group(c: u8) -> Option<u8> {
+    BECH32_CHARSET.iter().position(|b| *b == c).map(|group| group as u8)
+}
+
+/// Splits bytes into 5 bit groups, zero-padding the last one.
+pub fn bytes_to_groups(bytes: &[u8]) -> Vec<u8> {
+    let mut groups = Vec::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        acc = (acc << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            groups.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        groups.push(((acc << (5 - bits)) & 31) as u8);
+    }
+    groups
+}
+
+/// Leftover bits at the end are zero-padded into a last byte if `pad` is set, and dropped
+/// otherwise.
+pub fn groups_to_bytes(groups: &[u8], pad: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(groups.len() * 5 / 8 + 1);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for group in groups {
+        acc = (acc << 5) | *group as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    if pad && bits > 0 {
+        bytes.push((acc << (8 - bits)) as u8);
+    }
+    bytes
+}
+
+/// Reads up to 12 groups as a big-endian number.
+pub fn groups_to_u64(groups: &[u8]) -> Option<u64> {
+    if groups.len() > 12 {
+        return None;
+    }
+    Some(groups.iter().fold(0u64, |acc, group| (acc << 5) | *group as u64))
+}
+
+/// Writes a number as big-endian groups, zero-padded to at least `min_groups`.
+pub fn u64_to_groups(mut val: u64, min_groups: usize) -> Vec<u8> {
+    let mut groups = Vec::new();
+    while val > 0 || groups.len() < min_groups {
+        groups.push((val & 31) as u8);
+        val >>= 5;
+    }
+    groups.reverse();
+    groups
+}
+
+// The bech32 crate caps strings at 90 characters, which almost every invoice exceeds, and doesn't
+// know about bech32m, so we do our own checksums.
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ff_ffff) << 5) ^ *value as u32;
+        for (i, generator) in BECH32_GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|b| b & 31));
+    expanded
+}
+
+/// Encodes 5 bit groups with a checksum which works out to `checksum_const`.
+pub fn bech32_encode(hrp: &str, groups: &[u8], checksum_const: u32) -> String {
+    let mut values = bech32_hrp_expand(hrp);
+    values.extend_from_slice(groups);
+    values.extend_from_slice(&[0; BECH32_CHECKSUM_GROUPS]);
+    let polymod = bech32_polymod(&values) ^ checksum_const;
+    let checksum = (0..BECH32_CHECKSUM_GROUPS).map(|i| ((polymod >> (5 * (5 - i))) & 31) as u8);
+
+    let mut encoded = String::with_capacity(hrp.len() + 1 + groups.len() + BECH32_CHECKSUM_GROUPS);
+    encoded.push_str(hrp);
+    encoded.push('1');
+    for group in groups.iter().cloned().chain(checksum) {
+        encoded.push(BECH32_CHARSET[group as usize] as char);
+    }
+    encoded
+}
+
+/// Decodes a bech32 or bech32m string into its lowercased human-readable part, its 5 bit
+/// groups and what its checksum works out to, which is `BECH32_CONST` or `BECH32M_CONST` for
+/// valid strings.
+pub fn bech32_decode(s: &str) -> Option<(String, Vec<u8>, u32)> {
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let s = s.to_ascii_lowercase();
+    let separator = s.rfind('1')?;
+    let (hrp, data) = (&s[..separator], &s[(separator + 1)..]);
+    if hrp.is_empty() || data.len() < BECH32_CHECKSUM_GROUPS || hrp.bytes().any(|b| b < 33 || b > 126) {
+        return None;
+    }
+    let mut groups = Vec::with_capacity(data.len());
+    for b in data.bytes() {
+        groups.push(char_to_group(b)?);
+    }
+    let mut values = bech32_hrp_expand(hrp);
+    values.extend_from_slice(&groups);
+    let checksum_const = bech32_polymod(&values);
+    groups.truncate(groups.len() - BECH32_CHECKSUM_GROUPS);
+    Some((hrp.to_owned(), groups, checksum_const))
+}
+
+/// Encodes bytes as bech32 without a checksum, as BOLT 12 does.
+pub fn bech32_encode_unchecked(hrp: &str, bytes: &[u8]) -> String {
+    let groups = bytes_to_groups(bytes);
+    let mut encoded = String::with_capacity(hrp.len() + 1 + groups.len());
+    encoded.push_str(hrp);
+    encoded.push('1');
+    encoded.extend(groups.iter().map(|group| BECH32_CHARSET[*group as usize] as char));
+    encoded
+}
+
+/// Decodes a string from `bech32_encode_unchecked` into its lowercased human-readable part and
+/// its bytes. A `+` followed by whitespace may be used to split the string over several lines,
+/// but not at its start or end, or twice in a row.
+pub fn bech32_decode_unchecked(s: &str) -> Option<(String, Vec<u8>)> {
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let s = s.to_ascii_lowercase();
+    let mut chars = s.chars().peekable();
+    let mut joined = String::with_capacity(s.len());
+    let mut split = true;
+    while let Some(c) = chars.next() {
+        if c != '+' {
+            joined.push(c);
+            split = false;
+            continue;
+        }
+        if split {
+            return None;
+        }
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        split = true;
+    }
+    if split {
+        return None;
+    }
+
+    let separator = joined.find('1')?;
+    let mut groups = Vec::with_capacity(joined.len() - separator - 1);
+    for b in joined[(separator + 1)..].bytes() {
+        groups.push(char_to_group(b)?);
+    }
+    joined.truncate(separator);
+    Some((joined, groups_to_bytes(&groups, false)))
+}
@@ -0,0 +1,272 @@
+use super::*;
+
+#[derive(Debug, Fail)]
+pub enum BlindedPathError {
+    #[fail(display = "{}", _0)]
+    MsgTooShort(MsgTooShortError),
+    #[fail(display = "invalid public key in blinded path")]
+    InvalidPublicKey,
+    #[fail(display = "invalid introduction node in blinded path")]
+    InvalidIntroductionNode,
+    #[fail(display = "blinded path has no hops")]
+    NoHops,
+    #[fail(display = "blinded path has too many hops")]
+    TooManyHops,
+    #[fail(display = "invalid session key for blinded path")]
+    InvalidSessionKey,
+    #[fail(display = "failed to decrypt blinded hop data")]
+    DecryptionFailed,
+}
+
+impl From<MsgTooShortError> for BlindedPathError {
+    fn from(err: MsgTooShortError) -> BlindedPathError {
+        BlindedPathError::MsgTooShort(err)
+    }
+}
+
+impl From<ReadFieldError> for BlindedPathError {
+    fn from(err: ReadFieldError) -> BlindedPathError {
+        match err {
+            ReadFieldError::MsgTooShort(err) => BlindedPathError::MsgTooShort(err),
+            _ => BlindedPathError::InvalidPublicKey,
+        }
+    }
+}
+
+/// The unblinded first node of a blinded path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntroductionNode {
+    NodeId(secp256k1::PublicKey),
+    /// One end of a channel, for when the sender is expected to know the introduction node's
+    /// channels. `direction` is 0 for the lesser node id and 1 for the greater.
+    DirectedShortChannelId {
+        direction: u8,
+        short_channel_id: ShortChannelId,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlindedHop {
+    pub blinded_node_id: secp256k1::PublicKey,
+    /// Instructions for the hop, readable only by it.
+    pub encrypted_recipient_data: Vec<u8>,
+}
+
+/// A route blinding path, as used in offers and invoices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlindedPath {
+    pub introduction_node: IntroductionNode,
+    /// The ephemeral key the introduction node needs to decrypt its hop data.
+    pub first_path_key: secp256k1::PublicKey,
+    pub hops: Vec<BlindedHop>,
+}
+
+/// What it costs to pay through a blinded path as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlindedPayInfo {
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    pub cltv_expiry_delta: u16,
+    pub htlc_minimum_msat: u64,
+    pub htlc_maximum_msat: u64,
+    pub features: Vec<u8>,
+}
+
+/// The shared secret with a hop and the key which encrypts its data.
+fn blinding_secrets(
+    secp: &Secp256k1<secp256k1::All>,
+    public_key: &secp256k1::PublicKey,
+    secret_key: &secp256k1::SecretKey,
+) -> ([u8; 32], [u8; 32]) {
+    let shared_secret = secp256k1::ecdh::SharedSecret::new(secp, public_key, secret_key);
+    let shared_secret = slice_to_array!(shared_secret[..], 32);
+    let rho = hmac_sha256(b"rho", &[&shared_secret[..]]);
+    (shared_secret, rho)
+}
+
+/// The factor the path key is multiplied by to get the next hop's path key.
+fn path_key_tweak(
+    secp: &Secp256k1<secp256k1::All>,
+    path_key: &secp256k1::PublicKey,
+    shared_secret: &[u8; 32],
+) -> Result<secp256k1::SecretKey, secp256k1::Error> {
+    let hash = sha256(&[&path_key.serialize()[..], &shared_secret[..]]);
+    secp256k1::SecretKey::from_slice(secp, &hash[..])
+}
+
+impl BlindedPath {
+    /// Builds a path through `hops`, each given as a real node id and the data for that node. The
+    /// first hop is the introduction node, whose id is left in the clear.
+    pub fn new(
+        secp: &Secp256k1<secp256k1::All>,
+        session_key: &secp256k1::SecretKey,
+        hops: &[(secp256k1::PublicKey, Vec<u8>)],
+    ) -> Result<BlindedPath, BlindedPathError> {
+        if hops.is_empty() {
+            return Err(BlindedPathError::NoHops);
+        }
+        if hops.len() > u8::max_value() as usize {
+            return Err(BlindedPathError::TooManyHops);
+        }
+        let first_path_key = secp256k1::PublicKey::from_secret_key(secp, session_key);
+        let mut path_secret = session_key.clone();
+        let mut blinded_hops = Vec::with_capacity(hops.len());
+        for (node_id, data) in hops {
+            let path_key = secp256k1::PublicKey::from_secret_key(secp, &path_secret);
+            let (shared_secret, rho) = blinding_secrets(secp, node_id, &path_secret);
+
+            let blinding = hmac_sha256(b"blinded_node_id", &[&shared_secret[..]]);
+            let blinding = {
+                secp256k1::SecretKey::from_slice(secp, &blinding[..])
+                .map_err(|_| BlindedPathError::InvalidSessionKey)?
+            };
+            let mut blinded_node_id = *node_id;
+            blinded_node_id.mul_assign(secp, &blinding).map_err(|_| BlindedPathError::InvalidSessionKey)?;
+
+            let mut encrypted_recipient_data = Vec::with_capacity(data.len() + 16);
+            let tag = unwrap!(chacha20_poly1305_aead::encrypt(&rho[..], &[0; 12], &[], data, &mut encrypted_recipient_data));
+            encrypted_recipient_data.extend_from_slice(&tag[..]);
+            blinded_hops.push(BlindedHop { blinded_node_id, encrypted_recipient_data });
+
+            let tweak = path_key_tweak(secp, &path_key, &shared_secret).map_err(|_| BlindedPathError::InvalidSessionKey)?;
+            path_secret.mul_assign(secp, &tweak).map_err(|_| BlindedPathError::InvalidSessionKey)?;
+        }
+        Ok(BlindedPath {
+            introduction_node: IntroductionNode::NodeId(hops[0].0),
+            first_path_key,
+            hops: blinded_hops,
+        })
+    }
+
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<BlindedPath, BlindedPathError> {
+        let introduction_node = match cursor.remaining().first() {
+            Some(0) | Some(1) => IntroductionNode::DirectedShortChannelId {
+                direction: cursor.read_u8()?,
+                short_channel_id: cursor.read_short_channel_id()?,
+            },
+            Some(2) | Some(3) => IntroductionNode::NodeId(cursor.read_pub_key()?),
+            Some(_) => return Err(BlindedPathError::InvalidIntroductionNode),
+            None => return Err(BlindedPathError::MsgTooShort(MsgTooShortError)),
+        };
+        let first_path_key = cursor.read_pub_key()?;
+        let num_hops = cursor.read_u8()?;
+        if num_hops == 0 {
+            return Err(BlindedPathError::NoHops);
+        }
+        let mut hops = Vec::with_capacity(num_hops as usize);
+        for _ in 0..num_hops {
+            hops.push(BlindedHop {
+                blinded_node_id: cursor.read_pub_key()?,
+                encrypted_recipient_data: cursor.read_u16_len_slice()?.to_vec(),
+            });
+        }
+        Ok(BlindedPath { introduction_node, first_path_key, hops })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        match self.introduction_node {
+            IntroductionNode::NodeId(ref node_id) => cursor.write_pub_key(node_id),
+            IntroductionNode::DirectedShortChannelId { direction, short_channel_id } => {
+                cursor.write_u8(direction);
+                cursor.write_short_channel_id(short_channel_id);
+            },
+        }
+        cursor.write_pub_key(&self.first_path_key);
+        cursor.write_u8(self.hops.len() as u8);
+        for hop in &self.hops {
+            cursor.write_pub_key(&hop.blinded_node_id);
+            cursor.write_u16_len_slice(&hop.encrypted_recipient_data);
+        }
+    }
+}
+
+/// Decrypts the data meant for us in a blinded hop, given the path key we were handed along with
+/// it. Returns the data and the path key to hand to the next hop.
+pub fn decrypt_blinded_hop(
+    secp: &Secp256k1<secp256k1::All>,
+    node_secret: &secp256k1::SecretKey,
+    path_key: &secp256k1::PublicKey,
+    encrypted_recipient_data: &[u8],
+) -> Result<(Vec<u8>, secp256k1::PublicKey), BlindedPathError> {
+    let len = encrypted_recipient_data.len();
+    if len < 16 {
+        return Err(BlindedPathError::DecryptionFailed);
+    }
+    let (shared_secret, rho) = blinding_secrets(secp, path_key, node_secret);
+    let mut data = Vec::with_capacity(len - 16);
+    let (ciphertext, tag) = encrypted_recipient_data.split_at(len - 16);
+    chacha20_poly1305_aead::decrypt(&rho[..], &[0; 12], &[], ciphertext, tag, &mut data)
+    .map_err(|_| BlindedPathError::DecryptionFailed)?;
+
+    let tweak = path_key_tweak(secp, path_key, &shared_secret).map_err(|_| BlindedPathError::InvalidPublicKey)?;
+    let mut next_path_key = *path_key;
+    next_path_key.mul_assign(secp, &tweak).map_err(|_| BlindedPathError::InvalidPublicKey)?;
+    Ok((data, next_path_key))
+}
+
+impl BlindedPayInfo {
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<BlindedPayInfo, MsgTooShortError> {
+        Ok(BlindedPayInfo {
+            fee_base_msat: cursor.read_u32()?,
+            fee_proportional_millionths: cursor.read_u32()?,
+            cltv_expiry_delta: cursor.read_u16()?,
+            htlc_minimum_msat: cursor.read_u64()?,
+            htlc_maximum_msat: cursor.read_u64()?,
+            features: cursor.read_u16_len_slice()?.to_vec(),
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u32(self.fee_base_msat);
+        cursor.write_u32(self.fee_proportional_millionths);
+        cursor.write_u16(self.cltv_expiry_delta);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u64(self.htlc_maximum_msat);
+        cursor.write_u16_len_slice(&self.features);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hops_decrypt_their_data() {
+        let secp = Secp256k1::new();
+        let hops: Vec<(secp256k1::PublicKey, Vec<u8>)> = (0..3).map(|i| {
+            (test_key(&secp, 0x21 + i).1, vec![i; 10 + i as usize])
+        }).collect();
+        let path = unwrap!(BlindedPath::new(&secp, &test_key(&secp, 0x30).0, &hops));
+        assert_eq!(path.introduction_node, IntroductionNode::NodeId(hops[0].0));
+
+        let mut path_key = path.first_path_key;
+        for (i, hop) in path.hops.iter().enumerate() {
+            assert!(hop.blinded_node_id != hops[i].0);
+            let (node_secret, _) = test_key(&secp, 0x21 + i as u8);
+            let res = decrypt_blinded_hop(&secp, &node_secret, &path_key, &hop.encrypted_recipient_data);
+            let (data, next_path_key) = unwrap!(res);
+            assert_eq!(data, hops[i].1);
+            // Nobody else can read the hop's data.
+            let (other_secret, _) = test_key(&secp, 0x40);
+            assert!(decrypt_blinded_hop(&secp, &other_secret, &path_key, &hop.encrypted_recipient_data).is_err());
+            path_key = next_path_key;
+        }
+    }
+
+    #[test]
+    fn path_round_trip() {
+        let secp = Secp256k1::new();
+        let hops = [(test_key(&secp, 0x21).1, vec![1, 2, 3])];
+        let mut path = unwrap!(BlindedPath::new(&secp, &test_key(&secp, 0x30).0, &hops));
+        path.introduction_node = IntroductionNode::DirectedShortChannelId {
+            direction: 1,
+            short_channel_id: test_short_channel_id(600),
+        };
+        let mut cursor = WriteCursor::new();
+        path.write_to_cursor(&mut cursor);
+        let bytes = cursor.into_bytes();
+        let mut cursor = ReadCursor::new(&bytes);
+        assert_eq!(unwrap!(BlindedPath::read_from_cursor(&mut cursor)), path);
+        assert!(cursor.is_empty());
+    }
+}
@@ -0,0 +1,2110 @@
+use super::*;
+
+/// The largest `to_self_delay` we'll accept from a peer: two weeks of blocks.
+pub const MAX_TO_SELF_DELAY: u16 = 2016;
+/// The most HTLCs either side of a commitment can have, so that it stays under the maximum
+/// standard transaction weight.
+pub const MAX_ACCEPTED_HTLCS: u16 = 483;
+/// The lowest dust limit which keeps every output type we create standard.
+pub const MIN_DUST_LIMIT_SATOSHIS: u64 = 354;
+/// The lowest feerate bitcoind will relay.
+pub const MIN_FEERATE_PER_KW: u32 = 253;
+/// Channels must be funded with less than this, as we don't support `option_support_large_channel`.
+pub const MAX_FUNDING_SATOSHIS: u64 = 1 << 24;
+/// The first byte of a channel's persisted state.
+pub const CHANNEL_STATE_VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChannelState {
+    /// Waiting for the funding transaction to be built with `interactive-tx`.
+    AwaitingFundingTx,
+    /// We've signed the peer's first commitment and are waiting on their signature for ours.
+    AwaitingInitialCommitment,
+    /// Both first commitments are signed. Waiting for the funding transaction to confirm.
+    AwaitingChannelReady,
+    Normal,
+    /// One or both sides have sent `shutdown`. No new HTLCs can be added, and the existing ones
+    /// are being settled.
+    ShuttingDown,
+    /// Both sides have sent `shutdown` and there are no HTLCs left. Negotiating the closing fee.
+    NegotiatingClose,
+    /// The closing transaction is fully signed.
+    Closed,
+    /// The peer violated the protocol, or we couldn't persist the channel. The latest local
+    /// commitment should be broadcast.
+    Failed,
+}
+
+impl ChannelState {
+    fn from_u8(val: u8) -> Option<ChannelState> {
+        let state = match val {
+            0 => ChannelState::AwaitingFundingTx,
+            1 => ChannelState::AwaitingInitialCommitment,
+            2 => ChannelState::AwaitingChannelReady,
+            3 => ChannelState::Normal,
+            4 => ChannelState::ShuttingDown,
+            5 => ChannelState::NegotiatingClose,
+            6 => ChannelState::Closed,
+            7 => ChannelState::Failed,
+            _ => return None,
+        };
+        Some(state)
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum ChannelError {
+    #[fail(display = "message is for a different channel")]
+    WrongChannelId,
+    #[fail(display = "can't do that while the channel is {:?}", state)]
+    WrongState {
+        state: ChannelState,
+    },
+    #[fail(display = "unexpected message of type {}", type_tag)]
+    UnexpectedMessage {
+        type_tag: u16,
+    },
+    #[fail(display = "to_self_delay {} is over the maximum of 2016", to_self_delay)]
+    ToSelfDelayTooLarge {
+        to_self_delay: u16,
+    },
+    #[fail(display = "max_accepted_htlcs {} is over the maximum of 483", max_accepted_htlcs)]
+    MaxAcceptedHtlcsTooLarge {
+        max_accepted_htlcs: u16,
+    },
+    #[fail(display = "dust_limit_satoshis {} is under the minimum of 354", dust_limit_satoshis)]
+    DustLimitTooLow {
+        dust_limit_satoshis: u64,
+    },
+    #[fail(display = "channel_reserve_satoshis {} is under the dust limit {}", channel_reserve_satoshis, dust_limit_satoshis)]
+    ChannelReserveBelowDustLimit {
+        channel_reserve_satoshis: u64,
+        dust_limit_satoshis: u64,
+    },
+    #[fail(display = "push_msat {} is more than the funding amount", push_msat)]
+    PushTooLarge {
+        push_msat: u64,
+    },
+    #[fail(display = "funding_satoshis {} is over the maximum of 16777215", funding_satoshis)]
+    FundingTooLarge {
+        funding_satoshis: u64,
+    },
+    #[fail(display = "channel_reserve_satoshis {} is more than the funding amount {}", channel_reserve_satoshis, funding_satoshis)]
+    ChannelReserveTooLarge {
+        channel_reserve_satoshis: u64,
+        funding_satoshis: u64,
+    },
+    #[fail(display = "funding output index {} doesn't fit in funding_created", vout)]
+    FundingOutputIndexTooLarge {
+        vout: u32,
+    },
+    #[fail(display = "channel is for a different chain")]
+    WrongChainHash,
+    #[fail(display = "open_channel has no channel_type")]
+    MissingChannelType,
+    #[fail(display = "accept_channel's channel_type doesn't match open_channel's")]
+    ChannelTypeMismatch,
+    #[fail(display = "{}", _0)]
+    UnsupportedChannelType(#[fail(cause)] UnsupportedChannelTypeError),
+    #[fail(display = "failed to derive commitment keys")]
+    KeyDerivation,
+    #[fail(display = "htlc amount {} is under the minimum of {}", amount_msat, htlc_minimum_msat)]
+    HtlcBelowMinimum {
+        amount_msat: u64,
+        htlc_minimum_msat: u64,
+    },
+    #[fail(display = "invalid cltv_expiry {}", cltv_expiry)]
+    InvalidCltvExpiry {
+        cltv_expiry: u32,
+    },
+    #[fail(display = "adding the htlc would exceed max_accepted_htlcs {}", max_accepted_htlcs)]
+    TooManyHtlcs {
+        max_accepted_htlcs: u16,
+    },
+    #[fail(display = "adding the htlc would exceed max_htlc_value_in_flight_msat {}", max_htlc_value_in_flight_msat)]
+    HtlcValueInFlightExceeded {
+        max_htlc_value_in_flight_msat: u64,
+    },
+    #[fail(display = "sender can't afford an htlc of {} msat while keeping its reserve", amount_msat)]
+    CannotAffordHtlc {
+        amount_msat: u64,
+    },
+    #[fail(display = "adding the htlc would exceed our dust exposure limit of {} msat", max_dust_htlc_exposure_msat)]
+    DustExposureExceeded {
+        max_dust_htlc_exposure_msat: u64,
+    },
+    #[fail(display = "feerate {} is under the minimum of 253", feerate_per_kw)]
+    FeerateTooLow {
+        feerate_per_kw: u32,
+    },
+    #[fail(display = "funder can't afford the commitment fee at feerate {}", feerate_per_kw)]
+    CannotAffordFee {
+        feerate_per_kw: u32,
+    },
+    #[fail(display = "peer added an htlc after sending shutdown")]
+    AddHtlcAfterShutdown,
+    #[fail(display = "can't send shutdown with updates the peer hasn't signed yet")]
+    UnsignedUpdatesPending,
+    #[fail(display = "invalid commitment signature")]
+    InvalidCommitmentSignature,
+    #[fail(display = "expected {} htlc signatures, got {}", expected, received)]
+    WrongHtlcSignatureCount {
+        expected: usize,
+        received: usize,
+    },
+    #[fail(display = "invalid signature for htlc transaction {}", index)]
+    InvalidHtlcSignature {
+        index: usize,
+    },
+    #[fail(display = "per-commitment secret does not match the revoked commitment")]
+    InvalidRevocationSecret,
+    #[fail(display = "invalid closing signature")]
+    InvalidClosingSignature,
+    #[fail(display = "{}", _0)]
+    UpdateQueue(#[fail(cause)] UpdateQueueError),
+    #[fail(display = "{}", _0)]
+    Shachain(#[fail(cause)] ShachainError),
+    #[fail(display = "{}", _0)]
+    Shutdown(#[fail(cause)] ShutdownError),
+    #[fail(display = "{}", _0)]
+    ClosingFee(#[fail(cause)] ClosingFeeError),
+    #[fail(display = "failed to persist channel: {}", _0)]
+    Persist(#[fail(cause)] ChannelPersistError),
+}
+
+impl ChannelError {
+    /// Whether the peer broke the protocol, which fails the channel. The rest are our own
+    /// failures, which leave the channel as it was.
+    pub fn is_protocol_violation(&self) -> bool {
+        match self {
+            ChannelError::WrongChannelId
+            | ChannelError::KeyDerivation
+            | ChannelError::FundingOutputIndexTooLarge { .. }
+            | ChannelError::Persist(..) => false,
+            _ => true,
+        }
+    }
+}
+
+impl From<UpdateQueueError> for ChannelError {
+    fn from(e: UpdateQueueError) -> ChannelError {
+        ChannelError::UpdateQueue(e)
+    }
+}
+
+impl From<ShachainError> for ChannelError {
+    fn from(e: ShachainError) -> ChannelError {
+        ChannelError::Shachain(e)
+    }
+}
+
+impl From<ClosingFeeError> for ChannelError {
+    fn from(e: ClosingFeeError) -> ChannelError {
+        ChannelError::ClosingFee(e)
+    }
+}
+
+/// Error restoring a channel from its persisted state.
+#[derive(Debug, Fail)]
+pub enum ChannelStateError {
+    #[fail(display = "channel state is truncated")]
+    Truncated,
+    #[fail(display = "malformed channel state")]
+    Malformed,
+    #[fail(display = "unsupported channel state version {}", version)]
+    UnsupportedVersion {
+        version: u8,
+    },
+    #[fail(display = "channel secrets don't belong to this channel")]
+    WrongSecrets,
+}
+
+impl From<MsgTooShortError> for ChannelStateError {
+    fn from(_err: MsgTooShortError) -> ChannelStateError {
+        ChannelStateError::Truncated
+    }
+}
+
+impl From<ReadFieldError> for ChannelStateError {
+    fn from(err: ReadFieldError) -> ChannelStateError {
+        match err {
+            ReadFieldError::MsgTooShort(..) => ChannelStateError::Truncated,
+            _ => ChannelStateError::Malformed,
+        }
+    }
+}
+
+impl From<ShachainError> for ChannelStateError {
+    fn from(err: ShachainError) -> ChannelStateError {
+        match err {
+            ShachainError::MsgTooShort(..) => ChannelStateError::Truncated,
+            _ => ChannelStateError::Malformed,
+        }
+    }
+}
+
+impl From<TransactionError> for ChannelStateError {
+    fn from(err: TransactionError) -> ChannelStateError {
+        match err {
+            TransactionError::MsgTooShort(..) => ChannelStateError::Truncated,
+            _ => ChannelStateError::Malformed,
+        }
+    }
+}
+
+/// The limits one side sets in `open_channel2` or `accept_channel2`. The dust limit applies to
+/// that side's own commitments, the rest constrain the HTLCs the other side can offer it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelLimits {
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub htlc_minimum_msat: u64,
+    /// How long the other side has to wait to claim its own funds after a unilateral close.
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+}
+
+impl ChannelLimits {
+    fn check(&self) -> Result<(), ChannelError> {
+        if self.to_self_delay > MAX_TO_SELF_DELAY {
+            return Err(ChannelError::ToSelfDelayTooLarge { to_self_delay: self.to_self_delay });
+        }
+        if self.max_accepted_htlcs > MAX_ACCEPTED_HTLCS {
+            return Err(ChannelError::MaxAcceptedHtlcsTooLarge { max_accepted_htlcs: self.max_accepted_htlcs });
+        }
+        if self.dust_limit_satoshis < MIN_DUST_LIMIT_SATOSHIS {
+            return Err(ChannelError::DustLimitTooLow { dust_limit_satoshis: self.dust_limit_satoshis });
+        }
+        Ok(())
+    }
+
+    fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u64(self.dust_limit_satoshis);
+        cursor.write_u64(self.max_htlc_value_in_flight_msat);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u16(self.to_self_delay);
+        cursor.write_u16(self.max_accepted_htlcs);
+    }
+
+    fn read_from_cursor(cursor: &mut ReadCursor) -> Result<ChannelLimits, MsgTooShortError> {
+        Ok(ChannelLimits {
+            dust_limit_satoshis: cursor.read_u64()?,
+            max_htlc_value_in_flight_msat: cursor.read_u64()?,
+            htlc_minimum_msat: cursor.read_u64()?,
+            to_self_delay: cursor.read_u16()?,
+            max_accepted_htlcs: cursor.read_u16()?,
+        })
+    }
+}
+
+/// The `channel_id` of a dual-funded channel: `SHA256(lesser-revocation-basepoint ||
+/// greater-revocation-basepoint)`.
+pub fn dual_funded_channel_id(
+    first_revocation_basepoint: &secp256k1::PublicKey,
+    second_revocation_basepoint: &secp256k1::PublicKey,
+) -> ChannelId {
+    let first = first_revocation_basepoint.serialize();
+    let second = second_revocation_basepoint.serialize();
+    if first[..] < second[..] {
+        ChannelId(sha256(&[&first[..], &second[..]]))
+    } else {
+        ChannelId(sha256(&[&second[..], &first[..]]))
+    }
+}
+
+/// A dual-funded channel's reserve is 1% of the total funding, but never below the dust limit
+/// of the side keeping it.
+pub fn dual_funded_channel_reserve_satoshis(funding_satoshis: u64, dust_limit_satoshis: u64) -> u64 {
+    cmp::max(funding_satoshis / 100, dust_limit_satoshis)
+}
+
+/// The `channel_id` of a channel opened with `open_channel`: the funding txid with the output
+/// index XORed into its last two bytes.
+pub fn single_funded_channel_id(funding_outpoint: &OutPoint) -> ChannelId {
+    let mut channel_id = funding_outpoint.txid;
+    channel_id[30] ^= (funding_outpoint.vout >> 8) as u8;
+    channel_id[31] ^= funding_outpoint.vout as u8;
+    ChannelId(channel_id)
+}
+
+/// The channel type from the opening messages. The opener has to set one, and the accepter has
+/// to echo it back.
+fn negotiate_channel_type(open: &Option<Vec<u8>>, accept: &Option<Vec<u8>>) -> Result<ChannelType, ChannelError> {
+    let channel_type = match open {
+        Some(channel_type) => channel_type,
+        None => return Err(ChannelError::MissingChannelType),
+    };
+    if accept.as_ref() != Some(channel_type) {
+        return Err(ChannelError::ChannelTypeMismatch);
+    }
+    ChannelType::from_bytes(channel_type).map_err(ChannelError::UnsupportedChannelType)
+}
+
+fn write_optional_script(cursor: &mut WriteCursor, script: &Option<Vec<u8>>) {
+    match script {
+        Some(script) => {
+            cursor.write_u8(1);
+            cursor.write_u16_len_slice(script);
+        },
+        None => cursor.write_u8(0),
+    }
+}
+
+fn read_optional_script(cursor: &mut ReadCursor) -> Result<Option<Vec<u8>>, ChannelStateError> {
+    match cursor.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(cursor.read_u16_len_slice()?.to_vec())),
+        _ => Err(ChannelStateError::Malformed),
+    }
+}
+
+fn write_optional_tx(cursor: &mut WriteCursor, tx: &Option<Transaction>) {
+    match tx {
+        Some(tx) => {
+            cursor.write_u8(1);
+            tx.write_to_cursor(cursor);
+        },
+        None => cursor.write_u8(0),
+    }
+}
+
+fn read_optional_tx(cursor: &mut ReadCursor) -> Result<Option<Transaction>, ChannelStateError> {
+    match cursor.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(Transaction::read_from_cursor(cursor)?)),
+        _ => Err(ChannelStateError::Malformed),
+    }
+}
+
+fn other_side(origin: UpdateOrigin) -> UpdateOrigin {
+    match origin {
+        UpdateOrigin::Local => UpdateOrigin::Remote,
+        UpdateOrigin::Remote => UpdateOrigin::Local,
+    }
+}
+
+/// The total of the HTLCs which are too small to get an output in a commitment. They go to
+/// miners if the commitment is broadcast.
+fn dust_exposure_msat(params: &CommitmentParams, state: &CommitmentState) -> u64 {
+    state.htlcs.iter()
+    .filter(|htlc| is_htlc_trimmed(htlc, state.feerate_per_kw, params.dust_limit_satoshis, params.anchors))
+    .map(|htlc| htlc.amount_msat)
+    .sum()
+}
+
+/// What the funder pays out of its balance for a commitment: the fee, plus the anchors if any.
+fn funder_fee_msat(params: &CommitmentParams, state: &CommitmentState) -> u64 {
+    let untrimmed = {
+        state.htlcs.iter()
+        .filter(|htlc| !is_htlc_trimmed(htlc, state.feerate_per_kw, params.dust_limit_satoshis, params.anchors))
+        .count()
+    };
+    let mut fee_satoshis = commitment_fee_satoshis(state.feerate_per_kw, untrimmed, params.anchors);
+    if params.anchors {
+        fee_satoshis += 2 * ANCHOR_OUTPUT_SATOSHIS;
+    }
+    fee_satoshis * 1000
+}
+
+fn funding_msat(funding_satoshis: u64) -> Result<u64, ChannelError> {
+    funding_satoshis.checked_mul(1000).ok_or(ChannelError::FundingTooLarge { funding_satoshis })
+}
+
+/// Everything the opening messages settle, from our side.
+struct ChannelSetup {
+    channel_id: ChannelId,
+    dual_funded: bool,
+    local_is_funder: bool,
+    channel_type: ChannelType,
+    funding_satoshis: u64,
+    feerate_per_kw: u32,
+    local_limits: ChannelLimits,
+    remote_limits: ChannelLimits,
+    remote_basepoints: ChannelBasepoints,
+    local_reserve_satoshis: u64,
+    remote_reserve_satoshis: u64,
+    local_msat: u64,
+    remote_msat: u64,
+    remote_current_point: secp256k1::PublicKey,
+    remote_next_point: secp256k1::PublicKey,
+    remote_upfront_shutdown_script: Option<Vec<u8>>,
+}
+
+/// A channel from the end of `accept_channel` or `accept_channel2` until it's closed.
+///
+/// Messages from the peer go through `handle_msg`, which checks them against the channel's limits
+/// and returns any replies. A protocol violation moves the channel to `ChannelState::Failed`,
+/// after which our latest commitment should be broadcast. Our own updates go through `add_htlc`,
+/// `fulfill_htlc` and friends, which refuse anything the peer would reject without touching the
+/// channel state.
+///
+/// Replies carrying a `commitment_signed` or `revoke_and_ack` commit us to a new state, so every
+/// method which can produce one takes a `ChannelPersister` and stores the channel before
+/// returning. If that fails the channel is `Failed` and nothing should be sent.
+pub struct Channel {
+    channel_id: ChannelId,
+    state: ChannelState,
+    /// Opened with `open_channel2`, rather than `open_channel`.
+    dual_funded: bool,
+    local_is_funder: bool,
+    channel_type: ChannelType,
+    funding_satoshis: u64,
+    secrets: ChannelSecrets,
+    local_basepoints: ChannelBasepoints,
+    remote_basepoints: ChannelBasepoints,
+    local_limits: ChannelLimits,
+    remote_limits: ChannelLimits,
+    local_reserve_satoshis: u64,
+    remote_reserve_satoshis: u64,
+    max_dust_htlc_exposure_msat: u64,
+    remote_upfront_shutdown_script: Option<Vec<u8>>,
+    local_params: Option<CommitmentParams>,
+    remote_params: Option<CommitmentParams>,
+    queue: UpdateQueue,
+    /// Each side's balance, not counting HTLCs which are still in the update queue.
+    settled_local_msat: u64,
+    settled_remote_msat: u64,
+    /// The peer's per-commitment point for its current commitment, and for the one after.
+    remote_current_point: secp256k1::PublicKey,
+    remote_next_point: secp256k1::PublicKey,
+    /// Whether each side has sent `channel_ready`. The channel is `Normal` once both have.
+    channel_ready_sent: bool,
+    channel_ready_received: bool,
+    remote_secrets: ShachainStore,
+    local_commitment_tx: Option<Transaction>,
+    htlcs_to_fail: Vec<u64>,
+    local_shutdown_script: Option<Vec<u8>>,
+    remote_shutdown_script: Option<Vec<u8>>,
+    closing_fee_range: Option<FeeRange>,
+    closing_negotiator: Option<ClosingFeeNegotiator>,
+    closing_tx: Option<Transaction>,
+}
+
+impl Channel {
+    /// Sets up a channel once `open_channel2` and `accept_channel2` have been exchanged. The
+    /// opener is the funder and pays the commitment fees.
+    ///
+    /// `max_dust_htlc_exposure_msat` bounds the total of trimmed HTLCs in either commitment. We
+    /// never add an HTLC which would go over it, and HTLCs the peer adds which do so end up in
+    /// `htlcs_to_fail`.
+    pub fn new(
+        secp: &Secp256k1<secp256k1::All>,
+        secrets: ChannelSecrets,
+        chain_hash: &ChainHash,
+        open: &OpenChannel2Msg,
+        accept: &AcceptChannel2Msg,
+        local_is_opener: bool,
+        max_dust_htlc_exposure_msat: u64,
+    ) -> Result<Channel, ChannelError> {
+        if open.chain_hash != *chain_hash {
+            return Err(ChannelError::WrongChainHash);
+        }
+        let channel_type = negotiate_channel_type(&open.channel_type, &accept.channel_type)?;
+        let opener_limits = ChannelLimits {
+            dust_limit_satoshis: open.dust_limit_satoshis,
+            max_htlc_value_in_flight_msat: open.max_htlc_value_in_flight_msat,
+            htlc_minimum_msat: open.htlc_minimum_msat,
+            to_self_delay: open.to_self_delay,
+            max_accepted_htlcs: open.max_accepted_htlcs,
+        };
+        let accepter_limits = ChannelLimits {
+            dust_limit_satoshis: accept.dust_limit_satoshis,
+            max_htlc_value_in_flight_msat: accept.max_htlc_value_in_flight_msat,
+            htlc_minimum_msat: accept.htlc_minimum_msat,
+            to_self_delay: accept.to_self_delay,
+            max_accepted_htlcs: accept.max_accepted_htlcs,
+        };
+        let opener_basepoints = ChannelBasepoints {
+            funding_pubkey: open.funding_pubkey,
+            revocation_basepoint: open.revocation_basepoint,
+            payment_basepoint: open.payment_basepoint,
+            delayed_payment_basepoint: open.delayed_payment_basepoint,
+            htlc_basepoint: open.htlc_basepoint,
+        };
+        let accepter_basepoints = ChannelBasepoints {
+            funding_pubkey: accept.funding_pubkey,
+            revocation_basepoint: accept.revocation_basepoint,
+            payment_basepoint: accept.payment_basepoint,
+            delayed_payment_basepoint: accept.delayed_payment_basepoint,
+            htlc_basepoint: accept.htlc_basepoint,
+        };
+
+        let (local_limits, remote_limits, remote_basepoints) = if local_is_opener {
+            (opener_limits, accepter_limits, accepter_basepoints)
+        } else {
+            (accepter_limits, opener_limits, opener_basepoints)
+        };
+        let (local_funding_satoshis, remote_funding_satoshis) = if local_is_opener {
+            (open.funding_satoshis, accept.funding_satoshis)
+        } else {
+            (accept.funding_satoshis, open.funding_satoshis)
+        };
+        let (remote_current_point, remote_next_point, remote_upfront_shutdown_script) = if local_is_opener {
+            (accept.first_per_commitment_point, accept.second_per_commitment_point, accept.upfront_shutdown_script.clone())
+        } else {
+            (open.first_per_commitment_point, open.second_per_commitment_point, open.upfront_shutdown_script.clone())
+        };
+
+        let funding_satoshis = match local_funding_satoshis.checked_add(remote_funding_satoshis) {
+            Some(funding_satoshis) => funding_satoshis,
+            None => return Err(ChannelError::FundingTooLarge { funding_satoshis: u64::max_value() }),
+        };
+        let setup = ChannelSetup {
+            channel_id: dual_funded_channel_id(&open.revocation_basepoint, &accept.revocation_basepoint),
+            dual_funded: true,
+            local_is_funder: local_is_opener,
+            channel_type,
+            funding_satoshis,
+            feerate_per_kw: open.commitment_feerate_perkw,
+            local_reserve_satoshis: dual_funded_channel_reserve_satoshis(funding_satoshis, local_limits.dust_limit_satoshis),
+            remote_reserve_satoshis: dual_funded_channel_reserve_satoshis(funding_satoshis, remote_limits.dust_limit_satoshis),
+            local_limits,
+            remote_limits,
+            remote_basepoints,
+            local_msat: funding_msat(local_funding_satoshis)?,
+            remote_msat: funding_msat(remote_funding_satoshis)?,
+            remote_current_point,
+            remote_next_point,
+            remote_upfront_shutdown_script,
+        };
+        Channel::from_setup(secp, secrets, setup, max_dust_htlc_exposure_msat)
+    }
+
+    /// Sets up a channel once `open_channel` and `accept_channel` have been exchanged. The
+    /// opener is the funder: it goes on with `funding_created`, and the accepter answers the
+    /// peer's `funding_created` in `handle_msg`. Until then the channel goes by the temporary
+    /// channel id.
+    ///
+    /// The peer's second per-commitment point only comes with its `channel_ready`.
+    pub fn new_single_funded(
+        secp: &Secp256k1<secp256k1::All>,
+        secrets: ChannelSecrets,
+        chain_hash: &ChainHash,
+        open: &OpenChannelMsg,
+        accept: &AcceptChannelMsg,
+        local_is_opener: bool,
+        max_dust_htlc_exposure_msat: u64,
+    ) -> Result<Channel, ChannelError> {
+        if open.chain_hash != *chain_hash {
+            return Err(ChannelError::WrongChainHash);
+        }
+        let channel_type = negotiate_channel_type(&open.channel_type, &accept.channel_type)?;
+        let funding_msat = funding_msat(open.funding_satoshis)?;
+        if open.push_msat > funding_msat {
+            return Err(ChannelError::PushTooLarge { push_msat: open.push_msat });
+        }
+        let opener_limits = ChannelLimits {
+            dust_limit_satoshis: open.dust_limit_satoshis,
+            max_htlc_value_in_flight_msat: open.max_htlc_value_in_flight_msat,
+            htlc_minimum_msat: open.htlc_minimum_msat,
+            to_self_delay: open.to_self_delay,
+            max_accepted_htlcs: open.max_accepted_htlcs,
+        };
+        let accepter_limits = ChannelLimits {
+            dust_limit_satoshis: accept.dust_limit_satoshis,
+            max_htlc_value_in_flight_msat: accept.max_htlc_value_in_flight_msat,
+            htlc_minimum_msat: accept.htlc_minimum_msat,
+            to_self_delay: accept.to_self_delay,
+            max_accepted_htlcs: accept.max_accepted_htlcs,
+        };
+        let opener_basepoints = ChannelBasepoints {
+            funding_pubkey: open.funding_pubkey,
+            revocation_basepoint: open.revocation_basepoint,
+            payment_basepoint: open.payment_basepoint,
+            delayed_payment_basepoint: open.delayed_payment_basepoint,
+            htlc_basepoint: open.htlc_basepoint,
+        };
+        let accepter_basepoints = ChannelBasepoints {
+            funding_pubkey: accept.funding_pubkey,
+            revocation_basepoint: accept.revocation_basepoint,
+            payment_basepoint: accept.payment_basepoint,
+            delayed_payment_basepoint: accept.delayed_payment_basepoint,
+            htlc_basepoint: accept.htlc_basepoint,
+        };
+
+        // Each side's reserve is set by the other one.
+        let opener_msat = funding_msat - open.push_msat;
+        let (local_limits, remote_limits, remote_basepoints) = if local_is_opener {
+            (opener_limits, accepter_limits, accepter_basepoints)
+        } else {
+            (accepter_limits, opener_limits, opener_basepoints)
+        };
+        let (local_reserve_satoshis, remote_reserve_satoshis, local_msat, remote_msat) = if local_is_opener {
+            (accept.channel_reserve_satoshis, open.channel_reserve_satoshis, opener_msat, open.push_msat)
+        } else {
+            (open.channel_reserve_satoshis, accept.channel_reserve_satoshis, open.push_msat, opener_msat)
+        };
+        let (remote_first_point, remote_upfront_shutdown_script) = if local_is_opener {
+            (accept.first_per_commitment_point, accept.upfront_shutdown_script.clone())
+        } else {
+            (open.first_per_commitment_point, open.upfront_shutdown_script.clone())
+        };
+        for &(channel_reserve_satoshis, dust_limit_satoshis) in &[
+            (local_reserve_satoshis, local_limits.dust_limit_satoshis),
+            (remote_reserve_satoshis, remote_limits.dust_limit_satoshis),
+        ] {
+            if channel_reserve_satoshis < dust_limit_satoshis {
+                return Err(ChannelError::ChannelReserveBelowDustLimit { channel_reserve_satoshis, dust_limit_satoshis });
+            }
+        }
+
+        let setup = ChannelSetup {
+            channel_id: open.temporary_channel_id,
+            dual_funded: false,
+            local_is_funder: local_is_opener,
+            channel_type,
+            funding_satoshis: open.funding_satoshis,
+            feerate_per_kw: open.feerate_per_kw,
+            local_limits,
+            remote_limits,
+            remote_basepoints,
+            local_reserve_satoshis,
+            remote_reserve_satoshis,
+            local_msat,
+            remote_msat,
+            remote_current_point: remote_first_point,
+            remote_next_point: remote_first_point,
+            remote_upfront_shutdown_script,
+        };
+        Channel::from_setup(secp, secrets, setup, max_dust_htlc_exposure_msat)
+    }
+
+    /// Checks what the peer asked for, that the reserves fit in the channel, and that the funder
+    /// can pay for the first commitment while keeping its reserve.
+    fn from_setup(
+        secp: &Secp256k1<secp256k1::All>,
+        secrets: ChannelSecrets,
+        setup: ChannelSetup,
+        max_dust_htlc_exposure_msat: u64,
+    ) -> Result<Channel, ChannelError> {
+        setup.remote_limits.check()?;
+        if setup.funding_satoshis >= MAX_FUNDING_SATOSHIS {
+            return Err(ChannelError::FundingTooLarge { funding_satoshis: setup.funding_satoshis });
+        }
+        for &channel_reserve_satoshis in &[setup.local_reserve_satoshis, setup.remote_reserve_satoshis] {
+            if channel_reserve_satoshis > setup.funding_satoshis {
+                return Err(ChannelError::ChannelReserveTooLarge {
+                    channel_reserve_satoshis,
+                    funding_satoshis: setup.funding_satoshis,
+                });
+            }
+        }
+        if setup.feerate_per_kw < MIN_FEERATE_PER_KW {
+            return Err(ChannelError::FeerateTooLow { feerate_per_kw: setup.feerate_per_kw });
+        }
+        // An empty script means the peer didn't commit to one. Anything else has to be a script
+        // it could close to, which also keeps it short enough to persist.
+        if let Some(ref script) = setup.remote_upfront_shutdown_script {
+            if !script.is_empty() && !is_valid_shutdown_script(script) {
+                return Err(ChannelError::Shutdown(ShutdownError::NonStandardScript));
+            }
+        }
+        let anchors = setup.channel_type.anchors();
+        let mut fee_satoshis = commitment_fee_satoshis(setup.feerate_per_kw, 0, anchors);
+        if anchors {
+            fee_satoshis += 2 * ANCHOR_OUTPUT_SATOSHIS;
+        }
+        let (funder_msat, funder_reserve_satoshis) = if setup.local_is_funder {
+            (setup.local_msat, setup.local_reserve_satoshis)
+        } else {
+            (setup.remote_msat, setup.remote_reserve_satoshis)
+        };
+        let required_msat = fee_satoshis.checked_add(funder_reserve_satoshis).and_then(|sat| sat.checked_mul(1000));
+        if required_msat.map_or(true, |required_msat| funder_msat < required_msat) {
+            return Err(ChannelError::CannotAffordFee { feerate_per_kw: setup.feerate_per_kw });
+        }
+
+        Ok(Channel {
+            channel_id: setup.channel_id,
+            state: ChannelState::AwaitingFundingTx,
+            dual_funded: setup.dual_funded,
+            local_is_funder: setup.local_is_funder,
+            channel_type: setup.channel_type,
+            funding_satoshis: setup.funding_satoshis,
+            local_basepoints: secrets.basepoints(secp),
+            secrets,
+            remote_basepoints: setup.remote_basepoints,
+            local_limits: setup.local_limits,
+            remote_limits: setup.remote_limits,
+            local_reserve_satoshis: setup.local_reserve_satoshis,
+            remote_reserve_satoshis: setup.remote_reserve_satoshis,
+            max_dust_htlc_exposure_msat,
+            remote_upfront_shutdown_script: setup.remote_upfront_shutdown_script,
+            local_params: None,
+            remote_params: None,
+            queue: UpdateQueue::new(setup.channel_id, setup.local_is_funder, setup.feerate_per_kw),
+            settled_local_msat: setup.local_msat,
+            settled_remote_msat: setup.remote_msat,
+            remote_current_point: setup.remote_current_point,
+            remote_next_point: setup.remote_next_point,
+            channel_ready_sent: false,
+            channel_ready_received: false,
+            remote_secrets: ShachainStore::new(),
+            local_commitment_tx: None,
+            htlcs_to_fail: Vec::new(),
+            local_shutdown_script: None,
+            remote_shutdown_script: None,
+            closing_fee_range: None,
+            closing_negotiator: None,
+            closing_tx: None,
+        })
+    }
+
+    pub fn channel_id(&self) -> &ChannelId {
+        &self.channel_id
+    }
+
+    pub fn state(&self) -> ChannelState {
+        self.state
+    }
+
+    pub fn update_queue(&self) -> &UpdateQueue {
+        &self.queue
+    }
+
+    /// The per-commitment secrets the peer has revealed, which let us punish it for broadcasting
+    /// a revoked commitment.
+    pub fn remote_per_commitment_secrets(&self) -> &ShachainStore {
+        &self.remote_secrets
+    }
+
+    /// Our balance in our latest commitment, not counting HTLCs.
+    pub fn local_balance_msat(&self) -> u64 {
+        self.balances_msat(UpdateState::in_local_commitment).0
+    }
+
+    /// The peer's balance in our latest commitment, not counting HTLCs.
+    pub fn remote_balance_msat(&self) -> u64 {
+        self.balances_msat(UpdateState::in_local_commitment).1
+    }
+
+    /// HTLCs the peer added which we should fail back rather than forward, since they took our
+    /// dust exposure over the limit.
+    pub fn htlcs_to_fail(&self) -> &[u64] {
+        &self.htlcs_to_fail
+    }
+
+    /// Our latest commitment transaction, fully signed. This is what to broadcast if the channel
+    /// fails.
+    pub fn local_commitment_tx(&self) -> Option<&Transaction> {
+        self.local_commitment_tx.as_ref()
+    }
+
+    /// The fully signed closing transaction, once the channel is `Closed`.
+    pub fn closing_tx(&self) -> Option<&Transaction> {
+        self.closing_tx.as_ref()
+    }
+
+    /// Restores a channel from `to_bytes`. The secrets aren't part of the persisted state, so they
+    /// have to be passed back in.
+    ///
+    /// Updates which weren't covered by a `commitment_signed` are forgotten, as after
+    /// `disconnect`. A close which was being negotiated starts over with a fresh `closing_signed`.
+    pub fn from_bytes(
+        secp: &Secp256k1<secp256k1::All>,
+        secrets: ChannelSecrets,
+        bytes: &[u8],
+    ) -> Result<Channel, ChannelStateError> {
+        let mut cursor = ReadCursor::new(bytes);
+        let version = cursor.read_u8()?;
+        match version {
+            CHANNEL_STATE_VERSION => (),
+            // Older formats get upgraded here as the format changes.
+            _ => return Err(ChannelStateError::UnsupportedVersion { version }),
+        }
+        let channel_id = cursor.read_channel_id()?;
+        let state = match ChannelState::from_u8(cursor.read_u8()?) {
+            Some(state) => state,
+            None => return Err(ChannelStateError::Malformed),
+        };
+        let dual_funded = cursor.read_u8()? != 0;
+        let local_is_funder = cursor.read_u8()? != 0;
+        let channel_type = match ChannelType::from_u8(cursor.read_u8()?) {
+            Some(channel_type) => channel_type,
+            None => return Err(ChannelStateError::Malformed),
+        };
+        let funding_satoshis = cursor.read_u64()?;
+        let local_limits = ChannelLimits::read_from_cursor(&mut cursor)?;
+        let remote_limits = ChannelLimits::read_from_cursor(&mut cursor)?;
+        let local_reserve_satoshis = cursor.read_u64()?;
+        let remote_reserve_satoshis = cursor.read_u64()?;
+        let max_dust_htlc_exposure_msat = cursor.read_u64()?;
+        let local_funding_pubkey = cursor.read_pub_key()?;
+        let remote_basepoints = ChannelBasepoints {
+            funding_pubkey: cursor.read_pub_key()?,
+            revocation_basepoint: cursor.read_pub_key()?,
+            payment_basepoint: cursor.read_pub_key()?,
+            delayed_payment_basepoint: cursor.read_pub_key()?,
+            htlc_basepoint: cursor.read_pub_key()?,
+        };
+        let remote_upfront_shutdown_script = read_optional_script(&mut cursor)?;
+        let funding_outpoint = match cursor.read_u8()? {
+            0 => None,
+            1 => Some(OutPoint::read_from_cursor(&mut cursor)?),
+            _ => return Err(ChannelStateError::Malformed),
+        };
+        let queue = UpdateQueue::read_from_cursor(&mut cursor)?;
+        let settled_local_msat = cursor.read_u64()?;
+        let settled_remote_msat = cursor.read_u64()?;
+        let remote_current_point = cursor.read_pub_key()?;
+        let remote_next_point = cursor.read_pub_key()?;
+        let channel_ready_sent = cursor.read_u8()? != 0;
+        let channel_ready_received = cursor.read_u8()? != 0;
+        let remote_secrets = ShachainStore::read_from_cursor(&mut cursor)?;
+        let local_commitment_tx = read_optional_tx(&mut cursor)?;
+        let num_htlcs_to_fail = cursor.read_u32()?;
+        let mut htlcs_to_fail = Vec::new();
+        for _ in 0..num_htlcs_to_fail {
+            htlcs_to_fail.push(cursor.read_u64()?);
+        }
+        let local_shutdown_script = read_optional_script(&mut cursor)?;
+        let remote_shutdown_script = read_optional_script(&mut cursor)?;
+        let closing_fee_range = match cursor.read_u8()? {
+            0 => None,
+            1 => Some(FeeRange { min_fee_satoshis: cursor.read_u64()?, max_fee_satoshis: cursor.read_u64()? }),
+            _ => return Err(ChannelStateError::Malformed),
+        };
+        let closing_tx = read_optional_tx(&mut cursor)?;
+        if !cursor.is_empty() || *queue.channel_id() != channel_id || queue.is_funder() != local_is_funder {
+            return Err(ChannelStateError::Malformed);
+        }
+        let funded = state != ChannelState::AwaitingFundingTx && state != ChannelState::Failed;
+        if funded && funding_outpoint.is_none() {
+            return Err(ChannelStateError::Malformed);
+        }
+
+        let local_basepoints = secrets.basepoints(secp);
+        if local_basepoints.funding_pubkey != local_funding_pubkey {
+            return Err(ChannelStateError::WrongSecrets);
+        }
+        // A single-funded channel only gets its real id once the funding transaction is known.
+        let expected_channel_id = match funding_outpoint {
+            _ if dual_funded => Some(dual_funded_channel_id(
+                &local_basepoints.revocation_basepoint,
+                &remote_basepoints.revocation_basepoint,
+            )),
+            Some(ref funding_outpoint) => Some(single_funded_channel_id(funding_outpoint)),
+            None => None,
+        };
+        if expected_channel_id.map_or(false, |expected| expected != channel_id) {
+            return Err(ChannelStateError::Malformed);
+        }
+
+        let mut channel = Channel {
+            channel_id,
+            state,
+            dual_funded,
+            local_is_funder,
+            channel_type,
+            funding_satoshis,
+            secrets,
+            local_basepoints,
+            remote_basepoints,
+            local_reserve_satoshis,
+            remote_reserve_satoshis,
+            local_limits,
+            remote_limits,
+            max_dust_htlc_exposure_msat,
+            remote_upfront_shutdown_script,
+            local_params: None,
+            remote_params: None,
+            queue,
+            settled_local_msat,
+            settled_remote_msat,
+            remote_current_point,
+            remote_next_point,
+            channel_ready_sent,
+            channel_ready_received,
+            remote_secrets,
+            local_commitment_tx,
+            htlcs_to_fail,
+            local_shutdown_script,
+            remote_shutdown_script,
+            closing_fee_range,
+            closing_negotiator: None,
+            closing_tx,
+        };
+        if let Some(funding_outpoint) = funding_outpoint {
+            channel.set_funding_outpoint(funding_outpoint);
+        }
+        if state == ChannelState::NegotiatingClose {
+            let fee_range = match closing_fee_range {
+                Some(fee_range) => fee_range,
+                None => return Err(ChannelStateError::Malformed),
+            };
+            channel.closing_negotiator = Some(ClosingFeeNegotiator::new(channel_id, local_is_funder, fee_range));
+        }
+        channel.disconnect();
+        Ok(channel)
+    }
+
+    /// Everything needed to pick the channel back up after a restart, except the secrets. Starts
+    /// with `CHANNEL_STATE_VERSION`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = WriteCursor::new();
+        cursor.write_u8(CHANNEL_STATE_VERSION);
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u8(self.state as u8);
+        cursor.write_u8(self.dual_funded as u8);
+        cursor.write_u8(self.local_is_funder as u8);
+        cursor.write_u8(self.channel_type.to_u8());
+        cursor.write_u64(self.funding_satoshis);
+        self.local_limits.write_to_cursor(&mut cursor);
+        self.remote_limits.write_to_cursor(&mut cursor);
+        cursor.write_u64(self.local_reserve_satoshis);
+        cursor.write_u64(self.remote_reserve_satoshis);
+        cursor.write_u64(self.max_dust_htlc_exposure_msat);
+        cursor.write_pub_key(&self.local_basepoints.funding_pubkey);
+        cursor.write_pub_key(&self.remote_basepoints.funding_pubkey);
+        cursor.write_pub_key(&self.remote_basepoints.revocation_basepoint);
+        cursor.write_pub_key(&self.remote_basepoints.payment_basepoint);
+        cursor.write_pub_key(&self.remote_basepoints.delayed_payment_basepoint);
+        cursor.write_pub_key(&self.remote_basepoints.htlc_basepoint);
+        write_optional_script(&mut cursor, &self.remote_upfront_shutdown_script);
+        match self.local_params {
+            Some(ref params) => {
+                cursor.write_u8(1);
+                params.funding_outpoint.write_to_cursor(&mut cursor);
+            },
+            None => cursor.write_u8(0),
+        }
+        self.queue.write_to_cursor(&mut cursor);
+        cursor.write_u64(self.settled_local_msat);
+        cursor.write_u64(self.settled_remote_msat);
+        cursor.write_pub_key(&self.remote_current_point);
+        cursor.write_pub_key(&self.remote_next_point);
+        cursor.write_u8(self.channel_ready_sent as u8);
+        cursor.write_u8(self.channel_ready_received as u8);
+        self.remote_secrets.write_to_cursor(&mut cursor);
+        write_optional_tx(&mut cursor, &self.local_commitment_tx);
+        cursor.write_u32(self.htlcs_to_fail.len() as u32);
+        for id in &self.htlcs_to_fail {
+            cursor.write_u64(*id);
+        }
+        write_optional_script(&mut cursor, &self.local_shutdown_script);
+        write_optional_script(&mut cursor, &self.remote_shutdown_script);
+        match self.closing_fee_range {
+            Some(fee_range) => {
+                cursor.write_u8(1);
+                cursor.write_u64(fee_range.min_fee_satoshis);
+                cursor.write_u64(fee_range.max_fee_satoshis);
+            },
+            None => cursor.write_u8(0),
+        }
+        write_optional_tx(&mut cursor, &self.closing_tx);
+        cursor.into_bytes().to_vec()
+    }
+
+    /// Called once `interactive-tx` has produced the funding transaction. Returns our signature
+    /// for the peer's first commitment.
+    pub fn funding_tx_constructed<P>(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        persister: &mut P,
+        funding_outpoint: OutPoint,
+    ) -> Result<CommitmentSignedMsg, ChannelError>
+    where
+        P: ChannelPersister + ?Sized,
+    {
+        self.check_state(&[ChannelState::AwaitingFundingTx])?;
+        if !self.dual_funded {
+            return Err(ChannelError::WrongState { state: self.state });
+        }
+        self.set_funding_outpoint(funding_outpoint);
+        let state = self.commitment_state(UpdateOrigin::Remote, 0, UpdateState::in_remote_commitment);
+        let msg = self.sign_remote_commitment(secp, &state, &self.remote_current_point)?;
+        self.state = ChannelState::AwaitingInitialCommitment;
+        self.persist(persister)?;
+        Ok(msg)
+    }
+
+    /// Called by the funder of a single-funded channel once it has built the funding
+    /// transaction. From here on the channel goes by its real channel id.
+    pub fn funding_created<P>(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        persister: &mut P,
+        funding_outpoint: OutPoint,
+    ) -> Result<FundingCreatedMsg, ChannelError>
+    where
+        P: ChannelPersister + ?Sized,
+    {
+        self.check_state(&[ChannelState::AwaitingFundingTx])?;
+        if self.dual_funded || !self.local_is_funder {
+            return Err(ChannelError::WrongState { state: self.state });
+        }
+        if funding_outpoint.vout > u32::from(u16::max_value()) {
+            return Err(ChannelError::FundingOutputIndexTooLarge { vout: funding_outpoint.vout });
+        }
+        self.set_funding_outpoint(funding_outpoint);
+        let state = self.commitment_state(UpdateOrigin::Remote, 0, UpdateState::in_remote_commitment);
+        let signed = self.sign_remote_commitment(secp, &state, &self.remote_current_point)?;
+        let msg = FundingCreatedMsg {
+            temporary_channel_id: self.channel_id,
+            funding_txid: funding_outpoint.txid,
+            funding_output_index: funding_outpoint.vout as u16,
+            signature: signed.signature,
+        };
+        self.set_channel_id(single_funded_channel_id(&funding_outpoint));
+        self.state = ChannelState::AwaitingInitialCommitment;
+        self.persist(persister)?;
+        Ok(msg)
+    }
+
+    /// Called once the funding transaction is deep enough. Returns our `channel_ready`, with the
+    /// point for our second commitment. The channel is `Normal` once the peer's has arrived too.
+    pub fn funding_confirmed(&mut self, secp: &Secp256k1<secp256k1::All>) -> Result<ChannelReadyMsg, ChannelError> {
+        self.check_state(&[ChannelState::AwaitingChannelReady])?;
+        if self.channel_ready_sent {
+            return Err(ChannelError::WrongState { state: self.state });
+        }
+        let second_per_commitment_point = {
+            self.secrets.per_commitment_point(secp, 1)
+            .map_err(|_| ChannelError::KeyDerivation)?
+        };
+        self.channel_ready_sent = true;
+        if self.channel_ready_received {
+            self.state = ChannelState::Normal;
+        }
+        Ok(ChannelReadyMsg { channel_id: self.channel_id, second_per_commitment_point, short_channel_id_alias: None })
+    }
+
+    pub fn add_htlc(
+        &mut self,
+        amount_msat: u64,
+        payment_hash: [u8; 32],
+        cltv_expiry: u32,
+        onion_routing_packet: Vec<u8>,
+        blinding_point: Option<secp256k1::PublicKey>,
+    ) -> Result<UpdateAddHtlcMsg, ChannelError> {
+        self.check_state(&[ChannelState::Normal])?;
+        if self.check_add_htlc(UpdateOrigin::Local, amount_msat)? {
+            return Err(ChannelError::DustExposureExceeded {
+                max_dust_htlc_exposure_msat: self.max_dust_htlc_exposure_msat,
+            });
+        }
+        let msg = UpdateAddHtlcMsg {
+            channel_id: self.channel_id,
+            id: self.queue.next_local_htlc_id(),
+            amount_msat,
+            payment_hash,
+            cltv_expiry,
+            onion_routing_packet,
+            blinding_point,
+        };
+        self.queue.send_update(CommitmentUpdate::AddHtlc(msg.clone()))?;
+        Ok(msg)
+    }
+
+    pub fn fulfill_htlc(&mut self, id: u64, payment_preimage: [u8; 32]) -> Result<UpdateFulfillHtlcMsg, ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        let msg = UpdateFulfillHtlcMsg { channel_id: self.channel_id, id, payment_preimage };
+        self.queue.send_update(CommitmentUpdate::FulfillHtlc(msg.clone()))?;
+        Ok(msg)
+    }
+
+    pub fn fail_htlc(&mut self, id: u64, reason: Vec<u8>) -> Result<UpdateFailHtlcMsg, ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        let msg = UpdateFailHtlcMsg { channel_id: self.channel_id, id, reason };
+        self.queue.send_update(CommitmentUpdate::FailHtlc(msg.clone()))?;
+        self.htlcs_to_fail.retain(|htlc_id| *htlc_id != id);
+        Ok(msg)
+    }
+
+    /// Only the funder can change the feerate.
+    pub fn update_fee(&mut self, feerate_per_kw: u32) -> Result<UpdateFeeMsg, ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        self.check_update_fee(UpdateOrigin::Local, feerate_per_kw)?;
+        let msg = UpdateFeeMsg { channel_id: self.channel_id, feerate_per_kw };
+        self.queue.send_update(CommitmentUpdate::Fee(msg.clone()))?;
+        Ok(msg)
+    }
+
+    /// Signs a new commitment for the peer, covering all our updates so far and theirs which
+    /// we've acked.
+    pub fn commitment_signed<P>(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        persister: &mut P,
+    ) -> Result<CommitmentSignedMsg, ChannelError>
+    where
+        P: ChannelPersister + ?Sized,
+    {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        self.queue.send_commitment_signed()?;
+        let commitment_number = self.queue.remote_commitment_number();
+        let state = self.commitment_state(UpdateOrigin::Remote, commitment_number, UpdateState::in_remote_commitment);
+        let msg = self.sign_remote_commitment(secp, &state, &self.remote_next_point)?;
+        self.persist(persister)?;
+        Ok(msg)
+    }
+
+    /// Starts a cooperative close, paying out our balance to `scriptpubkey`. This is also how to
+    /// answer the peer's `shutdown`. `fee_range` is the closing fee we'll agree to; if we're the
+    /// funder it must not go over our balance.
+    pub fn shutdown(&mut self, scriptpubkey: Vec<u8>, fee_range: FeeRange) -> Result<ShutdownMsg, ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        if self.local_shutdown_script.is_some() {
+            return Err(ChannelError::WrongState { state: self.state });
+        }
+        if !is_valid_shutdown_script(&scriptpubkey) {
+            return Err(ChannelError::Shutdown(ShutdownError::NonStandardScript));
+        }
+        if self.queue.updates().iter().any(|queued| queued.state == UpdateState::SentUpdate) {
+            return Err(ChannelError::UnsignedUpdatesPending);
+        }
+        let msg = ShutdownMsg { channel_id: self.channel_id, scriptpubkey: scriptpubkey.clone() };
+        self.local_shutdown_script = Some(scriptpubkey);
+        self.closing_fee_range = Some(fee_range);
+        self.state = ChannelState::ShuttingDown;
+        self.maybe_start_closing_negotiation();
+        Ok(msg)
+    }
+
+    /// The funder's first `closing_signed`, once the channel is `NegotiatingClose`.
+    pub fn propose_closing_fee(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        estimate_fee_satoshis: u64,
+    ) -> Result<ClosingSignedMsg, ChannelError> {
+        self.check_state(&[ChannelState::NegotiatingClose])?;
+        let negotiator = self.closing_negotiator.as_mut().ok_or(ChannelError::WrongState { state: self.state })?;
+        let fee_satoshis = negotiator.initial_proposal(estimate_fee_satoshis)?;
+        let fee_range = negotiator.fee_range();
+        Ok(self.closing_signed_msg(secp, fee_satoshis, fee_range))
+    }
+
+    /// Forgets the updates the peer will forget too when the connection drops.
+    pub fn disconnect(&mut self) {
+        self.queue.disconnect();
+        let next_remote_htlc_id = self.queue.next_remote_htlc_id();
+        self.htlcs_to_fail.retain(|id| *id < next_remote_htlc_id);
+    }
+
+    /// Handles a message from the peer, returning the messages to send back. The channel is
+    /// persisted after every `commitment_signed`, `revoke_and_ack` and `closing_signed`.
+    pub fn handle_msg<P>(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        persister: &mut P,
+        msg: Msg,
+    ) -> Result<Vec<Msg>, ChannelError>
+    where
+        P: ChannelPersister + ?Sized,
+    {
+        let channel_id = match msg {
+            Msg::FundingCreated(ref msg) => msg.temporary_channel_id,
+            Msg::FundingSigned(ref msg) => msg.channel_id,
+            Msg::ChannelReady(ref msg) => msg.channel_id,
+            Msg::CommitmentSigned(ref msg) => msg.channel_id,
+            Msg::RevokeAndAck(ref msg) => msg.channel_id,
+            Msg::Shutdown(ref msg) => msg.channel_id,
+            Msg::ClosingSigned(ref msg) => msg.channel_id,
+            Msg::UpdateAddHtlc(ref msg) => msg.channel_id,
+            Msg::UpdateFulfillHtlc(ref msg) => msg.channel_id,
+            Msg::UpdateFailHtlc(ref msg) => msg.channel_id,
+            Msg::UpdateFailMalformedHtlc(ref msg) => msg.channel_id,
+            Msg::UpdateFee(ref msg) => msg.channel_id,
+            ref msg => return Err(ChannelError::UnexpectedMessage { type_tag: msg.msg_type() as u16 }),
+        };
+        if channel_id != self.channel_id {
+            return Err(ChannelError::WrongChannelId);
+        }
+        if self.state == ChannelState::Closed || self.state == ChannelState::Failed {
+            return Err(ChannelError::WrongState { state: self.state });
+        }
+
+        let needs_persist = match msg {
+            Msg::FundingCreated(..)
+            | Msg::FundingSigned(..)
+            | Msg::ChannelReady(..)
+            | Msg::CommitmentSigned(..)
+            | Msg::RevokeAndAck(..)
+            | Msg::ClosingSigned(..) => true,
+            _ => false,
+        };
+        let mut res = match msg {
+            Msg::FundingCreated(msg) => self.handle_funding_created(secp, &msg),
+            Msg::FundingSigned(msg) => self.handle_funding_signed(secp, &msg).map(|()| Vec::new()),
+            Msg::ChannelReady(msg) => self.handle_channel_ready(&msg).map(|()| Vec::new()),
+            Msg::CommitmentSigned(msg) => self.handle_commitment_signed(secp, &msg),
+            Msg::RevokeAndAck(msg) => self.handle_revoke_and_ack(secp, &msg).map(|()| Vec::new()),
+            Msg::Shutdown(msg) => self.handle_shutdown(msg).map(|()| Vec::new()),
+            Msg::ClosingSigned(msg) => self.handle_closing_signed(secp, &msg),
+            msg => {
+                let update = unwrap!(CommitmentUpdate::from_msg(msg).ok());
+                self.handle_update(update).map(|()| Vec::new())
+            },
+        };
+        if needs_persist && res.is_ok() {
+            if let Err(e) = self.persist(persister) {
+                res = Err(e);
+            }
+        }
+        if res.as_ref().err().map_or(false, ChannelError::is_protocol_violation) {
+            self.state = ChannelState::Failed;
+        }
+        res
+    }
+
+    fn handle_funding_created(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        msg: &FundingCreatedMsg,
+    ) -> Result<Vec<Msg>, ChannelError> {
+        if self.dual_funded || self.local_is_funder {
+            return Err(ChannelError::UnexpectedMessage { type_tag: MsgType::FundingCreated as u16 });
+        }
+        self.check_state(&[ChannelState::AwaitingFundingTx])?;
+        let funding_outpoint = OutPoint { txid: msg.funding_txid, vout: u32::from(msg.funding_output_index) };
+        self.set_funding_outpoint(funding_outpoint);
+        let commitment_signed = CommitmentSignedMsg {
+            channel_id: self.channel_id,
+            signature: msg.signature,
+            htlc_signatures: Vec::new(),
+        };
+        let state = self.commitment_state(UpdateOrigin::Local, 0, UpdateState::in_local_commitment);
+        self.verify_local_commitment(secp, &commitment_signed, &state)?;
+        let state = self.commitment_state(UpdateOrigin::Remote, 0, UpdateState::in_remote_commitment);
+        let signed = self.sign_remote_commitment(secp, &state, &self.remote_current_point)?;
+        self.set_channel_id(single_funded_channel_id(&funding_outpoint));
+        self.state = ChannelState::AwaitingChannelReady;
+        Ok(vec![Msg::FundingSigned(FundingSignedMsg { channel_id: self.channel_id, signature: signed.signature })])
+    }
+
+    fn handle_funding_signed(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        msg: &FundingSignedMsg,
+    ) -> Result<(), ChannelError> {
+        if self.dual_funded || !self.local_is_funder {
+            return Err(ChannelError::UnexpectedMessage { type_tag: MsgType::FundingSigned as u16 });
+        }
+        self.check_state(&[ChannelState::AwaitingInitialCommitment])?;
+        let commitment_signed = CommitmentSignedMsg {
+            channel_id: self.channel_id,
+            signature: msg.signature,
+            htlc_signatures: Vec::new(),
+        };
+        let state = self.commitment_state(UpdateOrigin::Local, 0, UpdateState::in_local_commitment);
+        self.verify_local_commitment(secp, &commitment_signed, &state)?;
+        self.state = ChannelState::AwaitingChannelReady;
+        Ok(())
+    }
+
+    /// The peer may send `channel_ready` again after a reconnection, which is ignored.
+    fn handle_channel_ready(&mut self, msg: &ChannelReadyMsg) -> Result<(), ChannelError> {
+        if self.channel_ready_received {
+            return Ok(());
+        }
+        self.check_state(&[ChannelState::AwaitingChannelReady])?;
+        self.remote_next_point = msg.second_per_commitment_point;
+        self.channel_ready_received = true;
+        if self.channel_ready_sent {
+            self.state = ChannelState::Normal;
+        }
+        Ok(())
+    }
+
+    fn handle_update(&mut self, update: CommitmentUpdate) -> Result<(), ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        let mut exceeds_dust_exposure = false;
+        match update {
+            CommitmentUpdate::AddHtlc(ref msg) => {
+                if self.remote_shutdown_script.is_some() {
+                    return Err(ChannelError::AddHtlcAfterShutdown);
+                }
+                if msg.cltv_expiry >= 500_000_000 {
+                    return Err(ChannelError::InvalidCltvExpiry { cltv_expiry: msg.cltv_expiry });
+                }
+                exceeds_dust_exposure = self.check_add_htlc(UpdateOrigin::Remote, msg.amount_msat)?;
+            },
+            CommitmentUpdate::Fee(ref msg) => self.check_update_fee(UpdateOrigin::Remote, msg.feerate_per_kw)?,
+            _ => (),
+        }
+        let id = match update {
+            CommitmentUpdate::AddHtlc(ref msg) => msg.id,
+            _ => 0,
+        };
+        self.queue.receive_update(update)?;
+        if exceeds_dust_exposure {
+            self.htlcs_to_fail.push(id);
+        }
+        Ok(())
+    }
+
+    fn handle_commitment_signed(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        msg: &CommitmentSignedMsg,
+    ) -> Result<Vec<Msg>, ChannelError> {
+        if self.state == ChannelState::AwaitingInitialCommitment {
+            if !self.dual_funded {
+                return Err(ChannelError::UnexpectedMessage { type_tag: MsgType::CommitmentSigned as u16 });
+            }
+            let state = self.commitment_state(UpdateOrigin::Local, 0, UpdateState::in_local_commitment);
+            self.verify_local_commitment(secp, msg, &state)?;
+            self.state = ChannelState::AwaitingChannelReady;
+            return Ok(Vec::new());
+        }
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+
+        self.queue.receive_commitment_signed()?;
+        let commitment_number = self.queue.local_commitment_number();
+        let state = self.commitment_state(UpdateOrigin::Local, commitment_number, UpdateState::in_local_commitment);
+        self.verify_local_commitment(secp, msg, &state)?;
+
+        let revoked_commitment_number = self.queue.revocations_sent();
+        let per_commitment_secret = {
+            self.secrets.per_commitment_secret(revoked_commitment_number)
+            .map_err(|_| ChannelError::KeyDerivation)?
+        };
+        let next_per_commitment_point = {
+            self.secrets.per_commitment_point(secp, revoked_commitment_number + 2)
+            .map_err(|_| ChannelError::KeyDerivation)?
+        };
+        self.settle_balances(UpdateState::ReceivedAckCommit);
+        self.queue.send_revoke_and_ack()?;
+        self.maybe_start_closing_negotiation();
+        Ok(vec![Msg::RevokeAndAck(RevokeAndAckMsg {
+            channel_id: self.channel_id,
+            per_commitment_secret,
+            next_per_commitment_point,
+        })])
+    }
+
+    fn handle_revoke_and_ack(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        msg: &RevokeAndAckMsg,
+    ) -> Result<(), ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        if !self.queue.awaiting_remote_revocation() {
+            return Err(UpdateQueueError::UnexpectedRevocation.into());
+        }
+        let revoked_commitment_number = self.queue.revocations_received();
+        let point = {
+            per_commitment_point(secp, &msg.per_commitment_secret)
+            .map_err(|_| ChannelError::InvalidRevocationSecret)?
+        };
+        if point != self.remote_current_point {
+            return Err(ChannelError::InvalidRevocationSecret);
+        }
+        self.remote_secrets.insert_secret(msg.per_commitment_secret, per_commitment_index(revoked_commitment_number)?)?;
+        self.settle_balances(UpdateState::SentAckCommit);
+        self.queue.receive_revoke_and_ack()?;
+        self.remote_current_point = self.remote_next_point;
+        self.remote_next_point = msg.next_per_commitment_point;
+        self.maybe_start_closing_negotiation();
+        Ok(())
+    }
+
+    fn handle_shutdown(&mut self, msg: ShutdownMsg) -> Result<(), ChannelError> {
+        self.check_state(&[ChannelState::Normal, ChannelState::ShuttingDown])?;
+        if self.remote_shutdown_script.is_some() {
+            return Err(ChannelError::UnexpectedMessage { type_tag: MsgType::Shutdown as u16 });
+        }
+        if !is_valid_shutdown_script(&msg.scriptpubkey) {
+            return Err(ChannelError::Shutdown(ShutdownError::NonStandardScript));
+        }
+        if let Some(ref upfront) = self.remote_upfront_shutdown_script {
+            if !upfront.is_empty() && *upfront != msg.scriptpubkey {
+                return Err(ChannelError::Shutdown(ShutdownError::UpfrontShutdownScriptMismatch));
+            }
+        }
+        self.remote_shutdown_script = Some(msg.scriptpubkey);
+        self.state = ChannelState::ShuttingDown;
+        self.maybe_start_closing_negotiation();
+        Ok(())
+    }
+
+    fn handle_closing_signed(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        msg: &ClosingSignedMsg,
+    ) -> Result<Vec<Msg>, ChannelError> {
+        self.check_state(&[ChannelState::NegotiatingClose])?;
+        let params = self.local_params.clone().ok_or(ChannelError::WrongState { state: self.state })?;
+        let tx = self.unsigned_closing_tx(msg.fee_satoshis);
+        let sighash = tx.bip143_sighash(0, &params.funding_script(), params.funding_satoshis, SIGHASH_ALL);
+        let sighash = unwrap!(secp256k1::Message::from_slice(&sighash[..]));
+        if secp.verify(&sighash, &msg.signature, &params.remote_funding_pubkey).is_err() {
+            return Err(ChannelError::InvalidClosingSignature);
+        }
+
+        let negotiator = self.closing_negotiator.as_mut().ok_or(ChannelError::WrongState { state: self.state })?;
+        let fee_range = negotiator.fee_range();
+        match negotiator.handle_closing_signed(msg)? {
+            ClosingFeeStep::Agreed { fee_satoshis, reply } => {
+                let mut tx = tx;
+                let signature = secp.sign(&sighash, &self.secrets.funding_key);
+                tx.inputs[0].witness = funding_witness(secp, &params, &signature, &msg.signature);
+                self.closing_tx = Some(tx);
+                self.state = ChannelState::Closed;
+                if !reply {
+                    return Ok(Vec::new());
+                }
+                Ok(vec![Msg::ClosingSigned(ClosingSignedMsg {
+                    channel_id: self.channel_id,
+                    fee_satoshis,
+                    signature,
+                    fee_range: Some(fee_range),
+                })])
+            },
+            ClosingFeeStep::CounterPropose { fee_satoshis } => {
+                Ok(vec![Msg::ClosingSigned(self.closing_signed_msg(secp, fee_satoshis, fee_range))])
+            },
+        }
+    }
+
+    /// Moves a single-funded channel from its temporary id to the one from the funding
+    /// transaction. Nothing has been queued yet, so the update queue can start over.
+    fn set_channel_id(&mut self, channel_id: ChannelId) {
+        self.channel_id = channel_id;
+        self.queue = UpdateQueue::new(channel_id, self.local_is_funder, self.queue.feerate_in_local_commitment());
+    }
+
+    fn set_funding_outpoint(&mut self, funding_outpoint: OutPoint) {
+        self.local_params = Some(CommitmentParams::new(
+            funding_outpoint,
+            self.funding_satoshis,
+            &self.local_basepoints,
+            &self.remote_basepoints,
+            self.local_is_funder,
+            self.remote_limits.to_self_delay,
+            self.local_limits.dust_limit_satoshis,
+            self.channel_type.anchors(),
+        ));
+        self.remote_params = Some(CommitmentParams::new(
+            funding_outpoint,
+            self.funding_satoshis,
+            &self.remote_basepoints,
+            &self.local_basepoints,
+            !self.local_is_funder,
+            self.local_limits.to_self_delay,
+            self.remote_limits.dust_limit_satoshis,
+            self.channel_type.anchors(),
+        ));
+    }
+
+    /// Stores the channel, failing it if that doesn't work: our in-memory state has moved on and
+    /// must not be exposed to the peer.
+    fn persist<P>(&mut self, persister: &mut P) -> Result<(), ChannelError>
+    where
+        P: ChannelPersister + ?Sized,
+    {
+        if let Err(e) = persister.persist_channel(&self.channel_id, &self.to_bytes()) {
+            self.state = ChannelState::Failed;
+            return Err(ChannelError::Persist(e));
+        }
+        Ok(())
+    }
+
+    fn check_state(&self, allowed: &[ChannelState]) -> Result<(), ChannelError> {
+        if allowed.contains(&self.state) {
+            Ok(())
+        } else {
+            Err(ChannelError::WrongState { state: self.state })
+        }
+    }
+
+    fn commitment_params(&self, holder: UpdateOrigin) -> &CommitmentParams {
+        match holder {
+            UpdateOrigin::Local => unwrap!(self.local_params.as_ref()),
+            UpdateOrigin::Remote => unwrap!(self.remote_params.as_ref()),
+        }
+    }
+
+    fn htlc_amount_msat(&self, offerer: UpdateOrigin, id: u64) -> u64 {
+        let add = self.queue.updates().iter().find_map(|queued| match queued.update {
+            CommitmentUpdate::AddHtlc(ref msg) if queued.origin == offerer && msg.id == id => Some(msg.amount_msat),
+            _ => None,
+        });
+        unwrap!(add)
+    }
+
+    /// Both balances in the commitment made up of the updates whose state passes `included`.
+    fn balances_msat<F>(&self, included: F) -> (u64, u64)
+    where
+        F: Fn(UpdateState) -> bool,
+    {
+        let (mut local_msat, mut remote_msat) = (self.settled_local_msat, self.settled_remote_msat);
+        for queued in self.queue.updates() {
+            if !included(queued.state) {
+                continue;
+            }
+            let (credited, amount_msat) = match queued.update {
+                CommitmentUpdate::AddHtlc(ref msg) => {
+                    match queued.origin {
+                        UpdateOrigin::Local => local_msat -= msg.amount_msat,
+                        UpdateOrigin::Remote => remote_msat -= msg.amount_msat,
+                    }
+                    continue;
+                },
+                CommitmentUpdate::Fee(..) => continue,
+                CommitmentUpdate::FulfillHtlc(ref msg) => {
+                    (queued.origin, self.htlc_amount_msat(other_side(queued.origin), msg.id))
+                },
+                _ => {
+                    let id = unwrap!(queued.update.removed_htlc_id());
+                    (other_side(queued.origin), self.htlc_amount_msat(other_side(queued.origin), id))
+                },
+            };
+            match credited {
+                UpdateOrigin::Local => local_msat += amount_msat,
+                UpdateOrigin::Remote => remote_msat += amount_msat,
+            }
+        }
+        (local_msat, remote_msat)
+    }
+
+    /// Moves the HTLCs fulfilled by updates in state `settling` into the settled balances. Must
+    /// be called just before the revocation which makes those updates irrevocable, since the
+    /// update queue drops them at that point.
+    fn settle_balances(&mut self, settling: UpdateState) {
+        let mut settled = Vec::new();
+        for queued in self.queue.updates() {
+            if let CommitmentUpdate::FulfillHtlc(ref msg) = queued.update {
+                if queued.state == settling {
+                    settled.push((queued.origin, self.htlc_amount_msat(other_side(queued.origin), msg.id)));
+                }
+            }
+        }
+        for (fulfiller, amount_msat) in settled {
+            match fulfiller {
+                UpdateOrigin::Local => {
+                    self.settled_local_msat += amount_msat;
+                    self.settled_remote_msat -= amount_msat;
+                },
+                UpdateOrigin::Remote => {
+                    self.settled_remote_msat += amount_msat;
+                    self.settled_local_msat -= amount_msat;
+                },
+            }
+        }
+    }
+
+    /// The commitment held by `holder` made up of the updates whose state passes `included`.
+    fn commitment_state<F>(&self, holder: UpdateOrigin, commitment_number: u64, included: F) -> CommitmentState
+    where
+        F: Fn(UpdateState) -> bool + Copy,
+    {
+        let (local_msat, remote_msat) = self.balances_msat(included);
+        let (to_local_msat, to_remote_msat) = match holder {
+            UpdateOrigin::Local => (local_msat, remote_msat),
+            UpdateOrigin::Remote => (remote_msat, local_msat),
+        };
+        let htlcs = {
+            self.queue.htlcs_in_commitment(included)
+            .into_iter()
+            .map(|(offerer, msg)| CommitmentHtlc {
+                offered: offerer == holder,
+                amount_msat: msg.amount_msat,
+                payment_hash: msg.payment_hash,
+                cltv_expiry: msg.cltv_expiry,
+            })
+            .collect()
+        };
+        CommitmentState {
+            commitment_number,
+            to_local_msat,
+            to_remote_msat,
+            feerate_per_kw: self.queue.feerate_in_commitment(included),
+            htlcs,
+        }
+    }
+
+    /// The next commitment `holder` will get, with every update sent to them so far.
+    fn next_commitment_state(&self, holder: UpdateOrigin) -> CommitmentState {
+        match holder {
+            UpdateOrigin::Local => {
+                let commitment_number = self.queue.local_commitment_number() + 1;
+                self.commitment_state(holder, commitment_number, UpdateState::in_next_local_commitment)
+            },
+            UpdateOrigin::Remote => {
+                let commitment_number = self.queue.remote_commitment_number() + 1;
+                self.commitment_state(holder, commitment_number, UpdateState::in_next_remote_commitment)
+            },
+        }
+    }
+
+    /// Checks an HTLC against the receiver's limits and the offerer's reserve, in the receiver's
+    /// next commitment. Returns whether it would take our dust exposure over the limit.
+    fn check_add_htlc(&self, offerer: UpdateOrigin, amount_msat: u64) -> Result<bool, ChannelError> {
+        let (receiver_limits, offerer_reserve_satoshis) = match offerer {
+            UpdateOrigin::Local => (&self.remote_limits, self.local_reserve_satoshis),
+            UpdateOrigin::Remote => (&self.local_limits, self.remote_reserve_satoshis),
+        };
+        if amount_msat == 0 || amount_msat < receiver_limits.htlc_minimum_msat {
+            return Err(ChannelError::HtlcBelowMinimum {
+                amount_msat,
+                htlc_minimum_msat: receiver_limits.htlc_minimum_msat,
+            });
+        }
+
+        let receiver = other_side(offerer);
+        let mut state = self.next_commitment_state(receiver);
+        let (num_offered, offered_msat) = {
+            state.htlcs.iter()
+            .filter(|htlc| !htlc.offered)
+            .fold((0, 0), |(num, total), htlc| (num + 1, total + htlc.amount_msat))
+        };
+        if num_offered + 1 > receiver_limits.max_accepted_htlcs as u64 {
+            return Err(ChannelError::TooManyHtlcs { max_accepted_htlcs: receiver_limits.max_accepted_htlcs });
+        }
+        let in_flight_msat = offered_msat.checked_add(amount_msat);
+        if in_flight_msat.map_or(true, |in_flight_msat| in_flight_msat > receiver_limits.max_htlc_value_in_flight_msat) {
+            return Err(ChannelError::HtlcValueInFlightExceeded {
+                max_htlc_value_in_flight_msat: receiver_limits.max_htlc_value_in_flight_msat,
+            });
+        }
+
+        let new_htlc = CommitmentHtlc { offered: false, amount_msat, payment_hash: [0; 32], cltv_expiry: 0 };
+        state.htlcs.push(new_htlc.clone());
+        let offerer_is_funder = (offerer == UpdateOrigin::Local) == self.local_is_funder;
+        let fee_msat = if offerer_is_funder { funder_fee_msat(self.commitment_params(receiver), &state) } else { 0 };
+        let required_msat = amount_msat.checked_add(fee_msat + offerer_reserve_satoshis * 1000);
+        if required_msat.map_or(true, |required_msat| state.to_remote_msat < required_msat) {
+            return Err(ChannelError::CannotAffordHtlc { amount_msat });
+        }
+
+        let offerer_state = {
+            let mut offerer_state = self.next_commitment_state(offerer);
+            offerer_state.htlcs.push(CommitmentHtlc { offered: true, ..new_htlc });
+            offerer_state
+        };
+        let exposure_msat = cmp::max(
+            dust_exposure_msat(self.commitment_params(receiver), &state),
+            dust_exposure_msat(self.commitment_params(offerer), &offerer_state),
+        );
+        Ok(exposure_msat > self.max_dust_htlc_exposure_msat)
+    }
+
+    /// Checks that the funder can still afford the receiver's next commitment at a new feerate.
+    fn check_update_fee(&self, sender: UpdateOrigin, feerate_per_kw: u32) -> Result<(), ChannelError> {
+        if feerate_per_kw < MIN_FEERATE_PER_KW {
+            return Err(ChannelError::FeerateTooLow { feerate_per_kw });
+        }
+        // The update queue turns away fee updates from the non-funder.
+        if (sender == UpdateOrigin::Local) != self.local_is_funder {
+            return Ok(());
+        }
+        let (receiver, sender_reserve_satoshis) = match sender {
+            UpdateOrigin::Local => (UpdateOrigin::Remote, self.local_reserve_satoshis),
+            UpdateOrigin::Remote => (UpdateOrigin::Local, self.remote_reserve_satoshis),
+        };
+        let mut state = self.next_commitment_state(receiver);
+        state.feerate_per_kw = feerate_per_kw;
+        let fee_msat = funder_fee_msat(self.commitment_params(receiver), &state);
+        if state.to_remote_msat < fee_msat + sender_reserve_satoshis * 1000 {
+            return Err(ChannelError::CannotAffordFee { feerate_per_kw });
+        }
+        Ok(())
+    }
+
+    fn sign_remote_commitment(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        state: &CommitmentState,
+        per_commitment_point: &secp256k1::PublicKey,
+    ) -> Result<CommitmentSignedMsg, ChannelError> {
+        let params = self.commitment_params(UpdateOrigin::Remote);
+        let keys = {
+            CommitmentKeys::derive(secp, per_commitment_point, &self.remote_basepoints, &self.local_basepoints, self.channel_type)
+            .map_err(|_| ChannelError::KeyDerivation)?
+        };
+        let htlc_key = {
+            derive_private_key(secp, &self.secrets.htlc_basepoint_secret, per_commitment_point)
+            .map_err(|_| ChannelError::KeyDerivation)?
+        };
+        let commitment = build_commitment_tx(params, &keys, state);
+        let htlc_signatures = {
+            build_htlc_txs(&commitment, params, &keys, state)
+            .iter()
+            .map(|htlc_tx| htlc_tx.sign_remote(secp, &htlc_key))
+            .collect()
+        };
+        Ok(CommitmentSignedMsg {
+            channel_id: self.channel_id,
+            signature: commitment.sign(secp, params, &self.secrets.funding_key),
+            htlc_signatures,
+        })
+    }
+
+    /// Checks the peer's signatures for our commitment, keeping the fully signed transaction if
+    /// they're good.
+    fn verify_local_commitment(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        msg: &CommitmentSignedMsg,
+        state: &CommitmentState,
+    ) -> Result<(), ChannelError> {
+        let params = self.commitment_params(UpdateOrigin::Local);
+        let keys = {
+            self.secrets.per_commitment_point(secp, state.commitment_number)
+            .and_then(|point| CommitmentKeys::derive(secp, &point, &self.local_basepoints, &self.remote_basepoints, self.channel_type))
+            .map_err(|_| ChannelError::KeyDerivation)?
+        };
+        let mut commitment = build_commitment_tx(params, &keys, state);
+        if !commitment.verify_remote_signature(secp, params, &msg.signature) {
+            return Err(ChannelError::InvalidCommitmentSignature);
+        }
+        let htlc_txs = build_htlc_txs(&commitment, params, &keys, state);
+        if htlc_txs.len() != msg.htlc_signatures.len() {
+            return Err(ChannelError::WrongHtlcSignatureCount {
+                expected: htlc_txs.len(),
+                received: msg.htlc_signatures.len(),
+            });
+        }
+        for (index, (htlc_tx, signature)) in htlc_txs.iter().zip(&msg.htlc_signatures).enumerate() {
+            if !htlc_tx.verify_remote_signature(secp, &keys, signature) {
+                return Err(ChannelError::InvalidHtlcSignature { index });
+            }
+        }
+
+        let local_signature = commitment.sign(secp, params, &self.secrets.funding_key);
+        commitment.add_funding_witness(secp, params, &local_signature, &msg.signature);
+        self.local_commitment_tx = Some(commitment.tx);
+        Ok(())
+    }
+
+    /// Moves on to fee negotiation once both sides have sent `shutdown` and every HTLC is gone.
+    fn maybe_start_closing_negotiation(&mut self) {
+        if self.state != ChannelState::ShuttingDown {
+            return;
+        }
+        let fee_range = match (&self.local_shutdown_script, &self.remote_shutdown_script, self.closing_fee_range) {
+            (Some(..), Some(..), Some(fee_range)) => fee_range,
+            _ => return,
+        };
+        if !self.queue.updates().is_empty()
+            || self.queue.awaiting_remote_revocation()
+            || self.queue.owe_local_revocation()
+        {
+            return;
+        }
+        self.closing_negotiator = Some(ClosingFeeNegotiator::new(self.channel_id, self.local_is_funder, fee_range));
+        self.state = ChannelState::NegotiatingClose;
+    }
+
+    fn unsigned_closing_tx(&self, fee_satoshis: u64) -> Transaction {
+        let params = self.commitment_params(UpdateOrigin::Local);
+        let mut local_satoshis = self.settled_local_msat / 1000;
+        let mut remote_satoshis = self.settled_remote_msat / 1000;
+        if self.local_is_funder {
+            local_satoshis = local_satoshis.saturating_sub(fee_satoshis);
+        } else {
+            remote_satoshis = remote_satoshis.saturating_sub(fee_satoshis);
+        }
+        build_closing_tx(
+            params.funding_outpoint,
+            unwrap!(self.local_shutdown_script.as_ref()),
+            local_satoshis,
+            unwrap!(self.remote_shutdown_script.as_ref()),
+            remote_satoshis,
+            cmp::max(self.local_limits.dust_limit_satoshis, self.remote_limits.dust_limit_satoshis),
+        )
+    }
+
+    fn closing_signed_msg(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        fee_satoshis: u64,
+        fee_range: FeeRange,
+    ) -> ClosingSignedMsg {
+        let params = self.commitment_params(UpdateOrigin::Local);
+        let tx = self.unsigned_closing_tx(fee_satoshis);
+        let sighash = tx.bip143_sighash(0, &params.funding_script(), params.funding_satoshis, SIGHASH_ALL);
+        let sighash = unwrap!(secp256k1::Message::from_slice(&sighash[..]));
+        ClosingSignedMsg {
+            channel_id: self.channel_id,
+            fee_satoshis,
+            signature: secp.sign(&sighash, &self.secrets.funding_key),
+            fee_range: Some(fee_range),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn open_pay_and_close() {
+        let secp = Secp256k1::new();
+        let mut opener_persister = TestChannelPersister::default();
+        let mut accepter_persister = TestChannelPersister::default();
+        let (mut opener, mut accepter) = test_channels(&secp, &mut opener_persister, &mut accepter_persister, |_| ());
+        assert_eq!(opener.state(), ChannelState::Normal);
+
+        let preimage = [0x55; 32];
+        let add = unwrap!(opener.add_htlc(200_000_000, sha256(&[&preimage[..]]), 600_000, vec![0; ONION_PACKET_LEN], None));
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(add)));
+        commitment_dance(&secp, &mut opener, &mut opener_persister, &mut accepter, &mut accepter_persister);
+        assert_eq!(opener.local_balance_msat(), 800_000_000);
+        assert_eq!(accepter.update_queue().htlcs_in_local_commitment().len(), 1);
+
+        let fulfill = unwrap!(accepter.fulfill_htlc(0, preimage));
+        unwrap!(opener.handle_msg(&secp, &mut opener_persister, Msg::UpdateFulfillHtlc(fulfill)));
+        commitment_dance(&secp, &mut accepter, &mut accepter_persister, &mut opener, &mut opener_persister);
+        assert_eq!(opener.local_balance_msat(), 800_000_000);
+        assert_eq!(opener.remote_balance_msat(), 700_000_000);
+        assert_eq!(accepter.local_balance_msat(), 700_000_000);
+        assert!(accepter.update_queue().updates().is_empty());
+        assert_eq!(opener.remote_per_commitment_secrets().min_index(), Some(unwrap!(per_commitment_index(1))));
+
+        let fee_range = FeeRange { min_fee_satoshis: 500, max_fee_satoshis: 5000 };
+        let opener_script = p2wpkh_script_pubkey(&test_key(&secp, 0x30).1);
+        let accepter_script = p2wpkh_script_pubkey(&test_key(&secp, 0x31).1);
+        let shutdown = unwrap!(opener.shutdown(opener_script.clone(), fee_range));
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::Shutdown(shutdown)));
+        assert_eq!(accepter.state(), ChannelState::ShuttingDown);
+        let shutdown = unwrap!(accepter.shutdown(accepter_script.clone(), fee_range));
+        unwrap!(opener.handle_msg(&secp, &mut opener_persister, Msg::Shutdown(shutdown)));
+        assert_eq!(opener.state(), ChannelState::NegotiatingClose);
+        assert_eq!(accepter.state(), ChannelState::NegotiatingClose);
+
+        let proposal = unwrap!(opener.propose_closing_fee(&secp, 1000));
+        let mut replies = unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::ClosingSigned(proposal)));
+        assert_eq!(replies.len(), 1);
+        assert!(unwrap!(opener.handle_msg(&secp, &mut opener_persister, replies.remove(0))).is_empty());
+        assert_eq!(opener.state(), ChannelState::Closed);
+        assert_eq!(accepter.state(), ChannelState::Closed);
+
+        let closing_tx = unwrap!(opener.closing_tx());
+        assert_eq!(Some(closing_tx), accepter.closing_tx());
+        let outputs: Vec<_> = closing_tx.outputs.iter().map(|output| (output.value, &output.script_pubkey)).collect();
+        assert_eq!(outputs, vec![(700_000, &accepter_script), (799_000, &opener_script)]);
+    }
+
+    #[test]
+    fn open_single_funded() {
+        let secp = Secp256k1::new();
+        let mut opener_persister = TestChannelPersister::default();
+        let mut accepter_persister = TestChannelPersister::default();
+        let (open, accept) = test_single_funded_msgs(&secp);
+        let new = |byte, local_is_opener| {
+            let secrets = test_channel_secrets(&secp, byte);
+            Channel::new_single_funded(&secp, secrets, &ChainHash::BITCOIN, &open, &accept, local_is_opener, 5_000_000)
+        };
+        let (mut opener, mut accepter) = (unwrap!(new(0x10, true)), unwrap!(new(0x20, false)));
+        assert_eq!(*opener.channel_id(), ChannelId([0x77; 32]));
+
+        match opener.funding_created(&secp, &mut opener_persister, OutPoint { txid: [0x42; 32], vout: 0x10000 }) {
+            Err(ChannelError::FundingOutputIndexTooLarge { vout: 0x10000 }) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        assert_eq!(opener.state(), ChannelState::AwaitingFundingTx);
+        let funding_outpoint = OutPoint { txid: [0x42; 32], vout: 0x0102 };
+        let created = unwrap!(opener.funding_created(&secp, &mut opener_persister, funding_outpoint));
+        assert_eq!(created.temporary_channel_id, ChannelId([0x77; 32]));
+        let mut replies = unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::FundingCreated(created)));
+        assert_eq!(replies.len(), 1);
+        assert!(unwrap!(opener.handle_msg(&secp, &mut opener_persister, replies.remove(0))).is_empty());
+        let mut channel_id = [0x42; 32];
+        channel_id[30] = 0x43;
+        channel_id[31] = 0x40;
+        assert_eq!(*opener.channel_id(), ChannelId(channel_id));
+        assert_eq!(accepter.channel_id(), opener.channel_id());
+        assert_eq!(opener.state(), ChannelState::AwaitingChannelReady);
+        assert_eq!(accepter.state(), ChannelState::AwaitingChannelReady);
+        assert!(opener.local_commitment_tx().is_some());
+
+        // The opener's channel_ready arrives before the accepter has seen the funding confirm.
+        let opener_ready = unwrap!(opener.funding_confirmed(&secp));
+        let opener_point = unwrap!(test_channel_secrets(&secp, 0x10).per_commitment_point(&secp, 1));
+        assert_eq!(opener_ready.second_per_commitment_point, opener_point);
+        assert!(unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::ChannelReady(opener_ready.clone()))).is_empty());
+        assert_eq!(accepter.state(), ChannelState::AwaitingChannelReady);
+        let accepter_ready = unwrap!(accepter.funding_confirmed(&secp));
+        assert_eq!(accepter.state(), ChannelState::Normal);
+        assert_eq!(opener.state(), ChannelState::AwaitingChannelReady);
+        assert!(unwrap!(opener.handle_msg(&secp, &mut opener_persister, Msg::ChannelReady(accepter_ready))).is_empty());
+        assert_eq!(opener.state(), ChannelState::Normal);
+        assert!(unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::ChannelReady(opener_ready))).is_empty());
+        assert_eq!(opener.local_balance_msat(), 900_000_000);
+        assert_eq!(accepter.local_balance_msat(), 100_000_000);
+
+        let add = unwrap!(opener.add_htlc(10_000_000, [1; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(add)));
+        commitment_dance(&secp, &mut opener, &mut opener_persister, &mut accepter, &mut accepter_persister);
+        let opener = unwrap!(Channel::from_bytes(&secp, test_channel_secrets(&secp, 0x10), &opener.to_bytes()));
+        assert_eq!(opener.channel_id(), accepter.channel_id());
+        assert_eq!(opener.local_balance_msat(), 890_000_000);
+    }
+
+    #[test]
+    fn rejects_bad_opening_params() {
+        let secp = Secp256k1::new();
+        let (open, accept) = test_single_funded_msgs(&secp);
+        let new = |open: &OpenChannelMsg, accept: &AcceptChannelMsg| {
+            let secrets = test_channel_secrets(&secp, 0x20);
+            Channel::new_single_funded(&secp, secrets, &ChainHash::BITCOIN, open, accept, false, 5_000_000).err()
+        };
+
+        let mut bad_open = open.clone();
+        bad_open.chain_hash = ChainHash([1; 32]);
+        match new(&bad_open, &accept) {
+            Some(ChannelError::WrongChainHash) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_open = open.clone();
+        bad_open.feerate_per_kw = MIN_FEERATE_PER_KW - 1;
+        match new(&bad_open, &accept) {
+            Some(ChannelError::FeerateTooLow { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // 12,000 sat covers the funder's reserve, but not the anchors and the fee on top.
+        let mut bad_open = open.clone();
+        bad_open.funding_satoshis = 12_000;
+        bad_open.push_msat = 0;
+        match new(&bad_open, &accept) {
+            Some(ChannelError::CannotAffordFee { feerate_per_kw: 2500 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_open = open.clone();
+        bad_open.funding_satoshis = MAX_FUNDING_SATOSHIS;
+        match new(&bad_open, &accept) {
+            Some(ChannelError::FundingTooLarge { funding_satoshis: MAX_FUNDING_SATOSHIS }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_open = open.clone();
+        bad_open.funding_satoshis = u64::max_value();
+        match new(&bad_open, &accept) {
+            Some(ChannelError::FundingTooLarge { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_open = open.clone();
+        bad_open.push_msat = open.funding_satoshis * 1000 + 1;
+        match new(&bad_open, &accept) {
+            Some(ChannelError::PushTooLarge { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_open = open.clone();
+        bad_open.upfront_shutdown_script = Some(vec![0x6a; 70_000]);
+        match new(&bad_open, &accept) {
+            Some(ChannelError::Shutdown(ShutdownError::NonStandardScript)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_open = open.clone();
+        bad_open.channel_type = None;
+        match new(&bad_open, &accept) {
+            Some(ChannelError::MissingChannelType) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_accept = accept.clone();
+        bad_accept.channel_type = Some(ChannelType::StaticRemoteKey.to_bytes());
+        match new(&open, &bad_accept) {
+            Some(ChannelError::ChannelTypeMismatch) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_accept = accept.clone();
+        bad_accept.channel_reserve_satoshis = 500;
+        match new(&open, &bad_accept) {
+            Some(ChannelError::ChannelReserveBelowDustLimit { channel_reserve_satoshis: 500, dust_limit_satoshis: 546 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_accept = accept.clone();
+        bad_accept.channel_reserve_satoshis = open.funding_satoshis + 1;
+        match new(&open, &bad_accept) {
+            Some(ChannelError::ChannelReserveTooLarge { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn protocol_violations_fail_the_channel() {
+        let secp = Secp256k1::new();
+        let mut opener_persister = TestChannelPersister::default();
+        let mut accepter_persister = TestChannelPersister::default();
+        let (mut opener, mut accepter) = {
+            test_channels(&secp, &mut opener_persister, &mut accepter_persister, |accept| accept.max_accepted_htlcs = 1)
+        };
+
+        // Our own updates over the peer's limits are refused without harming the channel.
+        unwrap!(opener.add_htlc(5_000_000, [1; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+        match opener.add_htlc(5_000_000, [2; 32], 600_000, vec![0; ONION_PACKET_LEN], None) {
+            Err(ChannelError::TooManyHtlcs { max_accepted_htlcs: 1 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match opener.add_htlc(999, [2; 32], 600_000, vec![0; ONION_PACKET_LEN], None) {
+            Err(ChannelError::HtlcBelowMinimum { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match opener.propose_closing_fee(&secp, 1000) {
+            Err(ChannelError::WrongState { state: ChannelState::Normal }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(opener.state(), ChannelState::Normal);
+
+        // The peer's aren't.
+        let add = |id| UpdateAddHtlcMsg {
+            channel_id: *accepter.channel_id(),
+            id,
+            amount_msat: 5_000_000,
+            payment_hash: [1; 32],
+            cltv_expiry: 600_000,
+            onion_routing_packet: vec![0; ONION_PACKET_LEN],
+            blinding_point: None,
+        };
+        let (first, second) = (add(0), add(1));
+        let mut other_channel = first.clone();
+        other_channel.channel_id = ChannelId([0; 32]);
+        match accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(other_channel)) {
+            Err(ChannelError::WrongChannelId) => (),
+            res => panic!("unexpected result: {:?}", res.err()),
+        }
+        assert_eq!(accepter.state(), ChannelState::Normal);
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(first)));
+        match accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(second)) {
+            Err(ChannelError::TooManyHtlcs { max_accepted_htlcs: 1 }) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("accepted an htlc over max_accepted_htlcs"),
+        }
+        assert_eq!(accepter.state(), ChannelState::Failed);
+        assert!(accepter.local_commitment_tx().is_some());
+
+        // As is a commitment with a bad signature.
+        let (mut opener, mut accepter) = test_channels(&secp, &mut opener_persister, &mut accepter_persister, |_| ());
+        let add = unwrap!(opener.add_htlc(5_000_000, [1; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(add)));
+        let mut signed = unwrap!(opener.commitment_signed(&secp, &mut opener_persister));
+        signed.signature = signed.htlc_signatures[0];
+        match accepter.handle_msg(&secp, &mut accepter_persister, Msg::CommitmentSigned(signed)) {
+            Err(ChannelError::InvalidCommitmentSignature) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("accepted a bad commitment signature"),
+        }
+        assert_eq!(accepter.state(), ChannelState::Failed);
+    }
+
+    #[test]
+    fn signatures_are_only_sent_once_persisted() {
+        let secp = Secp256k1::new();
+        let mut opener_persister = TestChannelPersister::default();
+        let mut accepter_persister = TestChannelPersister::default();
+        let (mut opener, mut accepter) = test_channels(&secp, &mut opener_persister, &mut accepter_persister, |_| ());
+        let add = unwrap!(opener.add_htlc(5_000_000, [1; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(add)));
+        commitment_dance(&secp, &mut opener, &mut opener_persister, &mut accepter, &mut accepter_persister);
+        assert_eq!(opener_persister.states[opener.channel_id()], opener.to_bytes());
+        assert_eq!(accepter_persister.states[accepter.channel_id()], accepter.to_bytes());
+
+        let restored = {
+            let state = &accepter_persister.states[accepter.channel_id()];
+            unwrap!(Channel::from_bytes(&secp, test_channel_secrets(&secp, 0x20), state))
+        };
+        assert_eq!(restored.to_bytes(), accepter.to_bytes());
+        assert_eq!(restored.local_commitment_tx(), accepter.local_commitment_tx());
+
+        let add = unwrap!(opener.add_htlc(5_000_000, [2; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+        unwrap!(accepter.handle_msg(&secp, &mut accepter_persister, Msg::UpdateAddHtlc(add)));
+        opener_persister.fail = true;
+        match opener.commitment_signed(&secp, &mut opener_persister) {
+            Err(ChannelError::Persist(..)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("signed a commitment which wasn't persisted"),
+        }
+        assert_eq!(opener.state(), ChannelState::Failed);
+    }
+}
@@ -0,0 +1,228 @@
+use super::*;
+
+/// The public keys a party commits to when opening a channel. Per-commitment keys are derived
+/// from them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelBasepoints {
+    pub funding_pubkey: secp256k1::PublicKey,
+    pub revocation_basepoint: secp256k1::PublicKey,
+    pub payment_basepoint: secp256k1::PublicKey,
+    pub delayed_payment_basepoint: secp256k1::PublicKey,
+    pub htlc_basepoint: secp256k1::PublicKey,
+}
+
+/// Our secrets for a channel.
+#[derive(Clone)]
+pub struct ChannelSecrets {
+    pub funding_key: secp256k1::SecretKey,
+    pub revocation_basepoint_secret: secp256k1::SecretKey,
+    pub payment_basepoint_secret: secp256k1::SecretKey,
+    pub delayed_payment_basepoint_secret: secp256k1::SecretKey,
+    pub htlc_basepoint_secret: secp256k1::SecretKey,
+    /// The shachain seed our per-commitment secrets are generated from.
+    pub commitment_seed: [u8; 32],
+}
+
+/// The keys used in one commitment transaction. "Local" is the party who holds the transaction
+/// and can broadcast it, "remote" the other party.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitmentKeys {
+    pub per_commitment_point: secp256k1::PublicKey,
+    /// Lets the remote party take everything if the local party broadcasts a revoked state.
+    pub revocation_pubkey: secp256k1::PublicKey,
+    pub local_delayed_pubkey: secp256k1::PublicKey,
+    pub local_htlc_pubkey: secp256k1::PublicKey,
+    pub remote_htlc_pubkey: secp256k1::PublicKey,
+    /// Pays the remote party's `to_remote` output. With `option_static_remotekey` this is just
+    /// their payment basepoint, so they can sweep it without knowing the per-commitment point.
+    /// Otherwise it's derived from the payment basepoint like the other keys.
+    pub remote_pubkey: secp256k1::PublicKey,
+}
+
+fn key_tweak(
+    secp: &Secp256k1<secp256k1::All>,
+    first: &secp256k1::PublicKey,
+    second: &secp256k1::PublicKey,
+) -> Result<secp256k1::SecretKey, secp256k1::Error> {
+    let hash = sha256(&[&first.serialize()[..], &second.serialize()[..]]);
+    secp256k1::SecretKey::from_slice(secp, &hash[..])
+}
+
+pub fn per_commitment_point(
+    secp: &Secp256k1<secp256k1::All>,
+    per_commitment_secret: &[u8; 32],
+) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+    let secret = secp256k1::SecretKey::from_slice(secp, &per_commitment_secret[..])?;
+    Ok(secp256k1::PublicKey::from_secret_key(secp, &secret))
+}
+
+/// `basepoint + SHA256(per_commitment_point || basepoint) * G`. Gives `localpubkey`,
+/// `remotepubkey`, `local_htlcpubkey`, `remote_htlcpubkey` and `local_delayedpubkey` from the
+/// matching basepoints.
+pub fn derive_public_key(
+    secp: &Secp256k1<secp256k1::All>,
+    basepoint: &secp256k1::PublicKey,
+    per_commitment_point: &secp256k1::PublicKey,
+) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+    let tweak = key_tweak(secp, per_commitment_point, basepoint)?;
+    let mut key = *basepoint;
+    key.add_exp_assign(secp, &tweak)?;
+    Ok(key)
+}
+
+/// The private key for `derive_public_key`.
+pub fn derive_private_key(
+    secp: &Secp256k1<secp256k1::All>,
+    basepoint_secret: &secp256k1::SecretKey,
+    per_commitment_point: &secp256k1::PublicKey,
+) -> Result<secp256k1::SecretKey, secp256k1::Error> {
+    let basepoint = secp256k1::PublicKey::from_secret_key(secp, basepoint_secret);
+    let tweak = key_tweak(secp, per_commitment_point, &basepoint)?;
+    let mut key = basepoint_secret.clone();
+    key.add_assign(secp, &tweak)?;
+    Ok(key)
+}
+
+/// `revocationpubkey`, which neither party can sign for alone: the remote party's revocation
+/// basepoint is combined with the local party's per-commitment point, whose secret the local
+/// party only hands over once the commitment is revoked.
+pub fn derive_revocation_public_key(
+    secp: &Secp256k1<secp256k1::All>,
+    revocation_basepoint: &secp256k1::PublicKey,
+    per_commitment_point: &secp256k1::PublicKey,
+) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+    let mut from_basepoint = *revocation_basepoint;
+    from_basepoint.mul_assign(secp, &key_tweak(secp, revocation_basepoint, per_commitment_point)?)?;
+    let mut from_per_commitment_point = *per_commitment_point;
+    from_per_commitment_point.mul_assign(secp, &key_tweak(secp, per_commitment_point, revocation_basepoint)?)?;
+    from_basepoint.combine(secp, &from_per_commitment_point)
+}
+
+/// The private key for `derive_revocation_public_key`, once the per-commitment secret is known.
+pub fn derive_revocation_private_key(
+    secp: &Secp256k1<secp256k1::All>,
+    revocation_basepoint_secret: &secp256k1::SecretKey,
+    per_commitment_secret: &[u8; 32],
+) -> Result<secp256k1::SecretKey, secp256k1::Error> {
+    let revocation_basepoint = secp256k1::PublicKey::from_secret_key(secp, revocation_basepoint_secret);
+    let per_commitment_secret = secp256k1::SecretKey::from_slice(secp, &per_commitment_secret[..])?;
+    let per_commitment_point = secp256k1::PublicKey::from_secret_key(secp, &per_commitment_secret);
+
+    let mut from_basepoint = revocation_basepoint_secret.clone();
+    from_basepoint.mul_assign(secp, &key_tweak(secp, &revocation_basepoint, &per_commitment_point)?)?;
+    let mut from_per_commitment_secret = per_commitment_secret;
+    from_per_commitment_secret.mul_assign(secp, &key_tweak(secp, &per_commitment_point, &revocation_basepoint)?)?;
+    from_basepoint.add_assign(secp, &from_per_commitment_secret)?;
+    Ok(from_basepoint)
+}
+
+impl ChannelSecrets {
+    pub fn basepoints(&self, secp: &Secp256k1<secp256k1::All>) -> ChannelBasepoints {
+        ChannelBasepoints {
+            funding_pubkey: secp256k1::PublicKey::from_secret_key(secp, &self.funding_key),
+            revocation_basepoint: secp256k1::PublicKey::from_secret_key(secp, &self.revocation_basepoint_secret),
+            payment_basepoint: secp256k1::PublicKey::from_secret_key(secp, &self.payment_basepoint_secret),
+            delayed_payment_basepoint: secp256k1::PublicKey::from_secret_key(secp, &self.delayed_payment_basepoint_secret),
+            htlc_basepoint: secp256k1::PublicKey::from_secret_key(secp, &self.htlc_basepoint_secret),
+        }
+    }
+
+    /// Our per-commitment secret for a commitment number.
+    pub fn per_commitment_secret(&self, commitment_number: u64) -> Result<[u8; 32], ShachainError> {
+        Ok(per_commitment_secret(&self.commitment_seed, per_commitment_index(commitment_number)?))
+    }
+
+    /// Commitment numbers past the last per-commitment secret have no point, which is reported
+    /// like any other invalid secret key.
+    pub fn per_commitment_point(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        commitment_number: u64,
+    ) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+        let secret = self.per_commitment_secret(commitment_number).map_err(|_| secp256k1::Error::InvalidSecretKey)?;
+        per_commitment_point(secp, &secret)
+    }
+}
+
+impl CommitmentKeys {
+    pub fn derive(
+        secp: &Secp256k1<secp256k1::All>,
+        per_commitment_point: &secp256k1::PublicKey,
+        local: &ChannelBasepoints,
+        remote: &ChannelBasepoints,
+        channel_type: ChannelType,
+    ) -> Result<CommitmentKeys, secp256k1::Error> {
+        let remote_pubkey = if channel_type.static_remotekey() {
+            remote.payment_basepoint
+        } else {
+            derive_public_key(secp, &remote.payment_basepoint, per_commitment_point)?
+        };
+        Ok(CommitmentKeys {
+            per_commitment_point: *per_commitment_point,
+            revocation_pubkey: derive_revocation_public_key(secp, &remote.revocation_basepoint, per_commitment_point)?,
+            local_delayed_pubkey: derive_public_key(secp, &local.delayed_payment_basepoint, per_commitment_point)?,
+            local_htlc_pubkey: derive_public_key(secp, &local.htlc_basepoint, per_commitment_point)?,
+            remote_htlc_pubkey: derive_public_key(secp, &remote.htlc_basepoint, per_commitment_point)?,
+            remote_pubkey,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn bolt3_key_derivation_vectors() {
+        let secp = Secp256k1::new();
+        let base_secret = unwrap!(secp256k1::SecretKey::from_slice(&secp, &hex!("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")));
+        let per_commitment_secret = hex!("1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100");
+        let base_point = secp256k1::PublicKey::from_secret_key(&secp, &base_secret);
+        assert_eq!(&base_point.serialize()[..], &hex!("036d6caac248af96f6afa7f904f550253a0f3ef3f5aa2fe6838a95b216691468e2")[..]);
+        let per_commitment_point = unwrap!(per_commitment_point(&secp, &per_commitment_secret));
+        assert_eq!(&per_commitment_point.serialize()[..], &hex!("025f7117a78150fe2ef97db7cfc83bd57b2e2c0d0dd25eaf467a4a1c2a45ce1486")[..]);
+
+        let pubkey = unwrap!(derive_public_key(&secp, &base_point, &per_commitment_point));
+        assert_eq!(&pubkey.serialize()[..], &hex!("0235f2dbfaa89b57ec7b055afe29849ef7ddfeb1cefdb9ebdc43f5494984db29e5")[..]);
+        let privkey = unwrap!(derive_private_key(&secp, &base_secret, &per_commitment_point));
+        assert_eq!(&privkey[..], &hex!("cbced912d3b21bf196a766651e436aff192362621ce317704ea2f75d87e7be0f")[..]);
+
+        let revocation_pubkey = unwrap!(derive_revocation_public_key(&secp, &base_point, &per_commitment_point));
+        assert_eq!(&revocation_pubkey.serialize()[..], &hex!("02916e326636d19c33f13e8c0c3a03dd157f332f3e99c317c141dd865eb01f8ff0")[..]);
+        let revocation_privkey = unwrap!(derive_revocation_private_key(&secp, &base_secret, &per_commitment_secret));
+        assert_eq!(&revocation_privkey[..], &hex!("d09ffff62ddb2297ab000cc85bcb4283fdeb6aa052affbc9dddcf33b61078110")[..]);
+    }
+
+    #[test]
+    fn commitment_keys_match_secrets() {
+        let secp = Secp256k1::new();
+        let secrets = |byte| ChannelSecrets {
+            funding_key: test_key(&secp, byte).0,
+            revocation_basepoint_secret: test_key(&secp, byte + 1).0,
+            payment_basepoint_secret: test_key(&secp, byte + 2).0,
+            delayed_payment_basepoint_secret: test_key(&secp, byte + 3).0,
+            htlc_basepoint_secret: test_key(&secp, byte + 4).0,
+            commitment_seed: [byte; 32],
+        };
+        let (local, remote) = (secrets(0x10), secrets(0x20));
+        let point = unwrap!(local.per_commitment_point(&secp, 3));
+        let (local_basepoints, remote_basepoints) = (local.basepoints(&secp), remote.basepoints(&secp));
+        let keys = unwrap!(CommitmentKeys::derive(&secp, &point, &local_basepoints, &remote_basepoints, ChannelType::StaticRemoteKey));
+
+        let delayed = unwrap!(derive_private_key(&secp, &local.delayed_payment_basepoint_secret, &point));
+        assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &delayed), keys.local_delayed_pubkey);
+        assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &remote.payment_basepoint_secret), keys.remote_pubkey);
+        let revocation = {
+            unwrap!(derive_revocation_private_key(&secp, &remote.revocation_basepoint_secret, &unwrap!(local.per_commitment_secret(3))))
+        };
+        assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &revocation), keys.revocation_pubkey);
+
+        // Without option_static_remotekey the to_remote key changes with every commitment.
+        let legacy = unwrap!(CommitmentKeys::derive(&secp, &point, &local_basepoints, &remote_basepoints, ChannelType::Legacy));
+        let remote_key = unwrap!(derive_private_key(&secp, &remote.payment_basepoint_secret, &point));
+        assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &remote_key), legacy.remote_pubkey);
+        assert_ne!(legacy.remote_pubkey, keys.remote_pubkey);
+        assert_eq!(legacy.local_delayed_pubkey, keys.local_delayed_pubkey);
+    }
+}
@@ -0,0 +1,264 @@
+use super::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const CHANNEL_FILE_EXTENSION: &str = "chan";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+
+/// channel_id (32 bytes), crc32c of the state (u32)
+const CHANNEL_FILE_HEADER_LEN: usize = 36;
+
+#[derive(Debug, Fail)]
+pub enum ChannelPersistError {
+    #[fail(display = "io error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "stored state of channel {:?} is corrupted", channel_id)]
+    Corrupted {
+        channel_id: ChannelId,
+    },
+    #[fail(display = "failed to restore channel {:?}: {}", channel_id, err)]
+    InvalidState {
+        channel_id: ChannelId,
+        err: ChannelStateError,
+    },
+}
+
+impl From<io::Error> for ChannelPersistError {
+    fn from(err: io::Error) -> ChannelPersistError {
+        ChannelPersistError::Io(err)
+    }
+}
+
+/// Durable storage for channel states, as produced by `Channel::to_bytes`.
+///
+/// `persist_channel` must not return until the state would survive a crash, and a crash part way
+/// through must leave the previous state in place.
+pub trait ChannelPersister {
+    fn persist_channel(&mut self, channel_id: &ChannelId, state: &[u8]) -> Result<(), ChannelPersistError>;
+
+    /// Forgets a channel once it's closed and its funds are swept.
+    fn remove_channel(&mut self, channel_id: &ChannelId) -> Result<(), ChannelPersistError>;
+
+    /// The latest state of every stored channel, in order of channel id.
+    fn load_channels(&self) -> Result<Vec<(ChannelId, Vec<u8>)>, ChannelPersistError>;
+}
+
+/// Stores each channel in its own file in a directory, named after the hex channel id.
+///
+/// A new state is written to a temporary file, synced, and renamed over the old one, and the
+/// directory is then synced so that the rename itself is durable. Each file carries a checksum
+/// so that a corrupted state is refused rather than half-loaded.
+pub struct FileChannelPersister {
+    dir: PathBuf,
+}
+
+fn channel_file_name(channel_id: &ChannelId) -> String {
+    let hex: String = channel_id.0.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.{}", hex, CHANNEL_FILE_EXTENSION)
+}
+
+fn parse_channel_file_name(path: &Path) -> Option<ChannelId> {
+    if path.extension()? != CHANNEL_FILE_EXTENSION {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    if stem.len() != 64 {
+        return None;
+    }
+    let mut channel_id = [0u8; 32];
+    for (i, byte) in channel_id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(stem.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(ChannelId(channel_id))
+}
+
+impl FileChannelPersister {
+    /// Opens (or creates) the store in `dir`.
+    pub fn open(dir: &Path) -> Result<FileChannelPersister, ChannelPersistError> {
+        fs::create_dir_all(dir)?;
+        // Leftovers from a crash while persisting. The previous state is still intact.
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |extension| extension == TEMP_FILE_EXTENSION) {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(FileChannelPersister { dir: dir.to_owned() })
+    }
+
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl ChannelPersister for FileChannelPersister {
+    fn persist_channel(&mut self, channel_id: &ChannelId, state: &[u8]) -> Result<(), ChannelPersistError> {
+        let path = self.dir.join(channel_file_name(channel_id));
+        let temp_path = path.with_extension(TEMP_FILE_EXTENSION);
+        let mut cursor = WriteCursor::new();
+        cursor.write_channel_id(channel_id);
+        cursor.write_u32(crc32c(state));
+        cursor.write_slice(state);
+
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?;
+        file.write_all(&cursor.into_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    fn remove_channel(&mut self, channel_id: &ChannelId) -> Result<(), ChannelPersistError> {
+        match fs::remove_file(self.dir.join(channel_file_name(channel_id))) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    fn load_channels(&self) -> Result<Vec<(ChannelId, Vec<u8>)>, ChannelPersistError> {
+        let mut channels = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let channel_id = match parse_channel_file_name(&path) {
+                Some(channel_id) => channel_id,
+                None => continue,
+            };
+            let contents = fs::read(&path)?;
+            if contents.len() < CHANNEL_FILE_HEADER_LEN {
+                return Err(ChannelPersistError::Corrupted { channel_id });
+            }
+            let mut cursor = ReadCursor::new(&contents);
+            let stored_channel_id = unwrap!(cursor.read_channel_id());
+            let crc = unwrap!(cursor.read_u32());
+            let state = cursor.read_to_end();
+            if stored_channel_id != channel_id || crc32c(state) != crc {
+                return Err(ChannelPersistError::Corrupted { channel_id });
+            }
+            channels.push((channel_id, state.to_vec()));
+        }
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(channels)
+    }
+}
+
+/// Restores every stored channel. `secrets_for` gives the secrets of each channel, which aren't
+/// part of the stored state.
+///
+/// States in an older format are upgraded by `Channel::from_bytes` and stored again in the
+/// current format straight away. A state from a newer version is refused and left untouched, so
+/// that a downgrade never destroys it.
+pub fn load_channels<P, F>(
+    persister: &mut P,
+    secp: &Secp256k1<secp256k1::All>,
+    mut secrets_for: F,
+) -> Result<Vec<Channel>, ChannelPersistError>
+where
+    P: ChannelPersister + ?Sized,
+    F: FnMut(&ChannelId) -> ChannelSecrets,
+{
+    let mut channels = Vec::new();
+    for (channel_id, state) in persister.load_channels()? {
+        let channel = {
+            Channel::from_bytes(secp, secrets_for(&channel_id), &state)
+            .map_err(|err| ChannelPersistError::InvalidState { channel_id, err })?
+        };
+        if *channel.channel_id() != channel_id {
+            return Err(ChannelPersistError::Corrupted { channel_id });
+        }
+        if state.first() != Some(&CHANNEL_STATE_VERSION) {
+            persister.persist_channel(&channel_id, &channel.to_bytes())?;
+        }
+        channels.push(channel);
+    }
+    Ok(channels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let name = format!("channel_store_test_{:016x}", rand::thread_rng().gen::<u64>());
+        std::env::temp_dir().join(name)
+    }
+
+    #[test]
+    fn channels_survive_restart() {
+        let secp = Secp256k1::new();
+        let dir = temp_dir();
+        let (channel_id, opener_state) = {
+            let mut persister = unwrap!(FileChannelPersister::open(&dir));
+            let mut peer_persister = TestChannelPersister::default();
+            let (mut opener, mut accepter) = test_channels(&secp, &mut persister, &mut peer_persister, |_| ());
+            let add = unwrap!(opener.add_htlc(200_000_000, [1; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+            unwrap!(accepter.handle_msg(&secp, &mut peer_persister, Msg::UpdateAddHtlc(add)));
+            commitment_dance(&secp, &mut opener, &mut persister, &mut accepter, &mut peer_persister);
+
+            // An update we never signed is forgotten, like after a disconnect.
+            unwrap!(opener.add_htlc(1_000_000, [2; 32], 600_000, vec![0; ONION_PACKET_LEN], None));
+            opener.disconnect();
+            (*opener.channel_id(), opener.to_bytes())
+        };
+        assert_eq!(opener_state[0], CHANNEL_STATE_VERSION);
+
+        // Simulate a crash half way through persisting.
+        let path = dir.join(channel_file_name(&channel_id));
+        unwrap!(fs::write(path.with_extension(TEMP_FILE_EXTENSION), &[1, 2, 3]));
+
+        let mut persister = unwrap!(FileChannelPersister::open(&dir));
+        assert!(!path.with_extension(TEMP_FILE_EXTENSION).exists());
+        let channels = unwrap!(load_channels(&mut persister, &secp, |_| test_channel_secrets(&secp, 0x10)));
+        assert_eq!(channels.len(), 1);
+        let opener = &channels[0];
+        assert_eq!(opener.to_bytes(), opener_state);
+        assert_eq!(opener.state(), ChannelState::Normal);
+        assert_eq!(opener.local_balance_msat(), 800_000_000);
+        assert_eq!(opener.update_queue().htlcs_in_local_commitment().len(), 1);
+        assert!(opener.local_commitment_tx().is_some());
+
+        unwrap!(persister.remove_channel(&channel_id));
+        assert!(unwrap!(persister.load_channels()).is_empty());
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn damaged_or_newer_states_are_refused() {
+        let secp = Secp256k1::new();
+        let dir = temp_dir();
+        let mut persister = unwrap!(FileChannelPersister::open(&dir));
+        let (opener, _accepter) = test_channels(&secp, &mut persister, &mut TestChannelPersister::default(), |_| ());
+        let channel_id = *opener.channel_id();
+        let path = dir.join(channel_file_name(&channel_id));
+
+        let mut contents = unwrap!(fs::read(&path));
+        let last = contents.len() - 1;
+        contents[last] ^= 1;
+        unwrap!(fs::write(&path, &contents));
+        match load_channels(&mut persister, &secp, |_| test_channel_secrets(&secp, 0x10)) {
+            Err(ChannelPersistError::Corrupted { channel_id: id }) => assert_eq!(id, channel_id),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("loaded a corrupted channel"),
+        }
+
+        let mut state = opener.to_bytes();
+        state[0] = CHANNEL_STATE_VERSION + 1;
+        unwrap!(persister.persist_channel(&channel_id, &state));
+        match load_channels(&mut persister, &secp, |_| test_channel_secrets(&secp, 0x10)) {
+            Err(ChannelPersistError::InvalidState { err: ChannelStateError::UnsupportedVersion { .. }, .. }) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("loaded a channel from a newer version"),
+        }
+        assert_eq!(unwrap!(persister.load_channels()), vec![(channel_id, state)]);
+
+        match Channel::from_bytes(&secp, test_channel_secrets(&secp, 0x20), &opener.to_bytes()) {
+            Err(ChannelStateError::WrongSecrets) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(..) => panic!("restored a channel with the wrong secrets"),
+        }
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+}
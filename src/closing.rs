@@ -0,0 +1,380 @@
+use super::*;
+
+#[derive(Debug, Fail)]
+pub enum ShutdownError {
+    #[fail(display = "message is for a different channel")]
+    WrongChannelId,
+    #[fail(display = "shutdown scriptpubkey is not a standard output script")]
+    NonStandardScript,
+    #[fail(display = "shutdown scriptpubkey does not match the upfront shutdown script")]
+    UpfrontShutdownScriptMismatch,
+}
+
+/// Checks a received `shutdown` message.
+///
+/// `remote_upfront_shutdown_script` is the `shutdown_scriptpubkey` the peer sent in
+/// `open_channel` or `accept_channel`, if any. If `option_upfront_shutdown_script` was negotiated
+/// and that script was non-empty, the peer is not allowed to close to any other script.
+pub fn check_shutdown(
+    channel_id: &ChannelId,
+    msg: &ShutdownMsg,
+    local_features: &LocalFeatures,
+    remote_features: &LocalFeatures,
+    remote_upfront_shutdown_script: Option<&[u8]>,
+) -> Result<(), ShutdownError> {
+    if msg.channel_id != *channel_id {
+        return Err(ShutdownError::WrongChannelId);
+    }
+    if !is_valid_shutdown_script(&msg.scriptpubkey) {
+        return Err(ShutdownError::NonStandardScript);
+    }
+    if local_features.upfront_shutdown_script_negotiated(remote_features) {
+        if let Some(upfront) = remote_upfront_shutdown_script {
+            if !upfront.is_empty() && upfront != &msg.scriptpubkey[..] {
+                return Err(ShutdownError::UpfrontShutdownScriptMismatch);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Shutdown scripts must be P2PKH, P2SH, P2WPKH or P2WSH.
+pub fn is_valid_shutdown_script(script: &[u8]) -> bool {
+    const OP_DUP: u8 = 0x76;
+    const OP_HASH160: u8 = 0xa9;
+    const OP_EQUALVERIFY: u8 = 0x88;
+    const OP_EQUAL: u8 = 0x87;
+    const OP_CHECKSIG: u8 = 0xac;
+
+    match script.len() {
+        25 => {
+            script[0] == OP_DUP && script[1] == OP_HASH160 && script[2] == 20
+            && script[23] == OP_EQUALVERIFY && script[24] == OP_CHECKSIG
+        },
+        23 => script[0] == OP_HASH160 && script[1] == 20 && script[22] == OP_EQUAL,
+        22 => script[0] == 0x00 && script[1] == 20,
+        34 => script[0] == 0x00 && script[1] == 32,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum ClosingFeeError {
+    #[fail(display = "message is for a different channel")]
+    WrongChannelId,
+    #[fail(display = "the non-funder can't propose a fee before the funder")]
+    UnexpectedFirstProposal,
+    #[fail(display = "peer's fee range does not overlap ours")]
+    NoFeeRangeOverlap,
+    #[fail(display = "fee {} is outside the overlap of our fee ranges", fee_satoshis)]
+    FeeOutsideOverlap {
+        fee_satoshis: u64,
+    },
+    #[fail(display = "fee {} is outside our fee range", fee_satoshis)]
+    FeeOutsideRange {
+        fee_satoshis: u64,
+    },
+    #[fail(display = "fee {} does not match the fee {} we proposed", fee_satoshis, sent_fee_satoshis)]
+    FeeMismatch {
+        fee_satoshis: u64,
+        sent_fee_satoshis: u64,
+    },
+    #[fail(display = "fee {} did not move towards our last proposal", fee_satoshis)]
+    FeeNotConverging {
+        fee_satoshis: u64,
+    },
+    #[fail(display = "negotiation has already finished")]
+    AlreadyAgreed,
+}
+
+/// What we should do after receiving a `closing_signed`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ClosingFeeStep {
+    /// Both sides have agreed on the fee. If `reply` is set we still need to send a
+    /// `closing_signed` with this fee so that the peer can also broadcast.
+    Agreed {
+        fee_satoshis: u64,
+        reply: bool,
+    },
+    /// Send a `closing_signed` proposing this fee.
+    CounterPropose {
+        fee_satoshis: u64,
+    },
+}
+
+/// Drives the `closing_signed` fee negotiation of a cooperative close.
+///
+/// If the peer sends a `fee_range` we use the range-based protocol which settles in at most two
+/// round trips. Otherwise we fall back to the legacy protocol where each side moves its proposal
+/// strictly towards the other until they meet.
+pub struct ClosingFeeNegotiator {
+    channel_id: ChannelId,
+    is_funder: bool,
+    fee_range: FeeRange,
+    last_sent_fee_satoshis: Option<u64>,
+    last_received_fee_satoshis: Option<u64>,
+    agreed_fee_satoshis: Option<u64>,
+}
+
+impl ClosingFeeNegotiator {
+    /// `fee_range` is the range of fees we're willing to pay (or have the funder pay). For the
+    /// funder, the maximum must not exceed what it can afford from its output.
+    pub fn new(channel_id: ChannelId, is_funder: bool, fee_range: FeeRange) -> ClosingFeeNegotiator {
+        ClosingFeeNegotiator {
+            channel_id,
+            is_funder,
+            fee_range,
+            last_sent_fee_satoshis: None,
+            last_received_fee_satoshis: None,
+            agreed_fee_satoshis: None,
+        }
+    }
+
+    pub fn fee_range(&self) -> FeeRange {
+        self.fee_range
+    }
+
+    pub fn agreed_fee_satoshis(&self) -> Option<u64> {
+        self.agreed_fee_satoshis
+    }
+
+    /// The funder's opening proposal. `estimate` is clamped into our fee range.
+    pub fn initial_proposal(&mut self, estimate_fee_satoshis: u64) -> Result<u64, ClosingFeeError> {
+        if !self.is_funder {
+            return Err(ClosingFeeError::UnexpectedFirstProposal);
+        }
+        let fee_satoshis = self.fee_range.clamp(estimate_fee_satoshis);
+        self.last_sent_fee_satoshis = Some(fee_satoshis);
+        Ok(fee_satoshis)
+    }
+
+    pub fn handle_closing_signed(&mut self, msg: &ClosingSignedMsg)
+        -> Result<ClosingFeeStep, ClosingFeeError>
+    {
+        if msg.channel_id != self.channel_id {
+            return Err(ClosingFeeError::WrongChannelId);
+        }
+        if self.agreed_fee_satoshis.is_some() {
+            return Err(ClosingFeeError::AlreadyAgreed);
+        }
+        let fee_satoshis = msg.fee_satoshis;
+
+        if self.last_sent_fee_satoshis == Some(fee_satoshis) {
+            return Ok(self.agree(fee_satoshis, false));
+        }
+
+        let step = match msg.fee_range {
+            Some(their_range) => self.handle_fee_range(fee_satoshis, &their_range)?,
+            None => self.handle_legacy(fee_satoshis)?,
+        };
+        self.last_received_fee_satoshis = Some(fee_satoshis);
+        Ok(step)
+    }
+
+    fn handle_fee_range(&mut self, fee_satoshis: u64, their_range: &FeeRange)
+        -> Result<ClosingFeeStep, ClosingFeeError>
+    {
+        let overlap = match self.fee_range.overlap(their_range) {
+            Some(overlap) => overlap,
+            None => return Err(ClosingFeeError::NoFeeRangeOverlap),
+        };
+        if self.is_funder {
+            if !overlap.contains(fee_satoshis) {
+                return Err(ClosingFeeError::FeeOutsideOverlap { fee_satoshis });
+            }
+            return Ok(self.agree(fee_satoshis, true));
+        }
+
+        if let Some(sent_fee_satoshis) = self.last_sent_fee_satoshis {
+            return Err(ClosingFeeError::FeeMismatch { fee_satoshis, sent_fee_satoshis });
+        }
+        if overlap.contains(fee_satoshis) {
+            return Ok(self.agree(fee_satoshis, true));
+        }
+        let counter_fee_satoshis = overlap.clamp(fee_satoshis);
+        self.last_sent_fee_satoshis = Some(counter_fee_satoshis);
+        Ok(ClosingFeeStep::CounterPropose { fee_satoshis: counter_fee_satoshis })
+    }
+
+    fn handle_legacy(&mut self, fee_satoshis: u64) -> Result<ClosingFeeStep, ClosingFeeError> {
+        if let (Some(sent), Some(received)) = (self.last_sent_fee_satoshis, self.last_received_fee_satoshis) {
+            let (low, high) = (cmp::min(sent, received), cmp::max(sent, received));
+            if fee_satoshis <= low || fee_satoshis >= high {
+                return Err(ClosingFeeError::FeeNotConverging { fee_satoshis });
+            }
+        }
+        if self.fee_range.contains(fee_satoshis) {
+            return Ok(self.agree(fee_satoshis, true));
+        }
+
+        // Move half way towards their fee, but never past the edge of our own range. Once we're
+        // stuck at the edge the peer wants a fee we won't accept.
+        let counter_fee_satoshis = match self.last_sent_fee_satoshis {
+            Some(sent) => {
+                let midpoint = (sent / 2) + (fee_satoshis / 2) + (sent % 2 + fee_satoshis % 2) / 2;
+                let counter_fee_satoshis = self.fee_range.clamp(midpoint);
+                if counter_fee_satoshis == sent {
+                    return Err(ClosingFeeError::FeeOutsideRange { fee_satoshis });
+                }
+                counter_fee_satoshis
+            },
+            None => self.fee_range.clamp(fee_satoshis),
+        };
+        self.last_sent_fee_satoshis = Some(counter_fee_satoshis);
+        Ok(ClosingFeeStep::CounterPropose { fee_satoshis: counter_fee_satoshis })
+    }
+
+    fn agree(&mut self, fee_satoshis: u64, reply: bool) -> ClosingFeeStep {
+        self.agreed_fee_satoshis = Some(fee_satoshis);
+        if reply {
+            self.last_sent_fee_satoshis = Some(fee_satoshis);
+        }
+        ClosingFeeStep::Agreed { fee_satoshis, reply }
+    }
+}
+
+/// Builds the unsigned closing transaction. `local_satoshis` and `remote_satoshis` are each
+/// side's balance after the funder has paid the fee. Outputs below `dust_limit_satoshis` are left
+/// out, and the rest are sorted as in BIP 69 so both sides build the same transaction.
+pub fn build_closing_tx(
+    funding_outpoint: OutPoint,
+    local_scriptpubkey: &[u8],
+    local_satoshis: u64,
+    remote_scriptpubkey: &[u8],
+    remote_satoshis: u64,
+    dust_limit_satoshis: u64,
+) -> Transaction {
+    let mut outputs = Vec::with_capacity(2);
+    if local_satoshis >= dust_limit_satoshis {
+        outputs.push(TxOut { value: local_satoshis, script_pubkey: local_scriptpubkey.to_vec() });
+    }
+    if remote_satoshis >= dust_limit_satoshis {
+        outputs.push(TxOut { value: remote_satoshis, script_pubkey: remote_scriptpubkey.to_vec() });
+    }
+    outputs.sort_by(|a, b| (a.value, &a.script_pubkey).cmp(&(b.value, &b.script_pubkey)));
+    Transaction {
+        version: 2,
+        inputs: vec![TxIn {
+            previous_output: funding_outpoint,
+            script_sig: Vec::new(),
+            sequence: 0xffff_ffff,
+            witness: Vec::new(),
+        }],
+        outputs,
+        lock_time: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL_ID: ChannelId = ChannelId([3; 32]);
+
+    fn dummy_signature() -> secp256k1::Signature {
+        let secp = Secp256k1::new();
+        let sk = unwrap!(secp256k1::SecretKey::from_slice(&secp, &[0x11; 32]));
+        let msg = unwrap!(secp256k1::Message::from_slice(&[0x22; 32]));
+        secp.sign(&msg, &sk)
+    }
+
+    fn closing_signed(fee_satoshis: u64, fee_range: Option<FeeRange>) -> ClosingSignedMsg {
+        ClosingSignedMsg {
+            channel_id: CHANNEL_ID,
+            fee_satoshis,
+            signature: dummy_signature(),
+            fee_range,
+        }
+    }
+
+    #[test]
+    fn fee_range_negotiation_converges_on_overlap() {
+        let funder_range = FeeRange { min_fee_satoshis: 100, max_fee_satoshis: 300 };
+        let fundee_range = FeeRange { min_fee_satoshis: 250, max_fee_satoshis: 500 };
+        let mut funder = ClosingFeeNegotiator::new(CHANNEL_ID, true, funder_range);
+        let mut fundee = ClosingFeeNegotiator::new(CHANNEL_ID, false, fundee_range);
+
+        let proposal = unwrap!(funder.initial_proposal(150));
+        assert_eq!(proposal, 150);
+        let step = unwrap!(fundee.handle_closing_signed(&closing_signed(proposal, Some(funder_range))));
+        assert_eq!(step, ClosingFeeStep::CounterPropose { fee_satoshis: 250 });
+
+        let step = unwrap!(funder.handle_closing_signed(&closing_signed(250, Some(fundee_range))));
+        assert_eq!(step, ClosingFeeStep::Agreed { fee_satoshis: 250, reply: true });
+
+        let step = unwrap!(fundee.handle_closing_signed(&closing_signed(250, Some(funder_range))));
+        assert_eq!(step, ClosingFeeStep::Agreed { fee_satoshis: 250, reply: false });
+    }
+
+    #[test]
+    fn fee_range_without_overlap_fails() {
+        let funder_range = FeeRange { min_fee_satoshis: 100, max_fee_satoshis: 200 };
+        let fundee_range = FeeRange { min_fee_satoshis: 250, max_fee_satoshis: 500 };
+        let mut fundee = ClosingFeeNegotiator::new(CHANNEL_ID, false, fundee_range);
+        match fundee.handle_closing_signed(&closing_signed(150, Some(funder_range))) {
+            Err(ClosingFeeError::NoFeeRangeOverlap) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn legacy_negotiation_moves_towards_agreement() {
+        let mut funder = ClosingFeeNegotiator::new(
+            CHANNEL_ID, true, FeeRange { min_fee_satoshis: 100, max_fee_satoshis: 300 },
+        );
+        let mut fundee = ClosingFeeNegotiator::new(
+            CHANNEL_ID, false, FeeRange { min_fee_satoshis: 280, max_fee_satoshis: 500 },
+        );
+
+        let proposal = unwrap!(funder.initial_proposal(100));
+        let step = unwrap!(fundee.handle_closing_signed(&closing_signed(proposal, None)));
+        assert_eq!(step, ClosingFeeStep::CounterPropose { fee_satoshis: 280 });
+        let step = unwrap!(funder.handle_closing_signed(&closing_signed(280, None)));
+        assert_eq!(step, ClosingFeeStep::Agreed { fee_satoshis: 280, reply: true });
+        let step = unwrap!(fundee.handle_closing_signed(&closing_signed(280, None)));
+        assert_eq!(step, ClosingFeeStep::Agreed { fee_satoshis: 280, reply: false });
+
+        // Without an overlap each side stops at the edge of its range and then gives up.
+        let mut funder = ClosingFeeNegotiator::new(
+            CHANNEL_ID, true, FeeRange { min_fee_satoshis: 100, max_fee_satoshis: 200 },
+        );
+        let mut fundee = ClosingFeeNegotiator::new(
+            CHANNEL_ID, false, FeeRange { min_fee_satoshis: 400, max_fee_satoshis: 500 },
+        );
+        let proposal = unwrap!(funder.initial_proposal(100));
+        let step = unwrap!(fundee.handle_closing_signed(&closing_signed(proposal, None)));
+        assert_eq!(step, ClosingFeeStep::CounterPropose { fee_satoshis: 400 });
+        let step = unwrap!(funder.handle_closing_signed(&closing_signed(400, None)));
+        assert_eq!(step, ClosingFeeStep::CounterPropose { fee_satoshis: 200 });
+        match fundee.handle_closing_signed(&closing_signed(200, None)) {
+            Err(ClosingFeeError::FeeOutsideRange { fee_satoshis: 200 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(fundee.agreed_fee_satoshis(), None);
+    }
+
+    #[test]
+    fn upfront_shutdown_script_is_enforced() {
+        let upfront = {
+            let mut script = vec![0x00, 0x14];
+            script.extend(&[0x01; 20][..]);
+            script
+        };
+        let other = {
+            let mut script = vec![0x00, 0x14];
+            script.extend(&[0x02; 20][..]);
+            script
+        };
+        let features = unwrap!(unwrap!(UnfilteredLocalFeatures::from_feature_flags(&[0x20])).filter());
+        assert!(features.upfront_shutdown_script_negotiated(&features));
+
+        let msg = ShutdownMsg { channel_id: CHANNEL_ID, scriptpubkey: other };
+        match check_shutdown(&CHANNEL_ID, &msg, &features, &features, Some(&upfront)) {
+            Err(ShutdownError::UpfrontShutdownScriptMismatch) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let msg = ShutdownMsg { channel_id: CHANNEL_ID, scriptpubkey: upfront.clone() };
+        unwrap!(check_shutdown(&CHANNEL_ID, &msg, &features, &features, Some(&upfront)));
+    }
+}
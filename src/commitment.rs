@@ -0,0 +1,552 @@
+use super::*;
+
+/// The weight of a commitment transaction with no HTLC outputs, with and without anchors. Each
+/// untrimmed HTLC adds `COMMITMENT_TX_WEIGHT_PER_HTLC`.
+pub const COMMITMENT_TX_BASE_WEIGHT: u64 = 724;
+pub const ANCHOR_COMMITMENT_TX_BASE_WEIGHT: u64 = 1124;
+pub const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
+pub const HTLC_TIMEOUT_TX_WEIGHT: u64 = 663;
+pub const HTLC_SUCCESS_TX_WEIGHT: u64 = 703;
+pub const ANCHOR_OUTPUT_SATOSHIS: u64 = 330;
+
+/// An HTLC as it appears in one commitment transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitmentHtlc {
+    /// Whether the commitment's holder offered the HTLC, in which case it times out back to
+    /// them. Otherwise they received it and can claim it with the preimage.
+    pub offered: bool,
+    pub amount_msat: u64,
+    pub payment_hash: [u8; 32],
+    pub cltv_expiry: u32,
+}
+
+/// What stays the same across all of one party's commitment transactions for a channel. As with
+/// `CommitmentKeys`, "local" is the party holding the commitment and "remote" the other party, so
+/// our commitment and theirs get built from mirrored params.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitmentParams {
+    pub funding_outpoint: OutPoint,
+    pub funding_satoshis: u64,
+    pub local_funding_pubkey: secp256k1::PublicKey,
+    pub remote_funding_pubkey: secp256k1::PublicKey,
+    /// The funder pays the commitment transaction fee.
+    pub local_is_funder: bool,
+    /// How long the local party has to wait to spend their `to_local` output. Chosen by the
+    /// remote party.
+    pub to_self_delay: u16,
+    /// The local party's dust limit. Outputs below it are left out.
+    pub dust_limit_satoshis: u64,
+    /// XORed with the commitment number before it goes in the locktime and sequence.
+    pub commitment_number_obscurer: u64,
+    /// `option_anchors_zero_fee_htlc_tx`: each party gets an anchor output to bump the
+    /// commitment's fee with, and HTLC transactions pay no fee of their own.
+    pub anchors: bool,
+}
+
+/// The balances and HTLCs in one commitment transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitmentState {
+    pub commitment_number: u64,
+    pub to_local_msat: u64,
+    pub to_remote_msat: u64,
+    pub feerate_per_kw: u32,
+    pub htlcs: Vec<CommitmentHtlc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitmentTx {
+    pub tx: Transaction,
+    pub fee_satoshis: u64,
+    /// The output index of each of the state's HTLCs, in the same order, or `None` for those
+    /// which were trimmed.
+    pub htlc_output_indexes: Vec<Option<u32>>,
+    /// The output index of the local party's anchor, if there is one.
+    pub local_anchor_output_index: Option<u32>,
+}
+
+/// The lower 48 bits of `SHA256(opener_payment_basepoint || accepter_payment_basepoint)`.
+pub fn commitment_number_obscurer(
+    opener_payment_basepoint: &secp256k1::PublicKey,
+    accepter_payment_basepoint: &secp256k1::PublicKey,
+) -> u64 {
+    let hash = sha256(&[&opener_payment_basepoint.serialize()[..], &accepter_payment_basepoint.serialize()[..]]);
+    let mut obscurer = [0; 8];
+    obscurer[2..].copy_from_slice(&hash[26..]);
+    u64::from_be_bytes(obscurer)
+}
+
+/// The 2-of-2 multisig script of the funding output, with the keys in lexicographic order.
+pub fn funding_script(first_pubkey: &secp256k1::PublicKey, second_pubkey: &secp256k1::PublicKey) -> Vec<u8> {
+    let (first, second) = (first_pubkey.serialize(), second_pubkey.serialize());
+    let (lesser, greater) = if first[..] < second[..] { (first, second) } else { (second, first) };
+    ScriptBuilder::new()
+    .push_opcode(OP_2)
+    .push_slice(&lesser[..])
+    .push_slice(&greater[..])
+    .push_opcode(OP_2)
+    .push_opcode(OP_CHECKMULTISIG)
+    .into_bytes()
+}
+
+/// Pays the local party after `to_self_delay` blocks, or the remote party straight away with the
+/// revocation key.
+pub fn to_local_script(keys: &CommitmentKeys, to_self_delay: u16) -> Vec<u8> {
+    ScriptBuilder::new()
+    .push_opcode(OP_IF)
+    .push_pubkey(&keys.revocation_pubkey)
+    .push_opcode(OP_ELSE)
+    .push_int(to_self_delay as i64)
+    .push_opcode(OP_CHECKSEQUENCEVERIFY)
+    .push_opcode(OP_DROP)
+    .push_pubkey(&keys.local_delayed_pubkey)
+    .push_opcode(OP_ENDIF)
+    .push_opcode(OP_CHECKSIG)
+    .into_bytes()
+}
+
+/// With anchors, the `to_remote` output is also delayed by a block so that it can't be used
+/// to pin the commitment with a chain of unconfirmed children.
+pub fn to_remote_anchors_script(remote_pubkey: &secp256k1::PublicKey) -> Vec<u8> {
+    ScriptBuilder::new()
+    .push_pubkey(remote_pubkey)
+    .push_opcode(OP_CHECKSIGVERIFY)
+    .push_int(1)
+    .push_opcode(OP_CHECKSEQUENCEVERIFY)
+    .into_bytes()
+}
+
+/// Spendable by its owner's funding key to CPFP the commitment, or by anyone after 16 blocks so
+/// that spent-to-nothing anchors get cleaned out of the UTXO set.
+pub fn anchor_script(funding_pubkey: &secp256k1::PublicKey) -> Vec<u8> {
+    ScriptBuilder::new()
+    .push_pubkey(funding_pubkey)
+    .push_opcode(OP_CHECKSIG)
+    .push_opcode(OP_IFDUP)
+    .push_opcode(OP_NOTIF)
+    .push_int(16)
+    .push_opcode(OP_CHECKSEQUENCEVERIFY)
+    .push_opcode(OP_ENDIF)
+    .into_bytes()
+}
+
+/// Appends the `1 OP_CHECKSEQUENCEVERIFY OP_DROP` anchor channels put on every non-revocation
+/// path of an HTLC script, just before its final `OP_ENDIF`.
+fn push_anchors_delay(builder: ScriptBuilder, anchors: bool) -> ScriptBuilder {
+    if !anchors {
+        return builder;
+    }
+    builder
+    .push_int(1)
+    .push_opcode(OP_CHECKSEQUENCEVERIFY)
+    .push_opcode(OP_DROP)
+}
+
+/// An HTLC offered by the local party: the remote party can claim it with the preimage, and the
+/// local party can take it back with an HTLC-timeout transaction, which the remote party also
+/// signs.
+pub fn offered_htlc_script(keys: &CommitmentKeys, payment_hash: &[u8; 32], anchors: bool) -> Vec<u8> {
+    let builder = {
+        ScriptBuilder::new()
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_HASH160)
+        .push_slice(&hash160(&keys.revocation_pubkey.serialize()[..])[..])
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_ELSE)
+        .push_pubkey(&keys.remote_htlc_pubkey)
+        .push_opcode(OP_SWAP)
+        .push_opcode(OP_SIZE)
+        .push_int(32)
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_NOTIF)
+        .push_opcode(OP_DROP)
+        .push_int(2)
+        .push_opcode(OP_SWAP)
+        .push_pubkey(&keys.local_htlc_pubkey)
+        .push_int(2)
+        .push_opcode(OP_CHECKMULTISIG)
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_HASH160)
+        .push_slice(&ripemd160(&payment_hash[..])[..])
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_ENDIF)
+    };
+    push_anchors_delay(builder, anchors)
+    .push_opcode(OP_ENDIF)
+    .into_bytes()
+}
+
+/// An HTLC received by the local party: they can claim it with the preimage through an
+/// HTLC-success transaction, and the remote party can take it back once `cltv_expiry` passes.
+pub fn received_htlc_script(
+    keys: &CommitmentKeys,
+    payment_hash: &[u8; 32],
+    cltv_expiry: u32,
+    anchors: bool,
+) -> Vec<u8> {
+    let builder = {
+        ScriptBuilder::new()
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_HASH160)
+        .push_slice(&hash160(&keys.revocation_pubkey.serialize()[..])[..])
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_ELSE)
+        .push_pubkey(&keys.remote_htlc_pubkey)
+        .push_opcode(OP_SWAP)
+        .push_opcode(OP_SIZE)
+        .push_int(32)
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_HASH160)
+        .push_slice(&ripemd160(&payment_hash[..])[..])
+        .push_opcode(OP_EQUALVERIFY)
+        .push_int(2)
+        .push_opcode(OP_SWAP)
+        .push_pubkey(&keys.local_htlc_pubkey)
+        .push_int(2)
+        .push_opcode(OP_CHECKMULTISIG)
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_DROP)
+        .push_int(cltv_expiry as i64)
+        .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_ENDIF)
+    };
+    push_anchors_delay(builder, anchors)
+    .push_opcode(OP_ENDIF)
+    .into_bytes()
+}
+
+pub fn htlc_script(keys: &CommitmentKeys, htlc: &CommitmentHtlc, anchors: bool) -> Vec<u8> {
+    if htlc.offered {
+        offered_htlc_script(keys, &htlc.payment_hash, anchors)
+    } else {
+        received_htlc_script(keys, &htlc.payment_hash, htlc.cltv_expiry, anchors)
+    }
+}
+
+/// The fee of the second-stage transaction which spends an HTLC output. With anchors it's zero,
+/// and whoever broadcasts it brings their own fee by adding inputs.
+pub fn htlc_tx_fee_satoshis(feerate_per_kw: u32, offered: bool, anchors: bool) -> u64 {
+    if anchors {
+        return 0;
+    }
+    let weight = if offered { HTLC_TIMEOUT_TX_WEIGHT } else { HTLC_SUCCESS_TX_WEIGHT };
+    feerate_per_kw as u64 * weight / 1000
+}
+
+/// An HTLC is trimmed if, once its second-stage transaction has paid its fee, what's left would
+/// be dust.
+pub fn is_htlc_trimmed(htlc: &CommitmentHtlc, feerate_per_kw: u32, dust_limit_satoshis: u64, anchors: bool) -> bool {
+    htlc.amount_msat / 1000 < dust_limit_satoshis + htlc_tx_fee_satoshis(feerate_per_kw, htlc.offered, anchors)
+}
+
+pub fn commitment_fee_satoshis(feerate_per_kw: u32, num_untrimmed_htlcs: usize, anchors: bool) -> u64 {
+    let base_weight = if anchors { ANCHOR_COMMITMENT_TX_BASE_WEIGHT } else { COMMITMENT_TX_BASE_WEIGHT };
+    let weight = base_weight + COMMITMENT_TX_WEIGHT_PER_HTLC * num_untrimmed_htlcs as u64;
+    feerate_per_kw as u64 * weight / 1000
+}
+
+impl CommitmentParams {
+    /// Params for the commitment held by the party with the `local` basepoints. The funder is the
+    /// opener, whose payment basepoint comes first in the commitment number obscurer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        funding_outpoint: OutPoint,
+        funding_satoshis: u64,
+        local: &ChannelBasepoints,
+        remote: &ChannelBasepoints,
+        local_is_funder: bool,
+        to_self_delay: u16,
+        dust_limit_satoshis: u64,
+        anchors: bool,
+    ) -> CommitmentParams {
+        let (opener, accepter) = if local_is_funder { (local, remote) } else { (remote, local) };
+        CommitmentParams {
+            funding_outpoint,
+            funding_satoshis,
+            local_funding_pubkey: local.funding_pubkey,
+            remote_funding_pubkey: remote.funding_pubkey,
+            local_is_funder,
+            to_self_delay,
+            dust_limit_satoshis,
+            commitment_number_obscurer: commitment_number_obscurer(&opener.payment_basepoint, &accepter.payment_basepoint),
+            anchors,
+        }
+    }
+
+    pub fn funding_script(&self) -> Vec<u8> {
+        funding_script(&self.local_funding_pubkey, &self.remote_funding_pubkey)
+    }
+}
+
+/// Builds the unsigned commitment transaction for `state`.
+pub fn build_commitment_tx(
+    params: &CommitmentParams,
+    keys: &CommitmentKeys,
+    state: &CommitmentState,
+) -> CommitmentTx {
+    let obscured = (state.commitment_number ^ params.commitment_number_obscurer) & 0xffff_ffff_ffff;
+
+    // Each output along with the CLTV expiry which breaks ties between otherwise identical HTLC
+    // outputs, and which HTLC it is.
+    let mut outputs: Vec<(TxOut, u32, Option<usize>)> = Vec::with_capacity(state.htlcs.len() + 2);
+    for (i, htlc) in state.htlcs.iter().enumerate() {
+        if is_htlc_trimmed(htlc, state.feerate_per_kw, params.dust_limit_satoshis, params.anchors) {
+            continue;
+        }
+        let output = TxOut {
+            value: htlc.amount_msat / 1000,
+            script_pubkey: p2wsh_script_pubkey(&htlc_script(keys, htlc, params.anchors)),
+        };
+        outputs.push((output, htlc.cltv_expiry, Some(i)));
+    }
+    let has_htlcs = !outputs.is_empty();
+
+    let fee_satoshis = commitment_fee_satoshis(state.feerate_per_kw, outputs.len(), params.anchors);
+    // The funder pays for both anchors too, whether or not they end up in the transaction.
+    let funder_pays = if params.anchors { fee_satoshis + 2 * ANCHOR_OUTPUT_SATOSHIS } else { fee_satoshis };
+    let mut to_local = state.to_local_msat / 1000;
+    let mut to_remote = state.to_remote_msat / 1000;
+    if params.local_is_funder {
+        to_local = to_local.saturating_sub(funder_pays);
+    } else {
+        to_remote = to_remote.saturating_sub(funder_pays);
+    }
+    let has_to_local = to_local >= params.dust_limit_satoshis;
+    let has_to_remote = to_remote >= params.dust_limit_satoshis;
+    if has_to_local {
+        let output = TxOut {
+            value: to_local,
+            script_pubkey: p2wsh_script_pubkey(&to_local_script(keys, params.to_self_delay)),
+        };
+        outputs.push((output, 0, None));
+    }
+    if has_to_remote {
+        let script_pubkey = if params.anchors {
+            p2wsh_script_pubkey(&to_remote_anchors_script(&keys.remote_pubkey))
+        } else {
+            p2wpkh_script_pubkey(&keys.remote_pubkey)
+        };
+        outputs.push((TxOut { value: to_remote, script_pubkey }, 0, None));
+    }
+    // Each party only gets an anchor if they have something in the transaction to protect.
+    let local_anchor = TxOut {
+        value: ANCHOR_OUTPUT_SATOSHIS,
+        script_pubkey: p2wsh_script_pubkey(&anchor_script(&params.local_funding_pubkey)),
+    };
+    if params.anchors && (has_to_local || has_htlcs) {
+        outputs.push((local_anchor.clone(), 0, None));
+    }
+    if params.anchors && (has_to_remote || has_htlcs) {
+        let output = TxOut {
+            value: ANCHOR_OUTPUT_SATOSHIS,
+            script_pubkey: p2wsh_script_pubkey(&anchor_script(&params.remote_funding_pubkey)),
+        };
+        outputs.push((output, 0, None));
+    }
+
+    // BIP 69 order, so both parties build the same transaction.
+    outputs.sort_by(|(a, a_cltv, _), (b, b_cltv, _)| {
+        (a.value, &a.script_pubkey, a_cltv).cmp(&(b.value, &b.script_pubkey, b_cltv))
+    });
+    let mut htlc_output_indexes = vec![None; state.htlcs.len()];
+    let mut local_anchor_output_index = None;
+    for (index, (output, _, htlc)) in outputs.iter().enumerate() {
+        match *htlc {
+            Some(htlc) => htlc_output_indexes[htlc] = Some(index as u32),
+            None if params.anchors && *output == local_anchor => local_anchor_output_index = Some(index as u32),
+            None => (),
+        }
+    }
+
+    let tx = Transaction {
+        version: 2,
+        inputs: vec![TxIn {
+            previous_output: params.funding_outpoint,
+            script_sig: Vec::new(),
+            sequence: (0x80 << 24) | (obscured >> 24) as u32,
+            witness: Vec::new(),
+        }],
+        outputs: outputs.into_iter().map(|(output, _, _)| output).collect(),
+        lock_time: (0x20 << 24) | (obscured & 0xff_ffff) as u32,
+    };
+    CommitmentTx { tx, fee_satoshis, htlc_output_indexes, local_anchor_output_index }
+}
+
+impl CommitmentTx {
+    pub fn sighash(&self, params: &CommitmentParams) -> [u8; 32] {
+        self.tx.bip143_sighash(0, &params.funding_script(), params.funding_satoshis, SIGHASH_ALL)
+    }
+
+    /// Our signature for the funding input, as sent in `commitment_signed`.
+    pub fn sign(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        params: &CommitmentParams,
+        funding_key: &secp256k1::SecretKey,
+    ) -> secp256k1::Signature {
+        let msg = unwrap!(secp256k1::Message::from_slice(&self.sighash(params)[..]));
+        secp.sign(&msg, funding_key)
+    }
+
+    /// Checks the remote party's signature for the funding input.
+    pub fn verify_remote_signature(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        params: &CommitmentParams,
+        signature: &secp256k1::Signature,
+    ) -> bool {
+        let msg = unwrap!(secp256k1::Message::from_slice(&self.sighash(params)[..]));
+        secp.verify(&msg, signature, &params.remote_funding_pubkey).is_ok()
+    }
+
+    /// Fills in the funding input's witness, making the transaction ready to broadcast.
+    pub fn add_funding_witness(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        params: &CommitmentParams,
+        local_signature: &secp256k1::Signature,
+        remote_signature: &secp256k1::Signature,
+    ) {
+        self.tx.inputs[0].witness = funding_witness(secp, params, local_signature, remote_signature);
+    }
+}
+
+/// The witness spending the 2-of-2 funding output, with both signatures over `SIGHASH_ALL`.
+pub fn funding_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    params: &CommitmentParams,
+    local_signature: &secp256k1::Signature,
+    remote_signature: &secp256k1::Signature,
+) -> Vec<Vec<u8>> {
+    let local_first = params.local_funding_pubkey.serialize()[..] < params.remote_funding_pubkey.serialize()[..];
+    let local_signature = witness_signature(secp, local_signature, SIGHASH_ALL);
+    let remote_signature = witness_signature(secp, remote_signature, SIGHASH_ALL);
+    let (first, second) = if local_first {
+        (local_signature, remote_signature)
+    } else {
+        (remote_signature, local_signature)
+    };
+    vec![Vec::new(), first, second, params.funding_script()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn bolt3_simple_commitment_tx() {
+        let secp = Secp256k1::new();
+        let (params, keys) = bolt3_params(&secp);
+        let state = CommitmentState {
+            commitment_number: 42,
+            to_local_msat: 7_000_000_000,
+            to_remote_msat: 3_000_000_000,
+            feerate_per_kw: 15000,
+            htlcs: Vec::new(),
+        };
+        assert_eq!(params.commitment_number_obscurer, 0x2bb0_3852_1914);
+
+        let mut commitment = build_commitment_tx(&params, &keys, &state);
+        assert_eq!(commitment.fee_satoshis, 10860);
+        let local_signature = commitment.sign(&secp, &params, &bolt3_funding_key(&secp));
+        let remote_signature = hex!("3045022100c3127b33dcc741dd6b05b1e63cbd1a9a7d816f37af9b6756fa2376b056f032370220408b96279808fe57eb7e463710804cdf4f108388bc5cf722d8c848d2c7f9f3b0");
+        let remote_signature = unwrap!(secp256k1::Signature::from_der(&secp, &remote_signature[..]));
+        assert!(commitment.verify_remote_signature(&secp, &params, &remote_signature));
+        assert!(!commitment.verify_remote_signature(&secp, &params, &local_signature));
+
+        commitment.add_funding_witness(&secp, &params, &local_signature, &remote_signature);
+        let expected = hex!("02000000000101bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8002c0c62d0000000000160014cc1b07838e387deacd0e5232e1e8b49f4c29e48454a56a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e04004730440220616210b2cc4d3afb601013c373bbd8aac54febd9f15400379a8cb65ce7deca60022034236c010991beb7ff770510561ae8dc885b8d38d1947248c38f2ae05564714201483045022100c3127b33dcc741dd6b05b1e63cbd1a9a7d816f37af9b6756fa2376b056f032370220408b96279808fe57eb7e463710804cdf4f108388bc5cf722d8c848d2c7f9f3b001475221023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb21030e9f7b623d2ccc7c9bd44d66d5ce21ce504c0acf6385a132cec6d3c39fa711c152ae3e195220");
+        assert_eq!(&commitment.tx.to_bytes()[..], &expected[..]);
+    }
+
+    #[test]
+    fn bolt3_htlc_trimming_vectors() {
+        let secp = Secp256k1::new();
+        let (params, keys) = bolt3_params(&secp);
+        let funding_key = bolt3_funding_key(&secp);
+        for vector in BOLT3_COMMITMENT_VECTORS {
+            let state = CommitmentState {
+                commitment_number: 42,
+                to_local_msat: 6_988_000_000,
+                to_remote_msat: 3_000_000_000,
+                feerate_per_kw: vector.feerate_per_kw,
+                htlcs: bolt3_htlcs(),
+            };
+            let mut commitment = build_commitment_tx(&params, &keys, &state);
+            assert_eq!(commitment.fee_satoshis, vector.fee_satoshis);
+            let remote_signature = unwrap!(secp256k1::Signature::from_der(&secp, vector.remote_signature));
+            assert!(commitment.verify_remote_signature(&secp, &params, &remote_signature));
+
+            let htlc_txs = build_htlc_txs(&commitment, &params, &keys, &state);
+            assert_eq!(htlc_txs.len(), vector.htlc_txs.len());
+            for (htlc_tx, (signature, _)) in htlc_txs.iter().zip(vector.htlc_txs) {
+                let signature = unwrap!(secp256k1::Signature::from_der(&secp, signature));
+                assert!(htlc_tx.verify_remote_signature(&secp, &keys, &signature));
+            }
+
+            // The local signature is part of the witness, so this checks it as well.
+            let local_signature = commitment.sign(&secp, &params, &funding_key);
+            commitment.add_funding_witness(&secp, &params, &local_signature, &remote_signature);
+            assert_eq!(&commitment.tx.to_bytes()[..], vector.tx);
+        }
+    }
+
+    #[test]
+    fn anchor_outputs() {
+        let secp = Secp256k1::new();
+        let (mut params, keys) = bolt3_params(&secp);
+        params.anchors = true;
+        let mut state = CommitmentState {
+            commitment_number: 42,
+            to_local_msat: 7_000_000_000,
+            to_remote_msat: 3_000_000_000,
+            feerate_per_kw: 15000,
+            htlcs: Vec::new(),
+        };
+        let local_anchor = p2wsh_script_pubkey(&anchor_script(&params.local_funding_pubkey));
+        let remote_anchor = p2wsh_script_pubkey(&anchor_script(&params.remote_funding_pubkey));
+
+        let commitment = build_commitment_tx(&params, &keys, &state);
+        assert_eq!(commitment.fee_satoshis, 16860);
+        let outputs = &commitment.tx.outputs;
+        assert_eq!(outputs.len(), 4);
+        let local_anchor_index = unwrap!(commitment.local_anchor_output_index) as usize;
+        assert_eq!(outputs[local_anchor_index].script_pubkey, local_anchor);
+        assert!(outputs.iter().any(|output| output.script_pubkey == remote_anchor));
+        let to_remote = p2wsh_script_pubkey(&to_remote_anchors_script(&keys.remote_pubkey));
+        assert!(outputs.iter().any(|output| output.script_pubkey == to_remote && output.value == 3_000_000));
+        // The funder pays for both anchors.
+        assert!(outputs.iter().any(|output| output.value == 7_000_000 - 16860 - 2 * ANCHOR_OUTPUT_SATOSHIS));
+
+        // Without a to_remote output or HTLCs, the remote party has nothing to anchor.
+        state.to_remote_msat = 0;
+        state.to_local_msat = 10_000_000_000;
+        let commitment = build_commitment_tx(&params, &keys, &state);
+        assert_eq!(commitment.tx.outputs.len(), 2);
+        assert!(commitment.local_anchor_output_index.is_some());
+        assert!(commitment.tx.outputs.iter().all(|output| output.script_pubkey != remote_anchor));
+
+        // HTLC transactions are free, so HTLCs only get trimmed when they're dust themselves.
+        state.htlcs = bolt3_htlcs();
+        state.to_local_msat -= 12_000_000;
+        let commitment = build_commitment_tx(&params, &keys, &state);
+        assert_eq!(commitment.tx.outputs.len(), 8);
+        assert!(commitment.htlc_output_indexes.iter().all(|index| index.is_some()));
+        for htlc in &state.htlcs {
+            assert!(htlc_script(&keys, htlc, true).ends_with(&[OP_1, OP_CHECKSEQUENCEVERIFY, OP_DROP, OP_ENDIF]));
+        }
+        for htlc_tx in build_htlc_txs(&commitment, &params, &keys, &state) {
+            assert_eq!(htlc_tx.tx.outputs[0].value, htlc_tx.htlc_amount_satoshis);
+            assert_eq!(htlc_tx.tx.inputs[0].sequence, 1);
+            assert_eq!(htlc_tx.remote_sighash_type(), SIGHASH_SINGLE | SIGHASH_ANYONECANPAY);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use super::*;
+use chacha20::{ChaCha20, Key, Nonce};
+use chacha20::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
+use hmac::{Hmac, Mac};
+
+pub fn sha256(blocks: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    for block in blocks {
+        hasher.process(block)
+    }
+    slice_to_array!(hasher.fixed_result(), 32)
+}
+
+pub fn double_sha256(blocks: &[&[u8]]) -> [u8; 32] {
+    let hash = sha256(blocks);
+    sha256(&[&hash[..]])
+}
+
+pub fn ripemd160(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Ripemd160::default();
+    hasher.process(data);
+    slice_to_array!(hasher.fixed_result(), 20)
+}
+
+/// `RIPEMD160(SHA256(data))`, as used for P2WPKH programs.
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    ripemd160(&sha256(&[data])[..])
+}
+
+/// CRC-32C (Castagnoli), as used for `channel_update` checksums in gossip queries.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0x82f6_3b78 & mask);
+        }
+    }
+    !crc
+}
+
+pub fn hmac_sha256(key: &[u8], blocks: &[&[u8]]) -> [u8; 32] {
+    // HMAC accepts keys of any length.
+    let mut mac = unwrap!(Hmac::<Sha256>::new_varkey(key));
+    for block in blocks {
+        mac.input(block);
+    }
+    slice_to_array!(mac.result().code(), 32)
+}
+
+/// Compares two MACs without leaking where they first differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// XORs `data` with the ChaCha20 (RFC 7539) key stream, starting at block `counter`.
+pub fn chacha20(key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &mut [u8]) {
+    let mut cipher = ChaCha20::new(&Key::from(*key), &Nonce::from(*nonce));
+    cipher.seek(counter as u64 * 64);
+    cipher.apply_keystream(data);
+}
+
+/// BIP 340 tagged hash: `SHA256(SHA256(tag) || SHA256(tag) || blocks)`.
+pub fn tagged_hash(tag: &[u8], blocks: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256(&[tag]);
+    let mut tagged_blocks = Vec::with_capacity(blocks.len() + 2);
+    tagged_blocks.push(&tag_hash[..]);
+    tagged_blocks.push(&tag_hash[..]);
+    tagged_blocks.extend_from_slice(blocks);
+    sha256(&tagged_blocks)
+}
+
+fn negate_secret_key(secp: &Secp256k1<secp256k1::All>, secret_key: &mut secp256k1::SecretKey) {
+    const MINUS_ONE: [u8; 32] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+        0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x40,
+    ];
+    let minus_one = unwrap!(secp256k1::SecretKey::from_slice(secp, &MINUS_ONE));
+    unwrap!(secret_key.mul_assign(secp, &minus_one));
+}
+
+fn has_even_y(public_key: &secp256k1::PublicKey) -> bool {
+    public_key.serialize()[0] == 0x02
+}
+
+/// Signs `msg` with a BIP 340 Schnorr signature. The signer's public key is the x-only form of
+/// `secret_key`'s public key. Hashes which fall outside the scalar range only turn up with
+/// negligible probability, so they're treated as unreachable.
+pub fn schnorr_sign(
+    secp: &Secp256k1<secp256k1::All>,
+    secret_key: &secp256k1::SecretKey,
+    msg: &[u8; 32],
+    aux_rand: &[u8; 32],
+) -> [u8; 64] {
+    let public_key = secp256k1::PublicKey::from_secret_key(secp, secret_key).serialize();
+    let mut d = secret_key.clone();
+    if public_key[0] != 0x02 {
+        negate_secret_key(secp, &mut d);
+    }
+    let aux_hash = tagged_hash(b"BIP0340/aux", &[&aux_rand[..]]);
+    let mut t = [0u8; 32];
+    for ((t, d), aux) in t.iter_mut().zip(d[..].iter()).zip(aux_hash.iter()) {
+        *t = d ^ aux;
+    }
+    let nonce = tagged_hash(b"BIP0340/nonce", &[&t[..], &public_key[1..], &msg[..]]);
+    let mut k = unwrap!(secp256k1::SecretKey::from_slice(secp, &nonce[..]));
+    let nonce_point = secp256k1::PublicKey::from_secret_key(secp, &k);
+    if !has_even_y(&nonce_point) {
+        negate_secret_key(secp, &mut k);
+    }
+    let r = nonce_point.serialize();
+    let challenge = tagged_hash(b"BIP0340/challenge", &[&r[1..], &public_key[1..], &msg[..]]);
+    let mut s = unwrap!(secp256k1::SecretKey::from_slice(secp, &challenge[..]));
+    unwrap!(s.mul_assign(secp, &d));
+    unwrap!(s.add_assign(secp, &k));
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&r[1..]);
+    signature[32..].copy_from_slice(&s[..]);
+    signature
+}
+
+/// Checks a BIP 340 Schnorr signature against an x-only public key.
+pub fn schnorr_verify(
+    secp: &Secp256k1<secp256k1::All>,
+    x_only_public_key: &[u8; 32],
+    msg: &[u8; 32],
+    signature: &[u8; 64],
+) -> bool {
+    let mut compressed = [0x02; 33];
+    compressed[1..].copy_from_slice(&x_only_public_key[..]);
+    let mut point = match secp256k1::PublicKey::from_slice(secp, &compressed) {
+        Ok(public_key) => public_key,
+        Err(..) => return false,
+    };
+    let s = match secp256k1::SecretKey::from_slice(secp, &signature[32..]) {
+        Ok(s) => s,
+        Err(..) => return false,
+    };
+    let challenge = tagged_hash(b"BIP0340/challenge", &[&signature[..32], &x_only_public_key[..], &msg[..]]);
+    let mut e = match secp256k1::SecretKey::from_slice(secp, &challenge[..]) {
+        Ok(e) => e,
+        Err(..) => return false,
+    };
+    // R = s*G - e*P must have even y and the signature's x coordinate.
+    negate_secret_key(secp, &mut e);
+    if point.mul_assign(secp, &e).is_err() || point.add_exp_assign(secp, &s).is_err() {
+        return false;
+    }
+    let r = point.serialize();
+    r[0] == 0x02 && r[1..] == signature[..32]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn chacha20_rfc7539_vector() {
+        let key = slice_to_array!(hex!("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"), 32);
+        let nonce = slice_to_array!(hex!("000000000000004a00000000"), 12);
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        chacha20(&key, &nonce, 1, &mut data);
+        assert_eq!(&data[..], &hex!("6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d")[..]);
+    }
+
+    #[test]
+    fn hmac_sha256_rfc4231_vector() {
+        let mac = hmac_sha256(b"Jefe", &[b"what do ya ", b"want for nothing?"]);
+        assert_eq!(mac, hex!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+    }
+
+    // From the BIP 340 test vectors, as carried by libsecp256k1's schnorrsig tests.
+    #[test]
+    fn schnorr_bip340_vectors() {
+        let secp = Secp256k1::new();
+        let signing_cases = [
+            (
+                hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+                hex!("f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9"),
+                hex!("0000000000000000000000000000000000000000000000000000000000000000"),
+                hex!("0000000000000000000000000000000000000000000000000000000000000000"),
+                hex!("e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca821525f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0"),
+            ),
+            (
+                hex!("b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef"),
+                hex!("dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659"),
+                hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+                hex!("243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89"),
+                hex!("6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a"),
+            ),
+            (
+                hex!("c90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74020bbea63b14e5c9"),
+                hex!("dd308afec5777e13121fa72b9cc1b7cc0139715309b086c960e18fd969774eb8"),
+                hex!("c87aa53824b4d7ae2eb035a2b5bbbccc080e76cdc6d1692c4b0b62d798e6d906"),
+                hex!("7e2d58d8b3bcdf1abadec7829054f90dda9805aab56c77333024b9d0a508b75c"),
+                hex!("5831aaeed7b44bb74e5eab94ba9d4294c49bcf2a60728d8b4c200f50dd313c1bab745879a5ad954a72c45a91c3a51d3c7adea98d82f8481e0e1e03674a6f3fb7"),
+            ),
+            (
+                hex!("0b432b2677937381aef05bb02a66ecd012773062cf3fa2549e44f58ed2401710"),
+                hex!("25d1dff95105f5253c4022f628a996ad3a0d95fbf21d468a1b33f8c160d8f517"),
+                hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+                hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+                hex!("7eb0509757e246f19449885651611cb965ecc1a187dd51b64fda1edc9637d5ec97582b9cb13db3933705b32ba982af5af25fd78881ebb32771fc5922efc66ea3"),
+            ),
+        ];
+        for (secret, x_only, aux_rand, msg, expected) in signing_cases.iter() {
+            let secret_key = unwrap!(secp256k1::SecretKey::from_slice(&secp, &secret[..]));
+            let public_key = secp256k1::PublicKey::from_secret_key(&secp, &secret_key).serialize();
+            assert_eq!(&public_key[1..], &x_only[..]);
+
+            let signature = schnorr_sign(&secp, &secret_key, msg, aux_rand);
+            assert_eq!(&signature[..], &expected[..]);
+            assert!(schnorr_verify(&secp, x_only, msg, &signature));
+            assert!(!schnorr_verify(&secp, x_only, &[0x55; 32], &signature));
+        }
+
+        let msg = hex!("243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89");
+        let x_only = hex!("dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659");
+        let verify_cases = [
+            // An r value with leading zero bytes.
+            (
+                hex!("d69c3509bb99e412e68b0fe8544e72837dfa30746d8be2aa65975f29d22dc7b9"),
+                hex!("4df3c3f68fcc83b27e9d42c90431a72499f17875c81a599b566c9889b9696703"),
+                hex!("00000000000000000000003b78ce563f89a0ed9414f5aa28ad0d96d6795f9c6376afb1548af603b3eb45c9f8207dee1060cb71c04e80f593060b07d28308d7f4"),
+                true,
+            ),
+            // A public key which isn't on the curve.
+            (
+                hex!("eefdea4cdb677750a420fee807eacf21eb9898ae79b9768766e4faa04a2d4a34"),
+                msg,
+                hex!("6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e17776969e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b"),
+                false,
+            ),
+            // R has an odd y.
+            (
+                x_only,
+                msg,
+                hex!("fff97bd5755eeea420453a14355235d382f6472f8568a18b2f057a14602975563cc27944640ac607cd107ae10923d9ef7a73c643e166be5ebeafa34b1ac553e2"),
+                false,
+            ),
+            // The message is negated.
+            (
+                x_only,
+                msg,
+                hex!("1fa62e331edbc21c394792d2ab1100a7b432b013df3f6ff4f99fcb33e0e1515f28890b3edb6e7189b630448b515ce4f8622a954cfe545735aaea5134fccdb2bd"),
+                false,
+            ),
+            // s is negated.
+            (
+                x_only,
+                msg,
+                hex!("6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e177769961764b3aa9b2ffcb6ef947b6887a226e8d7c93e00c5ed0c1834ff0d0c2e6da6"),
+                false,
+            ),
+            // R is the point at infinity, with an x coordinate of 0 or 1.
+            (
+                x_only,
+                msg,
+                hex!("0000000000000000000000000000000000000000000000000000000000000000123dda8328af9c23a94c1feecfd123ba4fb73476f0d594dcb65c6425bd186051"),
+                false,
+            ),
+            (
+                x_only,
+                msg,
+                hex!("00000000000000000000000000000000000000000000000000000000000000017615fbaf5ae28864013c099742deadb4dba87f11ac6754f93780d5a1837cf197"),
+                false,
+            ),
+            // r isn't an x coordinate on the curve.
+            (
+                x_only,
+                msg,
+                hex!("4a298dacae57395a15d0795ddbfd1dcb564da82b0f269bc70a74f8220429ba1d69e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b"),
+                false,
+            ),
+            // r is the field size.
+            (
+                x_only,
+                msg,
+                hex!("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f69e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b"),
+                false,
+            ),
+            // s is the curve order.
+            (
+                x_only,
+                msg,
+                hex!("6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e177769fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141"),
+                false,
+            ),
+            // The public key exceeds the field size.
+            (
+                hex!("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc30"),
+                msg,
+                hex!("6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e17776969e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b"),
+                false,
+            ),
+        ];
+        for (x_only, msg, signature, valid) in verify_cases.iter() {
+            assert_eq!(schnorr_verify(&secp, x_only, msg, signature), *valid);
+        }
+    }
+}
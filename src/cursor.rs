@@ -13,6 +13,15 @@ impl<'a> ReadCursor<'a> {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    /// The unread bytes, without consuming them.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.pos..]
+    }
+
     pub fn read_to_end(&mut self) -> &'a [u8] {
         let ret = &self.bytes[self.pos..];
         self.pos = self.bytes.len();
@@ -28,10 +37,75 @@ impl<'a> ReadCursor<'a> {
         Ok(ret)
     }
 
+    pub fn read_u8(&mut self) -> Result<u8, MsgTooShortError> {
+        Ok(self.read_slice(1)?[0])
+    }
+
     pub fn read_u16(&mut self) -> Result<u16, MsgTooShortError> {
         let ret = slice_to_array!(self.read_slice(2)?, 2);
         Ok(u16::from_be_bytes(ret))
     }
+
+    pub fn read_u32(&mut self) -> Result<u32, MsgTooShortError> {
+        let ret = slice_to_array!(self.read_slice(4)?, 4);
+        Ok(u32::from_be_bytes(ret))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, MsgTooShortError> {
+        let ret = slice_to_array!(self.read_slice(8)?, 8);
+        Ok(u64::from_be_bytes(ret))
+    }
+
+    pub fn read_bytes32(&mut self) -> Result<[u8; 32], MsgTooShortError> {
+        Ok(slice_to_array!(self.read_slice(32)?, 32))
+    }
+
+    /// Reads a slice prefixed with its length as a `u16`.
+    pub fn read_u16_len_slice(&mut self) -> Result<&'a [u8], MsgTooShortError> {
+        let len = self.read_u16()?;
+        self.read_slice(len as usize)
+    }
+
+    pub fn read_channel_id(&mut self) -> Result<ChannelId, MsgTooShortError> {
+        Ok(ChannelId(self.read_bytes32()?))
+    }
+
+    pub fn read_chain_hash(&mut self) -> Result<ChainHash, MsgTooShortError> {
+        Ok(ChainHash(self.read_bytes32()?))
+    }
+
+    pub fn read_short_channel_id(&mut self) -> Result<ShortChannelId, MsgTooShortError> {
+        Ok(ShortChannelId::from_u64(self.read_u64()?))
+    }
+
+    pub fn read_pub_key(&mut self) -> Result<secp256k1::PublicKey, ReadFieldError> {
+        let slice = self.read_slice(33)?;
+        let secp = Secp256k1::without_caps();
+        secp256k1::PublicKey::from_slice(&secp, slice).map_err(|_| ReadFieldError::InvalidPubKey)
+    }
+
+    pub fn read_signature(&mut self) -> Result<secp256k1::Signature, ReadFieldError> {
+        let slice = self.read_slice(64)?;
+        let secp = Secp256k1::without_caps();
+        secp256k1::Signature::from_compact(&secp, slice).map_err(|_| ReadFieldError::InvalidSignature)
+    }
+}
+
+/// Error reading a field which can be malformed as well as truncated.
+#[derive(Debug, Fail)]
+pub enum ReadFieldError {
+    #[fail(display = "{}", _0)]
+    MsgTooShort(MsgTooShortError),
+    #[fail(display = "invalid public key")]
+    InvalidPubKey,
+    #[fail(display = "invalid signature")]
+    InvalidSignature,
+}
+
+impl From<MsgTooShortError> for ReadFieldError {
+    fn from(err: MsgTooShortError) -> ReadFieldError {
+        ReadFieldError::MsgTooShort(err)
+    }
 }
 
 pub struct WriteCursor {
@@ -53,9 +127,51 @@ impl WriteCursor {
         let _ = self.bytes.extend_from_slice(slice);
     }
 
+    pub fn write_u8(&mut self, val: u8) {
+        self.write_slice(&[val]);
+    }
+
     pub fn write_u16(&mut self, val: u16) {
         let val = u16::to_be_bytes(val);
         self.write_slice(&val[..]);
     }
-}
 
+    pub fn write_u32(&mut self, val: u32) {
+        let val = u32::to_be_bytes(val);
+        self.write_slice(&val[..]);
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        let val = u64::to_be_bytes(val);
+        self.write_slice(&val[..]);
+    }
+
+    /// Writes the slice prefixed with its length. Panics if the length doesn't fit in a u16;
+    /// callers have to bound anything that isn't already, rather than have it silently truncated.
+    pub fn write_u16_len_slice(&mut self, slice: &[u8]) {
+        assert!(slice.len() <= u16::max_value() as usize, "slice of {} bytes is too long for a u16 length prefix", slice.len());
+        self.write_u16(slice.len() as u16);
+        self.write_slice(slice);
+    }
+
+    pub fn write_channel_id(&mut self, channel_id: &ChannelId) {
+        self.write_slice(&channel_id.0[..]);
+    }
+
+    pub fn write_chain_hash(&mut self, chain_hash: &ChainHash) {
+        self.write_slice(&chain_hash.0[..]);
+    }
+
+    pub fn write_short_channel_id(&mut self, short_channel_id: ShortChannelId) {
+        self.write_u64(short_channel_id.to_u64());
+    }
+
+    pub fn write_pub_key(&mut self, pub_key: &secp256k1::PublicKey) {
+        self.write_slice(&pub_key.serialize()[..]);
+    }
+
+    pub fn write_signature(&mut self, signature: &secp256k1::Signature) {
+        let secp = Secp256k1::without_caps();
+        self.write_slice(&signature.serialize_compact(&secp)[..]);
+    }
+}
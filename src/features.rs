@@ -1,6 +1,6 @@
 use super::*;
 
-const NUM_KNOWN_FEATURES: usize = 4;
+const NUM_KNOWN_FEATURES: usize = 12;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct GlobalFeatures {
@@ -13,6 +13,8 @@ pub struct LocalFeatures {
     initial_routing_sync: OptionalFeatureFlag,
     option_upfront_shutdown_script: FeatureFlag,
     gossip_queries: FeatureFlag,
+    option_static_remotekey: FeatureFlag,
+    option_anchors_zero_fee_htlc_tx: FeatureFlag,
 }
 
 #[derive(Debug, Fail)]
@@ -25,6 +27,11 @@ pub enum FilterFeaturesError {
     FeatureMustNotBeRequired {
         index: u16,
     },
+    #[fail(display = "feature (bit index {}) set without the feature it depends on (bit index {})", index, dependency)]
+    MissingDependency {
+        index: u16,
+        dependency: u16,
+    },
 }
 
 #[derive(Debug, Fail)]
@@ -64,10 +71,85 @@ pub enum OptionalFeatureFlag {
 #[fail(display = "malformed feature flag")]
 pub struct MalformedFeatureFlagError;
 
+/// The commitment format of a channel, as sent in the `channel_type` tlv of `open_channel` and
+/// friends. Only the combinations we support can be represented.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChannelType {
+    /// The `to_remote` key is tweaked with the per-commitment point.
+    Legacy,
+    /// `option_static_remotekey`: the `to_remote` key is the payment basepoint itself.
+    StaticRemoteKey,
+    /// `option_anchors_zero_fee_htlc_tx`, which builds on `option_static_remotekey`.
+    AnchorsZeroFeeHtlcTx,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "unsupported channel type")]
+pub struct UnsupportedChannelTypeError;
+
+impl ChannelType {
+    /// Parses a `channel_type`, which is a feature bitfield with only the required bits set.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ChannelType, UnsupportedChannelTypeError> {
+        let mut static_remotekey = false;
+        let mut anchors = false;
+        for (i, byte) in bytes.iter().rev().enumerate() {
+            for bit in 0..8 {
+                if byte & (1 << bit) == 0 {
+                    continue;
+                }
+                match i * 8 + bit {
+                    12 => static_remotekey = true,
+                    22 => anchors = true,
+                    _ => return Err(UnsupportedChannelTypeError),
+                }
+            }
+        }
+        match (static_remotekey, anchors) {
+            (false, false) => Ok(ChannelType::Legacy),
+            (true, false) => Ok(ChannelType::StaticRemoteKey),
+            (true, true) => Ok(ChannelType::AnchorsZeroFeeHtlcTx),
+            (false, true) => Err(UnsupportedChannelTypeError),
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            ChannelType::Legacy => Vec::new(),
+            ChannelType::StaticRemoteKey => vec![0x10, 0x00],
+            ChannelType::AnchorsZeroFeeHtlcTx => vec![0x40, 0x10, 0x00],
+        }
+    }
+
+    pub fn static_remotekey(self) -> bool {
+        self != ChannelType::Legacy
+    }
+
+    pub fn anchors(self) -> bool {
+        self == ChannelType::AnchorsZeroFeeHtlcTx
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<ChannelType> {
+        match value {
+            0 => Some(ChannelType::Legacy),
+            1 => Some(ChannelType::StaticRemoteKey),
+            2 => Some(ChannelType::AnchorsZeroFeeHtlcTx),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ChannelType::Legacy => 0,
+            ChannelType::StaticRemoteKey => 1,
+            ChannelType::AnchorsZeroFeeHtlcTx => 2,
+        }
+    }
+}
+
 impl FeatureFlag {
     fn from_index(bits: &[u8], index: u16) -> Result<FeatureFlag, MalformedFeatureFlagError> {
         let bits_len = bits.len();
-        if index >= bits_len as u16 {
+        if index as usize >= bits_len * 8 {
             return Ok(FeatureFlag::No);
         }
         let flags = (bits[bits_len - (1 + (index as usize) / 8)] >> (index % 8)) & 0x03;
@@ -89,6 +171,11 @@ impl FeatureFlag {
         bits[bits_len - (1 + (index as usize) / 8)] |= flags << (index % 8);
     }
 
+    /// Whether the feature is supported, either optionally or as a requirement.
+    pub fn is_set(self) -> bool {
+        self != FeatureFlag::No
+    }
+
     fn try_to_optional(self) -> Option<OptionalFeatureFlag> {
         match self {
             FeatureFlag::No => Some(OptionalFeatureFlag::No),
@@ -116,14 +203,71 @@ impl GlobalFeatures {
 }
 
 impl LocalFeatures {
+    /// The local features we advertise in `init`. `initial_routing_sync` asks the peer for a full
+    /// gossip dump, so it should only be set while we don't have a graph yet.
+    pub fn ours(initial_routing_sync: bool) -> LocalFeatures {
+        LocalFeatures {
+            option_data_loss_protect: FeatureFlag::Optional,
+            initial_routing_sync: if initial_routing_sync {
+                OptionalFeatureFlag::Optional
+            } else {
+                OptionalFeatureFlag::No
+            },
+            option_upfront_shutdown_script: FeatureFlag::Optional,
+            gossip_queries: FeatureFlag::Optional,
+            option_static_remotekey: FeatureFlag::Optional,
+            option_anchors_zero_fee_htlc_tx: FeatureFlag::Optional,
+        }
+    }
+
+    pub fn initial_routing_sync(&self) -> OptionalFeatureFlag {
+        self.initial_routing_sync
+    }
+
+    pub fn gossip_queries(&self) -> FeatureFlag {
+        self.gossip_queries
+    }
+
+    /// Whether the peer (whose features these are) wants us to send it all our gossip after
+    /// `init`. Peers which negotiate `gossip_queries` ask for what they want instead.
+    pub fn wants_initial_routing_sync(&self, ours: &LocalFeatures) -> bool {
+        let gossip_queries_negotiated = self.gossip_queries.is_set() && ours.gossip_queries.is_set();
+        self.initial_routing_sync == OptionalFeatureFlag::Optional && !gossip_queries_negotiated
+    }
+
+    pub fn option_upfront_shutdown_script(&self) -> FeatureFlag {
+        self.option_upfront_shutdown_script
+    }
+
+    /// `option_upfront_shutdown_script` only applies if both peers advertise it.
+    pub fn upfront_shutdown_script_negotiated(&self, remote: &LocalFeatures) -> bool {
+        self.option_upfront_shutdown_script.is_set() && remote.option_upfront_shutdown_script.is_set()
+    }
+
+    pub fn option_static_remotekey(&self) -> FeatureFlag {
+        self.option_static_remotekey
+    }
+
+    pub fn option_anchors_zero_fee_htlc_tx(&self) -> FeatureFlag {
+        self.option_anchors_zero_fee_htlc_tx
+    }
+
+    /// Whether channels with the peer (whose features these are) use anchor outputs and zero-fee
+    /// HTLC transactions. Both peers have to advertise it.
+    pub fn anchors_negotiated(&self, ours: &LocalFeatures) -> bool {
+        self.option_anchors_zero_fee_htlc_tx.is_set() && ours.option_anchors_zero_fee_htlc_tx.is_set()
+    }
+
     pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
-        cursor.write_u16(1);
-        let mut bytes = [0];
+        cursor.write_u16(3);
+        let mut bytes = [0; 3];
         let bytes = &mut bytes[..];
         self.option_data_loss_protect.to_index(bytes, 0);
         self.initial_routing_sync.to_index(bytes, 2);
         self.option_upfront_shutdown_script.to_index(bytes, 4);
         self.gossip_queries.to_index(bytes, 6);
+        self.option_static_remotekey.to_index(bytes, 12);
+        self.option_anchors_zero_fee_htlc_tx.to_index(bytes, 22);
         cursor.write_slice(bytes);
     }
 }
@@ -147,7 +291,7 @@ impl UnfilteredFeatures {
     pub fn from_feature_flags(bytes: &[u8]) -> Result<UnfilteredFeatures, MalformedFeatureFlagError> {
         let mut feature_flags = SmallVec::new();
         let mut index = 0;
-        while index < bytes.len() {
+        while index < bytes.len() * 8 {
             let feature_flag = FeatureFlag::from_index(bytes, index as u16)?;
             feature_flags.push(feature_flag);
             index += 2;
@@ -169,7 +313,7 @@ impl UnfilteredFeatures {
     pub fn get_index(&self, index: u16) -> FeatureFlag {
         assert_eq!(index % 2, 0);
         let half_index = index / 2;
-        self.feature_flags[half_index as usize]
+        self.feature_flags.get(half_index as usize).cloned().unwrap_or(FeatureFlag::No)
     }
     
     pub fn get_index_optional(&self, index: u16) -> Result<OptionalFeatureFlag, FilterFeaturesError> {
@@ -225,20 +369,72 @@ impl UnfilteredLocalFeatures {
         let initial_routing_sync = self.features.get_index_optional(2)?;
         let option_upfront_shutdown_script = self.features.get_index(4);
         let gossip_queries = self.features.get_index(6);
+        let option_static_remotekey = self.features.get_index(12);
+        let option_anchors_zero_fee_htlc_tx = self.features.get_index(22);
 
         for (half_index, feature_flag) in self.features.feature_flags.iter().enumerate().skip(4) {
-            if *feature_flag == FeatureFlag::Required {
+            let known = half_index == 6 || half_index == 11;
+            if *feature_flag == FeatureFlag::Required && !known {
                 Err(FilterFeaturesError::UnknownRequiredFeature {
                     index: half_index as u16 * 2,
                 })?;
             }
         }
+        if option_anchors_zero_fee_htlc_tx.is_set() && !option_static_remotekey.is_set() {
+            Err(FilterFeaturesError::MissingDependency {
+                index: 22,
+                dependency: 12,
+            })?;
+        }
         Ok(LocalFeatures {
             option_data_loss_protect,
             initial_routing_sync,
             option_upfront_shutdown_script,
             gossip_queries,
+            option_static_remotekey,
+            option_anchors_zero_fee_htlc_tx,
         })
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn anchors_feature_round_trip() {
+        let ours = LocalFeatures::ours(false);
+        let mut cursor = WriteCursor::new();
+        ours.write_to_cursor(&mut cursor);
+        let bytes = cursor.into_bytes();
+        assert_eq!(&bytes[..], &[0x00, 0x03, 0x80, 0x20, 0xa2][..]);
+        let parsed = unwrap!(unwrap!(UnfilteredLocalFeatures::from_feature_flags(&bytes[2..])).filter());
+        assert_eq!(parsed, ours);
+        assert!(parsed.anchors_negotiated(&ours));
+
+        // Requiring anchors is fine, since we know about them, but not so for bit 24.
+        let required = unwrap!(UnfilteredLocalFeatures::from_feature_flags(&[0x40, 0x10, 0x00]));
+        assert_eq!(unwrap!(required.filter()).option_anchors_zero_fee_htlc_tx(), FeatureFlag::Required);
+        // Anchors build on option_static_remotekey, so it has to be there too.
+        let missing = unwrap!(UnfilteredLocalFeatures::from_feature_flags(&[0x80, 0x00, 0x00]));
+        match missing.filter() {
+            Err(FilterLocalFeaturesError(FilterFeaturesError::MissingDependency { index: 22, dependency: 12 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let unknown = unwrap!(UnfilteredLocalFeatures::from_feature_flags(&[0x01, 0x00, 0x00, 0x00]));
+        assert!(unknown.filter().is_err());
+    }
+
+    #[test]
+    fn channel_type_round_trip() {
+        for channel_type in &[ChannelType::Legacy, ChannelType::StaticRemoteKey, ChannelType::AnchorsZeroFeeHtlcTx] {
+            assert_eq!(unwrap!(ChannelType::from_bytes(&channel_type.to_bytes())), *channel_type);
+        }
+        // Leading zeroes don't matter, but anchors without static_remotekey, optional bits and
+        // unknown bits aren't channel types we support.
+        assert_eq!(unwrap!(ChannelType::from_bytes(&[0x00, 0x10, 0x00])), ChannelType::StaticRemoteKey);
+        assert!(ChannelType::from_bytes(&[0x40, 0x00, 0x00]).is_err());
+        assert!(ChannelType::from_bytes(&[0x20, 0x00]).is_err());
+        assert!(ChannelType::from_bytes(&[0x01, 0x00, 0x00, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use super::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The first byte of every gossip store file.
+pub const GOSSIP_STORE_VERSION: u8 = 1;
+
+/// Set in a record's flags once a newer message has replaced it, or its channel has gone.
+const RECORD_FLAG_DELETED: u16 = 0x8000;
+
+/// flags (u16), len (u16), crc32c (u32), timestamp (u32)
+const RECORD_HEADER_LEN: u64 = 12;
+
+/// Compact once deleted records take up more than this fraction of the file.
+const COMPACTION_THRESHOLD: f64 = 0.5;
+
+#[derive(Debug, Fail)]
+pub enum GossipStoreError {
+    #[fail(display = "io error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "unsupported gossip store version {}", version)]
+    UnsupportedVersion {
+        version: u8,
+    },
+    #[fail(display = "only gossip messages can be stored")]
+    NotGossip,
+    #[fail(display = "message of {} bytes is too long to store", len)]
+    MsgTooLong {
+        len: usize,
+    },
+}
+
+impl From<io::Error> for GossipStoreError {
+    fn from(err: io::Error) -> GossipStoreError {
+        GossipStoreError::Io(err)
+    }
+}
+
+/// What a record is about. A newer record with the same key replaces the older one.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum GossipStoreKey {
+    ChannelAnnouncement(ShortChannelId),
+    ChannelUpdate(ShortChannelId, u8),
+    NodeAnnouncement(NodeId),
+}
+
+impl GossipStoreKey {
+    pub fn for_msg(msg: &Msg) -> Option<GossipStoreKey> {
+        match msg {
+            Msg::ChannelAnnouncement(msg) => Some(GossipStoreKey::ChannelAnnouncement(msg.short_channel_id)),
+            Msg::ChannelUpdate(msg) => Some(GossipStoreKey::ChannelUpdate(msg.short_channel_id, msg.direction())),
+            Msg::NodeAnnouncement(msg) => Some(GossipStoreKey::NodeAnnouncement(NodeId::from_pub_key(&msg.node_id))),
+            _ => None,
+        }
+    }
+}
+
+/// An append-only log of the raw gossip messages making up our network graph, so that it
+/// survives restarts.
+///
+/// Each record is a header followed by the message. Replaced records are never rewritten, just
+/// flagged as deleted, and are dropped when the file is compacted. A record torn by a crash is
+/// detected by its checksum when reopening and truncated away.
+pub struct GossipStore {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    live: HashMap<GossipStoreKey, (u64, u64)>,
+    deleted_bytes: u64,
+}
+
+fn record_crc(timestamp: u32, msg: &[u8]) -> u32 {
+    let mut data = Vec::with_capacity(4 + msg.len());
+    data.extend_from_slice(&u32::to_be_bytes(timestamp)[..]);
+    data.extend_from_slice(msg);
+    crc32c(&data)
+}
+
+fn write_record(file: &mut File, timestamp: u32, msg: &[u8]) -> Result<u64, GossipStoreError> {
+    if msg.len() > u16::max_value() as usize {
+        return Err(GossipStoreError::MsgTooLong { len: msg.len() });
+    }
+    let mut cursor = WriteCursor::new();
+    cursor.write_u16(0);
+    cursor.write_u16(msg.len() as u16);
+    cursor.write_u32(record_crc(timestamp, msg));
+    cursor.write_u32(timestamp);
+    cursor.write_slice(msg);
+    let record = cursor.into_bytes();
+    file.write_all(&record)?;
+    Ok(record.len() as u64)
+}
+
+/// Reads the next record, or `None` at the end of the valid part of the file.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<(u16, u32, Vec<u8>)>> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    if !read_exact_or_eof(reader, &mut header)? {
+        return Ok(None);
+    }
+    let mut cursor = ReadCursor::new(&header);
+    let flags = unwrap!(cursor.read_u16());
+    let len = unwrap!(cursor.read_u16());
+    let crc = unwrap!(cursor.read_u32());
+    let timestamp = unwrap!(cursor.read_u32());
+    let mut msg = vec![0u8; len as usize];
+    if !read_exact_or_eof(reader, &mut msg)? {
+        return Ok(None);
+    }
+    if record_crc(timestamp, &msg) != crc {
+        return Ok(None);
+    }
+    Ok(Some((flags, timestamp, msg)))
+}
+
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn compaction_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".compacting");
+    PathBuf::from(path)
+}
+
+/// Flushes the directory containing `path`, so that a rename of `path` survives a crash.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+impl GossipStore {
+    /// Opens (or creates) the store at `path` and replays it into `graph`. Records which the graph
+    /// rejects, eg. because they've gone stale, are flagged as deleted.
+    pub fn open(path: &Path, graph: &mut NetworkGraph) -> Result<GossipStore, GossipStoreError> {
+        // A leftover from a crash during compaction. The original is still intact.
+        let _ = fs::remove_file(compaction_path(path));
+
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let mut store = GossipStore {
+            path: path.to_owned(),
+            file: file.try_clone()?,
+            len: 1,
+            live: HashMap::new(),
+            deleted_bytes: 0,
+        };
+        if file.metadata()?.len() == 0 {
+            file.write_all(&[GOSSIP_STORE_VERSION])?;
+            file.sync_all()?;
+            return Ok(store);
+        }
+
+        let mut reader = BufReader::new(&file);
+        let mut version = [0u8];
+        reader.read_exact(&mut version)?;
+        if version[0] != GOSSIP_STORE_VERSION {
+            return Err(GossipStoreError::UnsupportedVersion { version: version[0] });
+        }
+        let mut to_delete = Vec::new();
+        while let Some((flags, timestamp, bytes)) = read_record(&mut reader)? {
+            let offset = store.len;
+            let record_len = RECORD_HEADER_LEN + bytes.len() as u64;
+            store.len += record_len;
+            if flags & RECORD_FLAG_DELETED != 0 {
+                store.deleted_bytes += record_len;
+                continue;
+            }
+            let msg = Msg::from_bytes(&bytes).ok();
+            let replayed = match msg {
+                Some(Msg::ChannelAnnouncement(ref msg)) => graph.handle_channel_announcement(msg, timestamp).is_ok(),
+                Some(Msg::ChannelUpdate(ref msg)) => graph.handle_channel_update(msg, timestamp).is_ok(),
+                Some(Msg::NodeAnnouncement(ref msg)) => graph.handle_node_announcement(msg).is_ok(),
+                _ => false,
+            };
+            let key = match msg.as_ref().and_then(GossipStoreKey::for_msg) {
+                Some(key) if replayed => key,
+                _ => {
+                    to_delete.push((offset, record_len));
+                    continue;
+                },
+            };
+            if let Some(replaced) = store.live.insert(key, (offset, record_len)) {
+                to_delete.push(replaced);
+            }
+        }
+        drop(reader);
+
+        // Anything past the last good record was torn by a crash.
+        if file.metadata()?.len() > store.len {
+            file.set_len(store.len)?;
+            file.sync_all()?;
+        }
+        for (offset, record_len) in to_delete {
+            store.mark_deleted(offset, record_len)?;
+        }
+        Ok(store)
+    }
+
+    /// Appends a gossip message which the graph has accepted, replacing any older record with
+    /// the same key. `timestamp` is when we received the message, which the graph is given again
+    /// when the store is replayed.
+    pub fn append(&mut self, msg: &Msg, timestamp: u32) -> Result<(), GossipStoreError> {
+        let key = match GossipStoreKey::for_msg(msg) {
+            Some(key) => key,
+            None => return Err(GossipStoreError::NotGossip),
+        };
+        let offset = self.len;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let record_len = write_record(&mut self.file, timestamp, &msg.to_bytes())?;
+        self.len += record_len;
+        if let Some((old_offset, old_len)) = self.live.insert(key, (offset, record_len)) {
+            self.mark_deleted(old_offset, old_len)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets a channel's announcement and updates.
+    pub fn remove_channel(&mut self, short_channel_id: ShortChannelId) -> Result<(), GossipStoreError> {
+        self.remove(GossipStoreKey::ChannelAnnouncement(short_channel_id))?;
+        self.remove(GossipStoreKey::ChannelUpdate(short_channel_id, 0))?;
+        self.remove(GossipStoreKey::ChannelUpdate(short_channel_id, 1))
+    }
+
+    /// Forgets a node's announcement, eg. once it has no channels left.
+    pub fn remove_node(&mut self, node_id: &NodeId) -> Result<(), GossipStoreError> {
+        self.remove(GossipStoreKey::NodeAnnouncement(*node_id))
+    }
+
+    fn remove(&mut self, key: GossipStoreKey) -> Result<(), GossipStoreError> {
+        if let Some((offset, record_len)) = self.live.remove(&key) {
+            self.mark_deleted(offset, record_len)?;
+        }
+        Ok(())
+    }
+
+    fn mark_deleted(&mut self, offset: u64, record_len: u64) -> Result<(), GossipStoreError> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&u16::to_be_bytes(RECORD_FLAG_DELETED)[..])?;
+        self.deleted_bytes += record_len;
+        Ok(())
+    }
+
+    /// Flushes appended records to disk.
+    pub fn sync(&mut self) -> Result<(), GossipStoreError> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.deleted_bytes as f64 > self.len as f64 * COMPACTION_THRESHOLD
+    }
+
+    /// Rewrites the store without its deleted records. The new file is written alongside the old
+    /// one and renamed over it, so a crash part way through leaves the old store intact.
+    pub fn compact(&mut self) -> Result<(), GossipStoreError> {
+        let mut records: Vec<(u64, u64)> = self.live.values().cloned().collect();
+        records.sort();
+
+        let new_path = compaction_path(&self.path);
+        let mut new_file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&new_path)?;
+        new_file.write_all(&[GOSSIP_STORE_VERSION])?;
+        let mut new_offsets = HashMap::new();
+        let mut new_len = 1;
+        for (offset, record_len) in records {
+            let mut record = vec![0u8; record_len as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut record)?;
+            new_file.write_all(&record)?;
+            new_offsets.insert(offset, new_len);
+            new_len += record_len;
+        }
+        new_file.sync_all()?;
+        fs::rename(&new_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        for (offset, _) in self.live.values_mut() {
+            *offset = new_offsets[&*offset];
+        }
+        self.file = new_file;
+        self.len = new_len;
+        self.deleted_bytes = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u32 = 1_600_000_000;
+
+    fn temp_path() -> PathBuf {
+        let name = format!("gossip_store_test_{:016x}", rand::thread_rng().gen::<u64>());
+        std::env::temp_dir().join(name)
+    }
+
+    #[test]
+    fn survives_reopen_and_torn_writes() {
+        let secp = Secp256k1::new();
+        let path = temp_path();
+        let announcement = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        let old_update = signed_channel_update(&secp, &announcement, 1, NOW, |_| ());
+        let new_update = signed_channel_update(&secp, &announcement, 1, NOW + 1, |update| update.fee_base_msat = 5);
+        let node_announcement = signed_node_announcement(&secp, 2, NOW);
+
+        {
+            let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+            let mut store = unwrap!(GossipStore::open(&path, &mut graph));
+            unwrap!(store.append(&Msg::ChannelAnnouncement(announcement.clone()), NOW));
+            unwrap!(store.append(&Msg::ChannelUpdate(old_update.clone()), NOW));
+            unwrap!(store.append(&Msg::ChannelUpdate(new_update.clone()), NOW));
+            unwrap!(store.append(&Msg::NodeAnnouncement(node_announcement.clone()), NOW));
+            unwrap!(store.sync());
+        }
+
+        // Simulate a crash half way through writing a record.
+        let len_before_crash = unwrap!(fs::metadata(&path)).len();
+        {
+            let mut file = unwrap!(OpenOptions::new().append(true).open(&path));
+            unwrap!(file.write_all(&[0, 0, 0, 200, 1, 2, 3]));
+        }
+
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let mut store = unwrap!(GossipStore::open(&path, &mut graph));
+        assert_eq!(unwrap!(fs::metadata(&path)).len(), len_before_crash);
+        let channel = unwrap!(graph.channel(announcement.short_channel_id));
+        assert_eq!(channel.announced_at, NOW);
+        let direction = unwrap!(channel.direction_from(&test_node_id(1)));
+        assert_eq!(unwrap!(channel.policy(direction)).fee_base_msat, 5);
+        assert!(unwrap!(graph.node(&test_node_id(2))).announcement.is_some());
+
+        unwrap!(store.compact());
+        assert!(store.file_len() < len_before_crash);
+        drop(store);
+
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let _store = unwrap!(GossipStore::open(&path, &mut graph));
+        assert_eq!(graph.num_channels(), 1);
+        let channel = unwrap!(graph.channel(announcement.short_channel_id));
+        assert_eq!(unwrap!(channel.policy(direction)).fee_base_msat, 5);
+        unwrap!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn removed_channels_stay_removed() {
+        let secp = Secp256k1::new();
+        let path = temp_path();
+        let announcement = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        {
+            let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+            let mut store = unwrap!(GossipStore::open(&path, &mut graph));
+            unwrap!(store.append(&Msg::ChannelAnnouncement(announcement.clone()), NOW));
+            unwrap!(store.remove_channel(announcement.short_channel_id));
+            assert!(store.needs_compaction());
+        }
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let _store = unwrap!(GossipStore::open(&path, &mut graph));
+        assert_eq!(graph.num_channels(), 0);
+        unwrap!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let secp = Secp256k1::new();
+        let path = temp_path();
+        let mut announcement = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        announcement.excess_data = vec![0; 0x10000];
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let mut store = unwrap!(GossipStore::open(&path, &mut graph));
+        match store.append(&Msg::ChannelAnnouncement(announcement), NOW) {
+            Err(GossipStoreError::MsgTooLong { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(store.file_len(), 1);
+        assert_eq!(unwrap!(fs::metadata(&path)).len(), 1);
+        unwrap!(fs::remove_file(&path));
+    }
+}
@@ -0,0 +1,693 @@
+use super::*;
+
+/// `query_option` bit asking for channel_update timestamps in `reply_channel_range`.
+pub const QUERY_OPTION_TIMESTAMPS: u64 = 1 << 0;
+/// `query_option` bit asking for channel_update checksums in `reply_channel_range`.
+pub const QUERY_OPTION_CHECKSUMS: u64 = 1 << 1;
+
+pub const QUERY_FLAG_CHANNEL_ANNOUNCEMENT: u64 = 1 << 0;
+pub const QUERY_FLAG_CHANNEL_UPDATE_1: u64 = 1 << 1;
+pub const QUERY_FLAG_CHANNEL_UPDATE_2: u64 = 1 << 2;
+pub const QUERY_FLAG_NODE_ANNOUNCEMENT_1: u64 = 1 << 3;
+pub const QUERY_FLAG_NODE_ANNOUNCEMENT_2: u64 = 1 << 4;
+pub const QUERY_FLAGS_ALL: u64 = 0x1f;
+
+/// The most short channel ids we put in a single `reply_channel_range` or
+/// `query_short_channel_ids`. With timestamps and checksums this still fits in a message, and
+/// every encoded array fits its u16 length prefix even when zlib doesn't manage to shrink it.
+pub const MAX_SHORT_IDS_PER_MSG: usize = 2000;
+
+/// What we know about a channel when answering or comparing a `reply_channel_range`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelRangeEntry {
+    pub short_channel_id: ShortChannelId,
+    pub timestamps: Option<ChannelUpdateTimestamps>,
+    pub checksums: Option<ChannelUpdateChecksums>,
+}
+
+/// The gossip we have, as needed to answer gossip queries.
+pub trait GossipQuerySource {
+    /// The announced channels whose funding transaction confirmed in the given block range, in
+    /// ascending order of short channel id. `end_blocknum` is exclusive.
+    fn channels_in_range(&self, first_blocknum: u32, end_blocknum: u64) -> Vec<ChannelRangeEntry>;
+
+    /// The gossip for a channel selected by `query_flags`, with the `channel_announcement` (if
+    /// selected) first. Returns nothing if we don't know the channel.
+    fn channel_gossip(&self, short_channel_id: ShortChannelId, query_flags: u64) -> Vec<Msg>;
+}
+
+impl<S: GossipQuerySource> GossipQuerySource for Mutex<S> {
+    fn channels_in_range(&self, first_blocknum: u32, end_blocknum: u64) -> Vec<ChannelRangeEntry> {
+        unwrap!(self.lock()).channels_in_range(first_blocknum, end_blocknum)
+    }
+
+    fn channel_gossip(&self, short_channel_id: ShortChannelId, query_flags: u64) -> Vec<Msg> {
+        unwrap!(self.lock()).channel_gossip(short_channel_id, query_flags)
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum GossipQueryError {
+    #[fail(display = "gossip query is for chain {:?}, not ours", chain_hash)]
+    WrongChain {
+        chain_hash: ChainHash,
+    },
+    #[fail(display = "received a reply without an outstanding query")]
+    UnexpectedReply,
+    #[fail(display = "reply_channel_range does not continue from the previous reply")]
+    ReplyNotContiguous,
+    #[fail(display = "short channel id {} is out of order or outside the replied range", short_channel_id)]
+    ShortChannelIdOutOfRange {
+        short_channel_id: ShortChannelId,
+    },
+    #[fail(display = "tlv array has {} entries for {} short channel ids", entries, short_channel_ids)]
+    MismatchedArrayLength {
+        entries: usize,
+        short_channel_ids: usize,
+    },
+}
+
+/// The CRC32C of a `channel_update` without its signature and timestamp, as sent in
+/// `reply_channel_range`. This lets a peer tell whether a newer update actually changed anything.
+pub fn channel_update_checksum(update: &ChannelUpdateMsg) -> u32 {
+    let mut cursor = WriteCursor::new();
+    cursor.write_chain_hash(&update.chain_hash);
+    cursor.write_short_channel_id(update.short_channel_id);
+    cursor.write_u8(update.encoded_message_flags());
+    cursor.write_u8(update.channel_flags);
+    cursor.write_u16(update.cltv_expiry_delta);
+    cursor.write_u64(update.htlc_minimum_msat);
+    cursor.write_u32(update.fee_base_msat);
+    cursor.write_u32(update.fee_proportional_millionths);
+    if let Some(htlc_maximum_msat) = update.htlc_maximum_msat {
+        cursor.write_u64(htlc_maximum_msat);
+    }
+    cursor.write_slice(&update.excess_data);
+    crc32c(&cursor.into_bytes())
+}
+
+fn check_array_len<T>(array: &Option<Vec<T>>, short_channel_ids: usize) -> Result<(), GossipQueryError> {
+    match array {
+        Some(entries) if entries.len() != short_channel_ids => {
+            Err(GossipQueryError::MismatchedArrayLength { entries: entries.len(), short_channel_ids })
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Answers a `query_channel_range`. The replies cover the queried range in order. A block's
+/// channels are only split across replies when there are too many for one, in which case those
+/// replies overlap on that block.
+pub fn reply_to_query_channel_range<S: GossipQuerySource>(
+    source: &S,
+    chain_hash: &ChainHash,
+    encoding: ShortIdEncoding,
+    query: &QueryChannelRangeMsg,
+) -> Vec<ReplyChannelRangeMsg> {
+    let query_option = query.query_option.unwrap_or(0);
+    let make_reply = |start: u64, end: u64, entries: &[ChannelRangeEntry], sync_complete: bool| {
+        let with_timestamps = query_option & QUERY_OPTION_TIMESTAMPS != 0;
+        let with_checksums = query_option & QUERY_OPTION_CHECKSUMS != 0;
+        ReplyChannelRangeMsg {
+            chain_hash: query.chain_hash,
+            first_blocknum: start as u32,
+            number_of_blocks: cmp::min(end - start, u32::max_value() as u64) as u32,
+            sync_complete,
+            encoding,
+            short_channel_ids: entries.iter().map(|entry| entry.short_channel_id).collect(),
+            timestamps: if with_timestamps {
+                Some(entries.iter().map(|entry| entry.timestamps.unwrap_or(ChannelUpdateTimestamps {
+                    timestamp_node_id_1: 0,
+                    timestamp_node_id_2: 0,
+                })).collect())
+            } else {
+                None
+            },
+            checksums: if with_checksums {
+                Some(entries.iter().map(|entry| entry.checksums.unwrap_or(ChannelUpdateChecksums {
+                    checksum_node_id_1: 0,
+                    checksum_node_id_2: 0,
+                })).collect())
+            } else {
+                None
+            },
+        }
+    };
+
+    let end = query.end_blocknum();
+    if query.chain_hash != *chain_hash {
+        return vec![make_reply(query.first_blocknum as u64, end, &[], false)];
+    }
+
+    let entries = source.channels_in_range(query.first_blocknum, end);
+    let mut replies = Vec::new();
+    let mut start = query.first_blocknum as u64;
+    let mut chunk_start = 0;
+    let mut index = 0;
+    while index < entries.len() {
+        let block_height = entries[index].short_channel_id.block_height;
+        let block_len = {
+            entries[index..]
+            .iter()
+            .take_while(|entry| entry.short_channel_id.block_height == block_height)
+            .count()
+        };
+        if index > chunk_start && index + block_len - chunk_start > MAX_SHORT_IDS_PER_MSG {
+            replies.push(make_reply(start, block_height as u64, &entries[chunk_start..index], true));
+            start = block_height as u64;
+            chunk_start = index;
+        }
+        while index + block_len - chunk_start > MAX_SHORT_IDS_PER_MSG {
+            let chunk_end = chunk_start + MAX_SHORT_IDS_PER_MSG;
+            replies.push(make_reply(start, block_height as u64 + 1, &entries[chunk_start..chunk_end], true));
+            start = block_height as u64;
+            chunk_start = chunk_end;
+        }
+        index += block_len;
+    }
+    replies.push(make_reply(start, end, &entries[chunk_start..], true));
+    replies
+}
+
+/// Answers a `query_short_channel_ids`. Returns the gossip to send followed by the
+/// `reply_short_channel_ids_end`. Each node announcement is sent at most once.
+pub fn reply_to_query_short_channel_ids<S: GossipQuerySource>(
+    source: &S,
+    chain_hash: &ChainHash,
+    query: &QueryShortChannelIdsMsg,
+) -> Result<Vec<Msg>, GossipQueryError> {
+    if query.chain_hash != *chain_hash {
+        return Ok(vec![Msg::ReplyShortChannelIdsEnd(ReplyShortChannelIdsEndMsg {
+            chain_hash: query.chain_hash,
+            full_information: false,
+        })]);
+    }
+    check_array_len(&query.query_flags, query.short_channel_ids.len())?;
+
+    let mut msgs = Vec::new();
+    let mut sent_nodes = HashSet::new();
+    for (index, short_channel_id) in query.short_channel_ids.iter().enumerate() {
+        let query_flags = match query.query_flags {
+            Some(ref query_flags) => query_flags[index],
+            None => QUERY_FLAGS_ALL,
+        };
+        push_channel_gossip(source.channel_gossip(*short_channel_id, query_flags), &mut sent_nodes, &mut msgs);
+    }
+    msgs.push(Msg::ReplyShortChannelIdsEnd(ReplyShortChannelIdsEndMsg {
+        chain_hash: query.chain_hash,
+        full_information: true,
+    }));
+    Ok(msgs)
+}
+
+fn push_channel_gossip(gossip: Vec<Msg>, sent_nodes: &mut HashSet<[u8; 33]>, msgs: &mut Vec<Msg>) {
+    for msg in gossip {
+        if let Msg::NodeAnnouncement(ref node_announcement) = msg {
+            if !sent_nodes.insert(node_announcement.node_id.serialize()) {
+                continue;
+            }
+        }
+        msgs.push(msg);
+    }
+}
+
+/// Drops the gossip outside `filter`. A `channel_announcement` has no timestamp, so it goes with
+/// its channel's updates.
+fn apply_timestamp_filter(gossip: &mut Vec<Msg>, filter: &GossipTimestampFilterMsg) {
+    let any_update_matches = gossip.iter().any(|msg| match msg {
+        Msg::ChannelUpdate(update) => filter.matches(update.timestamp),
+        _ => false,
+    });
+    gossip.retain(|msg| match msg {
+        Msg::ChannelAnnouncement(..) => any_update_matches,
+        Msg::ChannelUpdate(update) => filter.matches(update.timestamp),
+        Msg::NodeAnnouncement(announcement) => filter.matches(announcement.timestamp),
+        _ => true,
+    });
+}
+
+/// A dump of all our gossip, for a peer which set `initial_routing_sync`. It's produced in
+/// batches so that sending can be paced and we never hold a copy of the whole graph. Each
+/// channel's announcement comes first, then its updates, then its nodes' announcements.
+pub struct GossipDump {
+    short_channel_ids: VecDeque<ShortChannelId>,
+    sent_nodes: HashSet<[u8; 33]>,
+    timestamp_filter: Option<GossipTimestampFilterMsg>,
+}
+
+impl GossipDump {
+    /// Only gossip which passes `timestamp_filter`, the peer's `gossip_timestamp_filter`, is
+    /// dumped.
+    pub fn new<S: GossipQuerySource>(source: &S, timestamp_filter: Option<GossipTimestampFilterMsg>) -> GossipDump {
+        let short_channel_ids = {
+            source
+            .channels_in_range(0, u64::max_value())
+            .into_iter()
+            .map(|entry| entry.short_channel_id)
+            .collect()
+        };
+        GossipDump {
+            short_channel_ids,
+            sent_nodes: HashSet::new(),
+            timestamp_filter,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.short_channel_ids.is_empty()
+    }
+
+    /// The gossip for the next channels, stopping once there are at least `max_msgs` messages.
+    /// Channels which have been forgotten since the dump started are skipped. Returns an empty
+    /// batch once the dump is done.
+    pub fn next_batch<S: GossipQuerySource>(&mut self, source: &S, max_msgs: usize) -> Vec<Msg> {
+        let mut msgs = Vec::new();
+        while msgs.len() < max_msgs {
+            let short_channel_id = match self.short_channel_ids.pop_front() {
+                Some(short_channel_id) => short_channel_id,
+                None => break,
+            };
+            let mut gossip = source.channel_gossip(short_channel_id, QUERY_FLAGS_ALL);
+            if let Some(ref filter) = self.timestamp_filter {
+                apply_timestamp_filter(&mut gossip, filter);
+            }
+            push_channel_gossip(gossip, &mut self.sent_nodes, &mut msgs);
+        }
+        msgs
+    }
+}
+
+/// Our side of a `query_channel_range` exchange: collects the peer's replies until they cover the
+/// queried range.
+pub struct ChannelRangeQuery {
+    query: QueryChannelRangeMsg,
+    next_blocknum: u64,
+    last_first_blocknum: u32,
+    sync_complete: bool,
+    entries: BTreeMap<ShortChannelId, ChannelRangeEntry>,
+}
+
+impl ChannelRangeQuery {
+    pub fn new(
+        chain_hash: ChainHash,
+        first_blocknum: u32,
+        number_of_blocks: u32,
+        query_option: Option<u64>,
+    ) -> ChannelRangeQuery {
+        ChannelRangeQuery {
+            query: QueryChannelRangeMsg { chain_hash, first_blocknum, number_of_blocks, query_option },
+            next_blocknum: first_blocknum as u64,
+            last_first_blocknum: 0,
+            sync_complete: true,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn query(&self) -> &QueryChannelRangeMsg {
+        &self.query
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next_blocknum >= self.query.end_blocknum()
+    }
+
+    /// False if any reply said the peer doesn't have up-to-date gossip for the chain.
+    pub fn sync_complete(&self) -> bool {
+        self.sync_complete
+    }
+
+    /// Handles a `reply_channel_range`. Returns whether the queried range is now fully covered.
+    pub fn handle_reply_channel_range(&mut self, reply: &ReplyChannelRangeMsg) -> Result<bool, GossipQueryError> {
+        if reply.chain_hash != self.query.chain_hash {
+            return Err(GossipQueryError::WrongChain { chain_hash: reply.chain_hash });
+        }
+        if self.is_done() {
+            return Err(GossipQueryError::UnexpectedReply);
+        }
+        if (reply.first_blocknum as u64) > self.next_blocknum
+            || reply.first_blocknum < self.last_first_blocknum
+            || reply.end_blocknum() < self.next_blocknum
+        {
+            return Err(GossipQueryError::ReplyNotContiguous);
+        }
+        check_array_len(&reply.timestamps, reply.short_channel_ids.len())?;
+        check_array_len(&reply.checksums, reply.short_channel_ids.len())?;
+
+        let mut previous = None;
+        for short_channel_id in &reply.short_channel_ids {
+            let block_height = short_channel_id.block_height as u64;
+            let out_of_range = {
+                block_height < reply.first_blocknum as u64
+                || block_height >= reply.end_blocknum()
+                || previous.map(|previous| previous >= *short_channel_id).unwrap_or(false)
+            };
+            if out_of_range {
+                return Err(GossipQueryError::ShortChannelIdOutOfRange { short_channel_id: *short_channel_id });
+            }
+            previous = Some(*short_channel_id);
+        }
+
+        for (index, short_channel_id) in reply.short_channel_ids.iter().enumerate() {
+            let entry = ChannelRangeEntry {
+                short_channel_id: *short_channel_id,
+                timestamps: reply.timestamps.as_ref().map(|timestamps| timestamps[index]),
+                checksums: reply.checksums.as_ref().map(|checksums| checksums[index]),
+            };
+            self.entries.insert(*short_channel_id, entry);
+        }
+        self.last_first_blocknum = reply.first_blocknum;
+        self.next_blocknum = reply.end_blocknum();
+        self.sync_complete &= reply.sync_complete;
+        Ok(self.is_done())
+    }
+
+    /// Compares the peer's channels against ours and works out what gossip we're missing. Unknown
+    /// channels are fetched in full. For known channels, an update is fetched if the peer's is
+    /// newer and (when checksums are available) actually different.
+    pub fn missing_gossip<S: GossipQuerySource>(&self, source: &S) -> Vec<(ShortChannelId, u64)> {
+        let ours: HashMap<ShortChannelId, ChannelRangeEntry> = {
+            source
+            .channels_in_range(self.query.first_blocknum, self.query.end_blocknum())
+            .into_iter()
+            .map(|entry| (entry.short_channel_id, entry))
+            .collect()
+        };
+        let mut missing = Vec::new();
+        for (short_channel_id, theirs) in &self.entries {
+            let ours = match ours.get(short_channel_id) {
+                Some(ours) => ours,
+                None => {
+                    missing.push((*short_channel_id, QUERY_FLAGS_ALL));
+                    continue;
+                },
+            };
+            let (their_timestamps, our_timestamps) = match (theirs.timestamps, ours.timestamps) {
+                (Some(theirs), Some(ours)) => (theirs, ours),
+                _ => continue,
+            };
+            let checksums_differ = |direction: u8| match (theirs.checksums, ours.checksums) {
+                (Some(theirs), Some(ours)) => if direction == 0 {
+                    theirs.checksum_node_id_1 != ours.checksum_node_id_1
+                } else {
+                    theirs.checksum_node_id_2 != ours.checksum_node_id_2
+                },
+                _ => true,
+            };
+            let mut query_flags = 0;
+            if their_timestamps.timestamp_node_id_1 > our_timestamps.timestamp_node_id_1 && checksums_differ(0) {
+                query_flags |= QUERY_FLAG_CHANNEL_UPDATE_1;
+            }
+            if their_timestamps.timestamp_node_id_2 > our_timestamps.timestamp_node_id_2 && checksums_differ(1) {
+                query_flags |= QUERY_FLAG_CHANNEL_UPDATE_2;
+            }
+            if query_flags != 0 {
+                missing.push((*short_channel_id, query_flags));
+            }
+        }
+        missing
+    }
+}
+
+/// Our side of a series of `query_short_channel_ids` exchanges. Only one query may be
+/// outstanding at a time, so the rest are queued until the previous one is answered.
+pub struct ShortChannelIdsQuery {
+    chain_hash: ChainHash,
+    pending: VecDeque<QueryShortChannelIdsMsg>,
+    awaiting_reply: bool,
+    full_information: bool,
+}
+
+impl ShortChannelIdsQuery {
+    /// `use_query_flags` should only be set if the peer understands them, ie. it sent
+    /// timestamps or checksums in its `reply_channel_range`.
+    pub fn new(
+        chain_hash: ChainHash,
+        encoding: ShortIdEncoding,
+        wanted: &[(ShortChannelId, u64)],
+        use_query_flags: bool,
+    ) -> ShortChannelIdsQuery {
+        let pending = {
+            wanted
+            .chunks(MAX_SHORT_IDS_PER_MSG)
+            .map(|chunk| QueryShortChannelIdsMsg {
+                chain_hash,
+                encoding,
+                short_channel_ids: chunk.iter().map(|(short_channel_id, _)| *short_channel_id).collect(),
+                query_flags: if use_query_flags {
+                    Some(chunk.iter().map(|(_, query_flags)| *query_flags).collect())
+                } else {
+                    None
+                },
+            })
+            .collect()
+        };
+        ShortChannelIdsQuery {
+            chain_hash,
+            pending,
+            awaiting_reply: false,
+            full_information: true,
+        }
+    }
+
+    /// The next query to send, if we're not waiting on a reply and there's anything left to ask.
+    pub fn next_query(&mut self) -> Option<QueryShortChannelIdsMsg> {
+        if self.awaiting_reply {
+            return None;
+        }
+        let query = self.pending.pop_front()?;
+        self.awaiting_reply = true;
+        Some(query)
+    }
+
+    pub fn handle_reply_short_channel_ids_end(
+        &mut self,
+        reply: &ReplyShortChannelIdsEndMsg,
+    ) -> Result<(), GossipQueryError> {
+        if reply.chain_hash != self.chain_hash {
+            return Err(GossipQueryError::WrongChain { chain_hash: reply.chain_hash });
+        }
+        if !self.awaiting_reply {
+            return Err(GossipQueryError::UnexpectedReply);
+        }
+        self.awaiting_reply = false;
+        self.full_information &= reply.full_information;
+        Ok(())
+    }
+
+    pub fn is_done(&self) -> bool {
+        !self.awaiting_reply && self.pending.is_empty()
+    }
+
+    /// False if the peer said it didn't have up-to-date information for any of our queries.
+    pub fn full_information(&self) -> bool {
+        self.full_information
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestSource {
+        entries: Vec<ChannelRangeEntry>,
+    }
+
+    impl GossipQuerySource for TestSource {
+        fn channels_in_range(&self, first_blocknum: u32, end_blocknum: u64) -> Vec<ChannelRangeEntry> {
+            self.entries
+            .iter()
+            .filter(|entry| {
+                let block_height = entry.short_channel_id.block_height;
+                block_height >= first_blocknum && (block_height as u64) < end_blocknum
+            })
+            .cloned()
+            .collect()
+        }
+
+        /// Stands in for real gossip with one message per channel, tagged with its block height.
+        fn channel_gossip(&self, short_channel_id: ShortChannelId, _query_flags: u64) -> Vec<Msg> {
+            if !self.entries.iter().any(|entry| entry.short_channel_id == short_channel_id) {
+                return Vec::new();
+            }
+            vec![Msg::GossipTimestampFilter(GossipTimestampFilterMsg {
+                chain_hash: ChainHash::BITCOIN,
+                first_timestamp: short_channel_id.block_height,
+                timestamp_range: 0,
+            })]
+        }
+    }
+
+    fn entry(block_height: u32, tx_index: u32, timestamp: u32) -> ChannelRangeEntry {
+        ChannelRangeEntry {
+            short_channel_id: ShortChannelId { block_height, tx_index, output_index: 0 },
+            timestamps: Some(ChannelUpdateTimestamps {
+                timestamp_node_id_1: timestamp,
+                timestamp_node_id_2: timestamp,
+            }),
+            checksums: Some(ChannelUpdateChecksums {
+                checksum_node_id_1: timestamp,
+                checksum_node_id_2: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn replies_are_split_at_block_boundaries() {
+        let entries = {
+            (0..(MAX_SHORT_IDS_PER_MSG as u32 + 10))
+            .map(|i| entry(100 + i / 10, i % 10, 1))
+            .collect()
+        };
+        let source = TestSource { entries };
+        let query = QueryChannelRangeMsg {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum: 50,
+            number_of_blocks: u32::max_value(),
+            query_option: None,
+        };
+        let replies = reply_to_query_channel_range(&source, &ChainHash::BITCOIN, ShortIdEncoding::Uncompressed, &query);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].first_blocknum, 50);
+        assert_eq!(replies[0].short_channel_ids.len(), MAX_SHORT_IDS_PER_MSG);
+        assert_eq!(replies[1].first_blocknum as u64, replies[0].end_blocknum());
+        assert!(replies[1].end_blocknum() >= query.end_blocknum());
+
+        let mut sync = ChannelRangeQuery::new(ChainHash::BITCOIN, 50, u32::max_value(), None);
+        assert!(!unwrap!(sync.handle_reply_channel_range(&replies[0])));
+        assert!(unwrap!(sync.handle_reply_channel_range(&replies[1])));
+        match sync.handle_reply_channel_range(&replies[1]) {
+            Err(GossipQueryError::UnexpectedReply) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn large_blocks_are_split_across_replies() {
+        let entries = {
+            (0..(MAX_SHORT_IDS_PER_MSG as u32 * 2 + 5))
+            .map(|i| entry(100, i, 1))
+            .chain((0..3).map(|i| entry(101, i, 1)))
+            .collect()
+        };
+        let source = TestSource { entries };
+        let query = QueryChannelRangeMsg {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum: 50,
+            number_of_blocks: 100,
+            query_option: Some(QUERY_OPTION_TIMESTAMPS),
+        };
+        let replies = reply_to_query_channel_range(&source, &ChainHash::BITCOIN, ShortIdEncoding::Uncompressed, &query);
+        let ranges: Vec<_> = replies.iter().map(|reply| (reply.first_blocknum, reply.end_blocknum())).collect();
+        assert_eq!(ranges, vec![(50, 101), (100, 101), (100, 150)]);
+        let lens: Vec<_> = replies.iter().map(|reply| reply.short_channel_ids.len()).collect();
+        assert_eq!(lens, vec![MAX_SHORT_IDS_PER_MSG, MAX_SHORT_IDS_PER_MSG, 8]);
+
+        let mut sync = ChannelRangeQuery::new(ChainHash::BITCOIN, 50, 100, Some(QUERY_OPTION_TIMESTAMPS));
+        assert!(!unwrap!(sync.handle_reply_channel_range(&replies[0])));
+        assert!(!unwrap!(sync.handle_reply_channel_range(&replies[1])));
+        assert!(unwrap!(sync.handle_reply_channel_range(&replies[2])));
+        assert_eq!(sync.entries.len(), MAX_SHORT_IDS_PER_MSG * 2 + 8);
+    }
+
+    #[test]
+    fn full_messages_fit_the_length_limits() {
+        // Scrambled values so that zlib can't compress them.
+        let entries = {
+            (0..MAX_SHORT_IDS_PER_MSG as u32)
+            .map(|i| {
+                let noise = i.wrapping_mul(2_654_435_761);
+                let mut entry = entry(100 + i, noise & 0x00ff_ffff, noise);
+                entry.short_channel_id.output_index = noise as u16;
+                entry.checksums = Some(ChannelUpdateChecksums {
+                    checksum_node_id_1: noise,
+                    checksum_node_id_2: noise.rotate_left(16),
+                });
+                entry
+            })
+            .collect()
+        };
+        let source = TestSource { entries };
+        let wanted: Vec<_> = source.entries.iter().map(|entry| (entry.short_channel_id, u64::max_value())).collect();
+        for &encoding in &[ShortIdEncoding::Uncompressed, ShortIdEncoding::Zlib] {
+            let query = QueryChannelRangeMsg {
+                chain_hash: ChainHash::BITCOIN,
+                first_blocknum: 0,
+                number_of_blocks: u32::max_value(),
+                query_option: Some(QUERY_OPTION_TIMESTAMPS | QUERY_OPTION_CHECKSUMS),
+            };
+            let replies = reply_to_query_channel_range(&source, &ChainHash::BITCOIN, encoding, &query);
+            assert_eq!(replies.len(), 1);
+            let bytes = Msg::ReplyChannelRange(replies[0].clone()).to_bytes();
+            assert!(bytes.len() <= 65535, "{} bytes", bytes.len());
+            match unwrap!(Msg::from_bytes(&bytes)) {
+                Msg::ReplyChannelRange(reply) => assert_eq!(reply, replies[0]),
+                _ => panic!("wrong message type"),
+            }
+
+            let mut queries = ShortChannelIdsQuery::new(ChainHash::BITCOIN, encoding, &wanted, true);
+            let query = unwrap!(queries.next_query());
+            assert!(queries.next_query().is_none());
+            let bytes = Msg::QueryShortChannelIds(query.clone()).to_bytes();
+            assert!(bytes.len() <= 65535, "{} bytes", bytes.len());
+            match unwrap!(Msg::from_bytes(&bytes)) {
+                Msg::QueryShortChannelIds(parsed) => assert_eq!(parsed, query),
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+
+    #[test]
+    fn fetches_unknown_channels_and_newer_updates() {
+        let alice = TestSource { entries: vec![entry(100, 0, 10), entry(100, 1, 20), entry(101, 0, 30)] };
+        let bob = TestSource { entries: vec![entry(100, 0, 10), entry(100, 1, 15)] };
+
+        let mut sync = ChannelRangeQuery::new(
+            ChainHash::BITCOIN, 0, 1000, Some(QUERY_OPTION_TIMESTAMPS | QUERY_OPTION_CHECKSUMS),
+        );
+        let replies = reply_to_query_channel_range(&alice, &ChainHash::BITCOIN, ShortIdEncoding::Zlib, sync.query());
+        for reply in &replies {
+            unwrap!(sync.handle_reply_channel_range(reply));
+        }
+        assert!(sync.is_done());
+
+        let missing = sync.missing_gossip(&bob);
+        assert_eq!(missing, vec![
+            (ShortChannelId { block_height: 100, tx_index: 1, output_index: 0 }, QUERY_FLAG_CHANNEL_UPDATE_1),
+            (ShortChannelId { block_height: 101, tx_index: 0, output_index: 0 }, QUERY_FLAGS_ALL),
+        ]);
+
+        let mut queries = ShortChannelIdsQuery::new(ChainHash::BITCOIN, ShortIdEncoding::Uncompressed, &missing, true);
+        let query = unwrap!(queries.next_query());
+        assert!(queries.next_query().is_none());
+        let msgs = unwrap!(reply_to_query_short_channel_ids(&alice, &ChainHash::BITCOIN, &query));
+        assert_eq!(msgs.len(), missing.len() + 1);
+        match msgs.last() {
+            Some(Msg::ReplyShortChannelIdsEnd(end)) => unwrap!(queries.handle_reply_short_channel_ids_end(end)),
+            _ => panic!("expected reply_short_channel_ids_end last"),
+        }
+        assert!(queries.is_done());
+        assert!(queries.full_information());
+    }
+
+    #[test]
+    fn gossip_dump_comes_in_batches() {
+        let source = TestSource { entries: (0..25).map(|i| entry(100 + i, 0, 1)).collect() };
+        let mut dump = GossipDump::new(&source, None);
+        let mut batches = 0;
+        let mut total = 0;
+        while !dump.is_done() {
+            let batch = dump.next_batch(&source, 10);
+            assert!(batch.len() <= 10);
+            total += batch.len();
+            batches += 1;
+        }
+        assert_eq!(batches, 3);
+        assert_eq!(total, 25);
+        assert!(dump.next_batch(&source, 10).is_empty());
+    }
+}
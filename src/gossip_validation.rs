@@ -0,0 +1,257 @@
+use super::*;
+
+/// Reasons to reject a gossip message.
+#[derive(Debug, Fail)]
+pub enum GossipValidationError {
+    #[fail(display = "gossip is for chain {:?}, not ours", chain_hash)]
+    WrongChain {
+        chain_hash: ChainHash,
+    },
+    #[fail(display = "channel_announcement node ids are not in ascending order")]
+    NodeIdsOutOfOrder,
+    #[fail(display = "invalid node_signature_{} in channel_announcement", _0)]
+    InvalidNodeSignature(u8),
+    #[fail(display = "invalid bitcoin_signature_{} in channel_announcement", _0)]
+    InvalidBitcoinSignature(u8),
+    #[fail(display = "invalid signature in node_announcement")]
+    InvalidNodeAnnouncementSignature,
+    #[fail(display = "invalid signature in channel_update for direction {}", direction)]
+    InvalidChannelUpdateSignature {
+        direction: u8,
+    },
+    #[fail(display = "channel_update is for channel {} but was checked against {}", update, announcement)]
+    ShortChannelIdMismatch {
+        update: ShortChannelId,
+        announcement: ShortChannelId,
+    },
+    #[fail(display = "timestamp {} is not newer than previously seen {}", timestamp, previous_timestamp)]
+    StaleTimestamp {
+        timestamp: u32,
+        previous_timestamp: u32,
+    },
+}
+
+impl GossipValidationError {
+    /// Whether the error proves that the peer relayed gossip which it should have known was bogus.
+    /// Stale gossip can be explained by races between peers so it doesn't count against them.
+    pub fn is_misbehaviour(&self) -> bool {
+        match self {
+            GossipValidationError::StaleTimestamp { .. } => false,
+            GossipValidationError::WrongChain { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+fn signed_fields_hash<F>(write_signed_fields: F) -> secp256k1::Message
+where
+    F: FnOnce(&mut WriteCursor),
+{
+    let mut cursor = WriteCursor::new();
+    write_signed_fields(&mut cursor);
+    let hash = double_sha256(&[&cursor.into_bytes()[..]]);
+    unwrap!(secp256k1::Message::from_slice(&hash[..]))
+}
+
+impl ChannelAnnouncementMsg {
+    /// The double-SHA256 of the message, starting after the signatures.
+    pub fn signature_hash(&self) -> secp256k1::Message {
+        signed_fields_hash(|cursor| self.write_signed_fields_to_cursor(cursor))
+    }
+
+    /// Checks the four signatures and that the announcement is for the given chain. This does not
+    /// check that the funding output exists on-chain.
+    pub fn validate(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        chain_hash: &ChainHash,
+    ) -> Result<(), GossipValidationError> {
+        if self.chain_hash != *chain_hash {
+            return Err(GossipValidationError::WrongChain { chain_hash: self.chain_hash });
+        }
+        if self.node_id_1.serialize()[..] >= self.node_id_2.serialize()[..] {
+            return Err(GossipValidationError::NodeIdsOutOfOrder);
+        }
+        let hash = self.signature_hash();
+        if secp.verify(&hash, &self.node_signature_1, &self.node_id_1).is_err() {
+            return Err(GossipValidationError::InvalidNodeSignature(1));
+        }
+        if secp.verify(&hash, &self.node_signature_2, &self.node_id_2).is_err() {
+            return Err(GossipValidationError::InvalidNodeSignature(2));
+        }
+        if secp.verify(&hash, &self.bitcoin_signature_1, &self.bitcoin_key_1).is_err() {
+            return Err(GossipValidationError::InvalidBitcoinSignature(1));
+        }
+        if secp.verify(&hash, &self.bitcoin_signature_2, &self.bitcoin_key_2).is_err() {
+            return Err(GossipValidationError::InvalidBitcoinSignature(2));
+        }
+        Ok(())
+    }
+
+    /// The node which signs `channel_update`s for the given direction.
+    pub fn node_id_for_direction(&self, direction: u8) -> &secp256k1::PublicKey {
+        if direction == 0 {
+            &self.node_id_1
+        } else {
+            &self.node_id_2
+        }
+    }
+}
+
+impl NodeAnnouncementMsg {
+    pub fn signature_hash(&self) -> secp256k1::Message {
+        signed_fields_hash(|cursor| self.write_signed_fields_to_cursor(cursor))
+    }
+
+    /// Checks the signature and, if we've seen an announcement for this node before, that this
+    /// one is newer.
+    pub fn validate(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        previous_timestamp: Option<u32>,
+    ) -> Result<(), GossipValidationError> {
+        if secp.verify(&self.signature_hash(), &self.signature, &self.node_id).is_err() {
+            return Err(GossipValidationError::InvalidNodeAnnouncementSignature);
+        }
+        check_timestamp_increasing(self.timestamp, previous_timestamp)
+    }
+}
+
+impl ChannelUpdateMsg {
+    pub fn signature_hash(&self) -> secp256k1::Message {
+        signed_fields_hash(|cursor| self.write_signed_fields_to_cursor(cursor))
+    }
+
+    /// Checks the update against the announcement of its channel: it must be for the same chain
+    /// and channel, and be signed by the node its direction bit points at. If we've seen an update
+    /// for this direction before, this one must be newer.
+    pub fn validate(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        announcement: &ChannelAnnouncementMsg,
+        previous_timestamp: Option<u32>,
+    ) -> Result<(), GossipValidationError> {
+        if self.short_channel_id != announcement.short_channel_id {
+            return Err(GossipValidationError::ShortChannelIdMismatch {
+                update: self.short_channel_id,
+                announcement: announcement.short_channel_id,
+            });
+        }
+        let node_id = announcement.node_id_for_direction(self.direction());
+        self.validate_signed_by(secp, &announcement.chain_hash, node_id, previous_timestamp)
+    }
+
+    /// Like `validate`, for when we know which node should have signed the update but don't have
+    /// the channel's announcement.
+    pub fn validate_signed_by(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        chain_hash: &ChainHash,
+        node_id: &secp256k1::PublicKey,
+        previous_timestamp: Option<u32>,
+    ) -> Result<(), GossipValidationError> {
+        if self.chain_hash != *chain_hash {
+            return Err(GossipValidationError::WrongChain { chain_hash: self.chain_hash });
+        }
+        if secp.verify(&self.signature_hash(), &self.signature, node_id).is_err() {
+            return Err(GossipValidationError::InvalidChannelUpdateSignature { direction: self.direction() });
+        }
+        check_timestamp_increasing(self.timestamp, previous_timestamp)
+    }
+}
+
+fn check_timestamp_increasing(timestamp: u32, previous_timestamp: Option<u32>) -> Result<(), GossipValidationError> {
+    match previous_timestamp {
+        Some(previous_timestamp) if timestamp <= previous_timestamp => {
+            Err(GossipValidationError::StaleTimestamp { timestamp, previous_timestamp })
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn channel_announcement_signatures() {
+        let secp = Secp256k1::new();
+        let mut msg = signed_channel_announcement(&secp, 0x11, 0x22, test_short_channel_id(500_000));
+        unwrap!(msg.validate(&secp, &ChainHash::BITCOIN));
+
+        msg.excess_data.push(0);
+        match msg.validate(&secp, &ChainHash::BITCOIN) {
+            Err(GossipValidationError::InvalidNodeSignature(1)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        msg.excess_data.pop();
+
+        // Each signature is checked against its own key.
+        let hash = msg.signature_hash();
+        let mut bad_msg = msg.clone();
+        bad_msg.node_signature_2 = bad_msg.node_signature_1;
+        match bad_msg.validate(&secp, &ChainHash::BITCOIN) {
+            Err(ref err @ GossipValidationError::InvalidNodeSignature(2)) => assert!(err.is_misbehaviour()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_msg = msg.clone();
+        bad_msg.bitcoin_signature_1 = secp.sign(&hash, &test_key(&secp, 0x33).0);
+        match bad_msg.validate(&secp, &ChainHash::BITCOIN) {
+            Err(GossipValidationError::InvalidBitcoinSignature(1)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut bad_msg = msg.clone();
+        bad_msg.bitcoin_signature_2 = bad_msg.bitcoin_signature_1;
+        match bad_msg.validate(&secp, &ChainHash::BITCOIN) {
+            Err(GossipValidationError::InvalidBitcoinSignature(2)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        match msg.validate(&secp, &ChainHash([0; 32])) {
+            Err(ref err @ GossipValidationError::WrongChain { .. }) => assert!(!err.is_misbehaviour()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn node_announcement_signature_and_timestamp() {
+        let secp = Secp256k1::new();
+        let mut msg = signed_node_announcement(&secp, 0x11, 1000);
+        unwrap!(msg.validate(&secp, None));
+        unwrap!(msg.validate(&secp, Some(999)));
+        for &previous_timestamp in &[1000, 1001] {
+            match msg.validate(&secp, Some(previous_timestamp)) {
+                Err(GossipValidationError::StaleTimestamp { timestamp: 1000, .. }) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        msg.signature = secp.sign(&msg.signature_hash(), &test_key(&secp, 0x22).0);
+        match msg.validate(&secp, None) {
+            Err(GossipValidationError::InvalidNodeAnnouncementSignature) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn channel_update_direction_must_match_signer() {
+        let secp = Secp256k1::new();
+        let announcement = signed_channel_announcement(&secp, 0x11, 0x22, test_short_channel_id(500_000));
+        let node_2 = if test_key(&secp, 0x11).1 == announcement.node_id_2 { 0x11 } else { 0x22 };
+        let mut update = signed_channel_update(&secp, &announcement, node_2, 1000, |_| ());
+        unwrap!(update.validate(&secp, &announcement, Some(999)));
+
+        match update.validate(&secp, &announcement, Some(1000)) {
+            Err(ref err @ GossipValidationError::StaleTimestamp { .. }) => assert!(!err.is_misbehaviour()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Signed by node 2, but claiming to be from node 1.
+        update.channel_flags &= !CHANNEL_UPDATE_CHANNEL_FLAG_DIRECTION;
+        update.signature = secp.sign(&update.signature_hash(), &test_key(&secp, node_2).0);
+        match update.validate(&secp, &announcement, None) {
+            Err(GossipValidationError::InvalidChannelUpdateSignature { direction: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use super::*;
+
+const SNAPSHOT_MAGIC: &[u8] = b"LNGRAPH";
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+const POLICY_FLAG_DIRECTION: u8 = 1 << 0;
+const POLICY_FLAG_DISABLED: u8 = 1 << 1;
+const POLICY_FLAG_CLTV_EXPIRY_DELTA: u8 = 1 << 2;
+const POLICY_FLAG_HTLC_MINIMUM_MSAT: u8 = 1 << 3;
+const POLICY_FLAG_FEE_BASE_MSAT: u8 = 1 << 4;
+const POLICY_FLAG_FEE_PROPORTIONAL_MILLIONTHS: u8 = 1 << 5;
+const POLICY_FLAG_HTLC_MAXIMUM_MSAT: u8 = 1 << 6;
+const POLICY_FLAG_NO_HTLC_MAXIMUM_MSAT: u8 = 1 << 7;
+
+#[derive(Debug, Fail)]
+pub enum SnapshotError {
+    #[fail(display = "snapshot is truncated")]
+    Truncated,
+    #[fail(display = "malformed snapshot")]
+    Malformed,
+    #[fail(display = "not a graph snapshot")]
+    BadMagic,
+    #[fail(display = "unsupported snapshot version {}", version)]
+    UnsupportedVersion {
+        version: u8,
+    },
+    #[fail(display = "snapshot is for chain {:?}, not ours", chain_hash)]
+    WrongChain {
+        chain_hash: ChainHash,
+    },
+    #[fail(display = "snapshot refers to node {} but only has {}", index, num_nodes)]
+    NodeIndexOutOfRange {
+        index: u64,
+        num_nodes: usize,
+    },
+    #[fail(display = "invalid channel in snapshot: {}", _0)]
+    InvalidChannel(NetworkGraphError),
+}
+
+impl From<MsgTooShortError> for SnapshotError {
+    fn from(_err: MsgTooShortError) -> SnapshotError {
+        SnapshotError::Truncated
+    }
+}
+
+impl From<TlvStreamError> for SnapshotError {
+    fn from(err: TlvStreamError) -> SnapshotError {
+        match err {
+            TlvStreamError::MsgTooShort(..) => SnapshotError::Truncated,
+            _ => SnapshotError::Malformed,
+        }
+    }
+}
+
+/// The most common policy in a snapshot. Policies only encode the fields which differ from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DefaultPolicy {
+    cltv_expiry_delta: u16,
+    htlc_minimum_msat: u64,
+    fee_base_msat: u32,
+    fee_proportional_millionths: u32,
+    htlc_maximum_msat: u64,
+}
+
+fn most_common<T, I>(values: I, fallback: T) -> T
+where
+    T: Eq + std::hash::Hash + Copy + Ord,
+    I: Iterator<Item = T>,
+{
+    let mut counts = HashMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0usize) += 1;
+    }
+    counts
+    .into_iter()
+    .max_by_key(|(value, count)| (*count, cmp::Reverse(*value)))
+    .map(|(value, _)| value)
+    .unwrap_or(fallback)
+}
+
+impl DefaultPolicy {
+    fn from_policies(policies: &[(ShortChannelId, u8, &ChannelPolicy)]) -> DefaultPolicy {
+        let all = || policies.iter().map(|(_, _, policy)| *policy);
+        DefaultPolicy {
+            cltv_expiry_delta: most_common(all().map(|policy| policy.cltv_expiry_delta), 0),
+            htlc_minimum_msat: most_common(all().map(|policy| policy.htlc_minimum_msat), 0),
+            fee_base_msat: most_common(all().map(|policy| policy.fee_base_msat), 0),
+            fee_proportional_millionths: most_common(all().map(|policy| policy.fee_proportional_millionths), 0),
+            htlc_maximum_msat: most_common(all().filter_map(|policy| policy.htlc_maximum_msat), 0),
+        }
+    }
+
+    fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u16(self.cltv_expiry_delta);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u32(self.fee_base_msat);
+        cursor.write_u32(self.fee_proportional_millionths);
+        cursor.write_u64(self.htlc_maximum_msat);
+    }
+
+    fn read_from_cursor(cursor: &mut ReadCursor) -> Result<DefaultPolicy, SnapshotError> {
+        Ok(DefaultPolicy {
+            cltv_expiry_delta: cursor.read_u16()?,
+            htlc_minimum_msat: cursor.read_u64()?,
+            fee_base_msat: cursor.read_u32()?,
+            fee_proportional_millionths: cursor.read_u32()?,
+            htlc_maximum_msat: cursor.read_u64()?,
+        })
+    }
+}
+
+/// Exports a compact snapshot of the graph, without signatures, for clients which can't afford
+/// to download and verify gossip.
+///
+/// If `since` is non-zero the snapshot is incremental: it only contains channels and policies we
+/// received at or after `since`, by our own clock. Channels are never removed by a snapshot;
+/// clients prune channels whose policies stop being refreshed like they would with gossip.
+pub fn export_snapshot(graph: &NetworkGraph, since: u32, now: u32) -> Vec<u8> {
+    let channels: Vec<&ChannelInfo> = {
+        graph
+        .channels()
+        .filter(|channel| channel.announced_at >= since)
+        .collect()
+    };
+    let policies: Vec<(ShortChannelId, u8, &ChannelPolicy)> = {
+        graph
+        .channels()
+        .flat_map(|channel| {
+            let is_new = channel.announced_at >= since;
+            (0..2u8).filter_map(move |direction| {
+                let policy = channel.policy(direction)?;
+                if is_new || policy.received_at >= since {
+                    Some((channel.short_channel_id, direction, policy))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+    };
+
+    let mut node_ids: Vec<NodeId> = {
+        channels
+        .iter()
+        .flat_map(|channel| vec![channel.node_id_1, channel.node_id_2])
+        .collect()
+    };
+    node_ids.sort();
+    node_ids.dedup();
+    let node_index = |node_id: &NodeId| unwrap!(node_ids.binary_search(node_id)) as u64;
+
+    let default_policy = DefaultPolicy::from_policies(&policies);
+
+    let mut cursor = WriteCursor::new();
+    cursor.write_slice(SNAPSHOT_MAGIC);
+    cursor.write_u8(SNAPSHOT_VERSION);
+    cursor.write_chain_hash(graph.chain_hash());
+    cursor.write_u32(since);
+    cursor.write_u32(now);
+    default_policy.write_to_cursor(&mut cursor);
+
+    cursor.write_bigsize(node_ids.len() as u64);
+    for node_id in &node_ids {
+        cursor.write_slice(&node_id.0[..]);
+    }
+
+    cursor.write_bigsize(channels.len() as u64);
+    let mut previous = 0;
+    for channel in &channels {
+        let short_channel_id = channel.short_channel_id.to_u64();
+        cursor.write_bigsize(short_channel_id - previous);
+        previous = short_channel_id;
+        cursor.write_bigsize(node_index(&channel.node_id_1));
+        cursor.write_bigsize(node_index(&channel.node_id_2));
+        cursor.write_bigsize(channel.features.len() as u64);
+        cursor.write_slice(&channel.features);
+    }
+
+    cursor.write_bigsize(policies.len() as u64);
+    let mut previous = 0;
+    for (short_channel_id, direction, policy) in &policies {
+        let short_channel_id = short_channel_id.to_u64();
+        cursor.write_bigsize(short_channel_id - previous);
+        previous = short_channel_id;
+
+        let mut flags = 0;
+        if *direction == 1 {
+            flags |= POLICY_FLAG_DIRECTION;
+        }
+        if policy.disabled {
+            flags |= POLICY_FLAG_DISABLED;
+        }
+        if policy.cltv_expiry_delta != default_policy.cltv_expiry_delta {
+            flags |= POLICY_FLAG_CLTV_EXPIRY_DELTA;
+        }
+        if policy.htlc_minimum_msat != default_policy.htlc_minimum_msat {
+            flags |= POLICY_FLAG_HTLC_MINIMUM_MSAT;
+        }
+        if policy.fee_base_msat != default_policy.fee_base_msat {
+            flags |= POLICY_FLAG_FEE_BASE_MSAT;
+        }
+        if policy.fee_proportional_millionths != default_policy.fee_proportional_millionths {
+            flags |= POLICY_FLAG_FEE_PROPORTIONAL_MILLIONTHS;
+        }
+        match policy.htlc_maximum_msat {
+            None => flags |= POLICY_FLAG_NO_HTLC_MAXIMUM_MSAT,
+            Some(htlc_maximum_msat) if htlc_maximum_msat != default_policy.htlc_maximum_msat => {
+                flags |= POLICY_FLAG_HTLC_MAXIMUM_MSAT;
+            },
+            Some(..) => (),
+        }
+
+        cursor.write_u8(flags);
+        if flags & POLICY_FLAG_CLTV_EXPIRY_DELTA != 0 {
+            cursor.write_u16(policy.cltv_expiry_delta);
+        }
+        if flags & POLICY_FLAG_HTLC_MINIMUM_MSAT != 0 {
+            cursor.write_bigsize(policy.htlc_minimum_msat);
+        }
+        if flags & POLICY_FLAG_FEE_BASE_MSAT != 0 {
+            cursor.write_bigsize(policy.fee_base_msat as u64);
+        }
+        if flags & POLICY_FLAG_FEE_PROPORTIONAL_MILLIONTHS != 0 {
+            cursor.write_bigsize(policy.fee_proportional_millionths as u64);
+        }
+        if flags & POLICY_FLAG_HTLC_MAXIMUM_MSAT != 0 {
+            cursor.write_bigsize(unwrap!(policy.htlc_maximum_msat));
+        }
+    }
+    cursor.into_bytes().to_vec()
+}
+
+fn read_u32_bigsize(cursor: &mut ReadCursor) -> Result<u32, SnapshotError> {
+    let val = cursor.read_bigsize()?;
+    if val > u32::max_value() as u64 {
+        return Err(SnapshotError::Malformed);
+    }
+    Ok(val as u32)
+}
+
+/// A snapshot read in full, before any of it is applied.
+struct ParsedSnapshot {
+    timestamp: u32,
+    channels: Vec<(ShortChannelId, NodeId, NodeId, Vec<u8>)>,
+    policies: Vec<(ShortChannelId, u8, ChannelPolicy)>,
+}
+
+fn parse_snapshot(chain_hash: &ChainHash, snapshot: &[u8]) -> Result<ParsedSnapshot, SnapshotError> {
+    let mut cursor = ReadCursor::new(snapshot);
+    if cursor.read_slice(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = cursor.read_u8()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion { version });
+    }
+    let snapshot_chain_hash = cursor.read_chain_hash()?;
+    if snapshot_chain_hash != *chain_hash {
+        return Err(SnapshotError::WrongChain { chain_hash: snapshot_chain_hash });
+    }
+    let _since = cursor.read_u32()?;
+    let timestamp = cursor.read_u32()?;
+    let default_policy = DefaultPolicy::read_from_cursor(&mut cursor)?;
+
+    let num_nodes = cursor.read_bigsize()?;
+    let mut node_ids = Vec::new();
+    for _ in 0..num_nodes {
+        let node_id = NodeId(slice_to_array!(cursor.read_slice(33)?, 33));
+        if node_id.to_pub_key().is_err() {
+            return Err(SnapshotError::InvalidChannel(NetworkGraphError::InvalidNodeId { node_id }));
+        }
+        node_ids.push(node_id);
+    }
+    let node_id = |index: u64| match node_ids.get(index as usize) {
+        Some(node_id) => Ok(*node_id),
+        None => Err(SnapshotError::NodeIndexOutOfRange { index, num_nodes: node_ids.len() }),
+    };
+
+    let num_channels = cursor.read_bigsize()?;
+    let mut channels = Vec::new();
+    let mut previous = 0u64;
+    for _ in 0..num_channels {
+        previous = previous.checked_add(cursor.read_bigsize()?).ok_or(SnapshotError::Malformed)?;
+        let short_channel_id = ShortChannelId::from_u64(previous);
+        let node_id_1 = node_id(cursor.read_bigsize()?)?;
+        let node_id_2 = node_id(cursor.read_bigsize()?)?;
+        if node_id_1 >= node_id_2 {
+            return Err(SnapshotError::InvalidChannel(GossipValidationError::NodeIdsOutOfOrder.into()));
+        }
+        let features_len = cursor.read_bigsize()?;
+        let features = cursor.read_slice(features_len as usize)?.to_vec();
+        channels.push((short_channel_id, node_id_1, node_id_2, features));
+    }
+
+    let num_policies = cursor.read_bigsize()?;
+    let mut policies = Vec::new();
+    let mut previous = 0u64;
+    for _ in 0..num_policies {
+        previous = previous.checked_add(cursor.read_bigsize()?).ok_or(SnapshotError::Malformed)?;
+        let short_channel_id = ShortChannelId::from_u64(previous);
+        let flags = cursor.read_u8()?;
+        let mut policy = ChannelPolicy {
+            timestamp,
+            disabled: flags & POLICY_FLAG_DISABLED != 0,
+            cltv_expiry_delta: default_policy.cltv_expiry_delta,
+            htlc_minimum_msat: default_policy.htlc_minimum_msat,
+            htlc_maximum_msat: Some(default_policy.htlc_maximum_msat),
+            fee_base_msat: default_policy.fee_base_msat,
+            fee_proportional_millionths: default_policy.fee_proportional_millionths,
+            last_update: None,
+            received_at: timestamp,
+        };
+        if flags & POLICY_FLAG_CLTV_EXPIRY_DELTA != 0 {
+            policy.cltv_expiry_delta = cursor.read_u16()?;
+        }
+        if flags & POLICY_FLAG_HTLC_MINIMUM_MSAT != 0 {
+            policy.htlc_minimum_msat = cursor.read_bigsize()?;
+        }
+        if flags & POLICY_FLAG_FEE_BASE_MSAT != 0 {
+            policy.fee_base_msat = read_u32_bigsize(&mut cursor)?;
+        }
+        if flags & POLICY_FLAG_FEE_PROPORTIONAL_MILLIONTHS != 0 {
+            policy.fee_proportional_millionths = read_u32_bigsize(&mut cursor)?;
+        }
+        if flags & POLICY_FLAG_HTLC_MAXIMUM_MSAT != 0 {
+            policy.htlc_maximum_msat = Some(cursor.read_bigsize()?);
+        }
+        if flags & POLICY_FLAG_NO_HTLC_MAXIMUM_MSAT != 0 {
+            policy.htlc_maximum_msat = None;
+        }
+        policies.push((short_channel_id, flags & POLICY_FLAG_DIRECTION, policy));
+    }
+    if !cursor.is_empty() {
+        return Err(SnapshotError::Malformed);
+    }
+    Ok(ParsedSnapshot { timestamp, channels, policies })
+}
+
+/// Applies a (full or incremental) snapshot made by `export_snapshot` to the graph. The whole
+/// snapshot is checked first, so the graph is left untouched if it's invalid. Channels we already
+/// know and policies that came from signed updates are left alone, and policies for channels we
+/// don't know are skipped. All policies get the snapshot's timestamp. Returns the snapshot's
+/// timestamp, which should be passed as `since` when asking for the next incremental snapshot.
+pub fn apply_snapshot(graph: &mut NetworkGraph, snapshot: &[u8]) -> Result<u32, SnapshotError> {
+    let snapshot = parse_snapshot(graph.chain_hash(), snapshot)?;
+    for (short_channel_id, node_id_1, node_id_2, features) in snapshot.channels {
+        if graph.channel(short_channel_id).is_some() {
+            continue;
+        }
+        // The node ids were checked when parsing.
+        unwrap!(graph.add_unverified_channel(short_channel_id, node_id_1, node_id_2, features, snapshot.timestamp));
+    }
+    for (short_channel_id, direction, policy) in snapshot.policies {
+        if graph.channel(short_channel_id).is_none() {
+            continue;
+        }
+        unwrap!(graph.set_unverified_policy(short_channel_id, direction, policy));
+    }
+    Ok(snapshot.timestamp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u32 = 1_600_000_000;
+
+    fn server_graph(secp: &Secp256k1<secp256k1::All>) -> NetworkGraph {
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        for (i, &(a, b)) in [(1, 2), (2, 3), (3, 4)].iter().enumerate() {
+            let announcement = signed_channel_announcement(secp, a, b, test_short_channel_id(500 + i as u32));
+            unwrap!(graph.handle_channel_announcement(&announcement, NOW));
+            unwrap!(graph.handle_channel_update(&signed_channel_update(secp, &announcement, a, NOW, |_| ()), NOW));
+            unwrap!(graph.handle_channel_update(&signed_channel_update(secp, &announcement, b, NOW, |update| {
+                update.fee_base_msat = 1000 + i as u32;
+                update.htlc_maximum_msat = None;
+                update.message_flags = 0;
+            }), NOW));
+        }
+        graph
+    }
+
+    fn assert_same_policies(server: &NetworkGraph, client: &NetworkGraph) {
+        assert_eq!(server.num_channels(), client.num_channels());
+        for channel in server.channels() {
+            let client_channel = unwrap!(client.channel(channel.short_channel_id));
+            assert_eq!(client_channel.node_id_1, channel.node_id_1);
+            assert_eq!(client_channel.node_id_2, channel.node_id_2);
+            for direction in 0..2 {
+                let policy = unwrap!(channel.policy(direction));
+                let client_policy = unwrap!(client_channel.policy(direction));
+                assert_eq!(client_policy.fee_base_msat, policy.fee_base_msat);
+                assert_eq!(client_policy.htlc_maximum_msat, policy.htlc_maximum_msat);
+                assert_eq!(client_policy.cltv_expiry_delta, policy.cltv_expiry_delta);
+            }
+        }
+    }
+
+    #[test]
+    fn full_and_incremental_snapshots() {
+        let secp = Secp256k1::new();
+        let mut server = server_graph(&secp);
+        let mut client = NetworkGraph::new(ChainHash::BITCOIN);
+
+        let snapshot = export_snapshot(&server, 0, NOW + 50);
+        let synced_at = unwrap!(apply_snapshot(&mut client, &snapshot));
+        assert_eq!(synced_at, NOW + 50);
+        assert_same_policies(&server, &client);
+
+        // The update is signed before the last snapshot but only reaches the server after it, so
+        // the incremental snapshot must still carry it.
+        let later = NOW + 100;
+        let announcement = unwrap!(unwrap!(server.channel(test_short_channel_id(501))).announcement.clone());
+        let update = signed_channel_update(&secp, &announcement, 2, NOW + 10, |update| update.cltv_expiry_delta = 99);
+        unwrap!(server.handle_channel_update(&update, later));
+        let new_channel = signed_channel_announcement(&secp, 4, 5, test_short_channel_id(600));
+        unwrap!(server.handle_channel_announcement(&new_channel, later));
+        unwrap!(server.handle_channel_update(&signed_channel_update(&secp, &new_channel, 4, later, |_| ()), later));
+        unwrap!(server.handle_channel_update(&signed_channel_update(&secp, &new_channel, 5, later, |_| ()), later));
+
+        let incremental = export_snapshot(&server, synced_at, later);
+        assert!(incremental.len() < snapshot.len());
+        unwrap!(apply_snapshot(&mut client, &incremental));
+        assert_same_policies(&server, &client);
+    }
+
+    #[test]
+    fn rejects_truncated_snapshot() {
+        let secp = Secp256k1::new();
+        let snapshot = export_snapshot(&server_graph(&secp), 0, NOW);
+        let mut client = NetworkGraph::new(ChainHash::BITCOIN);
+        match apply_snapshot(&mut client, &snapshot[..snapshot.len() - 1]) {
+            Err(SnapshotError::Truncated) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Only the last policy is cut short, but nothing is applied.
+        assert_eq!(client.num_channels(), 0);
+    }
+
+    #[test]
+    fn keeps_verified_policies() {
+        let secp = Secp256k1::new();
+        let server = server_graph(&secp);
+        let mut client = NetworkGraph::new(ChainHash::BITCOIN);
+        let announcement = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        unwrap!(client.handle_channel_announcement(&announcement, NOW));
+        let update = signed_channel_update(&secp, &announcement, 1, NOW - 10, |update| update.fee_base_msat = 5000);
+        unwrap!(client.handle_channel_update(&update, NOW));
+
+        unwrap!(apply_snapshot(&mut client, &export_snapshot(&server, 0, NOW + 50)));
+        assert_eq!(client.num_channels(), 3);
+        let channel = unwrap!(client.channel(test_short_channel_id(500)));
+        assert!(channel.announcement.is_some());
+        let direction = update.direction();
+        let policy = unwrap!(channel.policy(direction));
+        assert_eq!(policy.fee_base_msat, 5000);
+        assert_eq!(policy.last_update.as_ref(), Some(&update));
+        // The other direction had no signed update, so the snapshot fills it in.
+        let server_policy = unwrap!(unwrap!(server.channel(test_short_channel_id(500))).policy(1 - direction));
+        let policy = unwrap!(channel.policy(1 - direction));
+        assert!(policy.last_update.is_none());
+        assert_eq!(policy.fee_base_msat, server_policy.fee_base_msat);
+    }
+}
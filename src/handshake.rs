@@ -148,14 +148,6 @@ fn init_handshake_state() -> ([u8; 32], [u8; 32]) {
     (h, ck)
 }
 
-fn sha256(blocks: &[&[u8]]) -> [u8; 32] {
-    let mut hasher = Sha256::default();
-    for block in blocks {
-        hasher.process(block)
-    }
-    slice_to_array!(hasher.fixed_result(), 32)
-}
-
 fn hkdf(salt: &[u8], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
     let hkdf = Hkdf::<Sha256>::extract(Some(salt), ikm);
     let mut expanded = [0u8; 64];
@@ -0,0 +1,427 @@
+use super::*;
+
+/// An HTLC-timeout or HTLC-success transaction: the second stage which takes an HTLC output of
+/// the local party's commitment transaction and locks it up behind the same delay and revocation
+/// key as their `to_local` output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtlcTx {
+    /// Which of the commitment state's HTLCs this spends.
+    pub htlc_index: usize,
+    pub tx: Transaction,
+    /// The script of the HTLC output being spent.
+    pub htlc_script: Vec<u8>,
+    pub htlc_amount_satoshis: u64,
+    /// HTLC-timeout, rather than HTLC-success.
+    pub is_timeout: bool,
+    /// Whether this is for an `option_anchors_zero_fee_htlc_tx` channel. If so, the remote party
+    /// signs with `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY` so the local party can add inputs and
+    /// outputs to pay the fee.
+    pub anchors: bool,
+}
+
+/// Builds the transaction spending the HTLC at `output_index` of a commitment transaction:
+/// HTLC-timeout for an HTLC the local party offered, which can't be mined before the HTLC
+/// expires, and HTLC-success for one they received.
+pub fn build_htlc_tx(
+    commitment_txid: &[u8; 32],
+    output_index: u32,
+    htlc: &CommitmentHtlc,
+    feerate_per_kw: u32,
+    to_self_delay: u16,
+    keys: &CommitmentKeys,
+    anchors: bool,
+) -> Transaction {
+    let fee_satoshis = htlc_tx_fee_satoshis(feerate_per_kw, htlc.offered, anchors);
+    Transaction {
+        version: 2,
+        inputs: vec![TxIn {
+            previous_output: OutPoint { txid: *commitment_txid, vout: output_index },
+            script_sig: Vec::new(),
+            // Anchor channels' HTLC outputs are all behind a 1 block CSV.
+            sequence: if anchors { 1 } else { 0 },
+            witness: Vec::new(),
+        }],
+        outputs: vec![TxOut {
+            value: htlc.amount_msat / 1000 - fee_satoshis,
+            script_pubkey: p2wsh_script_pubkey(&to_local_script(keys, to_self_delay)),
+        }],
+        lock_time: if htlc.offered { htlc.cltv_expiry } else { 0 },
+    }
+}
+
+/// The HTLC transactions for every untrimmed HTLC of a commitment, in output order. This is the
+/// order the remote party's signatures come in in `commitment_signed`.
+pub fn build_htlc_txs(
+    commitment: &CommitmentTx,
+    params: &CommitmentParams,
+    keys: &CommitmentKeys,
+    state: &CommitmentState,
+) -> Vec<HtlcTx> {
+    let commitment_txid = commitment.tx.txid();
+    let mut htlc_txs: Vec<HtlcTx> = {
+        state.htlcs.iter()
+        .zip(&commitment.htlc_output_indexes)
+        .enumerate()
+        .filter_map(|(htlc_index, (htlc, output_index))| {
+            let output_index = (*output_index)?;
+            Some(HtlcTx {
+                htlc_index,
+                tx: build_htlc_tx(
+                    &commitment_txid,
+                    output_index,
+                    htlc,
+                    state.feerate_per_kw,
+                    params.to_self_delay,
+                    keys,
+                    params.anchors,
+                ),
+                htlc_script: htlc_script(keys, htlc, params.anchors),
+                htlc_amount_satoshis: htlc.amount_msat / 1000,
+                is_timeout: htlc.offered,
+                anchors: params.anchors,
+            })
+        })
+        .collect()
+    };
+    htlc_txs.sort_by_key(|htlc_tx| htlc_tx.tx.inputs[0].previous_output.vout);
+    htlc_txs
+}
+
+impl HtlcTx {
+    /// The sighash type the remote party's signature commits to.
+    pub fn remote_sighash_type(&self) -> u32 {
+        if self.anchors { SIGHASH_SINGLE | SIGHASH_ANYONECANPAY } else { SIGHASH_ALL }
+    }
+
+    pub fn sighash(&self, sighash_type: u32) -> [u8; 32] {
+        self.tx.bip143_sighash(0, &self.htlc_script, self.htlc_amount_satoshis, sighash_type)
+    }
+
+    /// The local party's signature, needed to broadcast the transaction.
+    pub fn sign_local(&self, secp: &Secp256k1<secp256k1::All>, local_htlc_key: &secp256k1::SecretKey) -> secp256k1::Signature {
+        let msg = unwrap!(secp256k1::Message::from_slice(&self.sighash(SIGHASH_ALL)[..]));
+        secp.sign(&msg, local_htlc_key)
+    }
+
+    /// The remote party's signature, which they hand over in `commitment_signed`.
+    pub fn sign_remote(&self, secp: &Secp256k1<secp256k1::All>, remote_htlc_key: &secp256k1::SecretKey) -> secp256k1::Signature {
+        let msg = unwrap!(secp256k1::Message::from_slice(&self.sighash(self.remote_sighash_type())[..]));
+        secp.sign(&msg, remote_htlc_key)
+    }
+
+    pub fn verify_remote_signature(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        keys: &CommitmentKeys,
+        signature: &secp256k1::Signature,
+    ) -> bool {
+        let msg = unwrap!(secp256k1::Message::from_slice(&self.sighash(self.remote_sighash_type())[..]));
+        secp.verify(&msg, signature, &keys.remote_htlc_pubkey).is_ok()
+    }
+
+    /// Fills in the witness: `0 <remotehtlcsig> <localhtlcsig> <payment_preimage>` for
+    /// HTLC-success, with an empty element instead of the preimage for HTLC-timeout.
+    pub fn add_witness(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        local_signature: &secp256k1::Signature,
+        remote_signature: &secp256k1::Signature,
+        payment_preimage: Option<&[u8; 32]>,
+    ) {
+        self.tx.inputs[0].witness = vec![
+            Vec::new(),
+            witness_signature(secp, remote_signature, self.remote_sighash_type()),
+            witness_signature(secp, local_signature, SIGHASH_ALL),
+            payment_preimage.map(|preimage| preimage.to_vec()).unwrap_or_default(),
+            self.htlc_script.clone(),
+        ];
+    }
+}
+
+/// Spends an HTLC output of a revoked commitment: `<revocation_sig> <revocationpubkey>`.
+pub fn htlc_revocation_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    revocation_signature: &secp256k1::Signature,
+    revocation_pubkey: &secp256k1::PublicKey,
+    htlc_script: &[u8],
+) -> Vec<Vec<u8>> {
+    vec![
+        witness_signature(secp, revocation_signature, SIGHASH_ALL),
+        revocation_pubkey.serialize().to_vec(),
+        htlc_script.to_vec(),
+    ]
+}
+
+/// Lets the remote party claim an HTLC the local party offered, straight from the local party's
+/// commitment: `<remotehtlcsig> <payment_preimage>`. With anchors the spending input's sequence
+/// must be at least 1.
+pub fn offered_htlc_preimage_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    remote_htlc_signature: &secp256k1::Signature,
+    payment_preimage: &[u8; 32],
+    htlc_script: &[u8],
+) -> Vec<Vec<u8>> {
+    vec![
+        witness_signature(secp, remote_htlc_signature, SIGHASH_ALL),
+        payment_preimage.to_vec(),
+        htlc_script.to_vec(),
+    ]
+}
+
+/// Lets the remote party take back an HTLC the local party received once it expires, straight
+/// from the local party's commitment: `<remotehtlcsig> <>`. The spending transaction's locktime
+/// must be at least the HTLC's CLTV expiry.
+pub fn received_htlc_timeout_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    remote_htlc_signature: &secp256k1::Signature,
+    htlc_script: &[u8],
+) -> Vec<Vec<u8>> {
+    vec![
+        witness_signature(secp, remote_htlc_signature, SIGHASH_ALL),
+        Vec::new(),
+        htlc_script.to_vec(),
+    ]
+}
+
+/// Spends a `to_local` output, or an HTLC transaction's output, once `to_self_delay` has passed:
+/// `<local_delayedsig> <>`. The spending input's sequence must be at least the delay.
+pub fn to_local_delayed_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    delayed_signature: &secp256k1::Signature,
+    to_local_script: &[u8],
+) -> Vec<Vec<u8>> {
+    vec![
+        witness_signature(secp, delayed_signature, SIGHASH_ALL),
+        Vec::new(),
+        to_local_script.to_vec(),
+    ]
+}
+
+/// Spends an anchor channel's `to_remote` output: `<remote_sig>`. The spending input's sequence
+/// must be at least 1.
+pub fn to_remote_anchors_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    remote_signature: &secp256k1::Signature,
+    to_remote_script: &[u8],
+) -> Vec<Vec<u8>> {
+    vec![
+        witness_signature(secp, remote_signature, SIGHASH_ALL),
+        to_remote_script.to_vec(),
+    ]
+}
+
+/// Spends an anchor output with its owner's funding key, usually to bump the commitment's fee:
+/// `<funding_sig>`. Passing no signature gives the witness anyone can use once the anchor is 16
+/// blocks deep.
+pub fn anchor_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    funding_signature: Option<&secp256k1::Signature>,
+    anchor_script: &[u8],
+) -> Vec<Vec<u8>> {
+    let signature = match funding_signature {
+        Some(signature) => witness_signature(secp, signature, SIGHASH_ALL),
+        None => Vec::new(),
+    };
+    vec![signature, anchor_script.to_vec()]
+}
+
+/// Spends a revoked `to_local` output, or a revoked HTLC transaction's output:
+/// `<revocation_sig> 1`.
+pub fn to_local_revocation_witness(
+    secp: &Secp256k1<secp256k1::All>,
+    revocation_signature: &secp256k1::Signature,
+    to_local_script: &[u8],
+) -> Vec<Vec<u8>> {
+    vec![
+        witness_signature(secp, revocation_signature, SIGHASH_ALL),
+        vec![1],
+        to_local_script.to_vec(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    fn test_htlcs() -> Vec<CommitmentHtlc> {
+        (0..4u8).map(|i| CommitmentHtlc {
+            offered: i % 2 == 0,
+            amount_msat: 1_000_000 * (i as u64 + 1),
+            payment_hash: sha256(&[&[i; 32][..]]),
+            cltv_expiry: 500 + i as u32,
+        }).collect()
+    }
+
+    #[test]
+    fn bolt3_htlc_tx_vectors() {
+        let secp = Secp256k1::new();
+        let (params, keys) = bolt3_params(&secp);
+        let local_htlc_key = unwrap!(secp256k1::SecretKey::from_slice(&secp, &hex!("bb13b121cdc357cd2e608b0aea294afca36e2b34cf958e2e6451a2f274694491")));
+        for vector in BOLT3_COMMITMENT_VECTORS {
+            let state = CommitmentState {
+                commitment_number: 42,
+                to_local_msat: 6_988_000_000,
+                to_remote_msat: 3_000_000_000,
+                feerate_per_kw: vector.feerate_per_kw,
+                htlcs: bolt3_htlcs(),
+            };
+            let commitment = build_commitment_tx(&params, &keys, &state);
+            let htlc_txs = build_htlc_txs(&commitment, &params, &keys, &state);
+            assert_eq!(htlc_txs.len(), vector.htlc_txs.len());
+            for (mut htlc_tx, (remote_signature, expected)) in htlc_txs.into_iter().zip(vector.htlc_txs) {
+                let remote_signature = unwrap!(secp256k1::Signature::from_der(&secp, remote_signature));
+                assert!(htlc_tx.verify_remote_signature(&secp, &keys, &remote_signature));
+                let local_signature = htlc_tx.sign_local(&secp, &local_htlc_key);
+                // The payment preimages are all `[htlc_index; 32]`.
+                let preimage = [htlc_tx.htlc_index as u8; 32];
+                let preimage = if htlc_tx.is_timeout { None } else { Some(&preimage) };
+                htlc_tx.add_witness(&secp, &local_signature, &remote_signature, preimage);
+                assert_eq!(&htlc_tx.tx.to_bytes()[..], *expected);
+            }
+        }
+    }
+
+    #[test]
+    fn htlc_txs_spend_commitment_outputs() {
+        let secp = Secp256k1::new();
+        let (local, remote) = (test_channel_secrets(&secp, 0x10), test_channel_secrets(&secp, 0x20));
+        let funding_outpoint = OutPoint { txid: [0x42; 32], vout: 1 };
+        let params = CommitmentParams::new(funding_outpoint, 10_000_000, &local.basepoints(&secp), &remote.basepoints(&secp), true, 144, 546, false);
+        let point = unwrap!(local.per_commitment_point(&secp, 7));
+        let keys = unwrap!(CommitmentKeys::derive(&secp, &point, &local.basepoints(&secp), &remote.basepoints(&secp), ChannelType::StaticRemoteKey));
+        let state = CommitmentState {
+            commitment_number: 7,
+            to_local_msat: 6_000_000_000,
+            to_remote_msat: 3_990_000_000,
+            feerate_per_kw: 1000,
+            htlcs: test_htlcs(),
+        };
+        let commitment = build_commitment_tx(&params, &keys, &state);
+        let htlc_txs = build_htlc_txs(&commitment, &params, &keys, &state);
+        // The 1000 sat offered HTLC is trimmed at this feerate.
+        assert_eq!(htlc_txs.len(), 3);
+
+        let mut last_vout = None;
+        for htlc_tx in &htlc_txs {
+            let htlc = &state.htlcs[htlc_tx.htlc_index];
+            let input = &htlc_tx.tx.inputs[0];
+            assert_eq!(input.previous_output.txid, commitment.tx.txid());
+            assert!(Some(input.previous_output.vout) > last_vout);
+            last_vout = Some(input.previous_output.vout);
+            let spent = &commitment.tx.outputs[input.previous_output.vout as usize];
+            assert_eq!(spent.script_pubkey, p2wsh_script_pubkey(&htlc_tx.htlc_script));
+            assert_eq!(spent.value, htlc_tx.htlc_amount_satoshis);
+
+            let fee = htlc_tx_fee_satoshis(state.feerate_per_kw, htlc.offered, false);
+            assert_eq!(htlc_tx.tx.outputs[0].value, htlc.amount_msat / 1000 - fee);
+            assert_eq!(htlc_tx.tx.outputs[0].script_pubkey, p2wsh_script_pubkey(&to_local_script(&keys, 144)));
+            assert_eq!(htlc_tx.is_timeout, htlc.offered);
+            assert_eq!(htlc_tx.tx.lock_time, if htlc.offered { htlc.cltv_expiry } else { 0 });
+        }
+    }
+
+    #[test]
+    fn htlc_tx_signatures_and_witnesses() {
+        let secp = Secp256k1::new();
+        let (local, remote) = (test_channel_secrets(&secp, 0x10), test_channel_secrets(&secp, 0x20));
+        let funding_outpoint = OutPoint { txid: [0x42; 32], vout: 1 };
+        let params = CommitmentParams::new(funding_outpoint, 10_000_000, &local.basepoints(&secp), &remote.basepoints(&secp), false, 144, 546, false);
+        let point = unwrap!(local.per_commitment_point(&secp, 7));
+        let keys = unwrap!(CommitmentKeys::derive(&secp, &point, &local.basepoints(&secp), &remote.basepoints(&secp), ChannelType::StaticRemoteKey));
+        let state = CommitmentState {
+            commitment_number: 7,
+            to_local_msat: 6_000_000_000,
+            to_remote_msat: 3_990_000_000,
+            feerate_per_kw: 253,
+            htlcs: test_htlcs(),
+        };
+        let commitment = build_commitment_tx(&params, &keys, &state);
+        let mut htlc_txs = build_htlc_txs(&commitment, &params, &keys, &state);
+        assert_eq!(htlc_txs.len(), 4);
+
+        let local_htlc_key = unwrap!(derive_private_key(&secp, &local.htlc_basepoint_secret, &point));
+        let remote_htlc_key = unwrap!(derive_private_key(&secp, &remote.htlc_basepoint_secret, &point));
+        for htlc_tx in &mut htlc_txs {
+            let remote_signature = htlc_tx.sign_remote(&secp, &remote_htlc_key);
+            assert!(htlc_tx.verify_remote_signature(&secp, &keys, &remote_signature));
+            let local_signature = htlc_tx.sign_local(&secp, &local_htlc_key);
+            assert!(!htlc_tx.verify_remote_signature(&secp, &keys, &local_signature));
+
+            let preimage = [htlc_tx.htlc_index as u8; 32];
+            assert_eq!(sha256(&[&preimage[..]]), state.htlcs[htlc_tx.htlc_index].payment_hash);
+            let preimage = if htlc_tx.is_timeout { None } else { Some(&preimage) };
+            htlc_tx.add_witness(&secp, &local_signature, &remote_signature, preimage);
+            let witness = &htlc_tx.tx.inputs[0].witness;
+            assert_eq!(witness.len(), 5);
+            assert!(witness[0].is_empty());
+            assert_eq!(witness[3].len(), if htlc_tx.is_timeout { 0 } else { 32 });
+            assert_eq!(witness[4], htlc_tx.htlc_script);
+        }
+
+        // Once the commitment is revoked, the remote party can sweep every HTLC output straight
+        // from it with the revocation key.
+        let revocation_key = {
+            unwrap!(derive_revocation_private_key(&secp, &remote.revocation_basepoint_secret, &unwrap!(local.per_commitment_secret(7))))
+        };
+        let commitment_txid = commitment.tx.txid();
+        let mut penalty_tx = Transaction {
+            version: 2,
+            inputs: {
+                htlc_txs.iter()
+                .map(|htlc_tx| TxIn {
+                    previous_output: htlc_tx.tx.inputs[0].previous_output,
+                    script_sig: Vec::new(),
+                    sequence: 0xffff_fffd,
+                    witness: Vec::new(),
+                })
+                .collect()
+            },
+            outputs: vec![TxOut {
+                value: htlc_txs.iter().map(|htlc_tx| htlc_tx.htlc_amount_satoshis).sum::<u64>() - 1000,
+                script_pubkey: p2wpkh_script_pubkey(&test_key(&secp, 0x30).1),
+            }],
+            lock_time: 0,
+        };
+        let sighash_msg = |tx: &Transaction, input_index: usize, htlc_tx: &HtlcTx| {
+            let sighash = tx.bip143_sighash(input_index, &htlc_tx.htlc_script, htlc_tx.htlc_amount_satoshis, SIGHASH_ALL);
+            unwrap!(secp256k1::Message::from_slice(&sighash[..]))
+        };
+        for (input_index, htlc_tx) in htlc_txs.iter().enumerate() {
+            let msg = sighash_msg(&penalty_tx, input_index, htlc_tx);
+            let revocation_signature = secp.sign(&msg, &revocation_key);
+            // Neither party's HTLC key can stand in for the revocation key.
+            for htlc_key in &[&local_htlc_key, &remote_htlc_key] {
+                assert!(secp.verify(&msg, &secp.sign(&msg, htlc_key), &keys.revocation_pubkey).is_err());
+            }
+            let witness = htlc_revocation_witness(&secp, &revocation_signature, &keys.revocation_pubkey, &htlc_tx.htlc_script);
+            penalty_tx.inputs[input_index].witness = witness;
+        }
+
+        // Check each input's witness against the output it spends, as a verifier would.
+        let penalty_tx = unwrap!(Transaction::from_bytes(&penalty_tx.to_bytes()));
+        for (input_index, htlc_tx) in htlc_txs.iter().enumerate() {
+            let input = &penalty_tx.inputs[input_index];
+            assert_eq!(input.previous_output.txid, commitment_txid);
+            let spent = &commitment.tx.outputs[input.previous_output.vout as usize];
+            assert_eq!(spent.value, htlc_tx.htlc_amount_satoshis);
+
+            let witness = &input.witness;
+            assert_eq!(witness.len(), 3);
+            let witness_script = &witness[2];
+            assert_eq!(p2wsh_script_pubkey(witness_script), spent.script_pubkey);
+            // The script starts `OP_DUP OP_HASH160 <RIPEMD160(SHA256(revocationpubkey))> OP_EQUAL`.
+            assert_eq!(&witness_script[..3], &[0x76, 0xa9, 0x14][..]);
+            assert_eq!(&witness_script[3..23], &hash160(&witness[1])[..]);
+            let revocation_pubkey = unwrap!(secp256k1::PublicKey::from_slice(&secp, &witness[1]));
+            let (signature, sighash_type) = witness[0].split_at(witness[0].len() - 1);
+            assert_eq!(sighash_type, &[SIGHASH_ALL as u8][..]);
+            let signature = unwrap!(secp256k1::Signature::from_der(&secp, signature));
+            let msg = sighash_msg(&penalty_tx, input_index, htlc_tx);
+            assert!(secp.verify(&msg, &signature, &revocation_pubkey).is_ok());
+            // The signature commits to its own input.
+            let other_msg = sighash_msg(&penalty_tx, (input_index + 1) % htlc_txs.len(), htlc_tx);
+            assert!(secp.verify(&other_msg, &signature, &revocation_pubkey).is_err());
+        }
+    }
+}
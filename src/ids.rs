@@ -0,0 +1,76 @@
+use super::*;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct ChannelId(pub [u8; 32]);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct ChainHash(pub [u8; 32]);
+
+impl ChainHash {
+    /// The genesis block hash of bitcoin mainnet, in internal byte order.
+    pub const BITCOIN: ChainHash = ChainHash([
+        0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72,
+        0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7, 0x4f,
+        0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c,
+        0x68, 0xd6, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct ShortChannelId {
+    pub block_height: u32,
+    pub tx_index: u32,
+    pub output_index: u16,
+}
+
+impl ShortChannelId {
+    pub fn from_u64(val: u64) -> ShortChannelId {
+        ShortChannelId {
+            block_height: (val >> 40) as u32,
+            tx_index: ((val >> 16) & 0xff_ffff) as u32,
+            output_index: val as u16,
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        ((self.block_height as u64 & 0xff_ffff) << 40)
+        | ((self.tx_index as u64 & 0xff_ffff) << 16)
+        | self.output_index as u64
+    }
+}
+
+impl fmt::Display for ShortChannelId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}x{}x{}", self.block_height, self.tx_index, self.output_index)
+    }
+}
+
+/// A node's public key in compressed form. Unlike `secp256k1::PublicKey`, this is cheap to hash
+/// and orders the same way as the spec's lexicographic node ordering.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 33]);
+
+impl NodeId {
+    pub fn from_pub_key(pub_key: &secp256k1::PublicKey) -> NodeId {
+        NodeId(pub_key.serialize())
+    }
+
+    pub fn to_pub_key(&self) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+        secp256k1::PublicKey::from_slice(&Secp256k1::without_caps(), &self.0[..])
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "NodeId({})", self)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0[..] {
+            write!(fmt, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1235 @@
+use super::*;
+
+/// The maximum number of `tx_add_input` or `tx_add_output` messages we'll accept from the peer
+/// in a single negotiation.
+pub const MAX_RECEIVED_TX_ADD_MSGS: usize = 4096;
+
+/// The maximum number of inputs or outputs the finished transaction may have.
+pub const MAX_INPUTS_OUTPUTS_PER_TX: usize = 252;
+
+/// Sequence numbers above this don't signal replaceability.
+pub const MAX_RBF_SEQUENCE: u32 = 0xffff_fffd;
+
+/// Largest output value which can be valid (21 million btc in satoshis).
+pub const MAX_MONEY_SATOSHIS: u64 = 2_100_000_000_000_000;
+
+/// The weight of the version, locktime, segwit marker and flag, and the input and output counts,
+/// which the initiator pays for.
+const COMMON_FIELDS_WEIGHT: u64 = 42;
+
+/// The weight of an input without its witness.
+const INPUT_BASE_WEIGHT: u64 = 164;
+
+/// Witnesses aren't known until `tx_signatures`, so every input is assumed to spend a P2WPKH
+/// output with a worst case signature.
+const P2WPKH_WITNESS_WEIGHT: u64 = 109;
+
+/// Which side of the negotiation we are. The initiator picks even serial ids, the non-initiator
+/// picks odd serial ids.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InteractiveTxRole {
+    Initiator,
+    NonInitiator,
+}
+
+impl InteractiveTxRole {
+    fn serial_id_parity(self) -> u64 {
+        match self {
+            InteractiveTxRole::Initiator => 0,
+            InteractiveTxRole::NonInitiator => 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Contributor {
+    Us,
+    Them,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractiveTxInput {
+    pub contributor: Contributor,
+    pub serial_id: u64,
+    pub prev_tx: Vec<u8>,
+    pub prev_tx_vout: u32,
+    pub sequence: u32,
+    /// The output `prev_tx` and `prev_tx_vout` point at, and its value.
+    pub previous_output: OutPoint,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractiveTxOutput {
+    pub contributor: Contributor,
+    pub serial_id: u64,
+    pub sats: u64,
+    pub script: Vec<u8>,
+}
+
+/// What `open_channel2` and `accept_channel2`, or `tx_init_rbf` and `tx_ack_rbf`, settled about
+/// the funding transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingTxParams {
+    pub locktime: u32,
+    pub feerate_per_kw: u32,
+    /// The script of the shared funding output, which the initiator adds.
+    pub funding_script: Vec<u8>,
+    pub local_funding_satoshis: u64,
+    pub remote_funding_satoshis: u64,
+}
+
+#[derive(Debug, Fail)]
+pub enum InteractiveTxError {
+    #[fail(display = "message is for a different channel")]
+    WrongChannelId,
+    #[fail(display = "message sent out of turn")]
+    OutOfTurn,
+    #[fail(display = "negotiation has already completed")]
+    AlreadyComplete,
+    #[fail(display = "negotiation hasn't completed")]
+    NotComplete,
+    #[fail(display = "serial id {} has the wrong parity", serial_id)]
+    WrongSerialIdParity {
+        serial_id: u64,
+    },
+    #[fail(display = "serial id {} is already in use", serial_id)]
+    DuplicateSerialId {
+        serial_id: u64,
+    },
+    #[fail(display = "no input or output with serial id {}", serial_id)]
+    UnknownSerialId {
+        serial_id: u64,
+    },
+    #[fail(display = "too many tx_add_input messages received")]
+    TooManyInputsReceived,
+    #[fail(display = "too many tx_add_output messages received")]
+    TooManyOutputsReceived,
+    #[fail(display = "input spends the same outpoint as an existing input")]
+    DuplicateInput,
+    #[fail(display = "prev_tx of {} bytes doesn't fit in tx_add_input", len)]
+    PrevTxTooLarge {
+        len: usize,
+    },
+    #[fail(display = "prev_tx is not a valid transaction: {}", _0)]
+    InvalidPrevTx(TransactionError),
+    #[fail(display = "prev_tx has no output {}", prev_tx_vout)]
+    PrevTxOutputMissing {
+        prev_tx_vout: u32,
+    },
+    #[fail(display = "prev_tx output {} is not a segwit output", prev_tx_vout)]
+    PrevTxOutputNotSegwit {
+        prev_tx_vout: u32,
+    },
+    #[fail(display = "input sequence {} does not signal replaceability", sequence)]
+    NonReplaceableSequence {
+        sequence: u32,
+    },
+    #[fail(display = "output value {} is below the dust limit {}", sats, dust_limit_satoshis)]
+    OutputBelowDustLimit {
+        sats: u64,
+        dust_limit_satoshis: u64,
+    },
+    #[fail(display = "output value {} exceeds the maximum amount of money", sats)]
+    OutputAboveMaxMoney {
+        sats: u64,
+    },
+    #[fail(display = "output script is not a standard witness program")]
+    NonStandardOutputScript,
+    #[fail(display = "transaction has too many inputs ({})", count)]
+    TooManyInputs {
+        count: usize,
+    },
+    #[fail(display = "transaction has too many outputs ({})", count)]
+    TooManyOutputs {
+        count: usize,
+    },
+    #[fail(display = "transaction doesn't have a single funding output of {} sat", funding_satoshis)]
+    MissingFundingOutput {
+        funding_satoshis: u64,
+    },
+    #[fail(display = "inputs from {:?} don't cover its outputs and funding contribution", contributor)]
+    InsufficientInputs {
+        contributor: Contributor,
+    },
+    #[fail(display = "contribution from {:?} doesn't pay the agreed feerate", contributor)]
+    InsufficientFee {
+        contributor: Contributor,
+    },
+    #[fail(display = "transaction doesn't double spend every previous attempt")]
+    DoesNotReplacePrevious,
+    #[fail(display = "tx_signatures sent out of order")]
+    SignaturesOutOfOrder,
+    #[fail(display = "signatures were already sent")]
+    AlreadySigned,
+    #[fail(display = "tx_signatures is for a different transaction")]
+    WrongTxid,
+    #[fail(display = "expected {} witnesses, got {}", expected, received)]
+    WrongWitnessCount {
+        expected: usize,
+        received: usize,
+    },
+    #[fail(display = "invalid witness for input {}", index)]
+    InvalidWitness {
+        index: usize,
+    },
+    #[fail(display = "the transaction being replaced isn't fully signed")]
+    NotSigned,
+    #[fail(display = "unexpected rbf message")]
+    UnexpectedRbf,
+    #[fail(display = "rbf feerate {} is under the minimum of {}", feerate_per_kw, min_feerate_per_kw)]
+    RbfFeerateTooLow {
+        feerate_per_kw: u32,
+        min_feerate_per_kw: u32,
+    },
+    #[fail(display = "negative funding contribution {}", contribution)]
+    NegativeContribution {
+        contribution: i64,
+    },
+}
+
+/// Collaboratively builds a transaction with a peer by exchanging `tx_add_input`,
+/// `tx_add_output`, `tx_remove_input`, `tx_remove_output` and `tx_complete` messages.
+///
+/// The parties take turns sending exactly one message at a time, starting with the initiator.
+/// Negotiation finishes once both sides send `tx_complete` consecutively, after which both sides
+/// send `tx_signatures` with the witnesses for their inputs. The side contributing less sends
+/// first. The initiator can then replace the transaction with `tx_init_rbf`, which starts a new
+/// negotiation at a higher feerate.
+pub struct InteractiveTxConstructor {
+    channel_id: ChannelId,
+    role: InteractiveTxRole,
+    /// Breaks the tie when both sides contribute the same amount: the lower node id signs first.
+    local_node_id_is_lower: bool,
+    dust_limit_satoshis: u64,
+    params: FundingTxParams,
+    inputs: BTreeMap<u64, InteractiveTxInput>,
+    outputs: BTreeMap<u64, InteractiveTxOutput>,
+    next_serial_id: u64,
+    our_turn: bool,
+    sent_complete: bool,
+    received_complete: bool,
+    received_add_inputs: usize,
+    received_add_outputs: usize,
+    local_witnesses: Option<Vec<Vec<Vec<u8>>>>,
+    remote_witnesses: Option<Vec<Vec<Vec<u8>>>>,
+    /// The inputs of each transaction this one replaces. Every replacement has to spend one of
+    /// them, so that at most one of the attempts confirms.
+    previous_inputs: Vec<Vec<OutPoint>>,
+    /// The params we asked for in `tx_init_rbf`, until the peer acks them.
+    pending_rbf: Option<FundingTxParams>,
+}
+
+impl InteractiveTxConstructor {
+    pub fn new(
+        channel_id: ChannelId,
+        role: InteractiveTxRole,
+        local_node_id: &NodeId,
+        remote_node_id: &NodeId,
+        dust_limit_satoshis: u64,
+        params: FundingTxParams,
+    ) -> InteractiveTxConstructor {
+        InteractiveTxConstructor {
+            channel_id,
+            role,
+            local_node_id_is_lower: local_node_id < remote_node_id,
+            dust_limit_satoshis,
+            params,
+            inputs: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+            next_serial_id: role.serial_id_parity(),
+            our_turn: role == InteractiveTxRole::Initiator,
+            sent_complete: false,
+            received_complete: false,
+            received_add_inputs: 0,
+            received_add_outputs: 0,
+            local_witnesses: None,
+            remote_witnesses: None,
+            previous_inputs: Vec::new(),
+            pending_rbf: None,
+        }
+    }
+
+    pub fn role(&self) -> InteractiveTxRole {
+        self.role
+    }
+
+    pub fn params(&self) -> &FundingTxParams {
+        &self.params
+    }
+
+    pub fn is_our_turn(&self) -> bool {
+        self.our_turn && !self.is_complete()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.sent_complete && self.received_complete
+    }
+
+    /// The negotiated inputs, ordered by serial id.
+    pub fn inputs(&self) -> impl Iterator<Item = &InteractiveTxInput> {
+        self.inputs.values()
+    }
+
+    /// The negotiated outputs, ordered by serial id.
+    pub fn outputs(&self) -> impl Iterator<Item = &InteractiveTxOutput> {
+        self.outputs.values()
+    }
+
+    /// The negotiated transaction, without witnesses, once negotiation has completed. Inputs and
+    /// outputs are ordered by serial id.
+    pub fn unsigned_tx(&self) -> Option<Transaction> {
+        if !self.is_complete() {
+            return None;
+        }
+        Some(self.build_tx())
+    }
+
+    /// The shared funding output of the negotiated transaction.
+    pub fn funding_outpoint(&self) -> Option<OutPoint> {
+        let tx = self.unsigned_tx()?;
+        let vout = self.outputs.values().position(|output| output.script == self.params.funding_script)?;
+        Some(OutPoint { txid: tx.txid(), vout: vout as u32 })
+    }
+
+    /// The fully signed transaction, once both sides have sent `tx_signatures`.
+    pub fn signed_tx(&self) -> Option<Transaction> {
+        let (local_witnesses, remote_witnesses) = match (&self.local_witnesses, &self.remote_witnesses) {
+            (Some(local), Some(remote)) => (local, remote),
+            _ => return None,
+        };
+        let mut local_witnesses = local_witnesses.iter();
+        let mut remote_witnesses = remote_witnesses.iter();
+        let mut tx = self.build_tx();
+        for (tx_input, input) in tx.inputs.iter_mut().zip(self.inputs.values()) {
+            let witness = match input.contributor {
+                Contributor::Us => local_witnesses.next(),
+                Contributor::Them => remote_witnesses.next(),
+            };
+            tx_input.witness = unwrap!(witness).clone();
+        }
+        Some(tx)
+    }
+
+    /// Whether we have to send `tx_signatures` before the peer does.
+    pub fn sends_signatures_first(&self) -> bool {
+        let local_satoshis = self.contributed_satoshis(Contributor::Us);
+        let remote_satoshis = self.contributed_satoshis(Contributor::Them);
+        local_satoshis < remote_satoshis || (local_satoshis == remote_satoshis && self.local_node_id_is_lower)
+    }
+
+    pub fn add_input(&mut self, prev_tx: Vec<u8>, prev_tx_vout: u32, sequence: u32)
+        -> Result<TxAddInputMsg, InteractiveTxError>
+    {
+        self.start_send()?;
+        let serial_id = self.take_serial_id();
+        let input = new_input(Contributor::Us, serial_id, prev_tx, prev_tx_vout, sequence)?;
+        self.insert_input(input.clone())?;
+        self.finish_send(false);
+        Ok(TxAddInputMsg {
+            channel_id: self.channel_id,
+            serial_id,
+            prev_tx: input.prev_tx,
+            prev_tx_vout,
+            sequence,
+        })
+    }
+
+    pub fn add_output(&mut self, sats: u64, script: Vec<u8>)
+        -> Result<TxAddOutputMsg, InteractiveTxError>
+    {
+        self.start_send()?;
+        let serial_id = self.take_serial_id();
+        let output = InteractiveTxOutput {
+            contributor: Contributor::Us,
+            serial_id,
+            sats,
+            script,
+        };
+        self.insert_output(output.clone())?;
+        self.finish_send(false);
+        Ok(TxAddOutputMsg {
+            channel_id: self.channel_id,
+            serial_id,
+            sats,
+            script: output.script,
+        })
+    }
+
+    pub fn remove_input(&mut self, serial_id: u64)
+        -> Result<TxRemoveInputMsg, InteractiveTxError>
+    {
+        self.start_send()?;
+        self.check_serial_id_parity(serial_id, Contributor::Us)?;
+        if self.inputs.remove(&serial_id).is_none() {
+            return Err(InteractiveTxError::UnknownSerialId { serial_id });
+        }
+        self.finish_send(false);
+        Ok(TxRemoveInputMsg { channel_id: self.channel_id, serial_id })
+    }
+
+    pub fn remove_output(&mut self, serial_id: u64)
+        -> Result<TxRemoveOutputMsg, InteractiveTxError>
+    {
+        self.start_send()?;
+        self.check_serial_id_parity(serial_id, Contributor::Us)?;
+        if self.outputs.remove(&serial_id).is_none() {
+            return Err(InteractiveTxError::UnknownSerialId { serial_id });
+        }
+        self.finish_send(false);
+        Ok(TxRemoveOutputMsg { channel_id: self.channel_id, serial_id })
+    }
+
+    pub fn complete(&mut self) -> Result<TxCompleteMsg, InteractiveTxError> {
+        self.start_send()?;
+        if self.received_complete {
+            self.check_final_tx()?;
+        }
+        self.finish_send(true);
+        Ok(TxCompleteMsg { channel_id: self.channel_id })
+    }
+
+    pub fn handle_tx_add_input(&mut self, msg: &TxAddInputMsg) -> Result<(), InteractiveTxError> {
+        self.start_receive(&msg.channel_id)?;
+        self.received_add_inputs += 1;
+        if self.received_add_inputs > MAX_RECEIVED_TX_ADD_MSGS {
+            return Err(InteractiveTxError::TooManyInputsReceived);
+        }
+        self.check_serial_id_parity(msg.serial_id, Contributor::Them)?;
+        let input = new_input(Contributor::Them, msg.serial_id, msg.prev_tx.clone(), msg.prev_tx_vout, msg.sequence)?;
+        self.insert_input(input)?;
+        self.finish_receive(false);
+        Ok(())
+    }
+
+    pub fn handle_tx_add_output(&mut self, msg: &TxAddOutputMsg) -> Result<(), InteractiveTxError> {
+        self.start_receive(&msg.channel_id)?;
+        self.received_add_outputs += 1;
+        if self.received_add_outputs > MAX_RECEIVED_TX_ADD_MSGS {
+            return Err(InteractiveTxError::TooManyOutputsReceived);
+        }
+        self.check_serial_id_parity(msg.serial_id, Contributor::Them)?;
+        self.insert_output(InteractiveTxOutput {
+            contributor: Contributor::Them,
+            serial_id: msg.serial_id,
+            sats: msg.sats,
+            script: msg.script.clone(),
+        })?;
+        self.finish_receive(false);
+        Ok(())
+    }
+
+    pub fn handle_tx_remove_input(&mut self, msg: &TxRemoveInputMsg) -> Result<(), InteractiveTxError> {
+        self.start_receive(&msg.channel_id)?;
+        self.check_serial_id_parity(msg.serial_id, Contributor::Them)?;
+        if self.inputs.remove(&msg.serial_id).is_none() {
+            return Err(InteractiveTxError::UnknownSerialId { serial_id: msg.serial_id });
+        }
+        self.finish_receive(false);
+        Ok(())
+    }
+
+    pub fn handle_tx_remove_output(&mut self, msg: &TxRemoveOutputMsg) -> Result<(), InteractiveTxError> {
+        self.start_receive(&msg.channel_id)?;
+        self.check_serial_id_parity(msg.serial_id, Contributor::Them)?;
+        if self.outputs.remove(&msg.serial_id).is_none() {
+            return Err(InteractiveTxError::UnknownSerialId { serial_id: msg.serial_id });
+        }
+        self.finish_receive(false);
+        Ok(())
+    }
+
+    pub fn handle_tx_complete(&mut self, msg: &TxCompleteMsg) -> Result<(), InteractiveTxError> {
+        self.start_receive(&msg.channel_id)?;
+        if self.sent_complete {
+            self.check_final_tx()?;
+        }
+        self.finish_receive(true);
+        Ok(())
+    }
+
+    /// Sends the witnesses for our inputs, in serial id order.
+    pub fn tx_signatures(&mut self, witnesses: Vec<Vec<Vec<u8>>>) -> Result<TxSignaturesMsg, InteractiveTxError> {
+        if !self.is_complete() {
+            return Err(InteractiveTxError::NotComplete);
+        }
+        if self.local_witnesses.is_some() {
+            return Err(InteractiveTxError::AlreadySigned);
+        }
+        if !self.sends_signatures_first() && self.remote_witnesses.is_none() {
+            return Err(InteractiveTxError::SignaturesOutOfOrder);
+        }
+        let expected = self.inputs.values().filter(|input| input.contributor == Contributor::Us).count();
+        if witnesses.len() != expected {
+            return Err(InteractiveTxError::WrongWitnessCount { expected, received: witnesses.len() });
+        }
+        let msg = TxSignaturesMsg {
+            channel_id: self.channel_id,
+            txid: self.build_tx().txid(),
+            witnesses: witnesses.iter().map(|witness| write_witness(witness)).collect(),
+        };
+        self.local_witnesses = Some(witnesses);
+        Ok(msg)
+    }
+
+    /// The peer resends `tx_signatures` after a reconnection, so a repeat of the same witnesses is
+    /// ignored.
+    pub fn handle_tx_signatures(&mut self, msg: &TxSignaturesMsg) -> Result<(), InteractiveTxError> {
+        if msg.channel_id != self.channel_id {
+            return Err(InteractiveTxError::WrongChannelId);
+        }
+        if !self.is_complete() {
+            return Err(InteractiveTxError::NotComplete);
+        }
+        if msg.txid != self.build_tx().txid() {
+            return Err(InteractiveTxError::WrongTxid);
+        }
+        let expected = self.inputs.values().filter(|input| input.contributor == Contributor::Them).count();
+        if msg.witnesses.len() != expected {
+            return Err(InteractiveTxError::WrongWitnessCount { expected, received: msg.witnesses.len() });
+        }
+        let mut witnesses = Vec::with_capacity(expected);
+        for (index, witness) in msg.witnesses.iter().enumerate() {
+            match read_witness(witness) {
+                Some(witness) => witnesses.push(witness),
+                None => return Err(InteractiveTxError::InvalidWitness { index }),
+            }
+        }
+        if let Some(ref remote_witnesses) = self.remote_witnesses {
+            if *remote_witnesses == witnesses {
+                return Ok(());
+            }
+            return Err(InteractiveTxError::AlreadySigned);
+        }
+        if self.sends_signatures_first() && self.local_witnesses.is_none() {
+            return Err(InteractiveTxError::SignaturesOutOfOrder);
+        }
+        self.remote_witnesses = Some(witnesses);
+        Ok(())
+    }
+
+    /// Asks to replace the signed transaction with one paying `feerate_per_kw`, which has to be
+    /// at least 25/24 of the current feerate. Only the initiator can do this.
+    pub fn init_rbf(
+        &mut self,
+        locktime: u32,
+        feerate_per_kw: u32,
+        local_funding_satoshis: u64,
+    ) -> Result<TxInitRbfMsg, InteractiveTxError> {
+        if self.role != InteractiveTxRole::Initiator || self.pending_rbf.is_some() {
+            return Err(InteractiveTxError::UnexpectedRbf);
+        }
+        self.check_rbf(feerate_per_kw)?;
+        self.pending_rbf = Some(FundingTxParams {
+            locktime,
+            feerate_per_kw,
+            local_funding_satoshis,
+            ..self.params.clone()
+        });
+        Ok(TxInitRbfMsg {
+            channel_id: self.channel_id,
+            locktime,
+            feerate: feerate_per_kw,
+            funding_output_contribution: Some(local_funding_satoshis as i64),
+            require_confirmed_inputs: false,
+        })
+    }
+
+    /// Accepts the initiator's `tx_init_rbf` and starts a new negotiation, in which we contribute
+    /// `local_funding_satoshis`. A peer which leaves out its contribution keeps the previous one.
+    pub fn handle_tx_init_rbf(
+        &mut self,
+        msg: &TxInitRbfMsg,
+        local_funding_satoshis: u64,
+    ) -> Result<TxAckRbfMsg, InteractiveTxError> {
+        if msg.channel_id != self.channel_id {
+            return Err(InteractiveTxError::WrongChannelId);
+        }
+        if self.role != InteractiveTxRole::NonInitiator {
+            return Err(InteractiveTxError::UnexpectedRbf);
+        }
+        self.check_rbf(msg.feerate)?;
+        let remote_funding_satoshis = match msg.funding_output_contribution {
+            Some(contribution) => contribution_satoshis(contribution)?,
+            None => self.params.remote_funding_satoshis,
+        };
+        let params = FundingTxParams {
+            locktime: msg.locktime,
+            feerate_per_kw: msg.feerate,
+            funding_script: self.params.funding_script.clone(),
+            local_funding_satoshis,
+            remote_funding_satoshis,
+        };
+        self.restart(params);
+        Ok(TxAckRbfMsg {
+            channel_id: self.channel_id,
+            funding_output_contribution: Some(local_funding_satoshis as i64),
+            require_confirmed_inputs: false,
+        })
+    }
+
+    /// Starts the negotiation of the replacement we asked for in `init_rbf`.
+    pub fn handle_tx_ack_rbf(&mut self, msg: &TxAckRbfMsg) -> Result<(), InteractiveTxError> {
+        if msg.channel_id != self.channel_id {
+            return Err(InteractiveTxError::WrongChannelId);
+        }
+        let mut params = match self.pending_rbf.take() {
+            Some(params) => params,
+            None => return Err(InteractiveTxError::UnexpectedRbf),
+        };
+        if let Some(contribution) = msg.funding_output_contribution {
+            params.remote_funding_satoshis = contribution_satoshis(contribution)?;
+        }
+        self.restart(params);
+        Ok(())
+    }
+
+    fn start_send(&self) -> Result<(), InteractiveTxError> {
+        if self.is_complete() {
+            return Err(InteractiveTxError::AlreadyComplete);
+        }
+        if !self.our_turn {
+            return Err(InteractiveTxError::OutOfTurn);
+        }
+        Ok(())
+    }
+
+    fn finish_send(&mut self, complete: bool) {
+        self.sent_complete = complete;
+        if !complete {
+            self.received_complete = false;
+        }
+        self.our_turn = false;
+    }
+
+    fn start_receive(&self, channel_id: &ChannelId) -> Result<(), InteractiveTxError> {
+        if *channel_id != self.channel_id {
+            return Err(InteractiveTxError::WrongChannelId);
+        }
+        if self.is_complete() {
+            return Err(InteractiveTxError::AlreadyComplete);
+        }
+        if self.our_turn {
+            return Err(InteractiveTxError::OutOfTurn);
+        }
+        Ok(())
+    }
+
+    fn finish_receive(&mut self, complete: bool) {
+        self.received_complete = complete;
+        if !complete {
+            self.sent_complete = false;
+        }
+        self.our_turn = true;
+    }
+
+    fn take_serial_id(&mut self) -> u64 {
+        let serial_id = self.next_serial_id;
+        self.next_serial_id += 2;
+        serial_id
+    }
+
+    fn check_serial_id_parity(&self, serial_id: u64, contributor: Contributor)
+        -> Result<(), InteractiveTxError>
+    {
+        let our_parity = self.role.serial_id_parity();
+        let expected_parity = match contributor {
+            Contributor::Us => our_parity,
+            Contributor::Them => 1 - our_parity,
+        };
+        if serial_id % 2 != expected_parity {
+            return Err(InteractiveTxError::WrongSerialIdParity { serial_id });
+        }
+        Ok(())
+    }
+
+    fn insert_input(&mut self, input: InteractiveTxInput) -> Result<(), InteractiveTxError> {
+        if self.inputs.contains_key(&input.serial_id) {
+            return Err(InteractiveTxError::DuplicateSerialId { serial_id: input.serial_id });
+        }
+        // The same output can come with a differently serialized prev_tx, so compare outpoints.
+        if self.inputs.values().any(|existing| existing.previous_output == input.previous_output) {
+            return Err(InteractiveTxError::DuplicateInput);
+        }
+        self.inputs.insert(input.serial_id, input);
+        Ok(())
+    }
+
+    fn insert_output(&mut self, output: InteractiveTxOutput) -> Result<(), InteractiveTxError> {
+        if self.outputs.contains_key(&output.serial_id) {
+            return Err(InteractiveTxError::DuplicateSerialId { serial_id: output.serial_id });
+        }
+        if output.sats < self.dust_limit_satoshis {
+            return Err(InteractiveTxError::OutputBelowDustLimit {
+                sats: output.sats,
+                dust_limit_satoshis: self.dust_limit_satoshis,
+            });
+        }
+        if output.sats > MAX_MONEY_SATOSHIS {
+            return Err(InteractiveTxError::OutputAboveMaxMoney { sats: output.sats });
+        }
+        if !is_standard_witness_program(&output.script) {
+            return Err(InteractiveTxError::NonStandardOutputScript);
+        }
+        self.outputs.insert(output.serial_id, output);
+        Ok(())
+    }
+
+    /// Checks the transaction both sides are about to agree on: its size, the funding output, and
+    /// that each side's inputs pay for its outputs, its share of the funding output and the fee
+    /// for the weight it adds.
+    fn check_final_tx(&self) -> Result<(), InteractiveTxError> {
+        if self.inputs.len() > MAX_INPUTS_OUTPUTS_PER_TX {
+            return Err(InteractiveTxError::TooManyInputs { count: self.inputs.len() });
+        }
+        if self.outputs.len() > MAX_INPUTS_OUTPUTS_PER_TX {
+            return Err(InteractiveTxError::TooManyOutputs { count: self.outputs.len() });
+        }
+
+        let funding_satoshis = self.params.local_funding_satoshis.saturating_add(self.params.remote_funding_satoshis);
+        let mut funding_outputs = self.outputs.values().filter(|output| output.script == self.params.funding_script);
+        match (funding_outputs.next(), funding_outputs.next()) {
+            (Some(output), None) if output.sats == funding_satoshis => (),
+            _ => return Err(InteractiveTxError::MissingFundingOutput { funding_satoshis }),
+        }
+
+        for &contributor in &[Contributor::Us, Contributor::Them] {
+            let is_initiator = (contributor == Contributor::Us) == (self.role == InteractiveTxRole::Initiator);
+            let (mut spent_satoshis, mut weight) = if is_initiator {
+                (0, COMMON_FIELDS_WEIGHT + output_weight(&self.params.funding_script))
+            } else {
+                (0, 0)
+            };
+            spent_satoshis += match contributor {
+                Contributor::Us => self.params.local_funding_satoshis,
+                Contributor::Them => self.params.remote_funding_satoshis,
+            };
+            // Each output is at most MAX_MONEY_SATOSHIS, so only the funding amounts can overflow.
+            for output in self.outputs.values() {
+                if output.contributor == contributor && output.script != self.params.funding_script {
+                    spent_satoshis += output.sats;
+                    weight += output_weight(&output.script);
+                }
+            }
+            let num_inputs = self.inputs.values().filter(|input| input.contributor == contributor).count() as u64;
+            weight += num_inputs * (INPUT_BASE_WEIGHT + P2WPKH_WITNESS_WEIGHT);
+
+            let input_satoshis = self.contributed_satoshis(contributor);
+            if input_satoshis < spent_satoshis {
+                return Err(InteractiveTxError::InsufficientInputs { contributor });
+            }
+            if input_satoshis - spent_satoshis < u64::from(self.params.feerate_per_kw) * weight / 1000 {
+                return Err(InteractiveTxError::InsufficientFee { contributor });
+            }
+        }
+
+        for previous_inputs in &self.previous_inputs {
+            if !self.inputs.values().any(|input| previous_inputs.contains(&input.previous_output)) {
+                return Err(InteractiveTxError::DoesNotReplacePrevious);
+            }
+        }
+        Ok(())
+    }
+
+    fn build_tx(&self) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: self.inputs.values().map(|input| TxIn {
+                previous_output: input.previous_output,
+                script_sig: Vec::new(),
+                sequence: input.sequence,
+                witness: Vec::new(),
+            }).collect(),
+            outputs: self.outputs.values().map(|output| TxOut {
+                value: output.sats,
+                script_pubkey: output.script.clone(),
+            }).collect(),
+            lock_time: self.params.locktime,
+        }
+    }
+
+    /// The total value of the inputs `contributor` has added.
+    fn contributed_satoshis(&self, contributor: Contributor) -> u64 {
+        self.inputs.values()
+        .filter(|input| input.contributor == contributor)
+        .map(|input| input.value)
+        .sum()
+    }
+
+    fn check_rbf(&self, feerate_per_kw: u32) -> Result<(), InteractiveTxError> {
+        if self.signed_tx().is_none() {
+            return Err(InteractiveTxError::NotSigned);
+        }
+        let min_feerate_per_kw = (u64::from(self.params.feerate_per_kw) * 25 + 23) / 24;
+        if u64::from(feerate_per_kw) < min_feerate_per_kw {
+            return Err(InteractiveTxError::RbfFeerateTooLow {
+                feerate_per_kw,
+                min_feerate_per_kw: min_feerate_per_kw as u32,
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts negotiating a replacement for the signed transaction.
+    fn restart(&mut self, params: FundingTxParams) {
+        let inputs = self.inputs.values().map(|input| input.previous_output).collect();
+        self.previous_inputs.push(inputs);
+        self.params = params;
+        self.inputs.clear();
+        self.outputs.clear();
+        self.next_serial_id = self.role.serial_id_parity();
+        self.our_turn = self.role == InteractiveTxRole::Initiator;
+        self.sent_complete = false;
+        self.received_complete = false;
+        self.received_add_inputs = 0;
+        self.received_add_outputs = 0;
+        self.local_witnesses = None;
+        self.remote_witnesses = None;
+    }
+}
+
+/// Checks that `prev_tx` has a segwit output `prev_tx_vout`, which can be spent without changing
+/// the txid of the transaction being built.
+fn new_input(
+    contributor: Contributor,
+    serial_id: u64,
+    prev_tx: Vec<u8>,
+    prev_tx_vout: u32,
+    sequence: u32,
+) -> Result<InteractiveTxInput, InteractiveTxError> {
+    if sequence > MAX_RBF_SEQUENCE {
+        return Err(InteractiveTxError::NonReplaceableSequence { sequence });
+    }
+    if prev_tx.len() > usize::from(u16::max_value()) {
+        return Err(InteractiveTxError::PrevTxTooLarge { len: prev_tx.len() });
+    }
+    let parsed = Transaction::from_bytes(&prev_tx).map_err(InteractiveTxError::InvalidPrevTx)?;
+    let value = match parsed.outputs.get(prev_tx_vout as usize) {
+        Some(output) if !is_standard_witness_program(&output.script_pubkey) => {
+            return Err(InteractiveTxError::PrevTxOutputNotSegwit { prev_tx_vout });
+        },
+        Some(output) if output.value > MAX_MONEY_SATOSHIS => {
+            return Err(InteractiveTxError::OutputAboveMaxMoney { sats: output.value });
+        },
+        Some(output) => output.value,
+        None => return Err(InteractiveTxError::PrevTxOutputMissing { prev_tx_vout }),
+    };
+    Ok(InteractiveTxInput {
+        contributor,
+        serial_id,
+        previous_output: OutPoint { txid: parsed.txid(), vout: prev_tx_vout },
+        value,
+        prev_tx,
+        prev_tx_vout,
+        sequence,
+    })
+}
+
+fn contribution_satoshis(contribution: i64) -> Result<u64, InteractiveTxError> {
+    if contribution < 0 {
+        return Err(InteractiveTxError::NegativeContribution { contribution });
+    }
+    Ok(contribution as u64)
+}
+
+/// Outputs have to be standard, so their scripts are short enough for a one byte length.
+fn output_weight(script: &[u8]) -> u64 {
+    (8 + 1 + script.len() as u64) * 4
+}
+
+/// A witness stack as `tx_signatures` carries it: the number of items, then each item, all with
+/// compact size lengths.
+fn write_witness(witness: &[Vec<u8>]) -> Vec<u8> {
+    let mut cursor = WriteCursor::new();
+    cursor.write_compact_size(witness.len() as u64);
+    for item in witness {
+        cursor.write_compact_size_slice(item);
+    }
+    cursor.into_bytes().to_vec()
+}
+
+/// Spending a segwit output takes at least one witness item.
+fn read_witness(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut cursor = ReadCursor::new(bytes);
+    let num_items = cursor.read_compact_size().ok()?;
+    let mut witness = Vec::new();
+    for _ in 0..num_items {
+        witness.push(cursor.read_compact_size_slice().ok()?.to_vec());
+    }
+    if witness.is_empty() || !cursor.is_empty() {
+        return None;
+    }
+    Some(witness)
+}
+
+/// Checks that a script is a witness program: a version opcode followed by a single push of
+/// 2 to 40 bytes.
+fn is_standard_witness_program(script: &[u8]) -> bool {
+    if script.len() < 4 || script.len() > 42 {
+        return false;
+    }
+    let version_ok = script[0] == 0x00 || (script[0] >= 0x51 && script[0] <= 0x60);
+    version_ok && script[1] as usize == script.len() - 2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL_ID: ChannelId = ChannelId([7; 32]);
+
+    fn p2wpkh_script() -> Vec<u8> {
+        let mut script = vec![0x00, 0x14];
+        script.extend(&[0x42; 20][..]);
+        script
+    }
+
+    fn funding_script() -> Vec<u8> {
+        let mut script = vec![0x00, 0x20];
+        script.extend(&[0x55; 32][..]);
+        script
+    }
+
+    fn prev_tx(script_pubkey: Vec<u8>, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint { txid: [3; 32], vout: 0 },
+                script_sig: Vec::new(),
+                sequence: 0xffff_fffd,
+                witness: Vec::new(),
+            }],
+            outputs: vec![TxOut { value, script_pubkey }],
+            lock_time: 0,
+        }
+    }
+
+    /// The initiator puts 40,000 sat into the funding output and the non-initiator 20,000.
+    fn constructor(role: InteractiveTxRole) -> InteractiveTxConstructor {
+        let (local_node, remote_node, local_funding_satoshis, remote_funding_satoshis) = match role {
+            InteractiveTxRole::Initiator => (1, 2, 40_000, 20_000),
+            InteractiveTxRole::NonInitiator => (2, 1, 20_000, 40_000),
+        };
+        let params = FundingTxParams {
+            locktime: 600_000,
+            feerate_per_kw: 2_500,
+            funding_script: funding_script(),
+            local_funding_satoshis,
+            remote_funding_satoshis,
+        };
+        InteractiveTxConstructor::new(CHANNEL_ID, role, &test_node_id(local_node), &test_node_id(remote_node), 546, params)
+    }
+
+    /// Each side adds an input, the initiator the funding output and the non-initiator a change
+    /// output of 3,900 sat, then the initiator completes first. Returns the result of the
+    /// non-initiator's `tx_complete`, which runs the final checks.
+    fn negotiate(
+        initiator: &mut InteractiveTxConstructor,
+        non_initiator: &mut InteractiveTxConstructor,
+        input_sats: (u64, u64),
+        funding_output_sats: u64,
+    ) -> Result<(), InteractiveTxError> {
+        let msg = unwrap!(initiator.add_input(prev_tx(p2wpkh_script(), input_sats.0).to_bytes().to_vec(), 0, 0xffff_fffd));
+        unwrap!(non_initiator.handle_tx_add_input(&msg));
+        let msg = unwrap!(non_initiator.add_input(prev_tx(p2wpkh_script(), input_sats.1).to_bytes().to_vec(), 0, 0xffff_fffd));
+        unwrap!(initiator.handle_tx_add_input(&msg));
+        let msg = unwrap!(initiator.add_output(funding_output_sats, funding_script()));
+        unwrap!(non_initiator.handle_tx_add_output(&msg));
+        let msg = unwrap!(non_initiator.add_output(3_900, p2wpkh_script()));
+        unwrap!(initiator.handle_tx_add_output(&msg));
+        let msg = unwrap!(initiator.complete());
+        unwrap!(non_initiator.handle_tx_complete(&msg));
+        let msg = non_initiator.complete()?;
+        unwrap!(initiator.handle_tx_complete(&msg));
+        Ok(())
+    }
+
+    fn negotiated() -> (InteractiveTxConstructor, InteractiveTxConstructor) {
+        let mut initiator = constructor(InteractiveTxRole::Initiator);
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+        unwrap!(negotiate(&mut initiator, &mut non_initiator, (50_000, 25_000), 60_000));
+        (initiator, non_initiator)
+    }
+
+    fn exchange_signatures(initiator: &mut InteractiveTxConstructor, non_initiator: &mut InteractiveTxConstructor) {
+        let msg = unwrap!(non_initiator.tx_signatures(vec![vec![vec![1; 72], vec![2; 33]]]));
+        unwrap!(initiator.handle_tx_signatures(&msg));
+        let msg = unwrap!(initiator.tx_signatures(vec![vec![vec![3; 71], vec![4; 33]]]));
+        unwrap!(non_initiator.handle_tx_signatures(&msg));
+    }
+
+    #[test]
+    fn negotiation_completes_after_consecutive_tx_completes() {
+        let mut initiator = constructor(InteractiveTxRole::Initiator);
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+
+        let msg = unwrap!(initiator.add_input(prev_tx(p2wpkh_script(), 50_000).to_bytes().to_vec(), 0, 0xffff_fffd));
+        assert_eq!(msg.serial_id % 2, 0);
+        unwrap!(non_initiator.handle_tx_add_input(&msg));
+
+        let msg = unwrap!(non_initiator.add_output(10_000, p2wpkh_script()));
+        assert_eq!(msg.serial_id % 2, 1);
+        unwrap!(initiator.handle_tx_add_output(&msg));
+
+        let msg = unwrap!(initiator.add_output(60_000, funding_script()));
+        unwrap!(non_initiator.handle_tx_add_output(&msg));
+        let msg = unwrap!(non_initiator.add_input(prev_tx(p2wpkh_script(), 31_000).to_bytes().to_vec(), 0, 0xffff_fffd));
+        unwrap!(initiator.handle_tx_add_input(&msg));
+
+        let msg = unwrap!(initiator.complete());
+        unwrap!(non_initiator.handle_tx_complete(&msg));
+        assert!(!initiator.is_complete());
+        assert!(initiator.unsigned_tx().is_none());
+
+        let msg = unwrap!(non_initiator.complete());
+        unwrap!(initiator.handle_tx_complete(&msg));
+
+        assert!(initiator.is_complete());
+        assert!(non_initiator.is_complete());
+        assert_eq!(initiator.inputs().count(), 2);
+        assert_eq!(non_initiator.outputs().count(), 2);
+
+        // Inputs and outputs go by serial id, so the non-initiator's change output comes first.
+        let tx = unwrap!(initiator.unsigned_tx());
+        assert_eq!(Some(tx.clone()), non_initiator.unsigned_tx());
+        assert_eq!(tx.lock_time, 600_000);
+        assert_eq!(tx.inputs[0].previous_output.txid, prev_tx(p2wpkh_script(), 50_000).txid());
+        assert_eq!(tx.inputs[1].previous_output.txid, prev_tx(p2wpkh_script(), 31_000).txid());
+        assert_eq!(tx.outputs[0], TxOut { value: 10_000, script_pubkey: p2wpkh_script() });
+        assert_eq!(tx.outputs[1], TxOut { value: 60_000, script_pubkey: funding_script() });
+        assert_eq!(initiator.funding_outpoint(), Some(OutPoint { txid: tx.txid(), vout: 1 }));
+    }
+
+    #[test]
+    fn rejects_wrong_serial_id_parity() {
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+        let msg = TxAddOutputMsg {
+            channel_id: CHANNEL_ID,
+            serial_id: 1,
+            sats: 10_000,
+            script: p2wpkh_script(),
+        };
+        match non_initiator.handle_tx_add_output(&msg) {
+            Err(InteractiveTxError::WrongSerialIdParity { serial_id: 1 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rejects_messages_out_of_turn() {
+        let mut initiator = constructor(InteractiveTxRole::Initiator);
+        let msg = TxCompleteMsg { channel_id: CHANNEL_ID };
+        match initiator.handle_tx_complete(&msg) {
+            Err(InteractiveTxError::OutOfTurn) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn sending_a_change_resets_completion() {
+        let mut initiator = constructor(InteractiveTxRole::Initiator);
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+
+        let msg = unwrap!(initiator.complete());
+        unwrap!(non_initiator.handle_tx_complete(&msg));
+        let msg = unwrap!(non_initiator.add_input(prev_tx(p2wpkh_script(), 25_000).to_bytes().to_vec(), 0, 0xffff_fffd));
+        unwrap!(initiator.handle_tx_add_input(&msg));
+        let msg = unwrap!(initiator.add_input(prev_tx(p2wpkh_script(), 50_000).to_bytes().to_vec(), 0, 0xffff_fffd));
+        unwrap!(non_initiator.handle_tx_add_input(&msg));
+        let msg = unwrap!(non_initiator.complete());
+        unwrap!(initiator.handle_tx_complete(&msg));
+        let msg = unwrap!(initiator.add_output(60_000, funding_script()));
+        unwrap!(non_initiator.handle_tx_add_output(&msg));
+        let msg = unwrap!(non_initiator.complete());
+        unwrap!(initiator.handle_tx_complete(&msg));
+        assert!(!initiator.is_complete());
+        assert!(!non_initiator.is_complete());
+
+        let msg = unwrap!(initiator.complete());
+        unwrap!(non_initiator.handle_tx_complete(&msg));
+        assert!(initiator.is_complete());
+        assert!(non_initiator.is_complete());
+    }
+
+    #[test]
+    fn rejects_inputs_not_spending_a_segwit_output() {
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+        let mut msg = TxAddInputMsg {
+            channel_id: CHANNEL_ID,
+            serial_id: 0,
+            prev_tx: vec![1, 2, 3],
+            prev_tx_vout: 0,
+            sequence: 0xffff_fffd,
+        };
+        match non_initiator.handle_tx_add_input(&msg) {
+            Err(InteractiveTxError::InvalidPrevTx(..)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        msg.prev_tx = prev_tx(p2wpkh_script(), 50_000).to_bytes().to_vec();
+        msg.prev_tx_vout = 1;
+        match non_initiator.handle_tx_add_input(&msg) {
+            Err(InteractiveTxError::PrevTxOutputMissing { prev_tx_vout: 1 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // A p2pkh output can't be spent without changing the txid.
+        let mut p2pkh_script = vec![0x76, 0xa9, 0x14];
+        p2pkh_script.extend(&[0x42; 20][..]);
+        p2pkh_script.extend(&[0x88, 0xac][..]);
+        msg.prev_tx = prev_tx(p2pkh_script, 50_000).to_bytes().to_vec();
+        msg.prev_tx_vout = 0;
+        match non_initiator.handle_tx_add_input(&msg) {
+            Err(InteractiveTxError::PrevTxOutputNotSegwit { prev_tx_vout: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rejects_the_same_outpoint_twice() {
+        let mut initiator = constructor(InteractiveTxRole::Initiator);
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+        let spent = prev_tx(p2wpkh_script(), 50_000);
+        let msg = unwrap!(initiator.add_input(spent.to_bytes().to_vec(), 0, 0xffff_fffd));
+        unwrap!(non_initiator.handle_tx_add_input(&msg));
+
+        // With a witness the serialization differs, but the txid doesn't.
+        let mut with_witness = spent.clone();
+        with_witness.inputs[0].witness = vec![vec![1; 72], vec![2; 33]];
+        assert_eq!(with_witness.txid(), spent.txid());
+        let msg = TxAddInputMsg {
+            channel_id: CHANNEL_ID,
+            serial_id: 1,
+            prev_tx: with_witness.to_bytes().to_vec(),
+            prev_tx_vout: 0,
+            sequence: 0xffff_fffd,
+        };
+        match initiator.handle_tx_add_input(&msg) {
+            Err(InteractiveTxError::DuplicateInput) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn checks_the_funding_output_and_contributions() {
+        // The initiator pays 1217 sat of fees: 487 weight at 2500 sat/kw.
+        let check = |initiator_input_sats, funding_output_sats| {
+            let mut initiator = constructor(InteractiveTxRole::Initiator);
+            let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+            negotiate(&mut initiator, &mut non_initiator, (initiator_input_sats, 25_000), funding_output_sats).err()
+        };
+        match check(50_000, 59_999) {
+            Some(InteractiveTxError::MissingFundingOutput { funding_satoshis: 60_000 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match check(39_999, 60_000) {
+            Some(InteractiveTxError::InsufficientInputs { contributor: Contributor::Them }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match check(41_216, 60_000) {
+            Some(InteractiveTxError::InsufficientFee { contributor: Contributor::Them }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(check(41_217, 60_000).is_none());
+
+        // A second funding output isn't allowed either, even for the right amount.
+        let mut initiator = constructor(InteractiveTxRole::Initiator);
+        let mut non_initiator = constructor(InteractiveTxRole::NonInitiator);
+        let msg = unwrap!(initiator.add_output(60_000, funding_script()));
+        unwrap!(non_initiator.handle_tx_add_output(&msg));
+        let msg = unwrap!(non_initiator.add_output(60_000, funding_script()));
+        unwrap!(initiator.handle_tx_add_output(&msg));
+        let msg = unwrap!(initiator.complete());
+        unwrap!(non_initiator.handle_tx_complete(&msg));
+        match non_initiator.complete() {
+            Err(InteractiveTxError::MissingFundingOutput { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn tx_signatures_complete_the_transaction() {
+        let (mut initiator, mut non_initiator) = negotiated();
+        // The non-initiator put in less, so it signs first.
+        assert!(non_initiator.sends_signatures_first());
+        assert!(!initiator.sends_signatures_first());
+        match initiator.tx_signatures(vec![vec![vec![3; 71], vec![4; 33]]]) {
+            Err(InteractiveTxError::SignaturesOutOfOrder) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let msg = unwrap!(non_initiator.tx_signatures(vec![vec![vec![1; 72], vec![2; 33]]]));
+        assert_eq!(msg.txid, unwrap!(non_initiator.unsigned_tx()).txid());
+        match initiator.handle_tx_signatures(&TxSignaturesMsg { txid: [0; 32], ..msg.clone() }) {
+            Err(InteractiveTxError::WrongTxid) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match initiator.handle_tx_signatures(&TxSignaturesMsg { witnesses: Vec::new(), ..msg.clone() }) {
+            Err(InteractiveTxError::WrongWitnessCount { expected: 1, received: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // An empty witness stack can't spend a segwit output.
+        match initiator.handle_tx_signatures(&TxSignaturesMsg { witnesses: vec![vec![0]], ..msg.clone() }) {
+            Err(InteractiveTxError::InvalidWitness { index: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        unwrap!(initiator.handle_tx_signatures(&msg));
+        unwrap!(initiator.handle_tx_signatures(&msg));
+        assert!(initiator.signed_tx().is_none());
+
+        let msg = unwrap!(initiator.tx_signatures(vec![vec![vec![3; 71], vec![4; 33]]]));
+        unwrap!(non_initiator.handle_tx_signatures(&msg));
+        let tx = unwrap!(initiator.signed_tx());
+        assert_eq!(Some(tx.clone()), non_initiator.signed_tx());
+        assert_eq!(tx.txid(), unwrap!(initiator.unsigned_tx()).txid());
+        assert_eq!(tx.inputs[0].witness, vec![vec![3; 71], vec![4; 33]]);
+        assert_eq!(tx.inputs[1].witness, vec![vec![1; 72], vec![2; 33]]);
+        match initiator.tx_signatures(vec![vec![vec![3; 71], vec![4; 33]]]) {
+            Err(InteractiveTxError::AlreadySigned) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rbf_negotiates_a_replacement() {
+        let (mut initiator, mut non_initiator) = negotiated();
+        match initiator.init_rbf(600_001, 2_605, 40_000) {
+            Err(InteractiveTxError::NotSigned) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        exchange_signatures(&mut initiator, &mut non_initiator);
+        match initiator.init_rbf(600_001, 2_604, 40_000) {
+            Err(InteractiveTxError::RbfFeerateTooLow { feerate_per_kw: 2_604, min_feerate_per_kw: 2_605 }) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        match non_initiator.init_rbf(600_001, 2_605, 20_000) {
+            Err(InteractiveTxError::UnexpectedRbf) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        let init = unwrap!(initiator.init_rbf(600_001, 2_605, 40_000));
+        let ack = unwrap!(non_initiator.handle_tx_init_rbf(&init, 20_000));
+        unwrap!(initiator.handle_tx_ack_rbf(&ack));
+        match initiator.handle_tx_ack_rbf(&ack) {
+            Err(InteractiveTxError::UnexpectedRbf) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(initiator.unsigned_tx().is_none());
+        assert_eq!(non_initiator.params().feerate_per_kw, 2_605);
+        assert_eq!(non_initiator.params().locktime, 600_001);
+
+        // The replacement has to spend one of the inputs of the first transaction.
+        let mut replacement = (initiator, non_initiator);
+        unwrap!(negotiate(&mut replacement.0, &mut replacement.1, (50_000, 25_100), 60_000));
+        assert_eq!(unwrap!(replacement.0.unsigned_tx()).lock_time, 600_001);
+
+        let (mut initiator, mut non_initiator) = negotiated();
+        exchange_signatures(&mut initiator, &mut non_initiator);
+        let init = unwrap!(initiator.init_rbf(600_001, 2_605, 40_000));
+        let ack = unwrap!(non_initiator.handle_tx_init_rbf(&init, 20_000));
+        unwrap!(initiator.handle_tx_ack_rbf(&ack));
+        match negotiate(&mut initiator, &mut non_initiator, (51_000, 25_100), 60_000) {
+            Err(InteractiveTxError::DoesNotReplacePrevious) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,656 @@
+use super::*;
+
+pub const DEFAULT_INVOICE_EXPIRY_SECS: u64 = 3600;
+pub const DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA: u64 = 18;
+
+const TIMESTAMP_GROUPS: usize = 7;
+const SIGNATURE_GROUPS: usize = 104;
+const ROUTE_HINT_HOP_LEN: usize = 51;
+
+// Tagged field types, named by the bech32 character they're written as.
+const FIELD_PAYMENT_HASH: u8 = 1; // p
+const FIELD_ROUTE_HINT: u8 = 3; // r
+const FIELD_FEATURES: u8 = 5; // 9
+const FIELD_EXPIRY: u8 = 6; // x
+const FIELD_FALLBACK_ADDRESS: u8 = 9; // f
+const FIELD_DESCRIPTION: u8 = 13; // d
+const FIELD_PAYMENT_SECRET: u8 = 16; // s
+const FIELD_PAYEE: u8 = 19; // n
+const FIELD_DESCRIPTION_HASH: u8 = 23; // h
+const FIELD_MIN_FINAL_CLTV_EXPIRY: u8 = 24; // c
+const FIELD_METADATA: u8 = 27; // m
+
+const MSAT_PER_BTC: u64 = 100_000_000_000;
+
+/// The even bits of the invoice features we understand: `var_onion_optin`, `payment_secret`,
+/// `basic_mpp` and `option_payment_metadata`. An invoice requiring any other feature can't be
+/// paid.
+const KNOWN_REQUIRED_FEATURE_BITS: [usize; 4] = [8, 14, 16, 48];
+
+#[derive(Debug, Fail)]
+pub enum InvoiceError {
+    #[fail(display = "invalid bech32 string")]
+    InvalidBech32,
+    #[fail(display = "not a lightning invoice")]
+    NotAnInvoice,
+    #[fail(display = "unknown currency in invoice prefix {}", hrp)]
+    UnknownCurrency {
+        hrp: String,
+    },
+    #[fail(display = "invalid amount in invoice prefix {}", hrp)]
+    InvalidAmount {
+        hrp: String,
+    },
+    #[fail(display = "invoice is too short")]
+    TooShort,
+    #[fail(display = "malformed '{}' field in invoice", tag)]
+    MalformedField {
+        tag: char,
+    },
+    #[fail(display = "invoice has no payment hash")]
+    MissingPaymentHash,
+    #[fail(display = "invoice has no description or description hash")]
+    MissingDescription,
+    #[fail(display = "invalid invoice signature")]
+    InvalidSignature,
+    #[fail(display = "invoice payee does not match the signing key")]
+    PayeeMismatch,
+    #[fail(display = "invoice requires unknown feature bit {}", bit)]
+    UnknownRequiredFeature {
+        bit: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Currency {
+    Bitcoin,
+    BitcoinTestnet,
+    BitcoinRegtest,
+    BitcoinSignet,
+}
+
+impl Currency {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Currency::Bitcoin => "bc",
+            Currency::BitcoinTestnet => "tb",
+            Currency::BitcoinRegtest => "bcrt",
+            Currency::BitcoinSignet => "tbs",
+        }
+    }
+
+    /// Splits the currency prefix off the front of the rest of an invoice's human-readable part.
+    fn parse_prefix(s: &str) -> Option<(Currency, &str)> {
+        // Longer prefixes first, since "bc" is a prefix of "bcrt" and "tb" of "tbs".
+        let currencies = [Currency::BitcoinRegtest, Currency::BitcoinSignet, Currency::Bitcoin, Currency::BitcoinTestnet];
+        for currency in &currencies {
+            if s.starts_with(currency.prefix()) {
+                return Some((*currency, &s[currency.prefix().len()..]));
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvoiceDescription {
+    Direct(String),
+    /// The SHA256 of a description which is too long to put in the invoice.
+    Hash([u8; 32]),
+}
+
+/// An on-chain address to pay to if the payment can't be made over lightning. Version 17 is a
+/// P2PKH public key hash, 18 a P2SH script hash, and 0 to 16 are segwit witness programs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackAddress {
+    pub version: u8,
+    pub program: Vec<u8>,
+}
+
+/// A BOLT 11 payment request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub currency: Currency,
+    /// `None` if the payer may choose the amount.
+    pub amount_msat: Option<u64>,
+    pub timestamp: u64,
+    pub payment_hash: [u8; 32],
+    pub payment_secret: Option<[u8; 32]>,
+    pub description: InvoiceDescription,
+    /// The payee, written out explicitly. Usually left out since it can be recovered from the
+    /// signature.
+    pub payee_node_id: Option<secp256k1::PublicKey>,
+    pub expiry_secs: Option<u64>,
+    pub min_final_cltv_expiry_delta: Option<u64>,
+    pub fallback_addresses: Vec<FallbackAddress>,
+    pub route_hints: Vec<Vec<RouteHintHop>>,
+    /// Big-endian feature bits, like in `init` messages. Empty if the invoice has no features.
+    pub features: Vec<u8>,
+    pub metadata: Option<Vec<u8>>,
+}
+
+/// Feature bits are packed into 5 bit groups from the end, so bit 0 is the lowest bit of the
+/// last group.
+fn features_to_groups(features: &[u8]) -> Vec<u8> {
+    let num_bits = features.len() * 8;
+    let set_bits: Vec<usize> = {
+        (0..num_bits)
+        .filter(|bit| features[features.len() - 1 - bit / 8] & (1 << (bit % 8)) != 0)
+        .collect()
+    };
+    let num_groups = set_bits.last().map(|bit| bit / 5 + 1).unwrap_or(0);
+    let mut groups = vec![0; num_groups];
+    for bit in set_bits {
+        groups[num_groups - 1 - bit / 5] |= 1 << (bit % 5);
+    }
+    groups
+}
+
+fn groups_to_features(groups: &[u8]) -> Vec<u8> {
+    let set_bits: Vec<usize> = {
+        (0..(groups.len() * 5))
+        .filter(|bit| groups[groups.len() - 1 - bit / 5] & (1 << (bit % 5)) != 0)
+        .collect()
+    };
+    let num_bytes = set_bits.last().map(|bit| bit / 8 + 1).unwrap_or(0);
+    let mut features = vec![0; num_bytes];
+    for bit in set_bits {
+        features[num_bytes - 1 - bit / 8] |= 1 << (bit % 8);
+    }
+    features
+}
+
+/// The first even feature bit which is set but not one we know.
+fn unknown_required_feature(features: &[u8]) -> Option<usize> {
+    (0..(features.len() * 8))
+    .step_by(2)
+    .filter(|bit| features[features.len() - 1 - bit / 8] & (1 << (bit % 8)) != 0)
+    .find(|bit| !KNOWN_REQUIRED_FEATURE_BITS.contains(bit))
+}
+
+fn parse_amount(hrp: &str, amount: &str) -> Result<Option<u64>, InvoiceError> {
+    if amount.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || InvoiceError::InvalidAmount { hrp: hrp.to_owned() };
+    let (digits, multiplier) = match amount.as_bytes()[amount.len() - 1] {
+        b'0'..=b'9' => (amount, None),
+        multiplier => (&amount[..(amount.len() - 1)], Some(multiplier)),
+    };
+    if digits.is_empty() || digits.starts_with('0') || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let value = u64::from_str(digits).map_err(|_| invalid())?;
+    let amount_msat = match multiplier {
+        None => value.checked_mul(MSAT_PER_BTC),
+        Some(b'm') => value.checked_mul(MSAT_PER_BTC / 1_000),
+        Some(b'u') => value.checked_mul(MSAT_PER_BTC / 1_000_000),
+        Some(b'n') => value.checked_mul(MSAT_PER_BTC / 1_000_000_000),
+        // A pico-bitcoin is a tenth of a msat.
+        Some(b'p') if value % 10 == 0 => Some(value / 10),
+        _ => None,
+    };
+    amount_msat.map(Some).ok_or_else(invalid)
+}
+
+fn format_amount(amount_msat: u64) -> String {
+    let units = [
+        (MSAT_PER_BTC, ""),
+        (MSAT_PER_BTC / 1_000, "m"),
+        (MSAT_PER_BTC / 1_000_000, "u"),
+        (MSAT_PER_BTC / 1_000_000_000, "n"),
+    ];
+    for (msat_per_unit, multiplier) in &units {
+        if amount_msat % msat_per_unit == 0 {
+            return format!("{}{}", amount_msat / msat_per_unit, multiplier);
+        }
+    }
+    format!("{}p", amount_msat * 10)
+}
+
+fn push_field(groups: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    groups.push(tag);
+    groups.extend_from_slice(&u64_to_groups(data.len() as u64, 2));
+    groups.extend_from_slice(data);
+}
+
+fn field_tag_char(tag: u8) -> char {
+    BECH32_CHARSET[tag as usize] as char
+}
+
+fn signature_hash(hrp: &str, groups: &[u8]) -> secp256k1::Message {
+    let hash = sha256(&[hrp.as_bytes(), &groups_to_bytes(groups, true)]);
+    unwrap!(secp256k1::Message::from_slice(&hash[..]))
+}
+
+impl Invoice {
+    pub fn expiry_secs(&self) -> u64 {
+        self.expiry_secs.unwrap_or(DEFAULT_INVOICE_EXPIRY_SECS)
+    }
+
+    pub fn min_final_cltv_expiry_delta(&self) -> u64 {
+        self.min_final_cltv_expiry_delta.unwrap_or(DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.timestamp.saturating_add(self.expiry_secs())
+    }
+
+    fn hrp(&self) -> String {
+        let amount = self.amount_msat.map(format_amount).unwrap_or_default();
+        format!("ln{}{}", self.currency.prefix(), amount)
+    }
+
+    fn data_groups(&self) -> Vec<u8> {
+        let mut groups = u64_to_groups(self.timestamp, TIMESTAMP_GROUPS);
+        push_field(&mut groups, FIELD_PAYMENT_HASH, &bytes_to_groups(&self.payment_hash[..]));
+        match self.description {
+            InvoiceDescription::Direct(ref description) => {
+                push_field(&mut groups, FIELD_DESCRIPTION, &bytes_to_groups(description.as_bytes()));
+            },
+            InvoiceDescription::Hash(ref hash) => {
+                push_field(&mut groups, FIELD_DESCRIPTION_HASH, &bytes_to_groups(&hash[..]));
+            },
+        }
+        if let Some(ref payment_secret) = self.payment_secret {
+            push_field(&mut groups, FIELD_PAYMENT_SECRET, &bytes_to_groups(&payment_secret[..]));
+        }
+        if let Some(ref payee_node_id) = self.payee_node_id {
+            push_field(&mut groups, FIELD_PAYEE, &bytes_to_groups(&payee_node_id.serialize()[..]));
+        }
+        if let Some(expiry_secs) = self.expiry_secs {
+            push_field(&mut groups, FIELD_EXPIRY, &u64_to_groups(expiry_secs, 0));
+        }
+        if let Some(min_final_cltv_expiry_delta) = self.min_final_cltv_expiry_delta {
+            push_field(&mut groups, FIELD_MIN_FINAL_CLTV_EXPIRY, &u64_to_groups(min_final_cltv_expiry_delta, 0));
+        }
+        for fallback_address in &self.fallback_addresses {
+            let mut data = vec![fallback_address.version];
+            data.extend_from_slice(&bytes_to_groups(&fallback_address.program));
+            push_field(&mut groups, FIELD_FALLBACK_ADDRESS, &data);
+        }
+        for route_hint in &self.route_hints {
+            let mut cursor = WriteCursor::new();
+            for hop in route_hint {
+                cursor.write_slice(&hop.node_id.0[..]);
+                cursor.write_short_channel_id(hop.short_channel_id);
+                cursor.write_u32(hop.fee_base_msat);
+                cursor.write_u32(hop.fee_proportional_millionths);
+                cursor.write_u16(hop.cltv_expiry_delta);
+            }
+            push_field(&mut groups, FIELD_ROUTE_HINT, &bytes_to_groups(&cursor.into_bytes()));
+        }
+        if !self.features.is_empty() {
+            push_field(&mut groups, FIELD_FEATURES, &features_to_groups(&self.features));
+        }
+        if let Some(ref metadata) = self.metadata {
+            push_field(&mut groups, FIELD_METADATA, &bytes_to_groups(metadata));
+        }
+        groups
+    }
+
+    /// Signs the invoice with the payee's node key and encodes it.
+    pub fn encode(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        node_secret: &secp256k1::SecretKey,
+    ) -> Result<String, InvoiceError> {
+        if let Some(ref payee_node_id) = self.payee_node_id {
+            if *payee_node_id != secp256k1::PublicKey::from_secret_key(secp, node_secret) {
+                return Err(InvoiceError::PayeeMismatch);
+            }
+        }
+        let hrp = self.hrp();
+        let mut groups = self.data_groups();
+        let signature = secp.sign_recoverable(&signature_hash(&hrp, &groups), node_secret);
+        let (recovery_id, compact) = signature.serialize_compact(secp);
+        let mut signature_bytes = compact.to_vec();
+        signature_bytes.push(recovery_id.to_i32() as u8);
+        groups.extend_from_slice(&bytes_to_groups(&signature_bytes));
+        Ok(bech32_encode(&hrp, &groups, BECH32_CONST))
+    }
+
+    /// Decodes an invoice and checks its signature. Returns the invoice along with its payee.
+    pub fn decode(
+        secp: &Secp256k1<secp256k1::All>,
+        s: &str,
+    ) -> Result<(Invoice, secp256k1::PublicKey), InvoiceError> {
+        let s = if s.starts_with("lightning:") || s.starts_with("LIGHTNING:") { &s[10..] } else { s };
+        let (hrp, groups) = match bech32_decode(s) {
+            Some((hrp, groups, BECH32_CONST)) => (hrp, groups),
+            _ => return Err(InvoiceError::InvalidBech32),
+        };
+        if !hrp.starts_with("ln") {
+            return Err(InvoiceError::NotAnInvoice);
+        }
+        let (currency, amount) = match Currency::parse_prefix(&hrp[2..]) {
+            Some(parsed) => parsed,
+            None => return Err(InvoiceError::UnknownCurrency { hrp }),
+        };
+        let amount_msat = parse_amount(&hrp, amount)?;
+        if groups.len() < TIMESTAMP_GROUPS + SIGNATURE_GROUPS {
+            return Err(InvoiceError::TooShort);
+        }
+        let (data, signature_groups) = groups.split_at(groups.len() - SIGNATURE_GROUPS);
+        let timestamp = unwrap!(groups_to_u64(&data[..TIMESTAMP_GROUPS]));
+
+        let mut payment_hash = None;
+        let mut payment_secret = None;
+        let mut description = None;
+        let mut payee_node_id = None;
+        let mut expiry_secs = None;
+        let mut min_final_cltv_expiry_delta = None;
+        let mut fallback_addresses = Vec::new();
+        let mut route_hints = Vec::new();
+        let mut features = Vec::new();
+        let mut metadata = None;
+
+        let mut fields = &data[TIMESTAMP_GROUPS..];
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(InvoiceError::TooShort);
+            }
+            let tag = fields[0];
+            let len = unwrap!(groups_to_u64(&fields[1..3])) as usize;
+            if fields.len() < 3 + len {
+                return Err(InvoiceError::TooShort);
+            }
+            let value = &fields[3..(3 + len)];
+            fields = &fields[(3 + len)..];
+            let malformed = || InvoiceError::MalformedField { tag: field_tag_char(tag) };
+
+            // Fields of the wrong length are skipped, as required by the spec, rather than
+            // rejected.
+            match tag {
+                FIELD_PAYMENT_HASH if len == 52 && payment_hash.is_none() => {
+                    payment_hash = Some(slice_to_array!(groups_to_bytes(value, false), 32));
+                },
+                FIELD_PAYMENT_SECRET if len == 52 && payment_secret.is_none() => {
+                    payment_secret = Some(slice_to_array!(groups_to_bytes(value, false), 32));
+                },
+                FIELD_DESCRIPTION if description.is_none() => {
+                    let bytes = groups_to_bytes(value, false);
+                    let text = String::from_utf8(bytes).map_err(|_| malformed())?;
+                    description = Some(InvoiceDescription::Direct(text));
+                },
+                FIELD_DESCRIPTION_HASH if len == 52 && description.is_none() => {
+                    description = Some(InvoiceDescription::Hash(slice_to_array!(groups_to_bytes(value, false), 32)));
+                },
+                FIELD_PAYEE if len == 53 && payee_node_id.is_none() => {
+                    let bytes = groups_to_bytes(value, false);
+                    let secp = Secp256k1::without_caps();
+                    let node_id = secp256k1::PublicKey::from_slice(&secp, &bytes).map_err(|_| malformed())?;
+                    payee_node_id = Some(node_id);
+                },
+                FIELD_EXPIRY if expiry_secs.is_none() => {
+                    expiry_secs = Some(groups_to_u64(value).ok_or_else(malformed)?);
+                },
+                FIELD_MIN_FINAL_CLTV_EXPIRY if min_final_cltv_expiry_delta.is_none() => {
+                    min_final_cltv_expiry_delta = Some(groups_to_u64(value).ok_or_else(malformed)?);
+                },
+                FIELD_FALLBACK_ADDRESS if !value.is_empty() => {
+                    fallback_addresses.push(FallbackAddress {
+                        version: value[0],
+                        program: groups_to_bytes(&value[1..], false),
+                    });
+                },
+                FIELD_ROUTE_HINT => {
+                    let bytes = groups_to_bytes(value, false);
+                    if bytes.is_empty() || bytes.len() % ROUTE_HINT_HOP_LEN != 0 {
+                        return Err(malformed());
+                    }
+                    let mut cursor = ReadCursor::new(&bytes);
+                    let mut route_hint = Vec::new();
+                    while !cursor.is_empty() {
+                        route_hint.push(RouteHintHop {
+                            node_id: NodeId(slice_to_array!(unwrap!(cursor.read_slice(33)), 33)),
+                            short_channel_id: unwrap!(cursor.read_short_channel_id()),
+                            fee_base_msat: unwrap!(cursor.read_u32()),
+                            fee_proportional_millionths: unwrap!(cursor.read_u32()),
+                            cltv_expiry_delta: unwrap!(cursor.read_u16()),
+                        });
+                    }
+                    route_hints.push(route_hint);
+                },
+                FIELD_FEATURES => {
+                    features = groups_to_features(value);
+                    if let Some(bit) = unknown_required_feature(&features) {
+                        return Err(InvoiceError::UnknownRequiredFeature { bit });
+                    }
+                },
+                FIELD_METADATA if metadata.is_none() => metadata = Some(groups_to_bytes(value, false)),
+                _ => (),
+            }
+        }
+
+        let signature_bytes = groups_to_bytes(signature_groups, false);
+        let recovery_id = {
+            secp256k1::RecoveryId::from_i32(signature_bytes[64] as i32)
+            .map_err(|_| InvoiceError::InvalidSignature)?
+        };
+        let signature = {
+            secp256k1::RecoverableSignature::from_compact(secp, &signature_bytes[..64], recovery_id)
+            .map_err(|_| InvoiceError::InvalidSignature)?
+        };
+        let hash = signature_hash(&hrp, data);
+        let payee = match payee_node_id {
+            Some(payee_node_id) => {
+                secp.verify(&hash, &signature.to_standard(secp), &payee_node_id)
+                .map_err(|_| InvoiceError::InvalidSignature)?;
+                payee_node_id
+            },
+            None => secp.recover(&hash, &signature).map_err(|_| InvoiceError::InvalidSignature)?,
+        };
+
+        let invoice = Invoice {
+            currency,
+            amount_msat,
+            timestamp,
+            payment_hash: payment_hash.ok_or(InvoiceError::MissingPaymentHash)?,
+            payment_secret,
+            description: description.ok_or(InvoiceError::MissingDescription)?,
+            payee_node_id,
+            expiry_secs,
+            min_final_cltv_expiry_delta,
+            fallback_addresses,
+            route_hints,
+            features,
+            metadata,
+        };
+        Ok((invoice, payee))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    // The payee key and payment hash from the BOLT 11 test vectors.
+    fn payee_secret() -> [u8; 32] {
+        hex!("e126f68f7eafcc8b74f54d269fe206be715000f94dac067d1c04a8ca3b2db734")
+    }
+
+    fn payee() -> [u8; 33] {
+        hex!("03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad")
+    }
+
+    const TIMESTAMP: u64 = 1_496_314_658;
+
+    fn invoice(currency: Currency, amount_msat: Option<u64>, description: InvoiceDescription) -> Invoice {
+        Invoice {
+            currency,
+            amount_msat,
+            timestamp: TIMESTAMP,
+            payment_hash: hex!("0001020304050607080900010203040506070809000102030405060708090102"),
+            payment_secret: None,
+            description,
+            payee_node_id: None,
+            expiry_secs: None,
+            min_final_cltv_expiry_delta: None,
+            fallback_addresses: Vec::new(),
+            route_hints: Vec::new(),
+            features: Vec::new(),
+            metadata: None,
+        }
+    }
+
+    fn cake_hash() -> InvoiceDescription {
+        InvoiceDescription::Hash(hex!("3925b6f67e2c340036ed12093dd44e0368df1b6ea26c53dbe4811f58fd5db8c1"))
+    }
+
+    #[test]
+    fn bolt11_vectors() {
+        let secp = Secp256k1::new();
+        let node_secret = unwrap!(secp256k1::SecretKey::from_slice(&secp, &payee_secret()));
+
+        let donation = invoice(
+            Currency::Bitcoin,
+            None,
+            InvoiceDescription::Direct("Please consider supporting this project".to_owned()),
+        );
+        let mut coffee = invoice(
+            Currency::Bitcoin,
+            Some(250_000_000),
+            InvoiceDescription::Direct("1 cup coffee".to_owned()),
+        );
+        coffee.expiry_secs = Some(60);
+        let mut routed = invoice(Currency::Bitcoin, Some(2_000_000_000), cake_hash());
+        routed.fallback_addresses.push(FallbackAddress {
+            version: 17,
+            program: hex!("04b61f7dc1ea0dc99424464cc4064dc564d91e89").to_vec(),
+        });
+        routed.route_hints.push(vec![
+            RouteHintHop {
+                node_id: NodeId(hex!("029e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255")),
+                short_channel_id: ShortChannelId::from_u64(0x0102_0304_0506_0708),
+                fee_base_msat: 1,
+                fee_proportional_millionths: 20,
+                cltv_expiry_delta: 3,
+            },
+            RouteHintHop {
+                node_id: NodeId(hex!("039e03a901b85534ff1e92c43c74431f7ce72046060fcf7a95c37e148f78c77255")),
+                short_channel_id: ShortChannelId::from_u64(0x0304_0506_0708_090a),
+                fee_base_msat: 2,
+                fee_proportional_millionths: 30,
+                cltv_expiry_delta: 4,
+            },
+        ]);
+        let mut featured = invoice(
+            Currency::Bitcoin,
+            Some(2_500_000_000),
+            InvoiceDescription::Direct("coffee beans".to_owned()),
+        );
+        featured.payment_secret = Some([0x11; 32]);
+        // Feature bits 8, 14 and 99.
+        featured.features = hex!("08000000000000000000004100").to_vec();
+
+        let vectors = [
+            (donation, "lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq8rkx3yf5tcsyz3d73gafnh3cax9rn449d9p5uxz9ezhhypd0elx87sjle52x86fux2ypatgddc6k63n7erqz25le42c4u4ecky03ylcqca784w"),
+            (coffee, "lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp"),
+            (invoice(Currency::Bitcoin, Some(2_000_000_000), cake_hash()), "lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqscc6gd6ql3jrc5yzme8v4ntcewwz5cnw92tz0pc8qcuufvq7khhr8wpald05e92xw006sq94mg8v2ndf4sefvf9sygkshp5zfem29trqq2yxxz7"),
+            (routed, "lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqsfpp3qjmp7lwpagxun9pygexvgpjdc4jdj85fr9yq20q82gphp2nflc7jtzrcazrra7wwgzxqc8u7754cdlpfrmccae92qgzqvzq2ps8pqqqqqqpqqqqq9qqqvpeuqafqxu92d8lr6fvg0r5gv0heeeqgcrqlnm6jhphu9y00rrhy4grqszsvpcgpy9qqqqqqgqqqqq7qqzqj9n4evl6mr5aj9f58zp6fyjzup6ywn3x6sk8akg5v4tgn2q8g4fhx05wf6juaxu9760yp46454gpg5mtzgerlzezqcqvjnhjh8z3g2qqdhhwkj"),
+            (featured, "lnbc25m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5vdhkven9v5sxyetpdeessp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9q5sqqqqqqqqqqqqqqqqsgq2a25dxl5hrntdtn6zvydt7d66hyzsyhqs4wdynavys42xgl6sgx9c4g7me86a27t07mdtfry458rtjr0v92cnmswpsjscgt2vcse3sgpz3uapa"),
+        ];
+        for (expected, encoded) in vectors.iter() {
+            let (decoded, payee_key) = unwrap!(Invoice::decode(&secp, encoded));
+            assert_eq!(decoded, *expected);
+            assert_eq!(&payee_key.serialize()[..], &payee()[..]);
+            assert_eq!(unwrap!(expected.encode(&secp, &node_secret)), *encoded);
+        }
+
+        // Fields can come in any order, and are signed in the order they came in.
+        let testnet = "lntb20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfpp3x9et2e20v6pu37c5d9vax37wxq72un98kmzzhznpurw9sgl2v0nklu2g4d0keph5t7tj9tcqd8rexnd07ux4uv2cjvcqwaxgj7v4uwn5wmypjd5n69z2xm3xgksg28nwht7f6zspwp3f9t";
+        let (decoded, payee_key) = unwrap!(Invoice::decode(&secp, testnet));
+        assert_eq!(decoded.currency, Currency::BitcoinTestnet);
+        assert_eq!(decoded.fallback_addresses[0].program, hex!("3172b5654f6683c8fb146959d347ce303cae4ca7").to_vec());
+        assert_eq!(&payee_key.serialize()[..], &payee()[..]);
+
+        let fallbacks = [
+            // P2SH
+            (18, &hex!("8f55563b9a19f321c211e9b9f38cdf686ea07845")[..], "lnbc20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfppj3a24vwu6r8ejrss3axul8rxldph2q7z9kmrgvr7xlaqm47apw3d48zm203kzcq357a4ls9al2ea73r8jcceyjtya6fu5wzzpe50zrge6ulk4nvjcpxlekvmxl6qcs9j3tz0469gq5g658y"),
+            // P2WPKH
+            (0, &hex!("751e76e8199196d454941c45d1b3a323f1433bd6")[..], "lnbc20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfppqw508d6qejxtdg4y5r3zarvary0c5xw7kepvrhrm9s57hejg0p662ur5j5cr03890fa7k2pypgttmh4897d3raaq85a293e9jpuqwl0rnfuwzam7yr8e690nd2ypcq9hlkdwdvycqa0qza8"),
+            // P2WSH
+            (0, &hex!("1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262")[..], "lnbc20m1pvjluezhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfp4qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q28j0v3rwgy9pvjnd48ee2pl8xrpxysd5g44td63g6xcjcu003j3qe8878hluqlvl3km8rm92f5stamd3jw763n3hck0ct7p8wwj463cql26ava"),
+        ];
+        for (version, program, encoded) in fallbacks.iter() {
+            let mut expected = invoice(Currency::Bitcoin, Some(2_000_000_000), cake_hash());
+            expected.fallback_addresses.push(FallbackAddress { version: *version, program: program.to_vec() });
+            let (decoded, payee_key) = unwrap!(Invoice::decode(&secp, encoded));
+            assert_eq!(decoded, expected);
+            assert_eq!(&payee_key.serialize()[..], &payee()[..]);
+        }
+    }
+
+    #[test]
+    fn bolt11_invalid_vectors() {
+        let secp = Secp256k1::new();
+        let decode = |encoded: &str| Invoice::decode(&secp, encoded).map(|_| ());
+
+        // The bech32 checksum is invalid.
+        match decode("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpuyk0sg5g70me25alkluzd2x62aysf2pyy8edtjeevuv4p2d5p76r4zkmneet7uvyakky2zr4cusd45tftc9c5fh0nnqpnl2jfll544esqchsrnt") {
+            Err(InvoiceError::InvalidBech32) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // No separator.
+        match decode("pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpuyk0sg5g70me25alkluzd2x62aysf2pyy8edtjeevuv4p2d5p76r4zkmneet7uvyakky2zr4cusd45tftc9c5fh0nnqpnl2jfll544esqchsrny") {
+            Err(InvoiceError::InvalidBech32) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Mixed case.
+        match decode("LNBC2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpuyk0sg5g70me25alkluzd2x62aysf2pyy8edtjeevuv4p2d5p76r4zkmneet7uvyakky2zr4cusd45tftc9c5fh0nnqpnl2jfll544esqchsrny") {
+            Err(InvoiceError::InvalidBech32) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // The signature's recovery id is out of range.
+        match decode("lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaxtrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspk28uwq") {
+            Err(InvoiceError::InvalidSignature) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Too short to hold a signature.
+        match decode("lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6na6hlh") {
+            Err(InvoiceError::TooShort) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Unknown multiplier.
+        match decode("lnbc2500x1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpujr6jxr9gq9pv6g46y7d20jfkegkg4gljz2ea2a3m9lmvvr95tq2s0kvu70u3axgelz3kyvtp2ywwt0y8hkx2869zq5dll9nelr83zzqqpgl2zg") {
+            Err(InvoiceError::InvalidAmount { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // An amount which isn't a whole number of msat.
+        match decode("lnbc2500000001p1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu7hqtk93pkf7sw55rdv4k9z2vj050rxdr6za9ekfs3nlt5lr89jqpdmxsmlj9urqumg0h9wzpqecw7th56tdms40p2ny9q4ddvjsedzcplva53s") {
+            Err(InvoiceError::InvalidAmount { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // Requires feature bit 100.
+        match decode("lnbc25m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5vdhkven9v5sxyetpdeessp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9q4psqqqqqqqqqqqqqqqqsgqtqyx5vggfcsll4wu246hz02kp85x4katwsk9639we5n5yngc3yhqkm35jnjw4len8vrnqnf5ejh0mzj9n3vz2px97evektfm2l6wqccp3y7372") {
+            Err(InvoiceError::UnknownRequiredFeature { bit: 100 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_invoices() {
+        let secp = Secp256k1::new();
+        let node_secret = unwrap!(secp256k1::SecretKey::from_slice(&secp, &payee_secret()));
+        let mut expiring = invoice(Currency::BitcoinRegtest, Some(1), InvoiceDescription::Direct(String::new()));
+        expiring.payment_secret = Some([7; 32]);
+        expiring.min_final_cltv_expiry_delta = Some(144);
+        expiring.metadata = Some(vec![1, 2, 3]);
+        expiring.payee_node_id = Some(secp256k1::PublicKey::from_secret_key(&secp, &node_secret));
+        let encoded = unwrap!(expiring.encode(&secp, &node_secret));
+        assert!(encoded.starts_with("lnbcrt10p1"));
+        let (decoded, _) = unwrap!(Invoice::decode(&secp, &encoded.to_uppercase()));
+        assert_eq!(decoded, expiring);
+        assert!(decoded.is_expired(TIMESTAMP + DEFAULT_INVOICE_EXPIRY_SECS));
+
+        let mut tampered = encoded.into_bytes();
+        let i = tampered.len() - 10;
+        tampered[i] = if tampered[i] == b'q' { b'p' } else { b'q' };
+        match Invoice::decode(&secp, unwrap!(str::from_utf8(&tampered))) {
+            Err(InvoiceError::InvalidBech32) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -31,23 +31,82 @@ mod handshake;
 mod msg;
 mod features;
 mod cursor;
+mod crypto;
+mod base32;
+mod ids;
+mod interactive_tx;
+mod closing;
+mod update_queue;
+mod reestablish;
+mod gossip_validation;
+mod gossip_sync;
+mod network_graph;
+mod gossip_store;
+mod graph_snapshot;
+mod routing;
+mod scoring;
+mod onion;
+mod onion_failure;
+mod invoice;
+mod blinded_path;
+mod offers;
+mod shachain;
+mod channel_keys;
+mod script;
+mod transaction;
+mod commitment;
+mod htlc_tx;
+mod channel;
+mod channel_store;
+#[cfg(test)]
+mod test_utils;
 
 pub use self::bootstrap::bootstrap;
 pub use self::peer::*;
 pub use self::endpoint::*;
 pub use self::features::*;
 pub use self::msg::*;
+pub use self::ids::*;
+pub use self::interactive_tx::*;
+pub use self::closing::*;
+pub use self::update_queue::*;
+pub use self::reestablish::*;
+pub use self::gossip_validation::*;
+pub use self::gossip_sync::*;
+pub use self::network_graph::*;
+pub use self::gossip_store::*;
+pub use self::graph_snapshot::*;
+pub use self::routing::*;
+pub use self::scoring::*;
+pub use self::onion::*;
+pub use self::onion_failure::*;
+pub use self::invoice::*;
+pub use self::blinded_path::*;
+pub use self::offers::*;
+pub use self::shachain::*;
+pub use self::channel_keys::*;
+pub use self::script::*;
+pub use self::transaction::*;
+pub use self::commitment::*;
+pub use self::htlc_tx::*;
+pub use self::channel::*;
+pub use self::channel_store::*;
 use self::cursor::*;
+use self::crypto::*;
+use self::base32::*;
+#[cfg(test)]
+use self::test_utils::*;
 
 use tokio::net::{TcpStream, TcpListener};
 use futures::{future, stream, Future, Stream, Async};
 use std::net::SocketAddr;
 use std::str::FromStr;
 use sha2::Sha256;
+use ripemd160::Ripemd160;
 use std::sync::{Arc, Mutex};
-use std::{io, iter, mem, str, ops, fmt};
+use std::{io, iter, mem, str, ops, fmt, cmp};
 use std::io::Cursor;
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet, BinaryHeap, VecDeque};
 use hkdf::Hkdf;
 use unwrap::unwrap;
 use failure::Fail;
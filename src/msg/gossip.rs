@@ -0,0 +1,527 @@
+use super::*;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelAnnouncementMsg {
+    pub node_signature_1: secp256k1::Signature,
+    pub node_signature_2: secp256k1::Signature,
+    pub bitcoin_signature_1: secp256k1::Signature,
+    pub bitcoin_signature_2: secp256k1::Signature,
+    pub features: Vec<u8>,
+    pub chain_hash: ChainHash,
+    pub short_channel_id: ShortChannelId,
+    pub node_id_1: secp256k1::PublicKey,
+    pub node_id_2: secp256k1::PublicKey,
+    pub bitcoin_key_1: secp256k1::PublicKey,
+    pub bitcoin_key_2: secp256k1::PublicKey,
+    /// Fields added by future versions of the protocol. These are covered by the signatures.
+    pub excess_data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeAnnouncementMsg {
+    pub signature: secp256k1::Signature,
+    pub features: Vec<u8>,
+    pub timestamp: u32,
+    pub node_id: secp256k1::PublicKey,
+    pub rgb_color: [u8; 3],
+    pub alias: [u8; 32],
+    pub addresses: Vec<NetAddress>,
+    /// Address descriptors following the first one of an unknown type. We can't parse these but
+    /// they're covered by the signature.
+    pub unknown_addresses: Vec<u8>,
+    pub excess_data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelUpdateMsg {
+    pub signature: secp256k1::Signature,
+    pub chain_hash: ChainHash,
+    pub short_channel_id: ShortChannelId,
+    pub timestamp: u32,
+    pub message_flags: u8,
+    pub channel_flags: u8,
+    pub cltv_expiry_delta: u16,
+    pub htlc_minimum_msat: u64,
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    /// Present iff bit 0 of `message_flags` is set. When writing, that bit is set from this
+    /// rather than taken from `message_flags`.
+    pub htlc_maximum_msat: Option<u64>,
+    pub excess_data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnouncementSignaturesMsg {
+    pub channel_id: ChannelId,
+    pub short_channel_id: ShortChannelId,
+    pub node_signature: secp256k1::Signature,
+    pub bitcoin_signature: secp256k1::Signature,
+}
+
+/// An address descriptor from a `node_announcement`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetAddress {
+    Ipv4(SocketAddrV4),
+    Ipv6(SocketAddrV6),
+    /// Deprecated, but still seen in old announcements.
+    TorV2 {
+        onion_addr: [u8; 10],
+        port: u16,
+    },
+    TorV3 {
+        ed25519_pubkey: [u8; 32],
+        checksum: u16,
+        version: u8,
+        port: u16,
+    },
+    Hostname {
+        hostname: Hostname,
+        port: u16,
+    },
+}
+
+/// A DNS hostname for a `NetAddress`. Its length has to fit in a byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hostname(String);
+
+#[derive(Debug, Fail)]
+pub enum HostnameError {
+    #[fail(display = "hostname is {} bytes long, over the maximum of 255", len)]
+    TooLong {
+        len: usize,
+    },
+    #[fail(display = "hostname isn't ascii")]
+    NotAscii,
+}
+
+impl Hostname {
+    pub fn new(hostname: String) -> Result<Hostname, HostnameError> {
+        if hostname.len() > 255 {
+            return Err(HostnameError::TooLong { len: hostname.len() });
+        }
+        if !hostname.is_ascii() {
+            return Err(HostnameError::NotAscii);
+        }
+        Ok(Hostname(hostname))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A variable-length field of a message we built which doesn't fit its u16 length prefix.
+#[derive(Debug, Fail)]
+#[fail(display = "{} is {} bytes long, over the maximum of 65535", field, len)]
+pub struct FieldTooLongError {
+    pub field: &'static str,
+    pub len: usize,
+}
+
+fn check_u16_len(field: &'static str, bytes: &[u8]) -> Result<(), FieldTooLongError> {
+    if bytes.len() > u16::max_value() as usize {
+        return Err(FieldTooLongError { field, len: bytes.len() });
+    }
+    Ok(())
+}
+
+pub const CHANNEL_UPDATE_MESSAGE_FLAG_HTLC_MAXIMUM_MSAT: u8 = 0x01;
+pub const CHANNEL_UPDATE_CHANNEL_FLAG_DIRECTION: u8 = 0x01;
+pub const CHANNEL_UPDATE_CHANNEL_FLAG_DISABLE: u8 = 0x02;
+
+impl ChannelAnnouncementMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ChannelAnnouncementMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let node_signature_1 = cursor.read_signature()?;
+        let node_signature_2 = cursor.read_signature()?;
+        let bitcoin_signature_1 = cursor.read_signature()?;
+        let bitcoin_signature_2 = cursor.read_signature()?;
+        let features = cursor.read_u16_len_slice()?.to_vec();
+        let chain_hash = cursor.read_chain_hash()?;
+        let short_channel_id = cursor.read_short_channel_id()?;
+        let node_id_1 = cursor.read_pub_key()?;
+        let node_id_2 = cursor.read_pub_key()?;
+        let bitcoin_key_1 = cursor.read_pub_key()?;
+        let bitcoin_key_2 = cursor.read_pub_key()?;
+        let excess_data = cursor.read_to_end().to_vec();
+        Ok(ChannelAnnouncementMsg {
+            node_signature_1,
+            node_signature_2,
+            bitcoin_signature_1,
+            bitcoin_signature_2,
+            features,
+            chain_hash,
+            short_channel_id,
+            node_id_1,
+            node_id_2,
+            bitcoin_key_1,
+            bitcoin_key_2,
+            excess_data,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_signature(&self.node_signature_1);
+        cursor.write_signature(&self.node_signature_2);
+        cursor.write_signature(&self.bitcoin_signature_1);
+        cursor.write_signature(&self.bitcoin_signature_2);
+        self.write_signed_fields_to_cursor(cursor);
+    }
+
+    /// Checks that the variable-length fields fit their length prefixes. Parsed messages always
+    /// do; this is for ones we've built, before signing them.
+    pub fn check_field_lengths(&self) -> Result<(), FieldTooLongError> {
+        check_u16_len("features", &self.features)
+    }
+
+    /// Writes the part of the message covered by the signatures.
+    pub fn write_signed_fields_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u16_len_slice(&self.features);
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_short_channel_id(self.short_channel_id);
+        cursor.write_pub_key(&self.node_id_1);
+        cursor.write_pub_key(&self.node_id_2);
+        cursor.write_pub_key(&self.bitcoin_key_1);
+        cursor.write_pub_key(&self.bitcoin_key_2);
+        cursor.write_slice(&self.excess_data);
+    }
+}
+
+impl NodeAnnouncementMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<NodeAnnouncementMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let signature = cursor.read_signature()?;
+        let features = cursor.read_u16_len_slice()?.to_vec();
+        let timestamp = cursor.read_u32()?;
+        let node_id = cursor.read_pub_key()?;
+        let rgb_color = slice_to_array!(cursor.read_slice(3)?, 3);
+        let alias = cursor.read_bytes32()?;
+        let (addresses, unknown_addresses) = NetAddress::read_list(cursor.read_u16_len_slice()?)?;
+        let excess_data = cursor.read_to_end().to_vec();
+        Ok(NodeAnnouncementMsg {
+            signature,
+            features,
+            timestamp,
+            node_id,
+            rgb_color,
+            alias,
+            addresses,
+            unknown_addresses,
+            excess_data,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_signature(&self.signature);
+        self.write_signed_fields_to_cursor(cursor);
+    }
+
+    /// Checks that the variable-length fields fit their length prefixes. Parsed messages always
+    /// do; this is for ones we've built, before signing them.
+    pub fn check_field_lengths(&self) -> Result<(), FieldTooLongError> {
+        check_u16_len("features", &self.features)?;
+        check_u16_len("addresses", &self.encoded_addresses())
+    }
+
+    /// Writes the part of the message covered by the signature.
+    pub fn write_signed_fields_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u16_len_slice(&self.features);
+        cursor.write_u32(self.timestamp);
+        cursor.write_pub_key(&self.node_id);
+        cursor.write_slice(&self.rgb_color[..]);
+        cursor.write_slice(&self.alias[..]);
+        cursor.write_u16_len_slice(&self.encoded_addresses());
+        cursor.write_slice(&self.excess_data);
+    }
+
+    fn encoded_addresses(&self) -> Bytes {
+        let mut cursor = WriteCursor::new();
+        for address in &self.addresses {
+            address.write_to_cursor(&mut cursor);
+        }
+        cursor.write_slice(&self.unknown_addresses);
+        cursor.into_bytes()
+    }
+}
+
+impl ChannelUpdateMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ChannelUpdateMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let signature = cursor.read_signature()?;
+        let chain_hash = cursor.read_chain_hash()?;
+        let short_channel_id = cursor.read_short_channel_id()?;
+        let timestamp = cursor.read_u32()?;
+        let message_flags = cursor.read_u8()?;
+        let channel_flags = cursor.read_u8()?;
+        let cltv_expiry_delta = cursor.read_u16()?;
+        let htlc_minimum_msat = cursor.read_u64()?;
+        let fee_base_msat = cursor.read_u32()?;
+        let fee_proportional_millionths = cursor.read_u32()?;
+        let htlc_maximum_msat = if message_flags & CHANNEL_UPDATE_MESSAGE_FLAG_HTLC_MAXIMUM_MSAT != 0 {
+            Some(cursor.read_u64()?)
+        } else {
+            None
+        };
+        let excess_data = cursor.read_to_end().to_vec();
+        Ok(ChannelUpdateMsg {
+            signature,
+            chain_hash,
+            short_channel_id,
+            timestamp,
+            message_flags,
+            channel_flags,
+            cltv_expiry_delta,
+            htlc_minimum_msat,
+            fee_base_msat,
+            fee_proportional_millionths,
+            htlc_maximum_msat,
+            excess_data,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_signature(&self.signature);
+        self.write_signed_fields_to_cursor(cursor);
+    }
+
+    /// Writes the part of the message covered by the signature.
+    pub fn write_signed_fields_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_short_channel_id(self.short_channel_id);
+        cursor.write_u32(self.timestamp);
+        cursor.write_u8(self.encoded_message_flags());
+        cursor.write_u8(self.channel_flags);
+        cursor.write_u16(self.cltv_expiry_delta);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u32(self.fee_base_msat);
+        cursor.write_u32(self.fee_proportional_millionths);
+        if let Some(htlc_maximum_msat) = self.htlc_maximum_msat {
+            cursor.write_u64(htlc_maximum_msat);
+        }
+        cursor.write_slice(&self.excess_data);
+    }
+
+    /// `message_flags` as written, with bit 0 saying whether `htlc_maximum_msat` follows.
+    pub fn encoded_message_flags(&self) -> u8 {
+        match self.htlc_maximum_msat {
+            Some(..) => self.message_flags | CHANNEL_UPDATE_MESSAGE_FLAG_HTLC_MAXIMUM_MSAT,
+            None => self.message_flags & !CHANNEL_UPDATE_MESSAGE_FLAG_HTLC_MAXIMUM_MSAT,
+        }
+    }
+
+    /// 0 if this update is from `node_id_1` of the channel announcement, 1 if it's from
+    /// `node_id_2`.
+    pub fn direction(&self) -> u8 {
+        self.channel_flags & CHANNEL_UPDATE_CHANNEL_FLAG_DIRECTION
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.channel_flags & CHANNEL_UPDATE_CHANNEL_FLAG_DISABLE != 0
+    }
+}
+
+impl AnnouncementSignaturesMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<AnnouncementSignaturesMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let short_channel_id = cursor.read_short_channel_id()?;
+        let node_signature = cursor.read_signature()?;
+        let bitcoin_signature = cursor.read_signature()?;
+        Ok(AnnouncementSignaturesMsg { channel_id, short_channel_id, node_signature, bitcoin_signature })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_short_channel_id(self.short_channel_id);
+        cursor.write_signature(&self.node_signature);
+        cursor.write_signature(&self.bitcoin_signature);
+    }
+}
+
+impl NetAddress {
+    /// Parses a list of address descriptors. Parsing stops at the first descriptor of an unknown
+    /// type since we can't know its length; the remaining bytes are returned as-is.
+    pub fn read_list(bytes: &[u8]) -> Result<(Vec<NetAddress>, Vec<u8>), PayloadError> {
+        let mut cursor = ReadCursor::new(bytes);
+        let mut addresses = Vec::new();
+        while !cursor.is_empty() {
+            let remaining = cursor.remaining();
+            let address = match cursor.read_u8()? {
+                1 => {
+                    let ip = slice_to_array!(cursor.read_slice(4)?, 4);
+                    let port = cursor.read_u16()?;
+                    NetAddress::Ipv4(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+                },
+                2 => {
+                    let ip = slice_to_array!(cursor.read_slice(16)?, 16);
+                    let port = cursor.read_u16()?;
+                    NetAddress::Ipv6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+                },
+                3 => {
+                    let onion_addr = slice_to_array!(cursor.read_slice(10)?, 10);
+                    let port = cursor.read_u16()?;
+                    NetAddress::TorV2 { onion_addr, port }
+                },
+                4 => {
+                    let ed25519_pubkey = cursor.read_bytes32()?;
+                    let checksum = cursor.read_u16()?;
+                    let version = cursor.read_u8()?;
+                    let port = cursor.read_u16()?;
+                    NetAddress::TorV3 { ed25519_pubkey, checksum, version, port }
+                },
+                5 => {
+                    let len = cursor.read_u8()?;
+                    let hostname = cursor.read_slice(len as usize)?;
+                    let hostname = match str::from_utf8(hostname).map(|hostname| Hostname::new(hostname.to_owned())) {
+                        Ok(Ok(hostname)) => hostname,
+                        _ => return Err(PayloadError::InvalidValue("hostname")),
+                    };
+                    let port = cursor.read_u16()?;
+                    NetAddress::Hostname { hostname, port }
+                },
+                _ => return Ok((addresses, remaining.to_vec())),
+            };
+            addresses.push(address);
+        }
+        Ok((addresses, Vec::new()))
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        match self {
+            NetAddress::Ipv4(addr) => {
+                cursor.write_u8(1);
+                cursor.write_slice(&addr.ip().octets()[..]);
+                cursor.write_u16(addr.port());
+            },
+            NetAddress::Ipv6(addr) => {
+                cursor.write_u8(2);
+                cursor.write_slice(&addr.ip().octets()[..]);
+                cursor.write_u16(addr.port());
+            },
+            NetAddress::TorV2 { onion_addr, port } => {
+                cursor.write_u8(3);
+                cursor.write_slice(&onion_addr[..]);
+                cursor.write_u16(*port);
+            },
+            NetAddress::TorV3 { ed25519_pubkey, checksum, version, port } => {
+                cursor.write_u8(4);
+                cursor.write_slice(&ed25519_pubkey[..]);
+                cursor.write_u16(*checksum);
+                cursor.write_u8(*version);
+                cursor.write_u16(*port);
+            },
+            NetAddress::Hostname { hostname, port } => {
+                cursor.write_u8(5);
+                cursor.write_u8(hostname.as_str().len() as u8);
+                cursor.write_slice(hostname.as_str().as_bytes());
+                cursor.write_u16(*port);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn address_list_round_trip() {
+        let addresses = vec![
+            NetAddress::Ipv4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 9735)),
+            NetAddress::Ipv6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 9736, 0, 0)),
+            NetAddress::TorV3 { ed25519_pubkey: [0xab; 32], checksum: 0x1234, version: 3, port: 9737 },
+            NetAddress::Hostname { hostname: unwrap!(Hostname::new("ln.example.com".to_owned())), port: 9738 },
+            NetAddress::Hostname { hostname: unwrap!(Hostname::new("a".repeat(255))), port: 9739 },
+        ];
+        let mut cursor = WriteCursor::new();
+        for address in &addresses {
+            address.write_to_cursor(&mut cursor);
+        }
+        cursor.write_slice(&[0x63, 0xff, 0xff]);
+        let bytes = cursor.into_bytes();
+
+        let (parsed, unknown) = unwrap!(NetAddress::read_list(&bytes));
+        assert_eq!(parsed, addresses);
+        assert_eq!(unknown, vec![0x63, 0xff, 0xff]);
+
+        match Hostname::new("a".repeat(256)) {
+            Err(HostnameError::TooLong { len: 256 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn field_lengths_must_fit_their_prefixes() {
+        let secp = Secp256k1::new();
+        let mut msg = signed_node_announcement(&secp, 0x11, 1000);
+        msg.features = vec![0; 65535];
+        msg.unknown_addresses = vec![0x63; 65535];
+        unwrap!(msg.check_field_lengths());
+
+        // The address list is limited as a whole, known addresses included.
+        msg.addresses.push(NetAddress::Ipv4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 9735)));
+        match msg.check_field_lengths() {
+            Err(FieldTooLongError { field: "addresses", len: 65542 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        msg.features.push(0);
+        match msg.check_field_lengths() {
+            Err(FieldTooLongError { field: "features", len: 65536 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let mut msg = signed_channel_announcement(&secp, 0x11, 0x22, test_short_channel_id(500_000));
+        unwrap!(msg.check_field_lengths());
+        msg.features = vec![0; 65536];
+        match msg.check_field_lengths() {
+            Err(FieldTooLongError { field: "features", len: 65536 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn channel_update_without_htlc_maximum() {
+        let secp = Secp256k1::new();
+        let sk = unwrap!(secp256k1::SecretKey::from_slice(&secp, &[0x01; 32]));
+        let message = unwrap!(secp256k1::Message::from_slice(&[0x02; 32]));
+        let update = ChannelUpdateMsg {
+            signature: secp.sign(&message, &sk),
+            chain_hash: ChainHash::BITCOIN,
+            short_channel_id: ShortChannelId { block_height: 600_000, tx_index: 12, output_index: 1 },
+            timestamp: 1_600_000_000,
+            message_flags: 0,
+            channel_flags: CHANNEL_UPDATE_CHANNEL_FLAG_DIRECTION,
+            cltv_expiry_delta: 40,
+            htlc_minimum_msat: 1000,
+            fee_base_msat: 1000,
+            fee_proportional_millionths: 1,
+            htlc_maximum_msat: None,
+            excess_data: Vec::new(),
+        };
+        let bytes = Msg::ChannelUpdate(update.clone()).to_bytes();
+        match unwrap!(Msg::from_bytes(&bytes)) {
+            Msg::ChannelUpdate(parsed) => {
+                assert_eq!(parsed, update);
+                assert_eq!(parsed.direction(), 1);
+                assert!(!parsed.is_disabled());
+            },
+            _ => panic!("wrong message type"),
+        }
+
+        // The flag always matches whether htlc_maximum_msat is there.
+        let mut with_maximum = update.clone();
+        with_maximum.htlc_maximum_msat = Some(5_000_000);
+        let mut without_maximum = update.clone();
+        without_maximum.message_flags = CHANNEL_UPDATE_MESSAGE_FLAG_HTLC_MAXIMUM_MSAT;
+        for update in vec![with_maximum, without_maximum] {
+            match unwrap!(Msg::from_bytes(&Msg::ChannelUpdate(update.clone()).to_bytes())) {
+                Msg::ChannelUpdate(parsed) => {
+                    assert_eq!(parsed.htlc_maximum_msat, update.htlc_maximum_msat);
+                    assert_eq!(parsed.message_flags, update.encoded_message_flags());
+                },
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+}
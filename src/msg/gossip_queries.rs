@@ -0,0 +1,384 @@
+use super::*;
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+/// Decompressed arrays larger than this are rejected, rather than letting a peer make us inflate
+/// a zip bomb.
+const MAX_DECOMPRESSED_LEN: usize = 1 << 20;
+
+/// How an array of short channel ids, timestamps or query flags is encoded on the wire.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShortIdEncoding {
+    Uncompressed = 0,
+    /// Deprecated by the spec, but still sent by older nodes.
+    Zlib = 1,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ChannelUpdateTimestamps {
+    pub timestamp_node_id_1: u32,
+    pub timestamp_node_id_2: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ChannelUpdateChecksums {
+    pub checksum_node_id_1: u32,
+    pub checksum_node_id_2: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryShortChannelIdsMsg {
+    pub chain_hash: ChainHash,
+    /// Used for both the short channel ids and the query flags.
+    pub encoding: ShortIdEncoding,
+    pub short_channel_ids: Vec<ShortChannelId>,
+    /// If present, contains one entry per short channel id.
+    pub query_flags: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyShortChannelIdsEndMsg {
+    pub chain_hash: ChainHash,
+    pub full_information: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryChannelRangeMsg {
+    pub chain_hash: ChainHash,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    pub query_option: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyChannelRangeMsg {
+    pub chain_hash: ChainHash,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    pub sync_complete: bool,
+    /// Used for both the short channel ids and the timestamps.
+    pub encoding: ShortIdEncoding,
+    pub short_channel_ids: Vec<ShortChannelId>,
+    /// If present, contains one entry per short channel id.
+    pub timestamps: Option<Vec<ChannelUpdateTimestamps>>,
+    /// If present, contains one entry per short channel id.
+    pub checksums: Option<Vec<ChannelUpdateChecksums>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipTimestampFilterMsg {
+    pub chain_hash: ChainHash,
+    pub first_timestamp: u32,
+    pub timestamp_range: u32,
+}
+
+impl ShortIdEncoding {
+    /// Reads an encoding type byte followed by the encoded items.
+    fn read_array<T, F>(bytes: &[u8], mut read_item: F) -> Result<(ShortIdEncoding, Vec<T>), PayloadError>
+    where
+        F: FnMut(&mut ReadCursor) -> Result<T, PayloadError>,
+    {
+        let mut cursor = ReadCursor::new(bytes);
+        let (encoding, decoded) = match cursor.read_u8()? {
+            0 => (ShortIdEncoding::Uncompressed, cursor.read_to_end().to_vec()),
+            1 => {
+                let mut decoded = Vec::new();
+                let decoder = ZlibDecoder::new(cursor.read_to_end());
+                if decoder.take(MAX_DECOMPRESSED_LEN as u64 + 1).read_to_end(&mut decoded).is_err() {
+                    return Err(PayloadError::InvalidValue("zlib data"));
+                }
+                if decoded.len() > MAX_DECOMPRESSED_LEN {
+                    return Err(PayloadError::InvalidValue("zlib data"));
+                }
+                (ShortIdEncoding::Zlib, decoded)
+            },
+            _ => return Err(PayloadError::InvalidValue("encoding type")),
+        };
+        let mut cursor = ReadCursor::new(&decoded);
+        let mut items = Vec::new();
+        while !cursor.is_empty() {
+            items.push(read_item(&mut cursor)?);
+        }
+        Ok((encoding, items))
+    }
+
+    fn write_array<T, F>(self, items: &[T], mut write_item: F) -> Vec<u8>
+    where
+        F: FnMut(&mut WriteCursor, &T),
+    {
+        let mut cursor = WriteCursor::new();
+        for item in items {
+            write_item(&mut cursor, item);
+        }
+        let data = cursor.into_bytes();
+        let mut ret = vec![self as u8];
+        match self {
+            ShortIdEncoding::Uncompressed => ret.extend_from_slice(&data),
+            ShortIdEncoding::Zlib => {
+                let mut encoder = ZlibEncoder::new(ret, Compression::default());
+                unwrap!(encoder.write_all(&data));
+                ret = unwrap!(encoder.finish());
+            },
+        }
+        ret
+    }
+
+    fn read_short_channel_ids(bytes: &[u8]) -> Result<(ShortIdEncoding, Vec<ShortChannelId>), PayloadError> {
+        ShortIdEncoding::read_array(bytes, |cursor| Ok(cursor.read_short_channel_id()?))
+    }
+
+    fn write_short_channel_ids(self, short_channel_ids: &[ShortChannelId]) -> Vec<u8> {
+        self.write_array(short_channel_ids, |cursor, short_channel_id| {
+            cursor.write_short_channel_id(*short_channel_id)
+        })
+    }
+}
+
+impl QueryShortChannelIdsMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<QueryShortChannelIdsMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let (encoding, short_channel_ids) = ShortIdEncoding::read_short_channel_ids(cursor.read_u16_len_slice()?)?;
+        let mut query_flags = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                1 => {
+                    let (_, flags) = ShortIdEncoding::read_array(record.value, |cursor| Ok(cursor.read_bigsize()?))?;
+                    query_flags = Some(flags);
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(QueryShortChannelIdsMsg { chain_hash, encoding, short_channel_ids, query_flags })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_u16_len_slice(&self.encoding.write_short_channel_ids(&self.short_channel_ids));
+        if let Some(ref query_flags) = self.query_flags {
+            let value = self.encoding.write_array(query_flags, |cursor, flags| cursor.write_bigsize(*flags));
+            cursor.write_tlv_record(1, &value);
+        }
+    }
+}
+
+impl ReplyShortChannelIdsEndMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ReplyShortChannelIdsEndMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let full_information = cursor.read_u8()? != 0;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(ReplyShortChannelIdsEndMsg { chain_hash, full_information })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_u8(self.full_information as u8);
+    }
+}
+
+impl QueryChannelRangeMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<QueryChannelRangeMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let first_blocknum = cursor.read_u32()?;
+        let number_of_blocks = cursor.read_u32()?;
+        let mut query_option = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                1 => {
+                    let mut value = ReadCursor::new(record.value);
+                    query_option = Some(value.read_bigsize()?);
+                    if !value.is_empty() {
+                        return Err(record.malformed().into());
+                    }
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(QueryChannelRangeMsg { chain_hash, first_blocknum, number_of_blocks, query_option })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_u32(self.first_blocknum);
+        cursor.write_u32(self.number_of_blocks);
+        if let Some(query_option) = self.query_option {
+            let mut value = WriteCursor::new();
+            value.write_bigsize(query_option);
+            cursor.write_tlv_record(1, &value.into_bytes());
+        }
+    }
+
+    /// One past the last block covered by the query. This can exceed `u32::MAX` since peers are
+    /// allowed to ask for "everything from here on" with a huge `number_of_blocks`.
+    pub fn end_blocknum(&self) -> u64 {
+        self.first_blocknum as u64 + self.number_of_blocks as u64
+    }
+}
+
+impl ReplyChannelRangeMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ReplyChannelRangeMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let first_blocknum = cursor.read_u32()?;
+        let number_of_blocks = cursor.read_u32()?;
+        let sync_complete = cursor.read_u8()? != 0;
+        let (encoding, short_channel_ids) = ShortIdEncoding::read_short_channel_ids(cursor.read_u16_len_slice()?)?;
+        let mut timestamps = None;
+        let mut checksums = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                1 => {
+                    let (_, value) = ShortIdEncoding::read_array(record.value, |cursor| {
+                        let timestamp_node_id_1 = cursor.read_u32()?;
+                        let timestamp_node_id_2 = cursor.read_u32()?;
+                        Ok(ChannelUpdateTimestamps { timestamp_node_id_1, timestamp_node_id_2 })
+                    })?;
+                    timestamps = Some(value);
+                },
+                3 => {
+                    let mut value = ReadCursor::new(record.value);
+                    let mut value_checksums = Vec::new();
+                    while !value.is_empty() {
+                        let checksum_node_id_1 = value.read_u32()?;
+                        let checksum_node_id_2 = value.read_u32()?;
+                        value_checksums.push(ChannelUpdateChecksums { checksum_node_id_1, checksum_node_id_2 });
+                    }
+                    checksums = Some(value_checksums);
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(ReplyChannelRangeMsg {
+            chain_hash, first_blocknum, number_of_blocks, sync_complete, encoding, short_channel_ids,
+            timestamps, checksums,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_u32(self.first_blocknum);
+        cursor.write_u32(self.number_of_blocks);
+        cursor.write_u8(self.sync_complete as u8);
+        cursor.write_u16_len_slice(&self.encoding.write_short_channel_ids(&self.short_channel_ids));
+        if let Some(ref timestamps) = self.timestamps {
+            let value = self.encoding.write_array(timestamps, |cursor, timestamps| {
+                cursor.write_u32(timestamps.timestamp_node_id_1);
+                cursor.write_u32(timestamps.timestamp_node_id_2);
+            });
+            cursor.write_tlv_record(1, &value);
+        }
+        if let Some(ref checksums) = self.checksums {
+            let mut value = WriteCursor::new();
+            for checksums in checksums {
+                value.write_u32(checksums.checksum_node_id_1);
+                value.write_u32(checksums.checksum_node_id_2);
+            }
+            cursor.write_tlv_record(3, &value.into_bytes());
+        }
+    }
+
+    /// One past the last block covered by the reply.
+    pub fn end_blocknum(&self) -> u64 {
+        self.first_blocknum as u64 + self.number_of_blocks as u64
+    }
+}
+
+impl GossipTimestampFilterMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<GossipTimestampFilterMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let first_timestamp = cursor.read_u32()?;
+        let timestamp_range = cursor.read_u32()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(GossipTimestampFilterMsg { chain_hash, first_timestamp, timestamp_range })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_u32(self.first_timestamp);
+        cursor.write_u32(self.timestamp_range);
+    }
+
+    /// Whether gossip with the given timestamp should be relayed to the peer which sent the filter.
+    pub fn matches(&self, timestamp: u32) -> bool {
+        let end = self.first_timestamp as u64 + self.timestamp_range as u64;
+        timestamp >= self.first_timestamp && (timestamp as u64) < end
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn short_channel_ids() -> Vec<ShortChannelId> {
+        (0..100u32)
+        .map(|i| ShortChannelId { block_height: 600_000 + i / 3, tx_index: i, output_index: 1 })
+        .collect()
+    }
+
+    #[test]
+    fn reply_channel_range_round_trip() {
+        for &encoding in &[ShortIdEncoding::Uncompressed, ShortIdEncoding::Zlib] {
+            let short_channel_ids = short_channel_ids();
+            let timestamps = short_channel_ids.iter().map(|short_channel_id| ChannelUpdateTimestamps {
+                timestamp_node_id_1: short_channel_id.tx_index,
+                timestamp_node_id_2: 0,
+            }).collect();
+            let checksums = vec![ChannelUpdateChecksums { checksum_node_id_1: 1, checksum_node_id_2: 2 }; 100];
+            let msg = ReplyChannelRangeMsg {
+                chain_hash: ChainHash::BITCOIN,
+                first_blocknum: 600_000,
+                number_of_blocks: 34,
+                sync_complete: true,
+                encoding,
+                short_channel_ids,
+                timestamps: Some(timestamps),
+                checksums: Some(checksums),
+            };
+            let bytes = Msg::ReplyChannelRange(msg.clone()).to_bytes();
+            match unwrap!(Msg::from_bytes(&bytes)) {
+                Msg::ReplyChannelRange(parsed) => assert_eq!(parsed, msg),
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+
+    #[test]
+    fn query_short_channel_ids_zlib_is_smaller() {
+        let msg = QueryShortChannelIdsMsg {
+            chain_hash: ChainHash::BITCOIN,
+            encoding: ShortIdEncoding::Zlib,
+            short_channel_ids: short_channel_ids(),
+            query_flags: Some(vec![1; 100]),
+        };
+        let compressed = Msg::QueryShortChannelIds(msg.clone()).to_bytes();
+        let uncompressed = Msg::QueryShortChannelIds(QueryShortChannelIdsMsg {
+            encoding: ShortIdEncoding::Uncompressed,
+            ..msg.clone()
+        }).to_bytes();
+        assert!(compressed.len() < uncompressed.len());
+        match unwrap!(Msg::from_bytes(&compressed)) {
+            Msg::QueryShortChannelIds(parsed) => assert_eq!(parsed, msg),
+            _ => panic!("wrong message type"),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_encoding() {
+        let mut cursor = WriteCursor::new();
+        cursor.write_chain_hash(&ChainHash::BITCOIN);
+        cursor.write_u16_len_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        match QueryShortChannelIdsMsg::from_payload(&cursor.into_bytes()) {
+            Err(PayloadError::InvalidValue(..)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxAddInputMsg {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+    pub prev_tx: Vec<u8>,
+    pub prev_tx_vout: u32,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxAddOutputMsg {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+    pub sats: u64,
+    pub script: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxRemoveInputMsg {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxRemoveOutputMsg {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxCompleteMsg {
+    pub channel_id: ChannelId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxSignaturesMsg {
+    pub channel_id: ChannelId,
+    pub txid: [u8; 32],
+    /// Serialized witness stacks, one per input we contributed, in serial id order.
+    pub witnesses: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxInitRbfMsg {
+    pub channel_id: ChannelId,
+    pub locktime: u32,
+    pub feerate: u32,
+    pub funding_output_contribution: Option<i64>,
+    pub require_confirmed_inputs: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxAckRbfMsg {
+    pub channel_id: ChannelId,
+    pub funding_output_contribution: Option<i64>,
+    pub require_confirmed_inputs: bool,
+}
+
+impl TxAddInputMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxAddInputMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let serial_id = cursor.read_u64()?;
+        let prev_tx = cursor.read_u16_len_slice()?.to_vec();
+        let prev_tx_vout = cursor.read_u32()?;
+        let sequence = cursor.read_u32()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(TxAddInputMsg { channel_id, serial_id, prev_tx, prev_tx_vout, sequence })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.serial_id);
+        cursor.write_u16_len_slice(&self.prev_tx);
+        cursor.write_u32(self.prev_tx_vout);
+        cursor.write_u32(self.sequence);
+    }
+}
+
+impl TxAddOutputMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxAddOutputMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let serial_id = cursor.read_u64()?;
+        let sats = cursor.read_u64()?;
+        let script = cursor.read_u16_len_slice()?.to_vec();
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(TxAddOutputMsg { channel_id, serial_id, sats, script })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.serial_id);
+        cursor.write_u64(self.sats);
+        cursor.write_u16_len_slice(&self.script);
+    }
+}
+
+impl TxRemoveInputMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxRemoveInputMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let serial_id = cursor.read_u64()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(TxRemoveInputMsg { channel_id, serial_id })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.serial_id);
+    }
+}
+
+impl TxRemoveOutputMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxRemoveOutputMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let serial_id = cursor.read_u64()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(TxRemoveOutputMsg { channel_id, serial_id })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.serial_id);
+    }
+}
+
+impl TxCompleteMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxCompleteMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(TxCompleteMsg { channel_id })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+    }
+}
+
+impl TxSignaturesMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxSignaturesMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let txid = cursor.read_bytes32()?;
+        let num_witnesses = cursor.read_u16()?;
+        let mut witnesses = Vec::with_capacity(num_witnesses as usize);
+        for _ in 0..num_witnesses {
+            witnesses.push(cursor.read_u16_len_slice()?.to_vec());
+        }
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(TxSignaturesMsg { channel_id, txid, witnesses })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_slice(&self.txid[..]);
+        cursor.write_u16(self.witnesses.len() as u16);
+        for witness in &self.witnesses {
+            cursor.write_u16_len_slice(witness);
+        }
+    }
+}
+
+fn read_funding_output_contribution(record: &TlvRecord) -> Result<i64, TlvStreamError> {
+    Ok(record.read_u64()? as i64)
+}
+
+impl TxInitRbfMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxInitRbfMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let locktime = cursor.read_u32()?;
+        let feerate = cursor.read_u32()?;
+        let mut funding_output_contribution = None;
+        let mut require_confirmed_inputs = false;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                0 => funding_output_contribution = Some(read_funding_output_contribution(&record)?),
+                2 => require_confirmed_inputs = record.read_flag()?,
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(TxInitRbfMsg {
+            channel_id, locktime, feerate, funding_output_contribution, require_confirmed_inputs,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u32(self.locktime);
+        cursor.write_u32(self.feerate);
+        if let Some(contribution) = self.funding_output_contribution {
+            cursor.write_tlv_record(0, &i64::to_be_bytes(contribution)[..]);
+        }
+        if self.require_confirmed_inputs {
+            cursor.write_tlv_record(2, &[]);
+        }
+    }
+}
+
+impl TxAckRbfMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<TxAckRbfMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let mut funding_output_contribution = None;
+        let mut require_confirmed_inputs = false;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                0 => funding_output_contribution = Some(read_funding_output_contribution(&record)?),
+                2 => require_confirmed_inputs = record.read_flag()?,
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(TxAckRbfMsg { channel_id, funding_output_contribution, require_confirmed_inputs })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        if let Some(contribution) = self.funding_output_contribution {
+            cursor.write_tlv_record(0, &i64::to_be_bytes(contribution)[..]);
+        }
+        if self.require_confirmed_inputs {
+            cursor.write_tlv_record(2, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL_ID: ChannelId = ChannelId([7; 32]);
+
+    fn round_trip(msg: Msg) -> Msg {
+        unwrap!(Msg::from_bytes(&msg.to_bytes()))
+    }
+
+    #[test]
+    fn tx_msgs_round_trip() {
+        let add_input = TxAddInputMsg {
+            channel_id: CHANNEL_ID,
+            serial_id: 2,
+            prev_tx: vec![1, 2, 3, 4],
+            prev_tx_vout: 1,
+            sequence: 0xffff_fffd,
+        };
+        match round_trip(Msg::TxAddInput(add_input.clone())) {
+            Msg::TxAddInput(parsed) => assert_eq!(parsed, add_input),
+            _ => panic!("wrong message type"),
+        }
+
+        let add_output = TxAddOutputMsg {
+            channel_id: CHANNEL_ID,
+            serial_id: 3,
+            sats: 10_000,
+            script: vec![0x00, 0x14, 0x42, 0x42],
+        };
+        match round_trip(Msg::TxAddOutput(add_output.clone())) {
+            Msg::TxAddOutput(parsed) => assert_eq!(parsed, add_output),
+            _ => panic!("wrong message type"),
+        }
+
+        let remove_input = TxRemoveInputMsg { channel_id: CHANNEL_ID, serial_id: 4 };
+        match round_trip(Msg::TxRemoveInput(remove_input.clone())) {
+            Msg::TxRemoveInput(parsed) => assert_eq!(parsed, remove_input),
+            _ => panic!("wrong message type"),
+        }
+
+        let remove_output = TxRemoveOutputMsg { channel_id: CHANNEL_ID, serial_id: 5 };
+        match round_trip(Msg::TxRemoveOutput(remove_output.clone())) {
+            Msg::TxRemoveOutput(parsed) => assert_eq!(parsed, remove_output),
+            _ => panic!("wrong message type"),
+        }
+
+        let complete = TxCompleteMsg { channel_id: CHANNEL_ID };
+        match round_trip(Msg::TxComplete(complete.clone())) {
+            Msg::TxComplete(parsed) => assert_eq!(parsed, complete),
+            _ => panic!("wrong message type"),
+        }
+
+        let signatures = TxSignaturesMsg {
+            channel_id: CHANNEL_ID,
+            txid: [9; 32],
+            witnesses: vec![vec![2, 0x01, 0xaa, 0x02, 0xbb, 0xcc], vec![]],
+        };
+        match round_trip(Msg::TxSignatures(signatures.clone())) {
+            Msg::TxSignatures(parsed) => assert_eq!(parsed, signatures),
+            _ => panic!("wrong message type"),
+        }
+
+        for &(funding_output_contribution, require_confirmed_inputs) in &[(None, false), (Some(-30_000), true)] {
+            let init_rbf = TxInitRbfMsg {
+                channel_id: CHANNEL_ID,
+                locktime: 600_000,
+                feerate: 2_500,
+                funding_output_contribution,
+                require_confirmed_inputs,
+            };
+            match round_trip(Msg::TxInitRbf(init_rbf.clone())) {
+                Msg::TxInitRbf(parsed) => assert_eq!(parsed, init_rbf),
+                _ => panic!("wrong message type"),
+            }
+
+            let ack_rbf = TxAckRbfMsg {
+                channel_id: CHANNEL_ID,
+                funding_output_contribution,
+                require_confirmed_inputs,
+            };
+            match round_trip(Msg::TxAckRbf(ack_rbf.clone())) {
+                Msg::TxAckRbf(parsed) => assert_eq!(parsed, ack_rbf),
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+
+    #[test]
+    fn require_confirmed_inputs_must_be_empty() {
+        let mut cursor = WriteCursor::new();
+        cursor.write_channel_id(&CHANNEL_ID);
+        cursor.write_tlv_record(2, &[1]);
+        match TxAckRbfMsg::from_payload(&cursor.into_bytes()) {
+            Err(PayloadError::Tlv(TlvStreamError::MalformedValue { type_tag: 2 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
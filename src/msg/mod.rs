@@ -1,17 +1,103 @@
 pub use super::*;
 
 mod init;
+mod tlv;
+mod open_channel;
+mod open_channel2;
+mod interactive_tx;
+mod shutdown;
+mod update;
+mod reestablish;
+mod gossip;
+mod gossip_queries;
 
 use init::*;
+pub use self::tlv::*;
+pub use self::open_channel::*;
+pub use self::open_channel2::*;
+pub use self::interactive_tx::*;
+pub use self::shutdown::*;
+pub use self::update::*;
+pub use self::reestablish::*;
+pub use self::gossip::*;
+pub use self::gossip_queries::*;
 
 pub enum MsgType {
     Init = 16,
+    OpenChannel = 32,
+    AcceptChannel = 33,
+    FundingCreated = 34,
+    FundingSigned = 35,
+    ChannelReady = 36,
+    Shutdown = 38,
+    ClosingSigned = 39,
+    OpenChannel2 = 64,
+    AcceptChannel2 = 65,
+    TxAddInput = 66,
+    TxAddOutput = 67,
+    TxRemoveInput = 68,
+    TxRemoveOutput = 69,
+    TxComplete = 70,
+    TxSignatures = 71,
+    TxInitRbf = 72,
+    TxAckRbf = 73,
+    UpdateAddHtlc = 128,
+    UpdateFulfillHtlc = 130,
+    UpdateFailHtlc = 131,
+    CommitmentSigned = 132,
+    RevokeAndAck = 133,
+    UpdateFee = 134,
+    UpdateFailMalformedHtlc = 135,
+    ChannelReestablish = 136,
+    ChannelAnnouncement = 256,
+    NodeAnnouncement = 257,
+    ChannelUpdate = 258,
+    AnnouncementSignatures = 259,
+    QueryShortChannelIds = 261,
+    ReplyShortChannelIdsEnd = 262,
+    QueryChannelRange = 263,
+    ReplyChannelRange = 264,
+    GossipTimestampFilter = 265,
 }
 
 impl MsgType {
     pub fn from_type_tag(type_tag: u16) -> Result<MsgType, UnknownMsgType> {
         match type_tag {
             16 => Ok(MsgType::Init),
+            32 => Ok(MsgType::OpenChannel),
+            33 => Ok(MsgType::AcceptChannel),
+            34 => Ok(MsgType::FundingCreated),
+            35 => Ok(MsgType::FundingSigned),
+            36 => Ok(MsgType::ChannelReady),
+            38 => Ok(MsgType::Shutdown),
+            39 => Ok(MsgType::ClosingSigned),
+            64 => Ok(MsgType::OpenChannel2),
+            65 => Ok(MsgType::AcceptChannel2),
+            66 => Ok(MsgType::TxAddInput),
+            67 => Ok(MsgType::TxAddOutput),
+            68 => Ok(MsgType::TxRemoveInput),
+            69 => Ok(MsgType::TxRemoveOutput),
+            70 => Ok(MsgType::TxComplete),
+            71 => Ok(MsgType::TxSignatures),
+            72 => Ok(MsgType::TxInitRbf),
+            73 => Ok(MsgType::TxAckRbf),
+            128 => Ok(MsgType::UpdateAddHtlc),
+            130 => Ok(MsgType::UpdateFulfillHtlc),
+            131 => Ok(MsgType::UpdateFailHtlc),
+            132 => Ok(MsgType::CommitmentSigned),
+            133 => Ok(MsgType::RevokeAndAck),
+            134 => Ok(MsgType::UpdateFee),
+            135 => Ok(MsgType::UpdateFailMalformedHtlc),
+            136 => Ok(MsgType::ChannelReestablish),
+            256 => Ok(MsgType::ChannelAnnouncement),
+            257 => Ok(MsgType::NodeAnnouncement),
+            258 => Ok(MsgType::ChannelUpdate),
+            259 => Ok(MsgType::AnnouncementSignatures),
+            261 => Ok(MsgType::QueryShortChannelIds),
+            262 => Ok(MsgType::ReplyShortChannelIdsEnd),
+            263 => Ok(MsgType::QueryChannelRange),
+            264 => Ok(MsgType::ReplyChannelRange),
+            265 => Ok(MsgType::GossipTimestampFilter),
             _ => Err(UnknownMsgType { type_tag }),
         }
     }
@@ -31,6 +117,40 @@ impl UnknownMsgType {
 
 pub enum Msg {
     Init(InitMsg),
+    OpenChannel(OpenChannelMsg),
+    AcceptChannel(AcceptChannelMsg),
+    FundingCreated(FundingCreatedMsg),
+    FundingSigned(FundingSignedMsg),
+    ChannelReady(ChannelReadyMsg),
+    Shutdown(ShutdownMsg),
+    ClosingSigned(ClosingSignedMsg),
+    OpenChannel2(OpenChannel2Msg),
+    AcceptChannel2(AcceptChannel2Msg),
+    TxAddInput(TxAddInputMsg),
+    TxAddOutput(TxAddOutputMsg),
+    TxRemoveInput(TxRemoveInputMsg),
+    TxRemoveOutput(TxRemoveOutputMsg),
+    TxComplete(TxCompleteMsg),
+    TxSignatures(TxSignaturesMsg),
+    TxInitRbf(TxInitRbfMsg),
+    TxAckRbf(TxAckRbfMsg),
+    UpdateAddHtlc(UpdateAddHtlcMsg),
+    UpdateFulfillHtlc(UpdateFulfillHtlcMsg),
+    UpdateFailHtlc(UpdateFailHtlcMsg),
+    CommitmentSigned(CommitmentSignedMsg),
+    RevokeAndAck(RevokeAndAckMsg),
+    UpdateFee(UpdateFeeMsg),
+    UpdateFailMalformedHtlc(UpdateFailMalformedHtlcMsg),
+    ChannelReestablish(ChannelReestablishMsg),
+    ChannelAnnouncement(ChannelAnnouncementMsg),
+    NodeAnnouncement(NodeAnnouncementMsg),
+    ChannelUpdate(ChannelUpdateMsg),
+    AnnouncementSignatures(AnnouncementSignaturesMsg),
+    QueryShortChannelIds(QueryShortChannelIdsMsg),
+    ReplyShortChannelIdsEnd(ReplyShortChannelIdsEndMsg),
+    QueryChannelRange(QueryChannelRangeMsg),
+    ReplyChannelRange(ReplyChannelRangeMsg),
+    GossipTimestampFilter(GossipTimestampFilterMsg),
 }
 
 #[derive(Debug, Fail)]
@@ -41,22 +161,187 @@ pub enum MsgFromBytesError {
     MsgTooShort(#[fail(cause)] MsgTooShortError),
     #[fail(display = "failed to parse init msg: {}", _0)]
     Init(#[fail(cause)] InitMsgFromPayloadError),
+    #[fail(display = "failed to parse open_channel msg: {}", _0)]
+    OpenChannel(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse accept_channel msg: {}", _0)]
+    AcceptChannel(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse funding_created msg: {}", _0)]
+    FundingCreated(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse funding_signed msg: {}", _0)]
+    FundingSigned(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse channel_ready msg: {}", _0)]
+    ChannelReady(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse shutdown msg: {}", _0)]
+    Shutdown(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse closing_signed msg: {}", _0)]
+    ClosingSigned(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse open_channel2 msg: {}", _0)]
+    OpenChannel2(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse accept_channel2 msg: {}", _0)]
+    AcceptChannel2(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_add_input msg: {}", _0)]
+    TxAddInput(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_add_output msg: {}", _0)]
+    TxAddOutput(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_remove_input msg: {}", _0)]
+    TxRemoveInput(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_remove_output msg: {}", _0)]
+    TxRemoveOutput(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_complete msg: {}", _0)]
+    TxComplete(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_signatures msg: {}", _0)]
+    TxSignatures(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_init_rbf msg: {}", _0)]
+    TxInitRbf(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse tx_ack_rbf msg: {}", _0)]
+    TxAckRbf(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse update_add_htlc msg: {}", _0)]
+    UpdateAddHtlc(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse update_fulfill_htlc msg: {}", _0)]
+    UpdateFulfillHtlc(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse update_fail_htlc msg: {}", _0)]
+    UpdateFailHtlc(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse commitment_signed msg: {}", _0)]
+    CommitmentSigned(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse revoke_and_ack msg: {}", _0)]
+    RevokeAndAck(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse update_fee msg: {}", _0)]
+    UpdateFee(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse update_fail_malformed_htlc msg: {}", _0)]
+    UpdateFailMalformedHtlc(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse channel_reestablish msg: {}", _0)]
+    ChannelReestablish(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse channel_announcement msg: {}", _0)]
+    ChannelAnnouncement(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse node_announcement msg: {}", _0)]
+    NodeAnnouncement(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse channel_update msg: {}", _0)]
+    ChannelUpdate(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse announcement_signatures msg: {}", _0)]
+    AnnouncementSignatures(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse query_short_channel_ids msg: {}", _0)]
+    QueryShortChannelIds(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse reply_short_channel_ids_end msg: {}", _0)]
+    ReplyShortChannelIdsEnd(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse query_channel_range msg: {}", _0)]
+    QueryChannelRange(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse reply_channel_range msg: {}", _0)]
+    ReplyChannelRange(#[fail(cause)] PayloadError),
+    #[fail(display = "failed to parse gossip_timestamp_filter msg: {}", _0)]
+    GossipTimestampFilter(#[fail(cause)] PayloadError),
+}
+
+/// Error parsing the payload of a message which has no message-specific failure modes.
+#[derive(Debug, Fail)]
+pub enum PayloadError {
+    #[fail(display = "{}", _0)]
+    PayloadTooShort(MsgTooShortError),
+    #[fail(display = "{}", _0)]
+    ReadField(ReadFieldError),
+    #[fail(display = "invalid tlv stream: {}", _0)]
+    Tlv(TlvStreamError),
+    #[fail(display = "invalid {}", _0)]
+    InvalidValue(&'static str),
+}
+
+impl From<MsgTooShortError> for PayloadError {
+    fn from(err: MsgTooShortError) -> PayloadError {
+        PayloadError::PayloadTooShort(err)
+    }
+}
+
+impl From<ReadFieldError> for PayloadError {
+    fn from(err: ReadFieldError) -> PayloadError {
+        PayloadError::ReadField(err)
+    }
+}
+
+impl From<TlvStreamError> for PayloadError {
+    fn from(err: TlvStreamError) -> PayloadError {
+        PayloadError::Tlv(err)
+    }
 }
 
 impl Msg {
     pub fn msg_type(&self) -> MsgType {
         match self {
             Msg::Init { .. } => MsgType::Init,
+            Msg::OpenChannel { .. } => MsgType::OpenChannel,
+            Msg::AcceptChannel { .. } => MsgType::AcceptChannel,
+            Msg::FundingCreated { .. } => MsgType::FundingCreated,
+            Msg::FundingSigned { .. } => MsgType::FundingSigned,
+            Msg::ChannelReady { .. } => MsgType::ChannelReady,
+            Msg::Shutdown { .. } => MsgType::Shutdown,
+            Msg::ClosingSigned { .. } => MsgType::ClosingSigned,
+            Msg::OpenChannel2 { .. } => MsgType::OpenChannel2,
+            Msg::AcceptChannel2 { .. } => MsgType::AcceptChannel2,
+            Msg::TxAddInput { .. } => MsgType::TxAddInput,
+            Msg::TxAddOutput { .. } => MsgType::TxAddOutput,
+            Msg::TxRemoveInput { .. } => MsgType::TxRemoveInput,
+            Msg::TxRemoveOutput { .. } => MsgType::TxRemoveOutput,
+            Msg::TxComplete { .. } => MsgType::TxComplete,
+            Msg::TxSignatures { .. } => MsgType::TxSignatures,
+            Msg::TxInitRbf { .. } => MsgType::TxInitRbf,
+            Msg::TxAckRbf { .. } => MsgType::TxAckRbf,
+            Msg::UpdateAddHtlc { .. } => MsgType::UpdateAddHtlc,
+            Msg::UpdateFulfillHtlc { .. } => MsgType::UpdateFulfillHtlc,
+            Msg::UpdateFailHtlc { .. } => MsgType::UpdateFailHtlc,
+            Msg::CommitmentSigned { .. } => MsgType::CommitmentSigned,
+            Msg::RevokeAndAck { .. } => MsgType::RevokeAndAck,
+            Msg::UpdateFee { .. } => MsgType::UpdateFee,
+            Msg::UpdateFailMalformedHtlc { .. } => MsgType::UpdateFailMalformedHtlc,
+            Msg::ChannelReestablish { .. } => MsgType::ChannelReestablish,
+            Msg::ChannelAnnouncement { .. } => MsgType::ChannelAnnouncement,
+            Msg::NodeAnnouncement { .. } => MsgType::NodeAnnouncement,
+            Msg::ChannelUpdate { .. } => MsgType::ChannelUpdate,
+            Msg::AnnouncementSignatures { .. } => MsgType::AnnouncementSignatures,
+            Msg::QueryShortChannelIds { .. } => MsgType::QueryShortChannelIds,
+            Msg::ReplyShortChannelIdsEnd { .. } => MsgType::ReplyShortChannelIdsEnd,
+            Msg::QueryChannelRange { .. } => MsgType::QueryChannelRange,
+            Msg::ReplyChannelRange { .. } => MsgType::ReplyChannelRange,
+            Msg::GossipTimestampFilter { .. } => MsgType::GossipTimestampFilter,
         }
     }
 
     pub fn to_bytes(&self) -> Bytes {
         let mut cursor = WriteCursor::new();
+        cursor.write_u16(self.msg_type() as u16);
         match self {
-            Msg::Init(init_msg) => {
-                cursor.write_u16(MsgType::Init as u16);
-                init_msg.write_to_cursor(&mut cursor);
-            },
+            Msg::Init(init_msg) => init_msg.write_to_cursor(&mut cursor),
+            Msg::OpenChannel(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::AcceptChannel(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::FundingCreated(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::FundingSigned(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ChannelReady(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::Shutdown(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ClosingSigned(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::OpenChannel2(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::AcceptChannel2(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxAddInput(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxAddOutput(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxRemoveInput(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxRemoveOutput(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxComplete(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxSignatures(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxInitRbf(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::TxAckRbf(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::UpdateAddHtlc(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::UpdateFulfillHtlc(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::UpdateFailHtlc(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::CommitmentSigned(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::RevokeAndAck(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::UpdateFee(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::UpdateFailMalformedHtlc(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ChannelReestablish(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ChannelAnnouncement(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::NodeAnnouncement(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ChannelUpdate(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::AnnouncementSignatures(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::QueryShortChannelIds(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ReplyShortChannelIdsEnd(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::QueryChannelRange(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::ReplyChannelRange(msg) => msg.write_to_cursor(&mut cursor),
+            Msg::GossipTimestampFilter(msg) => msg.write_to_cursor(&mut cursor),
         }
         cursor.into_bytes()
     }
@@ -71,6 +356,142 @@ impl Msg {
                 let init_msg = InitMsg::from_payload(payload).map_err(MsgFromBytesError::Init)?;
                 Msg::Init(init_msg)
             },
+            MsgType::OpenChannel => {
+                let msg = OpenChannelMsg::from_payload(payload).map_err(MsgFromBytesError::OpenChannel)?;
+                Msg::OpenChannel(msg)
+            },
+            MsgType::AcceptChannel => {
+                let msg = AcceptChannelMsg::from_payload(payload).map_err(MsgFromBytesError::AcceptChannel)?;
+                Msg::AcceptChannel(msg)
+            },
+            MsgType::FundingCreated => {
+                let msg = FundingCreatedMsg::from_payload(payload).map_err(MsgFromBytesError::FundingCreated)?;
+                Msg::FundingCreated(msg)
+            },
+            MsgType::FundingSigned => {
+                let msg = FundingSignedMsg::from_payload(payload).map_err(MsgFromBytesError::FundingSigned)?;
+                Msg::FundingSigned(msg)
+            },
+            MsgType::ChannelReady => {
+                let msg = ChannelReadyMsg::from_payload(payload).map_err(MsgFromBytesError::ChannelReady)?;
+                Msg::ChannelReady(msg)
+            },
+            MsgType::Shutdown => {
+                let msg = ShutdownMsg::from_payload(payload).map_err(MsgFromBytesError::Shutdown)?;
+                Msg::Shutdown(msg)
+            },
+            MsgType::ClosingSigned => {
+                let msg = ClosingSignedMsg::from_payload(payload).map_err(MsgFromBytesError::ClosingSigned)?;
+                Msg::ClosingSigned(msg)
+            },
+            MsgType::OpenChannel2 => {
+                let msg = OpenChannel2Msg::from_payload(payload).map_err(MsgFromBytesError::OpenChannel2)?;
+                Msg::OpenChannel2(msg)
+            },
+            MsgType::AcceptChannel2 => {
+                let msg = AcceptChannel2Msg::from_payload(payload).map_err(MsgFromBytesError::AcceptChannel2)?;
+                Msg::AcceptChannel2(msg)
+            },
+            MsgType::TxAddInput => {
+                let msg = TxAddInputMsg::from_payload(payload).map_err(MsgFromBytesError::TxAddInput)?;
+                Msg::TxAddInput(msg)
+            },
+            MsgType::TxAddOutput => {
+                let msg = TxAddOutputMsg::from_payload(payload).map_err(MsgFromBytesError::TxAddOutput)?;
+                Msg::TxAddOutput(msg)
+            },
+            MsgType::TxRemoveInput => {
+                let msg = TxRemoveInputMsg::from_payload(payload).map_err(MsgFromBytesError::TxRemoveInput)?;
+                Msg::TxRemoveInput(msg)
+            },
+            MsgType::TxRemoveOutput => {
+                let msg = TxRemoveOutputMsg::from_payload(payload).map_err(MsgFromBytesError::TxRemoveOutput)?;
+                Msg::TxRemoveOutput(msg)
+            },
+            MsgType::TxComplete => {
+                let msg = TxCompleteMsg::from_payload(payload).map_err(MsgFromBytesError::TxComplete)?;
+                Msg::TxComplete(msg)
+            },
+            MsgType::TxSignatures => {
+                let msg = TxSignaturesMsg::from_payload(payload).map_err(MsgFromBytesError::TxSignatures)?;
+                Msg::TxSignatures(msg)
+            },
+            MsgType::TxInitRbf => {
+                let msg = TxInitRbfMsg::from_payload(payload).map_err(MsgFromBytesError::TxInitRbf)?;
+                Msg::TxInitRbf(msg)
+            },
+            MsgType::TxAckRbf => {
+                let msg = TxAckRbfMsg::from_payload(payload).map_err(MsgFromBytesError::TxAckRbf)?;
+                Msg::TxAckRbf(msg)
+            },
+            MsgType::UpdateAddHtlc => {
+                let msg = UpdateAddHtlcMsg::from_payload(payload).map_err(MsgFromBytesError::UpdateAddHtlc)?;
+                Msg::UpdateAddHtlc(msg)
+            },
+            MsgType::UpdateFulfillHtlc => {
+                let msg = UpdateFulfillHtlcMsg::from_payload(payload).map_err(MsgFromBytesError::UpdateFulfillHtlc)?;
+                Msg::UpdateFulfillHtlc(msg)
+            },
+            MsgType::UpdateFailHtlc => {
+                let msg = UpdateFailHtlcMsg::from_payload(payload).map_err(MsgFromBytesError::UpdateFailHtlc)?;
+                Msg::UpdateFailHtlc(msg)
+            },
+            MsgType::CommitmentSigned => {
+                let msg = CommitmentSignedMsg::from_payload(payload).map_err(MsgFromBytesError::CommitmentSigned)?;
+                Msg::CommitmentSigned(msg)
+            },
+            MsgType::RevokeAndAck => {
+                let msg = RevokeAndAckMsg::from_payload(payload).map_err(MsgFromBytesError::RevokeAndAck)?;
+                Msg::RevokeAndAck(msg)
+            },
+            MsgType::UpdateFee => {
+                let msg = UpdateFeeMsg::from_payload(payload).map_err(MsgFromBytesError::UpdateFee)?;
+                Msg::UpdateFee(msg)
+            },
+            MsgType::UpdateFailMalformedHtlc => {
+                let msg = UpdateFailMalformedHtlcMsg::from_payload(payload).map_err(MsgFromBytesError::UpdateFailMalformedHtlc)?;
+                Msg::UpdateFailMalformedHtlc(msg)
+            },
+            MsgType::ChannelReestablish => {
+                let msg = ChannelReestablishMsg::from_payload(payload).map_err(MsgFromBytesError::ChannelReestablish)?;
+                Msg::ChannelReestablish(msg)
+            },
+            MsgType::ChannelAnnouncement => {
+                let msg = ChannelAnnouncementMsg::from_payload(payload).map_err(MsgFromBytesError::ChannelAnnouncement)?;
+                Msg::ChannelAnnouncement(msg)
+            },
+            MsgType::NodeAnnouncement => {
+                let msg = NodeAnnouncementMsg::from_payload(payload).map_err(MsgFromBytesError::NodeAnnouncement)?;
+                Msg::NodeAnnouncement(msg)
+            },
+            MsgType::ChannelUpdate => {
+                let msg = ChannelUpdateMsg::from_payload(payload).map_err(MsgFromBytesError::ChannelUpdate)?;
+                Msg::ChannelUpdate(msg)
+            },
+            MsgType::AnnouncementSignatures => {
+                let msg = AnnouncementSignaturesMsg::from_payload(payload).map_err(MsgFromBytesError::AnnouncementSignatures)?;
+                Msg::AnnouncementSignatures(msg)
+            },
+            MsgType::QueryShortChannelIds => {
+                let msg = QueryShortChannelIdsMsg::from_payload(payload).map_err(MsgFromBytesError::QueryShortChannelIds)?;
+                Msg::QueryShortChannelIds(msg)
+            },
+            MsgType::ReplyShortChannelIdsEnd => {
+                let msg = ReplyShortChannelIdsEndMsg::from_payload(payload).map_err(MsgFromBytesError::ReplyShortChannelIdsEnd)?;
+                Msg::ReplyShortChannelIdsEnd(msg)
+            },
+            MsgType::QueryChannelRange => {
+                let msg = QueryChannelRangeMsg::from_payload(payload).map_err(MsgFromBytesError::QueryChannelRange)?;
+                Msg::QueryChannelRange(msg)
+            },
+            MsgType::ReplyChannelRange => {
+                let msg = ReplyChannelRangeMsg::from_payload(payload).map_err(MsgFromBytesError::ReplyChannelRange)?;
+                Msg::ReplyChannelRange(msg)
+            },
+            MsgType::GossipTimestampFilter => {
+                let msg = GossipTimestampFilterMsg::from_payload(payload).map_err(MsgFromBytesError::GossipTimestampFilter)?;
+                Msg::GossipTimestampFilter(msg)
+            },
         };
         Ok(msg)
     }
@@ -79,4 +500,3 @@ impl Msg {
 #[derive(Debug, Fail)]
 #[fail(display = "message too short")]
 pub struct MsgTooShortError;
-
@@ -0,0 +1,399 @@
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenChannelMsg {
+    pub chain_hash: ChainHash,
+    pub temporary_channel_id: ChannelId,
+    pub funding_satoshis: u64,
+    pub push_msat: u64,
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub channel_reserve_satoshis: u64,
+    pub htlc_minimum_msat: u64,
+    pub feerate_per_kw: u32,
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+    pub funding_pubkey: secp256k1::PublicKey,
+    pub revocation_basepoint: secp256k1::PublicKey,
+    pub payment_basepoint: secp256k1::PublicKey,
+    pub delayed_payment_basepoint: secp256k1::PublicKey,
+    pub htlc_basepoint: secp256k1::PublicKey,
+    pub first_per_commitment_point: secp256k1::PublicKey,
+    pub channel_flags: u8,
+    pub upfront_shutdown_script: Option<Vec<u8>>,
+    pub channel_type: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptChannelMsg {
+    pub temporary_channel_id: ChannelId,
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub channel_reserve_satoshis: u64,
+    pub htlc_minimum_msat: u64,
+    pub minimum_depth: u32,
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+    pub funding_pubkey: secp256k1::PublicKey,
+    pub revocation_basepoint: secp256k1::PublicKey,
+    pub payment_basepoint: secp256k1::PublicKey,
+    pub delayed_payment_basepoint: secp256k1::PublicKey,
+    pub htlc_basepoint: secp256k1::PublicKey,
+    pub first_per_commitment_point: secp256k1::PublicKey,
+    pub upfront_shutdown_script: Option<Vec<u8>>,
+    pub channel_type: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingCreatedMsg {
+    pub temporary_channel_id: ChannelId,
+    pub funding_txid: [u8; 32],
+    pub funding_output_index: u16,
+    pub signature: secp256k1::Signature,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingSignedMsg {
+    pub channel_id: ChannelId,
+    pub signature: secp256k1::Signature,
+}
+
+/// Sent by each side once the funding transaction is deep enough, with the per-commitment point
+/// for its second commitment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelReadyMsg {
+    pub channel_id: ChannelId,
+    pub second_per_commitment_point: secp256k1::PublicKey,
+    pub short_channel_id_alias: Option<ShortChannelId>,
+}
+
+/// The tlv records shared by `open_channel` and `accept_channel`.
+struct OpenChannelTlvs {
+    upfront_shutdown_script: Option<Vec<u8>>,
+    channel_type: Option<Vec<u8>>,
+}
+
+impl OpenChannelTlvs {
+    fn read(cursor: &mut ReadCursor) -> Result<OpenChannelTlvs, TlvStreamError> {
+        let mut tlvs = OpenChannelTlvs {
+            upfront_shutdown_script: None,
+            channel_type: None,
+        };
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                0 => tlvs.upfront_shutdown_script = Some(record.value.to_vec()),
+                1 => tlvs.channel_type = Some(record.value.to_vec()),
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(tlvs)
+    }
+
+    fn write(cursor: &mut WriteCursor, upfront_shutdown_script: &Option<Vec<u8>>, channel_type: &Option<Vec<u8>>) {
+        if let Some(script) = upfront_shutdown_script {
+            cursor.write_tlv_record(0, script);
+        }
+        if let Some(channel_type) = channel_type {
+            cursor.write_tlv_record(1, channel_type);
+        }
+    }
+}
+
+impl OpenChannelMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<OpenChannelMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let temporary_channel_id = cursor.read_channel_id()?;
+        let funding_satoshis = cursor.read_u64()?;
+        let push_msat = cursor.read_u64()?;
+        let dust_limit_satoshis = cursor.read_u64()?;
+        let max_htlc_value_in_flight_msat = cursor.read_u64()?;
+        let channel_reserve_satoshis = cursor.read_u64()?;
+        let htlc_minimum_msat = cursor.read_u64()?;
+        let feerate_per_kw = cursor.read_u32()?;
+        let to_self_delay = cursor.read_u16()?;
+        let max_accepted_htlcs = cursor.read_u16()?;
+        let funding_pubkey = cursor.read_pub_key()?;
+        let revocation_basepoint = cursor.read_pub_key()?;
+        let payment_basepoint = cursor.read_pub_key()?;
+        let delayed_payment_basepoint = cursor.read_pub_key()?;
+        let htlc_basepoint = cursor.read_pub_key()?;
+        let first_per_commitment_point = cursor.read_pub_key()?;
+        let channel_flags = cursor.read_u8()?;
+        let tlvs = OpenChannelTlvs::read(&mut cursor)?;
+        Ok(OpenChannelMsg {
+            chain_hash,
+            temporary_channel_id,
+            funding_satoshis,
+            push_msat,
+            dust_limit_satoshis,
+            max_htlc_value_in_flight_msat,
+            channel_reserve_satoshis,
+            htlc_minimum_msat,
+            feerate_per_kw,
+            to_self_delay,
+            max_accepted_htlcs,
+            funding_pubkey,
+            revocation_basepoint,
+            payment_basepoint,
+            delayed_payment_basepoint,
+            htlc_basepoint,
+            first_per_commitment_point,
+            channel_flags,
+            upfront_shutdown_script: tlvs.upfront_shutdown_script,
+            channel_type: tlvs.channel_type,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_channel_id(&self.temporary_channel_id);
+        cursor.write_u64(self.funding_satoshis);
+        cursor.write_u64(self.push_msat);
+        cursor.write_u64(self.dust_limit_satoshis);
+        cursor.write_u64(self.max_htlc_value_in_flight_msat);
+        cursor.write_u64(self.channel_reserve_satoshis);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u32(self.feerate_per_kw);
+        cursor.write_u16(self.to_self_delay);
+        cursor.write_u16(self.max_accepted_htlcs);
+        cursor.write_pub_key(&self.funding_pubkey);
+        cursor.write_pub_key(&self.revocation_basepoint);
+        cursor.write_pub_key(&self.payment_basepoint);
+        cursor.write_pub_key(&self.delayed_payment_basepoint);
+        cursor.write_pub_key(&self.htlc_basepoint);
+        cursor.write_pub_key(&self.first_per_commitment_point);
+        cursor.write_u8(self.channel_flags);
+        OpenChannelTlvs::write(cursor, &self.upfront_shutdown_script, &self.channel_type);
+    }
+}
+
+impl AcceptChannelMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<AcceptChannelMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let temporary_channel_id = cursor.read_channel_id()?;
+        let dust_limit_satoshis = cursor.read_u64()?;
+        let max_htlc_value_in_flight_msat = cursor.read_u64()?;
+        let channel_reserve_satoshis = cursor.read_u64()?;
+        let htlc_minimum_msat = cursor.read_u64()?;
+        let minimum_depth = cursor.read_u32()?;
+        let to_self_delay = cursor.read_u16()?;
+        let max_accepted_htlcs = cursor.read_u16()?;
+        let funding_pubkey = cursor.read_pub_key()?;
+        let revocation_basepoint = cursor.read_pub_key()?;
+        let payment_basepoint = cursor.read_pub_key()?;
+        let delayed_payment_basepoint = cursor.read_pub_key()?;
+        let htlc_basepoint = cursor.read_pub_key()?;
+        let first_per_commitment_point = cursor.read_pub_key()?;
+        let tlvs = OpenChannelTlvs::read(&mut cursor)?;
+        Ok(AcceptChannelMsg {
+            temporary_channel_id,
+            dust_limit_satoshis,
+            max_htlc_value_in_flight_msat,
+            channel_reserve_satoshis,
+            htlc_minimum_msat,
+            minimum_depth,
+            to_self_delay,
+            max_accepted_htlcs,
+            funding_pubkey,
+            revocation_basepoint,
+            payment_basepoint,
+            delayed_payment_basepoint,
+            htlc_basepoint,
+            first_per_commitment_point,
+            upfront_shutdown_script: tlvs.upfront_shutdown_script,
+            channel_type: tlvs.channel_type,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.temporary_channel_id);
+        cursor.write_u64(self.dust_limit_satoshis);
+        cursor.write_u64(self.max_htlc_value_in_flight_msat);
+        cursor.write_u64(self.channel_reserve_satoshis);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u32(self.minimum_depth);
+        cursor.write_u16(self.to_self_delay);
+        cursor.write_u16(self.max_accepted_htlcs);
+        cursor.write_pub_key(&self.funding_pubkey);
+        cursor.write_pub_key(&self.revocation_basepoint);
+        cursor.write_pub_key(&self.payment_basepoint);
+        cursor.write_pub_key(&self.delayed_payment_basepoint);
+        cursor.write_pub_key(&self.htlc_basepoint);
+        cursor.write_pub_key(&self.first_per_commitment_point);
+        OpenChannelTlvs::write(cursor, &self.upfront_shutdown_script, &self.channel_type);
+    }
+}
+
+impl FundingCreatedMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<FundingCreatedMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let temporary_channel_id = cursor.read_channel_id()?;
+        let funding_txid = cursor.read_bytes32()?;
+        let funding_output_index = cursor.read_u16()?;
+        let signature = cursor.read_signature()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(FundingCreatedMsg { temporary_channel_id, funding_txid, funding_output_index, signature })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.temporary_channel_id);
+        cursor.write_slice(&self.funding_txid);
+        cursor.write_u16(self.funding_output_index);
+        cursor.write_signature(&self.signature);
+    }
+}
+
+impl FundingSignedMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<FundingSignedMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let signature = cursor.read_signature()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(FundingSignedMsg { channel_id, signature })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_signature(&self.signature);
+    }
+}
+
+impl ChannelReadyMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ChannelReadyMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let second_per_commitment_point = cursor.read_pub_key()?;
+        let mut short_channel_id_alias = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                1 => {
+                    let mut value = ReadCursor::new(record.value);
+                    let alias = value.read_short_channel_id().map_err(|_| record.malformed())?;
+                    if !value.is_empty() {
+                        return Err(record.malformed().into());
+                    }
+                    short_channel_id_alias = Some(alias);
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(ChannelReadyMsg { channel_id, second_per_commitment_point, short_channel_id_alias })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_pub_key(&self.second_per_commitment_point);
+        if let Some(alias) = self.short_channel_id_alias {
+            let mut value = WriteCursor::new();
+            value.write_short_channel_id(alias);
+            cursor.write_tlv_record(1, &value.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(msg: Msg) -> Msg {
+        unwrap!(Msg::from_bytes(&msg.to_bytes()))
+    }
+
+    #[test]
+    fn open_and_accept_channel_round_trip() {
+        let secp = Secp256k1::new();
+        let (open, accept) = test_single_funded_msgs(&secp);
+        let script = vec![0x42; 22];
+        for (upfront_shutdown_script, channel_type) in &[
+            (None, None),
+            (Some(script.clone()), None),
+            (None, open.channel_type.clone()),
+            (Some(script.clone()), open.channel_type.clone()),
+        ] {
+            let open = OpenChannelMsg {
+                upfront_shutdown_script: upfront_shutdown_script.clone(),
+                channel_type: channel_type.clone(),
+                ..open.clone()
+            };
+            let accept = AcceptChannelMsg {
+                upfront_shutdown_script: upfront_shutdown_script.clone(),
+                channel_type: channel_type.clone(),
+                ..accept.clone()
+            };
+            match round_trip(Msg::OpenChannel(open.clone())) {
+                Msg::OpenChannel(parsed) => assert_eq!(parsed, open),
+                _ => panic!("wrong message type"),
+            }
+            match round_trip(Msg::AcceptChannel(accept.clone())) {
+                Msg::AcceptChannel(parsed) => assert_eq!(parsed, accept),
+                _ => panic!("wrong message type"),
+            }
+        }
+
+        // Both records go at the end, in type order.
+        let open = OpenChannelMsg { upfront_shutdown_script: Some(script.clone()), ..open };
+        let channel_type = unwrap!(open.channel_type.clone());
+        let mut tlvs = vec![0, 22];
+        tlvs.extend_from_slice(&script);
+        tlvs.extend_from_slice(&[1, channel_type.len() as u8]);
+        tlvs.extend_from_slice(&channel_type);
+        assert!(Msg::OpenChannel(open).to_bytes().ends_with(&tlvs));
+    }
+
+    #[test]
+    fn funding_and_channel_ready_round_trip() {
+        let secp = Secp256k1::new();
+        let created = FundingCreatedMsg {
+            temporary_channel_id: ChannelId([0x77; 32]),
+            funding_txid: [0x42; 32],
+            funding_output_index: 0x0102,
+            signature: test_signature(&secp),
+        };
+        match round_trip(Msg::FundingCreated(created.clone())) {
+            Msg::FundingCreated(parsed) => assert_eq!(parsed, created),
+            _ => panic!("wrong message type"),
+        }
+        let signed = FundingSignedMsg { channel_id: ChannelId([0x43; 32]), signature: test_signature(&secp) };
+        match round_trip(Msg::FundingSigned(signed.clone())) {
+            Msg::FundingSigned(parsed) => assert_eq!(parsed, signed),
+            _ => panic!("wrong message type"),
+        }
+        for &short_channel_id_alias in &[None, Some(test_short_channel_id(600_000))] {
+            let ready = ChannelReadyMsg {
+                channel_id: ChannelId([0x43; 32]),
+                second_per_commitment_point: test_key(&secp, 1).1,
+                short_channel_id_alias,
+            };
+            match round_trip(Msg::ChannelReady(ready.clone())) {
+                Msg::ChannelReady(parsed) => assert_eq!(parsed, ready),
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_tlv_records() {
+        let secp = Secp256k1::new();
+        let (open, _) = test_single_funded_msgs(&secp);
+        let bytes = Msg::OpenChannel(open.clone()).to_bytes();
+
+        let mut odd = bytes.to_vec();
+        odd.extend_from_slice(&[3, 1, 0xff]);
+        match unwrap!(Msg::from_bytes(&odd)) {
+            Msg::OpenChannel(parsed) => assert_eq!(parsed, open),
+            _ => panic!("wrong message type"),
+        }
+
+        let mut even = bytes[2..].to_vec();
+        even.extend_from_slice(&[2, 1, 0xff]);
+        match OpenChannelMsg::from_payload(&even) {
+            Err(PayloadError::Tlv(TlvStreamError::UnknownEvenType { type_tag: 2 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenChannel2Msg {
+    pub chain_hash: ChainHash,
+    pub temporary_channel_id: ChannelId,
+    pub funding_feerate_perkw: u32,
+    pub commitment_feerate_perkw: u32,
+    pub funding_satoshis: u64,
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub htlc_minimum_msat: u64,
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+    pub locktime: u32,
+    pub funding_pubkey: secp256k1::PublicKey,
+    pub revocation_basepoint: secp256k1::PublicKey,
+    pub payment_basepoint: secp256k1::PublicKey,
+    pub delayed_payment_basepoint: secp256k1::PublicKey,
+    pub htlc_basepoint: secp256k1::PublicKey,
+    pub first_per_commitment_point: secp256k1::PublicKey,
+    pub second_per_commitment_point: secp256k1::PublicKey,
+    pub channel_flags: u8,
+    pub upfront_shutdown_script: Option<Vec<u8>>,
+    pub channel_type: Option<Vec<u8>>,
+    pub require_confirmed_inputs: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptChannel2Msg {
+    pub temporary_channel_id: ChannelId,
+    pub funding_satoshis: u64,
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub htlc_minimum_msat: u64,
+    pub minimum_depth: u32,
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+    pub funding_pubkey: secp256k1::PublicKey,
+    pub revocation_basepoint: secp256k1::PublicKey,
+    pub payment_basepoint: secp256k1::PublicKey,
+    pub delayed_payment_basepoint: secp256k1::PublicKey,
+    pub htlc_basepoint: secp256k1::PublicKey,
+    pub first_per_commitment_point: secp256k1::PublicKey,
+    pub second_per_commitment_point: secp256k1::PublicKey,
+    pub upfront_shutdown_script: Option<Vec<u8>>,
+    pub channel_type: Option<Vec<u8>>,
+    pub require_confirmed_inputs: bool,
+}
+
+/// The tlv records shared by `open_channel2` and `accept_channel2`.
+struct OpenChannel2Tlvs {
+    upfront_shutdown_script: Option<Vec<u8>>,
+    channel_type: Option<Vec<u8>>,
+    require_confirmed_inputs: bool,
+}
+
+impl OpenChannel2Tlvs {
+    fn read(cursor: &mut ReadCursor) -> Result<OpenChannel2Tlvs, TlvStreamError> {
+        let mut tlvs = OpenChannel2Tlvs {
+            upfront_shutdown_script: None,
+            channel_type: None,
+            require_confirmed_inputs: false,
+        };
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                0 => tlvs.upfront_shutdown_script = Some(record.value.to_vec()),
+                1 => tlvs.channel_type = Some(record.value.to_vec()),
+                2 => tlvs.require_confirmed_inputs = record.read_flag()?,
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(tlvs)
+    }
+
+    fn write(
+        cursor: &mut WriteCursor,
+        upfront_shutdown_script: &Option<Vec<u8>>,
+        channel_type: &Option<Vec<u8>>,
+        require_confirmed_inputs: bool,
+    ) {
+        if let Some(script) = upfront_shutdown_script {
+            cursor.write_tlv_record(0, script);
+        }
+        if let Some(channel_type) = channel_type {
+            cursor.write_tlv_record(1, channel_type);
+        }
+        if require_confirmed_inputs {
+            cursor.write_tlv_record(2, &[]);
+        }
+    }
+}
+
+impl OpenChannel2Msg {
+    pub fn from_payload(payload: &[u8]) -> Result<OpenChannel2Msg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let chain_hash = cursor.read_chain_hash()?;
+        let temporary_channel_id = cursor.read_channel_id()?;
+        let funding_feerate_perkw = cursor.read_u32()?;
+        let commitment_feerate_perkw = cursor.read_u32()?;
+        let funding_satoshis = cursor.read_u64()?;
+        let dust_limit_satoshis = cursor.read_u64()?;
+        let max_htlc_value_in_flight_msat = cursor.read_u64()?;
+        let htlc_minimum_msat = cursor.read_u64()?;
+        let to_self_delay = cursor.read_u16()?;
+        let max_accepted_htlcs = cursor.read_u16()?;
+        let locktime = cursor.read_u32()?;
+        let funding_pubkey = cursor.read_pub_key()?;
+        let revocation_basepoint = cursor.read_pub_key()?;
+        let payment_basepoint = cursor.read_pub_key()?;
+        let delayed_payment_basepoint = cursor.read_pub_key()?;
+        let htlc_basepoint = cursor.read_pub_key()?;
+        let first_per_commitment_point = cursor.read_pub_key()?;
+        let second_per_commitment_point = cursor.read_pub_key()?;
+        let channel_flags = cursor.read_u8()?;
+        let tlvs = OpenChannel2Tlvs::read(&mut cursor)?;
+        Ok(OpenChannel2Msg {
+            chain_hash,
+            temporary_channel_id,
+            funding_feerate_perkw,
+            commitment_feerate_perkw,
+            funding_satoshis,
+            dust_limit_satoshis,
+            max_htlc_value_in_flight_msat,
+            htlc_minimum_msat,
+            to_self_delay,
+            max_accepted_htlcs,
+            locktime,
+            funding_pubkey,
+            revocation_basepoint,
+            payment_basepoint,
+            delayed_payment_basepoint,
+            htlc_basepoint,
+            first_per_commitment_point,
+            second_per_commitment_point,
+            channel_flags,
+            upfront_shutdown_script: tlvs.upfront_shutdown_script,
+            channel_type: tlvs.channel_type,
+            require_confirmed_inputs: tlvs.require_confirmed_inputs,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_chain_hash(&self.chain_hash);
+        cursor.write_channel_id(&self.temporary_channel_id);
+        cursor.write_u32(self.funding_feerate_perkw);
+        cursor.write_u32(self.commitment_feerate_perkw);
+        cursor.write_u64(self.funding_satoshis);
+        cursor.write_u64(self.dust_limit_satoshis);
+        cursor.write_u64(self.max_htlc_value_in_flight_msat);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u16(self.to_self_delay);
+        cursor.write_u16(self.max_accepted_htlcs);
+        cursor.write_u32(self.locktime);
+        cursor.write_pub_key(&self.funding_pubkey);
+        cursor.write_pub_key(&self.revocation_basepoint);
+        cursor.write_pub_key(&self.payment_basepoint);
+        cursor.write_pub_key(&self.delayed_payment_basepoint);
+        cursor.write_pub_key(&self.htlc_basepoint);
+        cursor.write_pub_key(&self.first_per_commitment_point);
+        cursor.write_pub_key(&self.second_per_commitment_point);
+        cursor.write_u8(self.channel_flags);
+        OpenChannel2Tlvs::write(
+            cursor,
+            &self.upfront_shutdown_script,
+            &self.channel_type,
+            self.require_confirmed_inputs,
+        );
+    }
+}
+
+impl AcceptChannel2Msg {
+    pub fn from_payload(payload: &[u8]) -> Result<AcceptChannel2Msg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let temporary_channel_id = cursor.read_channel_id()?;
+        let funding_satoshis = cursor.read_u64()?;
+        let dust_limit_satoshis = cursor.read_u64()?;
+        let max_htlc_value_in_flight_msat = cursor.read_u64()?;
+        let htlc_minimum_msat = cursor.read_u64()?;
+        let minimum_depth = cursor.read_u32()?;
+        let to_self_delay = cursor.read_u16()?;
+        let max_accepted_htlcs = cursor.read_u16()?;
+        let funding_pubkey = cursor.read_pub_key()?;
+        let revocation_basepoint = cursor.read_pub_key()?;
+        let payment_basepoint = cursor.read_pub_key()?;
+        let delayed_payment_basepoint = cursor.read_pub_key()?;
+        let htlc_basepoint = cursor.read_pub_key()?;
+        let first_per_commitment_point = cursor.read_pub_key()?;
+        let second_per_commitment_point = cursor.read_pub_key()?;
+        let tlvs = OpenChannel2Tlvs::read(&mut cursor)?;
+        Ok(AcceptChannel2Msg {
+            temporary_channel_id,
+            funding_satoshis,
+            dust_limit_satoshis,
+            max_htlc_value_in_flight_msat,
+            htlc_minimum_msat,
+            minimum_depth,
+            to_self_delay,
+            max_accepted_htlcs,
+            funding_pubkey,
+            revocation_basepoint,
+            payment_basepoint,
+            delayed_payment_basepoint,
+            htlc_basepoint,
+            first_per_commitment_point,
+            second_per_commitment_point,
+            upfront_shutdown_script: tlvs.upfront_shutdown_script,
+            channel_type: tlvs.channel_type,
+            require_confirmed_inputs: tlvs.require_confirmed_inputs,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.temporary_channel_id);
+        cursor.write_u64(self.funding_satoshis);
+        cursor.write_u64(self.dust_limit_satoshis);
+        cursor.write_u64(self.max_htlc_value_in_flight_msat);
+        cursor.write_u64(self.htlc_minimum_msat);
+        cursor.write_u32(self.minimum_depth);
+        cursor.write_u16(self.to_self_delay);
+        cursor.write_u16(self.max_accepted_htlcs);
+        cursor.write_pub_key(&self.funding_pubkey);
+        cursor.write_pub_key(&self.revocation_basepoint);
+        cursor.write_pub_key(&self.payment_basepoint);
+        cursor.write_pub_key(&self.delayed_payment_basepoint);
+        cursor.write_pub_key(&self.htlc_basepoint);
+        cursor.write_pub_key(&self.first_per_commitment_point);
+        cursor.write_pub_key(&self.second_per_commitment_point);
+        OpenChannel2Tlvs::write(
+            cursor,
+            &self.upfront_shutdown_script,
+            &self.channel_type,
+            self.require_confirmed_inputs,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(msg: Msg) -> Msg {
+        unwrap!(Msg::from_bytes(&msg.to_bytes()))
+    }
+
+    fn test_msgs(secp: &Secp256k1<secp256k1::All>) -> (OpenChannel2Msg, AcceptChannel2Msg) {
+        let open = OpenChannel2Msg {
+            chain_hash: ChainHash::BITCOIN,
+            temporary_channel_id: ChannelId([0x77; 32]),
+            funding_feerate_perkw: 2_500,
+            commitment_feerate_perkw: 2_000,
+            funding_satoshis: 600_000,
+            dust_limit_satoshis: 546,
+            max_htlc_value_in_flight_msat: 500_000_000,
+            htlc_minimum_msat: 1_000,
+            to_self_delay: 144,
+            max_accepted_htlcs: 30,
+            locktime: 600_000,
+            funding_pubkey: test_key(secp, 1).1,
+            revocation_basepoint: test_key(secp, 2).1,
+            payment_basepoint: test_key(secp, 3).1,
+            delayed_payment_basepoint: test_key(secp, 4).1,
+            htlc_basepoint: test_key(secp, 5).1,
+            first_per_commitment_point: test_key(secp, 6).1,
+            second_per_commitment_point: test_key(secp, 7).1,
+            channel_flags: 1,
+            upfront_shutdown_script: None,
+            channel_type: None,
+            require_confirmed_inputs: false,
+        };
+        let accept = AcceptChannel2Msg {
+            temporary_channel_id: ChannelId([0x77; 32]),
+            funding_satoshis: 400_000,
+            dust_limit_satoshis: 546,
+            max_htlc_value_in_flight_msat: 300_000_000,
+            htlc_minimum_msat: 1,
+            minimum_depth: 3,
+            to_self_delay: 720,
+            max_accepted_htlcs: 483,
+            funding_pubkey: test_key(secp, 11).1,
+            revocation_basepoint: test_key(secp, 12).1,
+            payment_basepoint: test_key(secp, 13).1,
+            delayed_payment_basepoint: test_key(secp, 14).1,
+            htlc_basepoint: test_key(secp, 15).1,
+            first_per_commitment_point: test_key(secp, 16).1,
+            second_per_commitment_point: test_key(secp, 17).1,
+            upfront_shutdown_script: None,
+            channel_type: None,
+            require_confirmed_inputs: false,
+        };
+        (open, accept)
+    }
+
+    #[test]
+    fn open_and_accept_channel2_round_trip() {
+        let secp = Secp256k1::new();
+        let (open, accept) = test_msgs(&secp);
+        let channel_type = ChannelType::AnchorsZeroFeeHtlcTx.to_bytes();
+        for (upfront_shutdown_script, channel_type, require_confirmed_inputs) in vec![
+            (None, None, false),
+            (Some(vec![0x42; 22]), None, false),
+            (None, Some(channel_type.clone()), true),
+            (Some(Vec::new()), Some(channel_type.clone()), true),
+        ] {
+            let open = OpenChannel2Msg {
+                upfront_shutdown_script: upfront_shutdown_script.clone(),
+                channel_type: channel_type.clone(),
+                require_confirmed_inputs,
+                ..open.clone()
+            };
+            let accept = AcceptChannel2Msg { upfront_shutdown_script, channel_type, require_confirmed_inputs, ..accept.clone() };
+            match round_trip(Msg::OpenChannel2(open.clone())) {
+                Msg::OpenChannel2(parsed) => assert_eq!(parsed, open),
+                _ => panic!("wrong message type"),
+            }
+            match round_trip(Msg::AcceptChannel2(accept.clone())) {
+                Msg::AcceptChannel2(parsed) => assert_eq!(parsed, accept),
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_tlv_records() {
+        let secp = Secp256k1::new();
+        let (_, accept) = test_msgs(&secp);
+        let accept = AcceptChannel2Msg { require_confirmed_inputs: true, ..accept };
+        let bytes = Msg::AcceptChannel2(accept.clone()).to_bytes();
+
+        let mut odd = bytes[2..].to_vec();
+        odd.extend_from_slice(&[5, 2, 0xff, 0xff]);
+        assert_eq!(unwrap!(AcceptChannel2Msg::from_payload(&odd)), accept);
+
+        let mut even = bytes[2..].to_vec();
+        even.extend_from_slice(&[4, 0]);
+        match AcceptChannel2Msg::from_payload(&even) {
+            Err(PayloadError::Tlv(TlvStreamError::UnknownEvenType { type_tag: 4 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelReestablishMsg {
+    pub channel_id: ChannelId,
+    pub next_commitment_number: u64,
+    pub next_revocation_number: u64,
+    /// Only present if the sender supports `option_data_loss_protect`.
+    pub data_loss_protect: Option<DataLossProtect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataLossProtect {
+    pub your_last_per_commitment_secret: [u8; 32],
+    pub my_current_per_commitment_point: secp256k1::PublicKey,
+}
+
+impl ChannelReestablishMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ChannelReestablishMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let next_commitment_number = cursor.read_u64()?;
+        let next_revocation_number = cursor.read_u64()?;
+        let data_loss_protect = if cursor.is_empty() {
+            None
+        } else {
+            let your_last_per_commitment_secret = cursor.read_bytes32()?;
+            let my_current_per_commitment_point = cursor.read_pub_key()?;
+            Some(DataLossProtect { your_last_per_commitment_secret, my_current_per_commitment_point })
+        };
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(ChannelReestablishMsg {
+            channel_id, next_commitment_number, next_revocation_number, data_loss_protect,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.next_commitment_number);
+        cursor.write_u64(self.next_revocation_number);
+        if let Some(ref data_loss_protect) = self.data_loss_protect {
+            cursor.write_slice(&data_loss_protect.your_last_per_commitment_secret[..]);
+            cursor.write_pub_key(&data_loss_protect.my_current_per_commitment_point);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn channel_reestablish_round_trip() {
+        let secp = Secp256k1::new();
+        let data_loss_protect = DataLossProtect {
+            your_last_per_commitment_secret: [0x42; 32],
+            my_current_per_commitment_point: test_key(&secp, 0x43).1,
+        };
+        for data_loss_protect in vec![None, Some(data_loss_protect)] {
+            let reestablish = ChannelReestablishMsg {
+                channel_id: ChannelId([0x11; 32]),
+                next_commitment_number: 0x0102_0304_0506,
+                next_revocation_number: 0x0102_0304_0505,
+                data_loss_protect,
+            };
+            let bytes = Msg::ChannelReestablish(reestablish.clone()).to_bytes();
+            let fields_len = if reestablish.data_loss_protect.is_some() { 32 + 33 } else { 0 };
+            assert_eq!(bytes.len(), 2 + 32 + 8 + 8 + fields_len);
+            match unwrap!(Msg::from_bytes(&bytes)) {
+                Msg::ChannelReestablish(parsed) => assert_eq!(parsed, reestablish),
+                _ => panic!("wrong message type"),
+            }
+            if reestablish.data_loss_protect.is_none() {
+                continue;
+            }
+
+            // Unknown odd records can follow the data loss protection fields.
+            let mut odd = bytes.to_vec();
+            odd.extend_from_slice(&[1, 1, 0]);
+            match unwrap!(Msg::from_bytes(&odd)) {
+                Msg::ChannelReestablish(parsed) => assert_eq!(parsed, reestablish),
+                _ => panic!("wrong message type"),
+            }
+            match ChannelReestablishMsg::from_payload(&bytes[2..bytes.len() - 1]) {
+                Err(PayloadError::ReadField(_)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownMsg {
+    pub channel_id: ChannelId,
+    pub scriptpubkey: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeRange {
+    pub min_fee_satoshis: u64,
+    pub max_fee_satoshis: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosingSignedMsg {
+    pub channel_id: ChannelId,
+    pub fee_satoshis: u64,
+    pub signature: secp256k1::Signature,
+    pub fee_range: Option<FeeRange>,
+}
+
+impl ShutdownMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ShutdownMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let scriptpubkey = cursor.read_u16_len_slice()?.to_vec();
+        Ok(ShutdownMsg { channel_id, scriptpubkey })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u16_len_slice(&self.scriptpubkey);
+    }
+}
+
+impl FeeRange {
+    pub fn contains(&self, fee_satoshis: u64) -> bool {
+        self.min_fee_satoshis <= fee_satoshis && fee_satoshis <= self.max_fee_satoshis
+    }
+
+    pub fn overlap(&self, other: &FeeRange) -> Option<FeeRange> {
+        let min_fee_satoshis = cmp::max(self.min_fee_satoshis, other.min_fee_satoshis);
+        let max_fee_satoshis = cmp::min(self.max_fee_satoshis, other.max_fee_satoshis);
+        if min_fee_satoshis > max_fee_satoshis {
+            return None;
+        }
+        Some(FeeRange { min_fee_satoshis, max_fee_satoshis })
+    }
+
+    pub fn clamp(&self, fee_satoshis: u64) -> u64 {
+        cmp::min(cmp::max(fee_satoshis, self.min_fee_satoshis), self.max_fee_satoshis)
+    }
+}
+
+impl ClosingSignedMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<ClosingSignedMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let fee_satoshis = cursor.read_u64()?;
+        let signature = cursor.read_signature()?;
+        let mut fee_range = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                1 => {
+                    if record.value.len() != 16 {
+                        return Err(record.malformed().into());
+                    }
+                    let mut value = ReadCursor::new(record.value);
+                    let min_fee_satoshis = value.read_u64()?;
+                    let max_fee_satoshis = value.read_u64()?;
+                    fee_range = Some(FeeRange { min_fee_satoshis, max_fee_satoshis });
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(ClosingSignedMsg { channel_id, fee_satoshis, signature, fee_range })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.fee_satoshis);
+        cursor.write_signature(&self.signature);
+        if let Some(fee_range) = self.fee_range {
+            let mut value = WriteCursor::new();
+            value.write_u64(fee_range.min_fee_satoshis);
+            value.write_u64(fee_range.max_fee_satoshis);
+            cursor.write_tlv_record(1, &value.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(msg: Msg) -> Msg {
+        unwrap!(Msg::from_bytes(&msg.to_bytes()))
+    }
+
+    #[test]
+    fn shutdown_and_closing_signed_round_trip() {
+        let secp = Secp256k1::new();
+        for scriptpubkey in vec![Vec::new(), vec![0x42; 22], vec![0x43; 34]] {
+            let shutdown = ShutdownMsg { channel_id: ChannelId([0x11; 32]), scriptpubkey };
+            match round_trip(Msg::Shutdown(shutdown.clone())) {
+                Msg::Shutdown(parsed) => assert_eq!(parsed, shutdown),
+                _ => panic!("wrong message type"),
+            }
+        }
+
+        let fee_range = FeeRange { min_fee_satoshis: 0x0102, max_fee_satoshis: 0x0304_0506 };
+        for &fee_range in &[None, Some(fee_range)] {
+            let closing_signed = ClosingSignedMsg {
+                channel_id: ChannelId([0x11; 32]),
+                fee_satoshis: 0x0203,
+                signature: test_signature(&secp),
+                fee_range,
+            };
+            let bytes = Msg::ClosingSigned(closing_signed.clone()).to_bytes();
+            // type, channel_id, fee_satoshis, signature and then the tlv record if there is one.
+            assert_eq!(bytes.len(), 2 + 32 + 8 + 64 + if fee_range.is_some() { 18 } else { 0 });
+            match unwrap!(Msg::from_bytes(&bytes)) {
+                Msg::ClosingSigned(parsed) => assert_eq!(parsed, closing_signed),
+                _ => panic!("wrong message type"),
+            }
+        }
+    }
+
+    #[test]
+    fn closing_signed_tlv_records() {
+        let secp = Secp256k1::new();
+        let closing_signed = ClosingSignedMsg {
+            channel_id: ChannelId([0x11; 32]),
+            fee_satoshis: 1000,
+            signature: test_signature(&secp),
+            fee_range: None,
+        };
+        let bytes = Msg::ClosingSigned(closing_signed.clone()).to_bytes();
+
+        let mut odd = bytes.to_vec();
+        odd.extend_from_slice(&[3, 1, 0]);
+        match unwrap!(Msg::from_bytes(&odd)) {
+            Msg::ClosingSigned(parsed) => assert_eq!(parsed, closing_signed),
+            _ => panic!("wrong message type"),
+        }
+
+        let mut even = bytes.to_vec();
+        even.extend_from_slice(&[2, 1, 0]);
+        match ClosingSignedMsg::from_payload(&even[2..]) {
+            Err(PayloadError::Tlv(TlvStreamError::UnknownEvenType { type_tag: 2 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let mut short_range = bytes.to_vec();
+        short_range.extend_from_slice(&[1, 8, 0, 0, 0, 0, 0, 0, 0, 1]);
+        match ClosingSignedMsg::from_payload(&short_range[2..]) {
+            Err(PayloadError::Tlv(TlvStreamError::MalformedValue { type_tag: 1 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
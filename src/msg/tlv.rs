@@ -0,0 +1,194 @@
+use super::*;
+
+#[derive(Debug, Fail)]
+pub enum TlvStreamError {
+    #[fail(display = "{}", _0)]
+    MsgTooShort(MsgTooShortError),
+    #[fail(display = "bigsize value was not minimally encoded")]
+    NonMinimalBigSize,
+    #[fail(display = "tlv record types are not strictly increasing")]
+    TypesNotIncreasing,
+    #[fail(display = "unknown even tlv record type ({})", type_tag)]
+    UnknownEvenType {
+        type_tag: u64,
+    },
+    #[fail(display = "malformed value for tlv record type {}", type_tag)]
+    MalformedValue {
+        type_tag: u64,
+    },
+}
+
+impl From<MsgTooShortError> for TlvStreamError {
+    fn from(err: MsgTooShortError) -> TlvStreamError {
+        TlvStreamError::MsgTooShort(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TlvRecord<'a> {
+    pub type_tag: u64,
+    pub value: &'a [u8],
+}
+
+impl<'a> TlvRecord<'a> {
+    /// Fails with `UnknownEvenType` if the record is even. Call this for every record the caller
+    /// doesn't understand.
+    pub fn ignore_if_odd(&self) -> Result<(), TlvStreamError> {
+        if self.type_tag % 2 == 0 {
+            return Err(TlvStreamError::UnknownEvenType { type_tag: self.type_tag });
+        }
+        Ok(())
+    }
+
+    pub fn malformed(&self) -> TlvStreamError {
+        TlvStreamError::MalformedValue { type_tag: self.type_tag }
+    }
+
+    pub fn read_tu64(&self) -> Result<u64, TlvStreamError> {
+        read_tu64(self.value).ok_or_else(|| self.malformed())
+    }
+
+    /// Reads a record whose presence is the whole value, so its value must be empty.
+    pub fn read_flag(&self) -> Result<bool, TlvStreamError> {
+        if !self.value.is_empty() {
+            return Err(self.malformed());
+        }
+        Ok(true)
+    }
+
+    pub fn read_u64(&self) -> Result<u64, TlvStreamError> {
+        if self.value.len() != 8 {
+            return Err(self.malformed());
+        }
+        Ok(u64::from_be_bytes(slice_to_array!(self.value, 8)))
+    }
+}
+
+impl<'a> ReadCursor<'a> {
+    pub fn read_bigsize(&mut self) -> Result<u64, TlvStreamError> {
+        let first = self.read_u8()?;
+        let (val, min) = match first {
+            0xfd => (self.read_u16()? as u64, 0xfd),
+            0xfe => (self.read_u32()? as u64, 0x1_0000),
+            0xff => (self.read_u64()?, 0x1_0000_0000),
+            _ => return Ok(first as u64),
+        };
+        if val < min {
+            return Err(TlvStreamError::NonMinimalBigSize);
+        }
+        Ok(val)
+    }
+
+    /// Reads a tlv stream from the remainder of the cursor. Records are returned in order and
+    /// are not interpreted. The caller is responsible for rejecting unknown even types.
+    pub fn read_tlv_stream(&mut self) -> Result<SmallVec<[TlvRecord<'a>; 4]>, TlvStreamError> {
+        let mut records: SmallVec<[TlvRecord<'a>; 4]> = SmallVec::new();
+        while !self.is_empty() {
+            let type_tag = self.read_bigsize()?;
+            if let Some(last) = records.last() {
+                if type_tag <= last.type_tag {
+                    return Err(TlvStreamError::TypesNotIncreasing);
+                }
+            }
+            let len = self.read_bigsize()?;
+            let value = self.read_slice(len as usize)?;
+            records.push(TlvRecord { type_tag, value });
+        }
+        Ok(records)
+    }
+}
+
+impl WriteCursor {
+    pub fn write_bigsize(&mut self, val: u64) {
+        if val < 0xfd {
+            self.write_u8(val as u8);
+        } else if val < 0x1_0000 {
+            self.write_u8(0xfd);
+            self.write_u16(val as u16);
+        } else if val < 0x1_0000_0000 {
+            self.write_u8(0xfe);
+            self.write_u32(val as u32);
+        } else {
+            self.write_u8(0xff);
+            self.write_u64(val);
+        }
+    }
+
+    /// Records must be written in strictly increasing type order.
+    pub fn write_tlv_record(&mut self, type_tag: u64, value: &[u8]) {
+        self.write_bigsize(type_tag);
+        self.write_bigsize(value.len() as u64);
+        self.write_slice(value);
+    }
+
+    pub fn write_tlv_tu64(&mut self, type_tag: u64, val: u64) {
+        let bytes = u64::to_be_bytes(val);
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        self.write_tlv_record(type_tag, &bytes[skip..]);
+    }
+}
+
+/// Decodes a truncated integer, rejecting leading zero bytes.
+pub fn read_tu64(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() > 8 {
+        return None;
+    }
+    if bytes.first() == Some(&0) {
+        return None;
+    }
+    let mut val = 0u64;
+    for byte in bytes {
+        val = (val << 8) | *byte as u64;
+    }
+    Some(val)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn bigsize_round_trip() {
+        let cases: &[(u64, &[u8])] = &[
+            (0, &hex!("00")),
+            (252, &hex!("fc")),
+            (253, &hex!("fd00fd")),
+            (65535, &hex!("fdffff")),
+            (65536, &hex!("fe00010000")),
+            (4294967295, &hex!("feffffffff")),
+            (4294967296, &hex!("ff0000000100000000")),
+            (18446744073709551615, &hex!("ffffffffffffffffff")),
+        ];
+        for (val, bytes) in cases {
+            let mut cursor = WriteCursor::new();
+            cursor.write_bigsize(*val);
+            assert_eq!(&cursor.into_bytes()[..], *bytes);
+
+            let mut cursor = ReadCursor::new(bytes);
+            assert_eq!(unwrap!(cursor.read_bigsize()), *val);
+        }
+    }
+
+    #[test]
+    fn bigsize_rejects_non_minimal() {
+        for bytes in &[&hex!("fd00fc")[..], &hex!("fe0000ffff")[..], &hex!("ff00000000ffffffff")[..]] {
+            let mut cursor = ReadCursor::new(bytes);
+            match cursor.read_bigsize() {
+                Err(TlvStreamError::NonMinimalBigSize) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn tlv_stream_rejects_unordered_types() {
+        let bytes = hex!("02000100");
+        let mut cursor = ReadCursor::new(&bytes);
+        let res = cursor.read_tlv_stream();
+        match res {
+            Err(TlvStreamError::TypesNotIncreasing) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
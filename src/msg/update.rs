@@ -0,0 +1,345 @@
+use super::*;
+
+pub const ONION_PACKET_LEN: usize = 1366;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateAddHtlcMsg {
+    pub channel_id: ChannelId,
+    pub id: u64,
+    pub amount_msat: u64,
+    pub payment_hash: [u8; 32],
+    pub cltv_expiry: u32,
+    /// Always `ONION_PACKET_LEN` bytes.
+    pub onion_routing_packet: Vec<u8>,
+    pub blinding_point: Option<secp256k1::PublicKey>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateFulfillHtlcMsg {
+    pub channel_id: ChannelId,
+    pub id: u64,
+    pub payment_preimage: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateFailHtlcMsg {
+    pub channel_id: ChannelId,
+    pub id: u64,
+    pub reason: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateFailMalformedHtlcMsg {
+    pub channel_id: ChannelId,
+    pub id: u64,
+    pub sha256_of_onion: [u8; 32],
+    pub failure_code: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitmentSignedMsg {
+    pub channel_id: ChannelId,
+    pub signature: secp256k1::Signature,
+    pub htlc_signatures: Vec<secp256k1::Signature>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevokeAndAckMsg {
+    pub channel_id: ChannelId,
+    pub per_commitment_secret: [u8; 32],
+    pub next_per_commitment_point: secp256k1::PublicKey,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateFeeMsg {
+    pub channel_id: ChannelId,
+    pub feerate_per_kw: u32,
+}
+
+impl UpdateAddHtlcMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<UpdateAddHtlcMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let id = cursor.read_u64()?;
+        let amount_msat = cursor.read_u64()?;
+        let payment_hash = cursor.read_bytes32()?;
+        let cltv_expiry = cursor.read_u32()?;
+        let onion_routing_packet = cursor.read_slice(ONION_PACKET_LEN)?.to_vec();
+        let mut blinding_point = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                0 => {
+                    let mut value = ReadCursor::new(record.value);
+                    blinding_point = Some(value.read_pub_key()?);
+                    if !value.is_empty() {
+                        return Err(record.malformed().into());
+                    }
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(UpdateAddHtlcMsg {
+            channel_id, id, amount_msat, payment_hash, cltv_expiry, onion_routing_packet,
+            blinding_point,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.id);
+        cursor.write_u64(self.amount_msat);
+        cursor.write_slice(&self.payment_hash[..]);
+        cursor.write_u32(self.cltv_expiry);
+        cursor.write_slice(&self.onion_routing_packet);
+        if let Some(blinding_point) = self.blinding_point {
+            cursor.write_tlv_record(0, &blinding_point.serialize()[..]);
+        }
+    }
+}
+
+impl UpdateFulfillHtlcMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<UpdateFulfillHtlcMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let id = cursor.read_u64()?;
+        let payment_preimage = cursor.read_bytes32()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(UpdateFulfillHtlcMsg { channel_id, id, payment_preimage })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.id);
+        cursor.write_slice(&self.payment_preimage[..]);
+    }
+}
+
+impl UpdateFailHtlcMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<UpdateFailHtlcMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let id = cursor.read_u64()?;
+        let reason = cursor.read_u16_len_slice()?.to_vec();
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(UpdateFailHtlcMsg { channel_id, id, reason })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.id);
+        cursor.write_u16_len_slice(&self.reason);
+    }
+}
+
+impl UpdateFailMalformedHtlcMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<UpdateFailMalformedHtlcMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let id = cursor.read_u64()?;
+        let sha256_of_onion = cursor.read_bytes32()?;
+        let failure_code = cursor.read_u16()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(UpdateFailMalformedHtlcMsg { channel_id, id, sha256_of_onion, failure_code })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u64(self.id);
+        cursor.write_slice(&self.sha256_of_onion[..]);
+        cursor.write_u16(self.failure_code);
+    }
+}
+
+impl CommitmentSignedMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<CommitmentSignedMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let signature = cursor.read_signature()?;
+        let num_htlcs = cursor.read_u16()?;
+        let mut htlc_signatures = Vec::with_capacity(num_htlcs as usize);
+        for _ in 0..num_htlcs {
+            htlc_signatures.push(cursor.read_signature()?);
+        }
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(CommitmentSignedMsg { channel_id, signature, htlc_signatures })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_signature(&self.signature);
+        cursor.write_u16(self.htlc_signatures.len() as u16);
+        for htlc_signature in &self.htlc_signatures {
+            cursor.write_signature(htlc_signature);
+        }
+    }
+}
+
+impl RevokeAndAckMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<RevokeAndAckMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let per_commitment_secret = cursor.read_bytes32()?;
+        let next_per_commitment_point = cursor.read_pub_key()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(RevokeAndAckMsg { channel_id, per_commitment_secret, next_per_commitment_point })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_slice(&self.per_commitment_secret[..]);
+        cursor.write_pub_key(&self.next_per_commitment_point);
+    }
+}
+
+impl UpdateFeeMsg {
+    pub fn from_payload(payload: &[u8]) -> Result<UpdateFeeMsg, PayloadError> {
+        let mut cursor = ReadCursor::new(payload);
+        let channel_id = cursor.read_channel_id()?;
+        let feerate_per_kw = cursor.read_u32()?;
+        for record in cursor.read_tlv_stream()? {
+            record.ignore_if_odd()?;
+        }
+        Ok(UpdateFeeMsg { channel_id, feerate_per_kw })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u32(self.feerate_per_kw);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(msg: Msg) -> Msg {
+        unwrap!(Msg::from_bytes(&msg.to_bytes()))
+    }
+
+    fn test_update_add_htlc(blinding_point: Option<secp256k1::PublicKey>) -> UpdateAddHtlcMsg {
+        UpdateAddHtlcMsg {
+            channel_id: ChannelId([0x11; 32]),
+            id: 7,
+            amount_msat: 0x0102_0304_0506,
+            payment_hash: [0x42; 32],
+            cltv_expiry: 600_144,
+            onion_routing_packet: (0..ONION_PACKET_LEN).map(|i| i as u8).collect(),
+            blinding_point,
+        }
+    }
+
+    #[test]
+    fn update_add_htlc_round_trip() {
+        let secp = Secp256k1::new();
+        for &blinding_point in &[None, Some(test_key(&secp, 0x22).1)] {
+            let add = test_update_add_htlc(blinding_point);
+            let bytes = Msg::UpdateAddHtlc(add.clone()).to_bytes();
+            // type, channel_id, id, amount_msat, payment_hash, cltv_expiry, the onion and then the
+            // blinding point record if there is one.
+            let tlv_len = if blinding_point.is_some() { 2 + 33 } else { 0 };
+            assert_eq!(bytes.len(), 2 + 32 + 8 + 8 + 32 + 4 + ONION_PACKET_LEN + tlv_len);
+            match unwrap!(Msg::from_bytes(&bytes)) {
+                Msg::UpdateAddHtlc(parsed) => assert_eq!(parsed, add),
+                _ => panic!("wrong message type"),
+            }
+        }
+
+        let bytes = Msg::UpdateAddHtlc(test_update_add_htlc(None)).to_bytes();
+        let mut odd = bytes.to_vec();
+        odd.extend_from_slice(&[1, 1, 0]);
+        match unwrap!(Msg::from_bytes(&odd)) {
+            Msg::UpdateAddHtlc(parsed) => assert_eq!(parsed, test_update_add_htlc(None)),
+            _ => panic!("wrong message type"),
+        }
+        let mut long_point = bytes.to_vec();
+        long_point.extend_from_slice(&[0, 34]);
+        long_point.extend_from_slice(&test_key(&secp, 0x22).1.serialize()[..]);
+        long_point.push(0);
+        match UpdateAddHtlcMsg::from_payload(&long_point[2..]) {
+            Err(PayloadError::Tlv(TlvStreamError::MalformedValue { type_tag: 0 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut even = bytes.to_vec();
+        even.extend_from_slice(&[2, 1, 0]);
+        match UpdateAddHtlcMsg::from_payload(&even[2..]) {
+            Err(PayloadError::Tlv(TlvStreamError::UnknownEvenType { type_tag: 2 })) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn htlc_resolution_and_fee_round_trip() {
+        let fulfill = UpdateFulfillHtlcMsg {
+            channel_id: ChannelId([0x11; 32]),
+            id: 0x0102_0304,
+            payment_preimage: [0x42; 32],
+        };
+        match round_trip(Msg::UpdateFulfillHtlc(fulfill.clone())) {
+            Msg::UpdateFulfillHtlc(parsed) => assert_eq!(parsed, fulfill),
+            _ => panic!("wrong message type"),
+        }
+
+        for reason in vec![Vec::new(), vec![0x43; 292]] {
+            let fail = UpdateFailHtlcMsg { channel_id: ChannelId([0x11; 32]), id: 3, reason };
+            match round_trip(Msg::UpdateFailHtlc(fail.clone())) {
+                Msg::UpdateFailHtlc(parsed) => assert_eq!(parsed, fail),
+                _ => panic!("wrong message type"),
+            }
+        }
+
+        let fail_malformed = UpdateFailMalformedHtlcMsg {
+            channel_id: ChannelId([0x11; 32]),
+            id: 4,
+            sha256_of_onion: [0x44; 32],
+            failure_code: 0xc005,
+        };
+        match round_trip(Msg::UpdateFailMalformedHtlc(fail_malformed.clone())) {
+            Msg::UpdateFailMalformedHtlc(parsed) => assert_eq!(parsed, fail_malformed),
+            _ => panic!("wrong message type"),
+        }
+
+        let update_fee = UpdateFeeMsg { channel_id: ChannelId([0x11; 32]), feerate_per_kw: 0x0102_0304 };
+        let bytes = Msg::UpdateFee(update_fee.clone()).to_bytes();
+        assert_eq!(bytes.len(), 2 + 32 + 4);
+        match unwrap!(Msg::from_bytes(&bytes)) {
+            Msg::UpdateFee(parsed) => assert_eq!(parsed, update_fee),
+            _ => panic!("wrong message type"),
+        }
+    }
+
+    #[test]
+    fn commitment_signed_and_revoke_and_ack_round_trip() {
+        let secp = Secp256k1::new();
+        for &num_htlcs in &[0, 3] {
+            let commitment_signed = CommitmentSignedMsg {
+                channel_id: ChannelId([0x11; 32]),
+                signature: test_signature(&secp),
+                htlc_signatures: vec![test_signature(&secp); num_htlcs],
+            };
+            match round_trip(Msg::CommitmentSigned(commitment_signed.clone())) {
+                Msg::CommitmentSigned(parsed) => assert_eq!(parsed, commitment_signed),
+                _ => panic!("wrong message type"),
+            }
+        }
+
+        let revoke_and_ack = RevokeAndAckMsg {
+            channel_id: ChannelId([0x11; 32]),
+            per_commitment_secret: [0x45; 32],
+            next_per_commitment_point: test_key(&secp, 0x46).1,
+        };
+        match round_trip(Msg::RevokeAndAck(revoke_and_ack.clone())) {
+            Msg::RevokeAndAck(parsed) => assert_eq!(parsed, revoke_and_ack),
+            _ => panic!("wrong message type"),
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use super::*;
+
+/// Channels whose oldest `channel_update` is older than this may be pruned.
+pub const STALE_CHANNEL_AGE_SECS: u32 = 14 * 24 * 60 * 60;
+
+/// The routing policy one side of a channel has announced for forwarding through it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelPolicy {
+    pub timestamp: u32,
+    pub disabled: bool,
+    pub cltv_expiry_delta: u16,
+    pub htlc_minimum_msat: u64,
+    pub htlc_maximum_msat: Option<u64>,
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    /// The update the policy came from, for relaying and answering gossip queries. `None` if
+    /// the policy came from a snapshot.
+    pub last_update: Option<ChannelUpdateMsg>,
+    /// When we received the policy, by our own clock. `timestamp` is set by the channel's node
+    /// and can be arbitrarily old, so incremental snapshots go by this instead.
+    pub received_at: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub short_channel_id: ShortChannelId,
+    /// `None` if the channel came from a snapshot.
+    pub announcement: Option<ChannelAnnouncementMsg>,
+    pub features: Vec<u8>,
+    pub node_id_1: NodeId,
+    pub node_id_2: NodeId,
+    /// Indexed by the direction bit of the `channel_update`, ie. `policies[0]` is set by
+    /// `node_id_1` and applies to payments forwarded from `node_id_1` to `node_id_2`.
+    pub policies: [Option<ChannelPolicy>; 2],
+    /// When we learnt about the channel. Stands in for the timestamp of missing updates when
+    /// pruning.
+    pub announced_at: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub channels: BTreeSet<ShortChannelId>,
+    pub announcement: Option<NodeAnnouncementMsg>,
+}
+
+#[derive(Debug, Fail)]
+pub enum NetworkGraphError {
+    #[fail(display = "invalid gossip: {}", _0)]
+    Invalid(GossipValidationError),
+    #[fail(display = "unknown channel {}", short_channel_id)]
+    UnknownChannel {
+        short_channel_id: ShortChannelId,
+    },
+    #[fail(display = "node {} has no announced channels", node_id)]
+    UnknownNode {
+        node_id: NodeId,
+    },
+    #[fail(display = "{} is not a valid node id", node_id)]
+    InvalidNodeId {
+        node_id: NodeId,
+    },
+    #[fail(display = "channel {} has already been announced", short_channel_id)]
+    DuplicateChannel {
+        short_channel_id: ShortChannelId,
+    },
+    #[fail(display = "{} is not a channel direction", direction)]
+    InvalidDirection {
+        direction: u8,
+    },
+}
+
+impl From<GossipValidationError> for NetworkGraphError {
+    fn from(err: GossipValidationError) -> NetworkGraphError {
+        NetworkGraphError::Invalid(err)
+    }
+}
+
+impl NetworkGraphError {
+    /// Whether the peer that relayed the gossip should be penalised for it. Gossip about unknown
+    /// channels or nodes can be explained by it arriving out of order.
+    pub fn is_misbehaviour(&self) -> bool {
+        match self {
+            NetworkGraphError::Invalid(err) => err.is_misbehaviour(),
+            _ => false,
+        }
+    }
+}
+
+impl ChannelPolicy {
+    fn from_update(update: &ChannelUpdateMsg, received_at: u32) -> ChannelPolicy {
+        ChannelPolicy {
+            timestamp: update.timestamp,
+            disabled: update.is_disabled(),
+            cltv_expiry_delta: update.cltv_expiry_delta,
+            htlc_minimum_msat: update.htlc_minimum_msat,
+            htlc_maximum_msat: update.htlc_maximum_msat,
+            fee_base_msat: update.fee_base_msat,
+            fee_proportional_millionths: update.fee_proportional_millionths,
+            last_update: Some(update.clone()),
+            received_at,
+        }
+    }
+
+    /// The fee charged for forwarding `amount_msat` through the channel.
+    pub fn fee_msat(&self, amount_msat: u64) -> u64 {
+        let proportional = amount_msat as u128 * self.fee_proportional_millionths as u128 / 1_000_000;
+        self.fee_base_msat as u64 + proportional as u64
+    }
+}
+
+impl ChannelInfo {
+    pub fn node_id(&self, direction: u8) -> &NodeId {
+        if direction == 0 {
+            &self.node_id_1
+        } else {
+            &self.node_id_2
+        }
+    }
+
+    /// The direction of payments forwarded by `node_id` through this channel, if it's one of the
+    /// channel's endpoints.
+    pub fn direction_from(&self, node_id: &NodeId) -> Option<u8> {
+        if *node_id == self.node_id_1 {
+            Some(0)
+        } else if *node_id == self.node_id_2 {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    pub fn policy(&self, direction: u8) -> Option<&ChannelPolicy> {
+        self.policies.get(direction as usize)?.as_ref()
+    }
+
+    fn oldest_update_timestamp(&self) -> u32 {
+        let timestamp = |direction: usize| {
+            self.policies[direction]
+            .as_ref()
+            .map(|policy| policy.timestamp)
+            .unwrap_or(self.announced_at)
+        };
+        cmp::min(timestamp(0), timestamp(1))
+    }
+}
+
+/// The public channel graph, as learnt from gossip.
+pub struct NetworkGraph {
+    secp: Secp256k1<secp256k1::All>,
+    chain_hash: ChainHash,
+    channels: BTreeMap<ShortChannelId, ChannelInfo>,
+    nodes: HashMap<NodeId, NodeInfo>,
+}
+
+impl NetworkGraph {
+    pub fn new(chain_hash: ChainHash) -> NetworkGraph {
+        NetworkGraph {
+            secp: Secp256k1::new(),
+            chain_hash,
+            channels: BTreeMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    pub fn chain_hash(&self) -> &ChainHash {
+        &self.chain_hash
+    }
+
+    /// Adds an announced channel once its signatures check out. The caller is responsible for
+    /// checking that the funding output exists and is unspent. `now` is the current unix time.
+    pub fn handle_channel_announcement(
+        &mut self,
+        msg: &ChannelAnnouncementMsg,
+        now: u32,
+    ) -> Result<(), NetworkGraphError> {
+        let short_channel_id = msg.short_channel_id;
+        if self.channels.contains_key(&short_channel_id) {
+            return Err(NetworkGraphError::DuplicateChannel { short_channel_id });
+        }
+        msg.validate(&self.secp, &self.chain_hash)?;
+        self.insert_channel(ChannelInfo {
+            short_channel_id,
+            announcement: Some(msg.clone()),
+            features: msg.features.clone(),
+            node_id_1: NodeId::from_pub_key(&msg.node_id_1),
+            node_id_2: NodeId::from_pub_key(&msg.node_id_2),
+            policies: [None, None],
+            announced_at: now,
+        });
+        Ok(())
+    }
+
+    /// Adds a channel we haven't seen the signed announcement for, eg. from a snapshot served by
+    /// a node we trust. The node ids must be valid public keys, in ascending order.
+    pub fn add_unverified_channel(
+        &mut self,
+        short_channel_id: ShortChannelId,
+        node_id_1: NodeId,
+        node_id_2: NodeId,
+        features: Vec<u8>,
+        now: u32,
+    ) -> Result<(), NetworkGraphError> {
+        if self.channels.contains_key(&short_channel_id) {
+            return Err(NetworkGraphError::DuplicateChannel { short_channel_id });
+        }
+        if node_id_1 >= node_id_2 {
+            return Err(GossipValidationError::NodeIdsOutOfOrder.into());
+        }
+        for node_id in &[node_id_1, node_id_2] {
+            if node_id.to_pub_key().is_err() {
+                return Err(NetworkGraphError::InvalidNodeId { node_id: *node_id });
+            }
+        }
+        self.insert_channel(ChannelInfo {
+            short_channel_id,
+            announcement: None,
+            features,
+            node_id_1,
+            node_id_2,
+            policies: [None, None],
+            announced_at: now,
+        });
+        Ok(())
+    }
+
+    fn insert_channel(&mut self, channel: ChannelInfo) {
+        for node_id in &[channel.node_id_1, channel.node_id_2] {
+            let node = self.nodes.entry(*node_id).or_insert_with(|| NodeInfo {
+                channels: BTreeSet::new(),
+                announcement: None,
+            });
+            node.channels.insert(channel.short_channel_id);
+        }
+        self.channels.insert(channel.short_channel_id, channel);
+    }
+
+    /// Updates a channel's policy in one direction. `now` is the current unix time.
+    pub fn handle_channel_update(&mut self, msg: &ChannelUpdateMsg, now: u32) -> Result<(), NetworkGraphError> {
+        let short_channel_id = msg.short_channel_id;
+        let channel = match self.channels.get_mut(&short_channel_id) {
+            Some(channel) => channel,
+            None => return Err(NetworkGraphError::UnknownChannel { short_channel_id }),
+        };
+        let direction = msg.direction();
+        let previous_timestamp = channel.policy(direction).map(|policy| policy.timestamp);
+        // Node ids are checked to be valid keys when channels are added.
+        let node_id = unwrap!(channel.node_id(direction).to_pub_key());
+        msg.validate_signed_by(&self.secp, &self.chain_hash, &node_id, previous_timestamp)?;
+        channel.policies[direction as usize] = Some(ChannelPolicy::from_update(msg, now));
+        Ok(())
+    }
+
+    /// Sets a policy we haven't seen the signed update for, eg. from a snapshot. `direction` must be
+    /// 0 or 1. A policy which came from a signed update is kept: we can't tell whether it's older
+    /// than the unverified one, and it's the one we can relay.
+    pub fn set_unverified_policy(
+        &mut self,
+        short_channel_id: ShortChannelId,
+        direction: u8,
+        policy: ChannelPolicy,
+    ) -> Result<(), NetworkGraphError> {
+        if direction > 1 {
+            return Err(NetworkGraphError::InvalidDirection { direction });
+        }
+        let channel = match self.channels.get_mut(&short_channel_id) {
+            Some(channel) => channel,
+            None => return Err(NetworkGraphError::UnknownChannel { short_channel_id }),
+        };
+        let current = &mut channel.policies[direction as usize];
+        if current.as_ref().map_or(false, |current| current.last_update.is_some()) {
+            return Ok(());
+        }
+        *current = Some(policy);
+        Ok(())
+    }
+
+    /// Node announcements are only accepted for nodes with at least one announced channel.
+    pub fn handle_node_announcement(&mut self, msg: &NodeAnnouncementMsg) -> Result<(), NetworkGraphError> {
+        let node_id = NodeId::from_pub_key(&msg.node_id);
+        let node = match self.nodes.get_mut(&node_id) {
+            Some(node) => node,
+            None => return Err(NetworkGraphError::UnknownNode { node_id }),
+        };
+        let previous_timestamp = node.announcement.as_ref().map(|announcement| announcement.timestamp);
+        msg.validate(&self.secp, previous_timestamp)?;
+        node.announcement = Some(msg.clone());
+        Ok(())
+    }
+
+    /// Removes a channel, eg. because its funding output was spent. Nodes left without channels
+    /// are forgotten too.
+    pub fn remove_channel(&mut self, short_channel_id: ShortChannelId) -> Option<ChannelInfo> {
+        let channel = self.channels.remove(&short_channel_id)?;
+        for node_id in &[channel.node_id_1, channel.node_id_2] {
+            let remove_node = match self.nodes.get_mut(node_id) {
+                Some(node) => {
+                    node.channels.remove(&short_channel_id);
+                    node.channels.is_empty()
+                },
+                None => false,
+            };
+            if remove_node {
+                self.nodes.remove(node_id);
+            }
+        }
+        Some(channel)
+    }
+
+    /// Prunes channels whose oldest update is more than two weeks older than `now`. Returns the
+    /// removed channels.
+    pub fn remove_stale_channels(&mut self, now: u32) -> Vec<ShortChannelId> {
+        let cutoff = now.saturating_sub(STALE_CHANNEL_AGE_SECS);
+        let stale: Vec<ShortChannelId> = {
+            self.channels
+            .values()
+            .filter(|channel| channel.oldest_update_timestamp() < cutoff)
+            .map(|channel| channel.short_channel_id)
+            .collect()
+        };
+        for short_channel_id in &stale {
+            self.remove_channel(*short_channel_id);
+        }
+        stale
+    }
+
+    pub fn channel(&self, short_channel_id: ShortChannelId) -> Option<&ChannelInfo> {
+        self.channels.get(&short_channel_id)
+    }
+
+    pub fn node(&self, node_id: &NodeId) -> Option<&NodeInfo> {
+        self.nodes.get(node_id)
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &ChannelInfo> {
+        self.channels.values()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (&NodeId, &NodeInfo)> {
+        self.nodes.iter()
+    }
+
+    /// The channels of a node, in order of short channel id.
+    pub fn node_channels<'a>(&'a self, node_id: &NodeId) -> impl Iterator<Item = &'a ChannelInfo> + 'a {
+        let channels = self.nodes.get(node_id).map(|node| &node.channels);
+        channels
+        .into_iter()
+        .flat_map(|channels| channels.iter())
+        .filter_map(move |short_channel_id| self.channels.get(short_channel_id))
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+impl GossipQuerySource for NetworkGraph {
+    fn channels_in_range(&self, first_blocknum: u32, end_blocknum: u64) -> Vec<ChannelRangeEntry> {
+        let start = ShortChannelId { block_height: first_blocknum, tx_index: 0, output_index: 0 };
+        self.channels
+        .range(start..)
+        .take_while(|(short_channel_id, _)| (short_channel_id.block_height as u64) < end_blocknum)
+        .map(|(short_channel_id, channel)| {
+            let timestamp = |direction| channel.policy(direction).map(|policy| policy.timestamp).unwrap_or(0);
+            let checksum = |direction| {
+                channel.policy(direction)
+                .and_then(|policy| policy.last_update.as_ref())
+                .map(channel_update_checksum)
+                .unwrap_or(0)
+            };
+            ChannelRangeEntry {
+                short_channel_id: *short_channel_id,
+                timestamps: Some(ChannelUpdateTimestamps {
+                    timestamp_node_id_1: timestamp(0),
+                    timestamp_node_id_2: timestamp(1),
+                }),
+                checksums: Some(ChannelUpdateChecksums {
+                    checksum_node_id_1: checksum(0),
+                    checksum_node_id_2: checksum(1),
+                }),
+            }
+        })
+        .collect()
+    }
+
+    fn channel_gossip(&self, short_channel_id: ShortChannelId, query_flags: u64) -> Vec<Msg> {
+        let channel = match self.channels.get(&short_channel_id) {
+            Some(channel) => channel,
+            None => return Vec::new(),
+        };
+        let mut msgs = Vec::new();
+        if query_flags & QUERY_FLAG_CHANNEL_ANNOUNCEMENT != 0 {
+            if let Some(ref announcement) = channel.announcement {
+                msgs.push(Msg::ChannelAnnouncement(announcement.clone()));
+            }
+        }
+        let update_flags = [QUERY_FLAG_CHANNEL_UPDATE_1, QUERY_FLAG_CHANNEL_UPDATE_2];
+        let node_flags = [QUERY_FLAG_NODE_ANNOUNCEMENT_1, QUERY_FLAG_NODE_ANNOUNCEMENT_2];
+        for direction in 0..2u8 {
+            if query_flags & update_flags[direction as usize] == 0 {
+                continue;
+            }
+            if let Some(update) = channel.policy(direction).and_then(|policy| policy.last_update.as_ref()) {
+                msgs.push(Msg::ChannelUpdate(update.clone()));
+            }
+        }
+        for direction in 0..2u8 {
+            if query_flags & node_flags[direction as usize] == 0 {
+                continue;
+            }
+            let announcement = {
+                self.nodes
+                .get(channel.node_id(direction))
+                .and_then(|node| node.announcement.as_ref())
+            };
+            if let Some(announcement) = announcement {
+                msgs.push(Msg::NodeAnnouncement(announcement.clone()));
+            }
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u32 = 1_600_000_000;
+
+    #[test]
+    fn ingests_gossip() {
+        let secp = Secp256k1::new();
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let announcement = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        let short_channel_id = announcement.short_channel_id;
+
+        let update = signed_channel_update(&secp, &announcement, 2, NOW, |update| {
+            update.fee_base_msat = 7;
+            update.channel_flags = CHANNEL_UPDATE_CHANNEL_FLAG_DISABLE;
+        });
+        match graph.handle_channel_update(&update, NOW) {
+            Err(NetworkGraphError::UnknownChannel { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        unwrap!(graph.handle_channel_announcement(&announcement, NOW));
+        unwrap!(graph.handle_channel_update(&update, NOW));
+        unwrap!(graph.handle_node_announcement(&signed_node_announcement(&secp, 1, NOW)));
+        match graph.handle_node_announcement(&signed_node_announcement(&secp, 3, NOW)) {
+            Err(NetworkGraphError::UnknownNode { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let channel = unwrap!(graph.channel(short_channel_id));
+        let node_2 = test_node_id(2);
+        let direction = unwrap!(channel.direction_from(&node_2));
+        let policy = unwrap!(channel.policy(direction));
+        assert_eq!(policy.fee_base_msat, 7);
+        assert!(policy.disabled);
+        assert!(channel.policy(1 - direction).is_none());
+        assert_eq!(graph.node_channels(&node_2).count(), 1);
+        assert!(unwrap!(graph.node(&test_node_id(1))).announcement.is_some());
+
+        match graph.handle_channel_update(&update, NOW) {
+            Err(ref err @ NetworkGraphError::Invalid(GossipValidationError::StaleTimestamp { .. })) => {
+                assert!(!err.is_misbehaviour());
+            },
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let policy = ChannelPolicy::from_update(&update, NOW);
+        match graph.set_unverified_policy(short_channel_id, 2, policy) {
+            Err(NetworkGraphError::InvalidDirection { direction: 2 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(unwrap!(graph.channel(short_channel_id)).policy(2).is_none());
+    }
+
+    #[test]
+    fn prunes_stale_channels() {
+        let secp = Secp256k1::new();
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let fresh = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        let stale = signed_channel_announcement(&secp, 2, 3, test_short_channel_id(501));
+        unwrap!(graph.handle_channel_announcement(&fresh, NOW));
+        unwrap!(graph.handle_channel_announcement(&stale, NOW));
+        let later = NOW + STALE_CHANNEL_AGE_SECS + 10;
+        for &node in &[1, 2] {
+            unwrap!(graph.handle_channel_update(&signed_channel_update(&secp, &fresh, node, later, |_| ()), later));
+        }
+        unwrap!(graph.handle_channel_update(&signed_channel_update(&secp, &stale, 2, later, |_| ()), later));
+
+        assert_eq!(graph.remove_stale_channels(later), vec![stale.short_channel_id]);
+        assert_eq!(graph.num_channels(), 1);
+        assert!(graph.node(&test_node_id(3)).is_none());
+        assert_eq!(graph.num_nodes(), 2);
+    }
+}
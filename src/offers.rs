@@ -0,0 +1,1081 @@
+use super::*;
+
+pub const OFFER_PREFIX: &str = "lno";
+pub const INVOICE_REQUEST_PREFIX: &str = "lnr";
+pub const BOLT12_INVOICE_PREFIX: &str = "lni";
+pub const DEFAULT_BOLT12_INVOICE_RELATIVE_EXPIRY_SECS: u64 = 7200;
+
+const TLV_INVREQ_METADATA: u64 = 0;
+const TLV_OFFER_CHAINS: u64 = 2;
+const TLV_OFFER_METADATA: u64 = 4;
+const TLV_OFFER_CURRENCY: u64 = 6;
+const TLV_OFFER_AMOUNT: u64 = 8;
+const TLV_OFFER_DESCRIPTION: u64 = 10;
+const TLV_OFFER_FEATURES: u64 = 12;
+const TLV_OFFER_ABSOLUTE_EXPIRY: u64 = 14;
+const TLV_OFFER_PATHS: u64 = 16;
+const TLV_OFFER_ISSUER: u64 = 18;
+const TLV_OFFER_QUANTITY_MAX: u64 = 20;
+const TLV_OFFER_ISSUER_ID: u64 = 22;
+const TLV_INVREQ_CHAIN: u64 = 80;
+const TLV_INVREQ_AMOUNT: u64 = 82;
+const TLV_INVREQ_FEATURES: u64 = 84;
+const TLV_INVREQ_QUANTITY: u64 = 86;
+const TLV_INVREQ_PAYER_ID: u64 = 88;
+const TLV_INVREQ_PAYER_NOTE: u64 = 89;
+const TLV_INVREQ_PATHS: u64 = 90;
+const TLV_INVOICE_PATHS: u64 = 160;
+const TLV_INVOICE_BLINDEDPAY: u64 = 162;
+const TLV_INVOICE_CREATED_AT: u64 = 164;
+const TLV_INVOICE_RELATIVE_EXPIRY: u64 = 166;
+const TLV_INVOICE_PAYMENT_HASH: u64 = 168;
+const TLV_INVOICE_AMOUNT: u64 = 170;
+const TLV_INVOICE_FALLBACKS: u64 = 172;
+const TLV_INVOICE_FEATURES: u64 = 174;
+const TLV_INVOICE_NODE_ID: u64 = 176;
+const TLV_SIGNATURE: u64 = 240;
+
+/// The even feature bits we understand in each message. BOLT 12 doesn't define any for offers or
+/// invoice requests yet; invoices can require `basic_mpp`.
+const KNOWN_OFFER_REQUIRED_FEATURE_BITS: &[usize] = &[];
+const KNOWN_INVREQ_REQUIRED_FEATURE_BITS: &[usize] = &[];
+const KNOWN_INVOICE_REQUIRED_FEATURE_BITS: &[usize] = &[16];
+
+#[derive(Debug, Fail)]
+pub enum OfferError {
+    #[fail(display = "invalid bech32 string")]
+    InvalidBech32,
+    #[fail(display = "expected a string starting with {}", prefix)]
+    UnexpectedPrefix {
+        prefix: &'static str,
+    },
+    #[fail(display = "{}", _0)]
+    Tlv(TlvStreamError),
+    #[fail(display = "{}", _0)]
+    BlindedPath(BlindedPathError),
+    #[fail(display = "unexpected tlv record type {}", type_tag)]
+    UnexpectedRecordType {
+        type_tag: u64,
+    },
+    #[fail(display = "missing {} field", field)]
+    MissingField {
+        field: &'static str,
+    },
+    #[fail(display = "offer has an amount but no description")]
+    MissingDescription,
+    #[fail(display = "offer has a currency but no amount")]
+    CurrencyWithoutAmount,
+    #[fail(display = "chain is not supported by the offer")]
+    UnsupportedChain,
+    #[fail(display = "offer or invoice has expired")]
+    Expired,
+    #[fail(display = "amount is below what the offer asks for")]
+    InvalidAmount,
+    #[fail(display = "quantity is not allowed by the offer")]
+    InvalidQuantity,
+    #[fail(display = "invoice has a different number of paths and payinfos")]
+    PathsMismatch,
+    #[fail(display = "invalid signature")]
+    InvalidSignature,
+    #[fail(display = "signing key does not match the message")]
+    SigningKeyMismatch,
+    #[fail(display = "invoice does not match the invoice request")]
+    InvoiceMismatch,
+    #[fail(display = "{} requires unknown feature bit {}", field, bit)]
+    UnknownRequiredFeature {
+        field: &'static str,
+        bit: usize,
+    },
+}
+
+impl From<TlvStreamError> for OfferError {
+    fn from(err: TlvStreamError) -> OfferError {
+        OfferError::Tlv(err)
+    }
+}
+
+impl From<BlindedPathError> for OfferError {
+    fn from(err: BlindedPathError) -> OfferError {
+        OfferError::BlindedPath(err)
+    }
+}
+
+/// A reusable request for payment. Payers fetch an invoice for it by sending the issuer an
+/// `InvoiceRequest`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Offer {
+    /// The chains the offer can be paid on. Empty means bitcoin only.
+    pub chains: Vec<ChainHash>,
+    pub metadata: Option<Vec<u8>>,
+    /// An ISO 4217 currency code. If set, `amount` is in the currency's minor unit instead of
+    /// msat.
+    pub currency: Option<String>,
+    pub amount: Option<u64>,
+    pub description: Option<String>,
+    pub features: Vec<u8>,
+    /// Seconds since the epoch.
+    pub absolute_expiry: Option<u64>,
+    pub paths: Vec<BlindedPath>,
+    pub issuer: Option<String>,
+    /// How many items may be paid for at once. 0 means there's no limit, `None` that quantities
+    /// aren't allowed.
+    pub quantity_max: Option<u64>,
+    pub issuer_id: Option<secp256k1::PublicKey>,
+    /// Odd records we don't understand, kept so they're mirrored into invoice requests.
+    pub unknown_records: Vec<(u64, Vec<u8>)>,
+}
+
+/// A payer's request for an invoice for an offer. It repeats the offer's fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvoiceRequest {
+    pub metadata: Vec<u8>,
+    pub offer: Offer,
+    pub chain: Option<ChainHash>,
+    pub amount_msat: Option<u64>,
+    pub features: Vec<u8>,
+    pub quantity: Option<u64>,
+    pub payer_id: secp256k1::PublicKey,
+    pub payer_note: Option<String>,
+    pub paths: Vec<BlindedPath>,
+    pub unknown_records: Vec<(u64, Vec<u8>)>,
+}
+
+/// An invoice sent in response to an `InvoiceRequest`. It repeats the request's fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bolt12Invoice {
+    pub invoice_request: InvoiceRequest,
+    pub paths: Vec<(BlindedPath, BlindedPayInfo)>,
+    pub created_at: u64,
+    pub relative_expiry: Option<u32>,
+    pub payment_hash: [u8; 32],
+    pub amount_msat: u64,
+    pub fallbacks: Vec<FallbackAddress>,
+    pub features: Vec<u8>,
+    pub node_id: secp256k1::PublicKey,
+    pub unknown_records: Vec<(u64, Vec<u8>)>,
+}
+
+fn is_offer_type(type_tag: u64) -> bool {
+    (1..80).contains(&type_tag) || (1_000_000_000..2_000_000_000).contains(&type_tag)
+}
+
+fn is_invoice_request_type(type_tag: u64) -> bool {
+    type_tag == TLV_INVREQ_METADATA
+    || (80..160).contains(&type_tag)
+    || (2_000_000_000..3_000_000_000).contains(&type_tag)
+}
+
+fn is_invoice_type(type_tag: u64) -> bool {
+    (160..240).contains(&type_tag) || (3_000_000_000..4_000_000_000).contains(&type_tag)
+}
+
+fn is_signature_type(type_tag: u64) -> bool {
+    (240..=1000).contains(&type_tag)
+}
+
+fn check_record_types(records: &[TlvRecord], allowed: &[fn(u64) -> bool]) -> Result<(), OfferError> {
+    match records.iter().find(|record| !allowed.iter().any(|allowed| allowed(record.type_tag))) {
+        Some(record) => Err(OfferError::UnexpectedRecordType { type_tag: record.type_tag }),
+        None => Ok(()),
+    }
+}
+
+fn read_utf8(record: &TlvRecord) -> Result<String, TlvStreamError> {
+    String::from_utf8(record.value.to_vec()).map_err(|_| record.malformed())
+}
+
+fn read_point(record: &TlvRecord) -> Result<secp256k1::PublicKey, TlvStreamError> {
+    let secp = Secp256k1::without_caps();
+    if record.value.len() != 33 {
+        return Err(record.malformed());
+    }
+    secp256k1::PublicKey::from_slice(&secp, record.value).map_err(|_| record.malformed())
+}
+
+/// Reads a features record, failing on the first even bit which is set but not one we know.
+fn read_features(record: &TlvRecord, field: &'static str, known_required_bits: &[usize]) -> Result<Vec<u8>, OfferError> {
+    let features = record.value;
+    let unknown_bit = {
+        (0..(features.len() * 8))
+        .step_by(2)
+        .filter(|bit| features[features.len() - 1 - bit / 8] & (1 << (bit % 8)) != 0)
+        .find(|bit| !known_required_bits.contains(bit))
+    };
+    if let Some(bit) = unknown_bit {
+        return Err(OfferError::UnknownRequiredFeature { field, bit });
+    }
+    Ok(features.to_vec())
+}
+
+fn read_paths(record: &TlvRecord) -> Result<Vec<BlindedPath>, OfferError> {
+    let mut cursor = ReadCursor::new(record.value);
+    let mut paths = Vec::new();
+    while !cursor.is_empty() {
+        paths.push(BlindedPath::read_from_cursor(&mut cursor)?);
+    }
+    if paths.is_empty() {
+        return Err(record.malformed().into());
+    }
+    Ok(paths)
+}
+
+fn paths_bytes<'a, I: IntoIterator<Item = &'a BlindedPath>>(paths: I) -> Vec<u8> {
+    let mut cursor = WriteCursor::new();
+    for path in paths {
+        path.write_to_cursor(&mut cursor);
+    }
+    cursor.into_bytes().to_vec()
+}
+
+fn point_bytes(point: &secp256k1::PublicKey) -> Vec<u8> {
+    point.serialize().to_vec()
+}
+
+fn tu64_bytes(val: u64) -> Vec<u8> {
+    let bytes = u64::to_be_bytes(val);
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+/// Serializes records, which needn't be in order.
+fn tlv_stream_bytes(mut records: Vec<(u64, Vec<u8>)>) -> Vec<u8> {
+    records.sort_by_key(|(type_tag, _)| *type_tag);
+    let mut cursor = WriteCursor::new();
+    for (type_tag, value) in &records {
+        cursor.write_tlv_record(*type_tag, value);
+    }
+    cursor.into_bytes().to_vec()
+}
+
+/// The root of the Merkle tree over a message's records which signatures commit to. Signature
+/// records are left out.
+pub fn bolt12_merkle_root(records: &[TlvRecord]) -> [u8; 32] {
+    fn record_bytes(record: &TlvRecord) -> Bytes {
+        let mut cursor = WriteCursor::new();
+        cursor.write_tlv_record(record.type_tag, record.value);
+        cursor.into_bytes()
+    }
+
+    fn branch(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+        let (lesser, greater) = if a < b { (a, b) } else { (b, a) };
+        tagged_hash(b"LnBranch", &[&lesser[..], &greater[..]])
+    }
+
+    let records: Vec<&TlvRecord> = records.iter().filter(|record| !is_signature_type(record.type_tag)).collect();
+    let first = match records.first() {
+        Some(first) => record_bytes(first),
+        None => return [0; 32],
+    };
+    let mut nonce_tag = b"LnNonce".to_vec();
+    nonce_tag.extend_from_slice(&first);
+
+    // Each record's leaf sits next to a nonce leaf which hides it from anyone given only part of
+    // the tree.
+    let mut leaves = Vec::with_capacity(records.len() * 2);
+    for record in records {
+        leaves.push(tagged_hash(b"LnLeaf", &[&record_bytes(record)[..]]));
+        let mut type_cursor = WriteCursor::new();
+        type_cursor.write_bigsize(record.type_tag);
+        leaves.push(tagged_hash(&nonce_tag, &[&type_cursor.into_bytes()[..]]));
+    }
+
+    // Pair up neighbours level by level. An odd one out is carried up as is, which leaves the
+    // tree deepest on the lowest-order leaves.
+    let num_leaves = leaves.len();
+    let mut step = 2;
+    while step / 2 < num_leaves {
+        for i in (0..num_leaves).step_by(step) {
+            if i + step / 2 < num_leaves {
+                leaves[i] = branch(&leaves[i], &leaves[i + step / 2]);
+            }
+        }
+        step *= 2;
+    }
+    leaves[0]
+}
+
+fn signature_message(message_name: &str, records: &[TlvRecord]) -> [u8; 32] {
+    let tag = format!("lightning{}signature", message_name);
+    tagged_hash(tag.as_bytes(), &[&bolt12_merkle_root(records)[..]])
+}
+
+/// Appends a signature record to `records`, signing everything before it.
+fn sign_records(
+    secp: &Secp256k1<secp256k1::All>,
+    message_name: &str,
+    records: Vec<(u64, Vec<u8>)>,
+    public_key: &secp256k1::PublicKey,
+    secret_key: &secp256k1::SecretKey,
+) -> Result<Vec<u8>, OfferError> {
+    if secp256k1::PublicKey::from_secret_key(secp, secret_key) != *public_key {
+        return Err(OfferError::SigningKeyMismatch);
+    }
+    let mut bytes = tlv_stream_bytes(records);
+    let msg = {
+        let records = unwrap!(ReadCursor::new(&bytes).read_tlv_stream());
+        signature_message(message_name, &records)
+    };
+    let aux_rand: [u8; 32] = rand::thread_rng().gen();
+    let signature = schnorr_sign(secp, secret_key, &msg, &aux_rand);
+    let mut cursor = WriteCursor::new();
+    cursor.write_tlv_record(TLV_SIGNATURE, &signature[..]);
+    bytes.extend_from_slice(&cursor.into_bytes());
+    Ok(bytes)
+}
+
+fn verify_records(
+    secp: &Secp256k1<secp256k1::All>,
+    message_name: &str,
+    records: &[TlvRecord],
+    public_key: &secp256k1::PublicKey,
+) -> Result<(), OfferError> {
+    let signature = match records.iter().find(|record| record.type_tag == TLV_SIGNATURE) {
+        Some(record) if record.value.len() == 64 => slice_to_array!(record.value, 64),
+        Some(record) => return Err(record.malformed().into()),
+        None => return Err(OfferError::MissingField { field: "signature" }),
+    };
+    let x_only_public_key = slice_to_array!(public_key.serialize()[1..], 32);
+    let msg = signature_message(message_name, records);
+    if !schnorr_verify(secp, &x_only_public_key, &msg, &signature) {
+        return Err(OfferError::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Decodes an offer or one of its messages. They're written in bech32 without a checksum, since
+/// they're meant to be scanned from QR codes rather than typed.
+fn decode_with_prefix(prefix: &'static str, s: &str) -> Result<Vec<u8>, OfferError> {
+    let (hrp, bytes) = bech32_decode_unchecked(s).ok_or(OfferError::InvalidBech32)?;
+    if hrp != prefix {
+        return Err(OfferError::UnexpectedPrefix { prefix });
+    }
+    Ok(bytes)
+}
+
+impl Offer {
+    pub fn supports_chain(&self, chain: &ChainHash) -> bool {
+        if self.chains.is_empty() {
+            return *chain == ChainHash::BITCOIN;
+        }
+        self.chains.contains(chain)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.absolute_expiry.map(|absolute_expiry| now >= absolute_expiry).unwrap_or(false)
+    }
+
+    /// What `quantity` items cost in msat, if the offer has a bitcoin amount.
+    pub fn amount_msat(&self, quantity: u64) -> Option<u64> {
+        if self.currency.is_some() {
+            return None;
+        }
+        self.amount.and_then(|amount| amount.checked_mul(quantity))
+    }
+
+    fn write_records(&self, records: &mut Vec<(u64, Vec<u8>)>) {
+        if !self.chains.is_empty() {
+            let chains = self.chains.iter().flat_map(|chain| chain.0.iter().cloned()).collect();
+            records.push((TLV_OFFER_CHAINS, chains));
+        }
+        if let Some(ref metadata) = self.metadata {
+            records.push((TLV_OFFER_METADATA, metadata.clone()));
+        }
+        if let Some(ref currency) = self.currency {
+            records.push((TLV_OFFER_CURRENCY, currency.as_bytes().to_vec()));
+        }
+        if let Some(amount) = self.amount {
+            records.push((TLV_OFFER_AMOUNT, tu64_bytes(amount)));
+        }
+        if let Some(ref description) = self.description {
+            records.push((TLV_OFFER_DESCRIPTION, description.as_bytes().to_vec()));
+        }
+        if !self.features.is_empty() {
+            records.push((TLV_OFFER_FEATURES, self.features.clone()));
+        }
+        if let Some(absolute_expiry) = self.absolute_expiry {
+            records.push((TLV_OFFER_ABSOLUTE_EXPIRY, tu64_bytes(absolute_expiry)));
+        }
+        if !self.paths.is_empty() {
+            records.push((TLV_OFFER_PATHS, paths_bytes(&self.paths)));
+        }
+        if let Some(ref issuer) = self.issuer {
+            records.push((TLV_OFFER_ISSUER, issuer.as_bytes().to_vec()));
+        }
+        if let Some(quantity_max) = self.quantity_max {
+            records.push((TLV_OFFER_QUANTITY_MAX, tu64_bytes(quantity_max)));
+        }
+        if let Some(ref issuer_id) = self.issuer_id {
+            records.push((TLV_OFFER_ISSUER_ID, point_bytes(issuer_id)));
+        }
+        records.extend(self.unknown_records.iter().cloned());
+    }
+
+    /// Reads the offer's fields out of a message's records, ignoring those of other types.
+    fn from_records(records: &[TlvRecord]) -> Result<Offer, OfferError> {
+        let mut offer = Offer::default();
+        for record in records.iter().filter(|record| is_offer_type(record.type_tag)) {
+            match record.type_tag {
+                TLV_OFFER_CHAINS => {
+                    if record.value.is_empty() || record.value.len() % 32 != 0 {
+                        return Err(record.malformed().into());
+                    }
+                    offer.chains = record.value.chunks(32).map(|chain| ChainHash(slice_to_array!(chain, 32))).collect();
+                },
+                TLV_OFFER_METADATA => offer.metadata = Some(record.value.to_vec()),
+                TLV_OFFER_CURRENCY => offer.currency = Some(read_utf8(record)?),
+                TLV_OFFER_AMOUNT => offer.amount = Some(record.read_tu64()?),
+                TLV_OFFER_DESCRIPTION => offer.description = Some(read_utf8(record)?),
+                TLV_OFFER_FEATURES => {
+                    offer.features = read_features(record, "offer_features", KNOWN_OFFER_REQUIRED_FEATURE_BITS)?;
+                },
+                TLV_OFFER_ABSOLUTE_EXPIRY => offer.absolute_expiry = Some(record.read_tu64()?),
+                TLV_OFFER_PATHS => offer.paths = read_paths(record)?,
+                TLV_OFFER_ISSUER => offer.issuer = Some(read_utf8(record)?),
+                TLV_OFFER_QUANTITY_MAX => offer.quantity_max = Some(record.read_tu64()?),
+                TLV_OFFER_ISSUER_ID => offer.issuer_id = Some(read_point(record)?),
+                _ => {
+                    record.ignore_if_odd()?;
+                    offer.unknown_records.push((record.type_tag, record.value.to_vec()));
+                },
+            }
+        }
+        if offer.amount.is_some() && offer.description.is_none() {
+            return Err(OfferError::MissingDescription);
+        }
+        if offer.currency.is_some() && offer.amount.is_none() {
+            return Err(OfferError::CurrencyWithoutAmount);
+        }
+        if offer.issuer_id.is_none() && offer.paths.is_empty() {
+            return Err(OfferError::MissingField { field: "offer_issuer_id" });
+        }
+        Ok(offer)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        self.write_records(&mut records);
+        tlv_stream_bytes(records)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Offer, OfferError> {
+        let records = ReadCursor::new(bytes).read_tlv_stream()?;
+        check_record_types(&records, &[is_offer_type])?;
+        Offer::from_records(&records)
+    }
+
+    pub fn encode(&self) -> String {
+        bech32_encode_unchecked(OFFER_PREFIX, &self.to_bytes())
+    }
+
+    pub fn decode(s: &str) -> Result<Offer, OfferError> {
+        Offer::from_bytes(&decode_with_prefix(OFFER_PREFIX, s)?)
+    }
+}
+
+impl InvoiceRequest {
+    /// A request for a single item of `offer`. `metadata` should be random, so that requests
+    /// can't be linked to each other.
+    pub fn for_offer(offer: &Offer, metadata: Vec<u8>, payer_id: secp256k1::PublicKey) -> InvoiceRequest {
+        InvoiceRequest {
+            metadata,
+            offer: offer.clone(),
+            chain: None,
+            amount_msat: None,
+            features: Vec::new(),
+            quantity: None,
+            payer_id,
+            payer_note: None,
+            paths: Vec::new(),
+            unknown_records: Vec::new(),
+        }
+    }
+
+    pub fn chain(&self) -> ChainHash {
+        self.chain.unwrap_or(ChainHash::BITCOIN)
+    }
+
+    /// What the invoice for this request should be for, if it can be worked out without
+    /// converting from another currency.
+    pub fn amount_due_msat(&self) -> Option<u64> {
+        self.amount_msat.or_else(|| self.offer.amount_msat(self.quantity.unwrap_or(1)))
+    }
+
+    /// Checks that the request is one the offer's issuer should answer.
+    pub fn check_offer(&self, now: u64) -> Result<(), OfferError> {
+        if self.offer.is_expired(now) {
+            return Err(OfferError::Expired);
+        }
+        if !self.offer.supports_chain(&self.chain()) {
+            return Err(OfferError::UnsupportedChain);
+        }
+        match (self.offer.quantity_max, self.quantity) {
+            (Some(quantity_max), Some(quantity)) if quantity > 0 && (quantity_max == 0 || quantity <= quantity_max) => (),
+            (None, None) => (),
+            _ => return Err(OfferError::InvalidQuantity),
+        }
+        if self.offer.amount.is_none() && self.amount_msat.is_none() {
+            return Err(OfferError::MissingField { field: "invreq_amount" });
+        }
+        if let Some(amount_msat) = self.amount_msat {
+            if self.offer.amount.is_some() && self.offer.currency.is_none() {
+                match self.offer.amount_msat(self.quantity.unwrap_or(1)) {
+                    Some(expected) if amount_msat >= expected => (),
+                    _ => return Err(OfferError::InvalidAmount),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_records(&self, records: &mut Vec<(u64, Vec<u8>)>) {
+        records.push((TLV_INVREQ_METADATA, self.metadata.clone()));
+        self.offer.write_records(records);
+        if let Some(ref chain) = self.chain {
+            records.push((TLV_INVREQ_CHAIN, chain.0.to_vec()));
+        }
+        if let Some(amount_msat) = self.amount_msat {
+            records.push((TLV_INVREQ_AMOUNT, tu64_bytes(amount_msat)));
+        }
+        if !self.features.is_empty() {
+            records.push((TLV_INVREQ_FEATURES, self.features.clone()));
+        }
+        if let Some(quantity) = self.quantity {
+            records.push((TLV_INVREQ_QUANTITY, tu64_bytes(quantity)));
+        }
+        records.push((TLV_INVREQ_PAYER_ID, point_bytes(&self.payer_id)));
+        if let Some(ref payer_note) = self.payer_note {
+            records.push((TLV_INVREQ_PAYER_NOTE, payer_note.as_bytes().to_vec()));
+        }
+        if !self.paths.is_empty() {
+            records.push((TLV_INVREQ_PATHS, paths_bytes(&self.paths)));
+        }
+        records.extend(self.unknown_records.iter().cloned());
+    }
+
+    fn from_records(records: &[TlvRecord]) -> Result<InvoiceRequest, OfferError> {
+        let offer = Offer::from_records(records)?;
+        let mut metadata = None;
+        let mut chain = None;
+        let mut amount_msat = None;
+        let mut features = Vec::new();
+        let mut quantity = None;
+        let mut payer_id = None;
+        let mut payer_note = None;
+        let mut paths = Vec::new();
+        let mut unknown_records = Vec::new();
+        for record in records.iter().filter(|record| is_invoice_request_type(record.type_tag)) {
+            match record.type_tag {
+                TLV_INVREQ_METADATA => metadata = Some(record.value.to_vec()),
+                TLV_INVREQ_CHAIN => {
+                    if record.value.len() != 32 {
+                        return Err(record.malformed().into());
+                    }
+                    chain = Some(ChainHash(slice_to_array!(record.value, 32)));
+                },
+                TLV_INVREQ_AMOUNT => amount_msat = Some(record.read_tu64()?),
+                TLV_INVREQ_FEATURES => {
+                    features = read_features(record, "invreq_features", KNOWN_INVREQ_REQUIRED_FEATURE_BITS)?;
+                },
+                TLV_INVREQ_QUANTITY => quantity = Some(record.read_tu64()?),
+                TLV_INVREQ_PAYER_ID => payer_id = Some(read_point(record)?),
+                TLV_INVREQ_PAYER_NOTE => payer_note = Some(read_utf8(record)?),
+                TLV_INVREQ_PATHS => paths = read_paths(record)?,
+                _ => {
+                    record.ignore_if_odd()?;
+                    unknown_records.push((record.type_tag, record.value.to_vec()));
+                },
+            }
+        }
+        Ok(InvoiceRequest {
+            metadata: metadata.ok_or(OfferError::MissingField { field: "invreq_metadata" })?,
+            offer,
+            chain,
+            amount_msat,
+            features,
+            quantity,
+            payer_id: payer_id.ok_or(OfferError::MissingField { field: "invreq_payer_id" })?,
+            payer_note,
+            paths,
+            unknown_records,
+        })
+    }
+
+    /// Serializes the request, signed with the secret key for `payer_id`.
+    pub fn to_bytes(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        payer_secret: &secp256k1::SecretKey,
+    ) -> Result<Vec<u8>, OfferError> {
+        let mut records = Vec::new();
+        self.write_records(&mut records);
+        sign_records(secp, "invoice_request", records, &self.payer_id, payer_secret)
+    }
+
+    pub fn from_bytes(secp: &Secp256k1<secp256k1::All>, bytes: &[u8]) -> Result<InvoiceRequest, OfferError> {
+        let records = ReadCursor::new(bytes).read_tlv_stream()?;
+        check_record_types(&records, &[is_offer_type, is_invoice_request_type, is_signature_type])?;
+        let invoice_request = InvoiceRequest::from_records(&records)?;
+        verify_records(secp, "invoice_request", &records, &invoice_request.payer_id)?;
+        Ok(invoice_request)
+    }
+
+    pub fn encode(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        payer_secret: &secp256k1::SecretKey,
+    ) -> Result<String, OfferError> {
+        Ok(bech32_encode_unchecked(INVOICE_REQUEST_PREFIX, &self.to_bytes(secp, payer_secret)?))
+    }
+
+    pub fn decode(secp: &Secp256k1<secp256k1::All>, s: &str) -> Result<InvoiceRequest, OfferError> {
+        InvoiceRequest::from_bytes(secp, &decode_with_prefix(INVOICE_REQUEST_PREFIX, s)?)
+    }
+}
+
+impl Bolt12Invoice {
+    /// An invoice answering `invoice_request`, for the amount it's due.
+    pub fn for_request(
+        invoice_request: &InvoiceRequest,
+        paths: Vec<(BlindedPath, BlindedPayInfo)>,
+        created_at: u64,
+        payment_hash: [u8; 32],
+        node_id: secp256k1::PublicKey,
+    ) -> Result<Bolt12Invoice, OfferError> {
+        if paths.is_empty() {
+            return Err(OfferError::MissingField { field: "invoice_paths" });
+        }
+        let amount_msat = invoice_request.amount_due_msat().ok_or(OfferError::MissingField { field: "invreq_amount" })?;
+        Ok(Bolt12Invoice {
+            invoice_request: invoice_request.clone(),
+            paths,
+            created_at,
+            relative_expiry: None,
+            payment_hash,
+            amount_msat,
+            fallbacks: Vec::new(),
+            features: Vec::new(),
+            node_id,
+            unknown_records: Vec::new(),
+        })
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        let relative_expiry = {
+            self.relative_expiry
+            .map(u64::from)
+            .unwrap_or(DEFAULT_BOLT12_INVOICE_RELATIVE_EXPIRY_SECS)
+        };
+        now >= self.created_at.saturating_add(relative_expiry)
+    }
+
+    /// Checks that the invoice answers the request we sent, and comes from the offer's issuer.
+    pub fn check_request(&self, invoice_request: &InvoiceRequest) -> Result<(), OfferError> {
+        if self.invoice_request != *invoice_request {
+            return Err(OfferError::InvoiceMismatch);
+        }
+        if let Some(amount_msat) = invoice_request.amount_due_msat() {
+            if self.amount_msat != amount_msat {
+                return Err(OfferError::InvalidAmount);
+            }
+        }
+        let offer = &invoice_request.offer;
+        let from_issuer = match offer.issuer_id {
+            Some(ref issuer_id) => *issuer_id == self.node_id,
+            None => {
+                offer.paths.iter()
+                .filter_map(|path| path.hops.last())
+                .any(|hop| hop.blinded_node_id == self.node_id)
+            },
+        };
+        if !from_issuer {
+            return Err(OfferError::InvoiceMismatch);
+        }
+        Ok(())
+    }
+
+    fn write_records(&self, records: &mut Vec<(u64, Vec<u8>)>) {
+        self.invoice_request.write_records(records);
+        records.push((TLV_INVOICE_PATHS, paths_bytes(self.paths.iter().map(|(path, _)| path))));
+        let mut cursor = WriteCursor::new();
+        for (_, payinfo) in &self.paths {
+            payinfo.write_to_cursor(&mut cursor);
+        }
+        records.push((TLV_INVOICE_BLINDEDPAY, cursor.into_bytes().to_vec()));
+        records.push((TLV_INVOICE_CREATED_AT, tu64_bytes(self.created_at)));
+        if let Some(relative_expiry) = self.relative_expiry {
+            records.push((TLV_INVOICE_RELATIVE_EXPIRY, tu64_bytes(relative_expiry as u64)));
+        }
+        records.push((TLV_INVOICE_PAYMENT_HASH, self.payment_hash.to_vec()));
+        records.push((TLV_INVOICE_AMOUNT, tu64_bytes(self.amount_msat)));
+        if !self.fallbacks.is_empty() {
+            let mut cursor = WriteCursor::new();
+            for fallback in &self.fallbacks {
+                cursor.write_u8(fallback.version);
+                cursor.write_u16_len_slice(&fallback.program);
+            }
+            records.push((TLV_INVOICE_FALLBACKS, cursor.into_bytes().to_vec()));
+        }
+        if !self.features.is_empty() {
+            records.push((TLV_INVOICE_FEATURES, self.features.clone()));
+        }
+        records.push((TLV_INVOICE_NODE_ID, point_bytes(&self.node_id)));
+        records.extend(self.unknown_records.iter().cloned());
+    }
+
+    fn from_records(records: &[TlvRecord]) -> Result<Bolt12Invoice, OfferError> {
+        let invoice_request = InvoiceRequest::from_records(records)?;
+        let mut paths = Vec::new();
+        let mut payinfos = Vec::new();
+        let mut created_at = None;
+        let mut relative_expiry = None;
+        let mut payment_hash = None;
+        let mut amount_msat = None;
+        let mut fallbacks = Vec::new();
+        let mut features = Vec::new();
+        let mut node_id = None;
+        let mut unknown_records = Vec::new();
+        for record in records.iter().filter(|record| is_invoice_type(record.type_tag)) {
+            match record.type_tag {
+                TLV_INVOICE_PATHS => paths = read_paths(record)?,
+                TLV_INVOICE_BLINDEDPAY => {
+                    let mut cursor = ReadCursor::new(record.value);
+                    while !cursor.is_empty() {
+                        payinfos.push(BlindedPayInfo::read_from_cursor(&mut cursor).map_err(|_| record.malformed())?);
+                    }
+                },
+                TLV_INVOICE_CREATED_AT => created_at = Some(record.read_tu64()?),
+                TLV_INVOICE_RELATIVE_EXPIRY => {
+                    let relative_expiry_secs = record.read_tu64()?;
+                    if relative_expiry_secs > u32::max_value() as u64 {
+                        return Err(record.malformed().into());
+                    }
+                    relative_expiry = Some(relative_expiry_secs as u32);
+                },
+                TLV_INVOICE_PAYMENT_HASH => {
+                    if record.value.len() != 32 {
+                        return Err(record.malformed().into());
+                    }
+                    payment_hash = Some(slice_to_array!(record.value, 32));
+                },
+                TLV_INVOICE_AMOUNT => amount_msat = Some(record.read_tu64()?),
+                TLV_INVOICE_FALLBACKS => {
+                    let mut cursor = ReadCursor::new(record.value);
+                    while !cursor.is_empty() {
+                        let version = cursor.read_u8().map_err(|_| record.malformed())?;
+                        let program = cursor.read_u16_len_slice().map_err(|_| record.malformed())?;
+                        fallbacks.push(FallbackAddress { version, program: program.to_vec() });
+                    }
+                },
+                TLV_INVOICE_FEATURES => {
+                    features = read_features(record, "invoice_features", KNOWN_INVOICE_REQUIRED_FEATURE_BITS)?;
+                },
+                TLV_INVOICE_NODE_ID => node_id = Some(read_point(record)?),
+                _ => {
+                    record.ignore_if_odd()?;
+                    unknown_records.push((record.type_tag, record.value.to_vec()));
+                },
+            }
+        }
+        if paths.is_empty() {
+            return Err(OfferError::MissingField { field: "invoice_paths" });
+        }
+        if paths.len() != payinfos.len() {
+            return Err(OfferError::PathsMismatch);
+        }
+        Ok(Bolt12Invoice {
+            invoice_request,
+            paths: paths.into_iter().zip(payinfos).collect(),
+            created_at: created_at.ok_or(OfferError::MissingField { field: "invoice_created_at" })?,
+            relative_expiry,
+            payment_hash: payment_hash.ok_or(OfferError::MissingField { field: "invoice_payment_hash" })?,
+            amount_msat: amount_msat.ok_or(OfferError::MissingField { field: "invoice_amount" })?,
+            fallbacks,
+            features,
+            node_id: node_id.ok_or(OfferError::MissingField { field: "invoice_node_id" })?,
+            unknown_records,
+        })
+    }
+
+    /// Serializes the invoice, signed with the secret key for `node_id`.
+    pub fn to_bytes(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        node_secret: &secp256k1::SecretKey,
+    ) -> Result<Vec<u8>, OfferError> {
+        let mut records = Vec::new();
+        self.write_records(&mut records);
+        sign_records(secp, "invoice", records, &self.node_id, node_secret)
+    }
+
+    pub fn from_bytes(secp: &Secp256k1<secp256k1::All>, bytes: &[u8]) -> Result<Bolt12Invoice, OfferError> {
+        let records = ReadCursor::new(bytes).read_tlv_stream()?;
+        check_record_types(&records, &[is_offer_type, is_invoice_request_type, is_invoice_type, is_signature_type])?;
+        let invoice = Bolt12Invoice::from_records(&records)?;
+        verify_records(secp, "invoice", &records, &invoice.node_id)?;
+        Ok(invoice)
+    }
+
+    pub fn encode(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        node_secret: &secp256k1::SecretKey,
+    ) -> Result<String, OfferError> {
+        Ok(bech32_encode_unchecked(BOLT12_INVOICE_PREFIX, &self.to_bytes(secp, node_secret)?))
+    }
+
+    pub fn decode(secp: &Secp256k1<secp256k1::All>, s: &str) -> Result<Bolt12Invoice, OfferError> {
+        Bolt12Invoice::from_bytes(secp, &decode_with_prefix(BOLT12_INVOICE_PREFIX, s)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn merkle_root_vectors() {
+        // From the BOLT 12 signature test vectors.
+        let records = [TlvRecord { type_tag: 1, value: &hex!("03e8") }];
+        assert_eq!(bolt12_merkle_root(&records), hex!("b013756c8fee86503a0b4abdab4cddeb1af5d344ca6fc2fa8b6c08938caa6f93"));
+        let records = [
+            TlvRecord { type_tag: 1, value: &hex!("03e8") },
+            TlvRecord { type_tag: 2, value: &hex!("0000010000020003") },
+            TlvRecord { type_tag: 240, value: &[0; 64] },
+        ];
+        assert_eq!(bolt12_merkle_root(&records), hex!("c3774abbf4815aa54ccaa026bff6581f01f3be5fe814c620a252534f434bc0d1"));
+    }
+
+    #[test]
+    fn bolt12_offer_vectors() {
+        // From the BOLT 12 offer test vectors. The issuer's key is `[0x41; 32]`.
+        let secp = Secp256k1::new();
+        let issuer_id = test_key(&secp, 0x41).1;
+        let offer = |description: Option<&str>| Offer {
+            description: description.map(|description| description.to_owned()),
+            issuer_id: Some(issuer_id),
+            ..Offer::default()
+        };
+        let testnet = ChainHash(hex!("43497fd7f826957108f4a30fd9cec3aeba79972084e90ead01ea330900000000"));
+        let vectors = [
+            ("lno1zcss9mk8y3wkklfvevcrszlmu23kfrxh49px20665dqwmn4p72pksese", offer(None)),
+            ("lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg", offer(Some("Test vectors"))),
+            (
+                "lno1qgsyxjtl6luzd9t3pr62xr7eemp6awnejusgf6gw45q75vcfqqqqqqq2p32x2um5ypmx2cm5dae8x93pqthvwfzadd7jejes8q9lhc4rvjxd022zv5l44g6qah82ru5rdpnpj",
+                Offer { chains: vec![testnet], ..offer(Some("Test vectors")) },
+            ),
+            (
+                "lno1qsgqqqqqqqqqqqqqqqqqqqqqqqqqqzsv23jhxapqwejkxar0wfe3vggzamrjghtt05kvkvpcp0a79gmy3nt6jsn98ad2xs8de6sl9qmgvcvs",
+                Offer { metadata: Some(vec![0; 16]), ..offer(Some("Test vectors")) },
+            ),
+            (
+                "lno1pqpzwyq2p32x2um5ypmx2cm5dae8x93pqthvwfzadd7jejes8q9lhc4rvjxd022zv5l44g6qah82ru5rdpnpj",
+                Offer { amount: Some(10_000), ..offer(Some("Test vectors")) },
+            ),
+            (
+                "lno1qcp4256ypqpzwyq2p32x2um5ypmx2cm5dae8x93pqthvwfzadd7jejes8q9lhc4rvjxd022zv5l44g6qah82ru5rdpnpj",
+                Offer { currency: Some("USD".to_owned()), amount: Some(10_000), ..offer(Some("Test vectors")) },
+            ),
+            (
+                "lno1pgx9getnwss8vetrw3hhyucwq3ay997czcss9mk8y3wkklfvevcrszlmu23kfrxh49px20665dqwmn4p72pksese",
+                Offer { absolute_expiry: Some(2_051_184_600), ..offer(Some("Test vectors")) },
+            ),
+            (
+                "lno1pgx9getnwss8vetrw3hhyucjy358garswvaz7tmzdak8gvfj9ehhyeeqgf85c4p3xgsxjmnyw4ehgunfv4e3vggzamrjghtt05kvkvpcp0a79gmy3nt6jsn98ad2xs8de6sl9qmgvcvs",
+                Offer { issuer: Some("https://bolt12.org BOLT12 industries".to_owned()), ..offer(Some("Test vectors")) },
+            ),
+            (
+                "lno1pgx9getnwss8vetrw3hhyuc5qyz3vggzamrjghtt05kvkvpcp0a79gmy3nt6jsn98ad2xs8de6sl9qmgvcvs",
+                Offer { quantity_max: Some(5), ..offer(Some("Test vectors")) },
+            ),
+        ];
+        for (encoded, expected) in vectors.iter() {
+            assert_eq!(unwrap!(Offer::decode(encoded)), *expected);
+            assert_eq!(expected.encode(), *encoded);
+        }
+
+        let split = [
+            "lno1pgx9getnwss8vetrw3hhyuc+kyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+            "LNO1PGX9GETNWSS8VETRW3HHYUC+KYYPWA3EYT44H6TXTXQUQH7LZ5DJGE4AFGFJN7K4RGRKUAG0JSD5XVXG",
+            "lno1pgx9getnwss8vetrw3hhyuc+ kyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+            "lno1pgx9getnwss8vetrw3hhyuc+\r\n kyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+        ];
+        for encoded in split.iter() {
+            assert_eq!(unwrap!(Offer::decode(encoded)), offer(Some("Test vectors")));
+        }
+
+        let malformed = [
+            "+lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+            "lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg+",
+            "lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg+ ",
+            "lno1pgx9getnwss8vetrw3hhyuc++kyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+            "lno1pgx9getnwss8vetrw3hhyuc+ +kyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+            "lno1pgx9getnwss8vetrw3hhyuc kyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg",
+            "lno1pgx9getnwss8vetrw3hhyucKYYPWA3EYT44H6TXTXQUQH7LZ5DJGE4AFGFJN7K4RGRKUAG0JSD5XVXG",
+        ];
+        for encoded in malformed.iter() {
+            match Offer::decode(encoded) {
+                Err(OfferError::InvalidBech32) => (),
+                res => panic!("unexpected result for {:?}: {:?}", encoded, res),
+            }
+        }
+
+        // Only a description.
+        match Offer::decode("lno1pgx9getnwss8vetrw3hhyuc") {
+            Err(OfferError::MissingField { field: "offer_issuer_id" }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // An amount but no description.
+        match Offer::decode("lno1pqpzwyqkyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg") {
+            Err(OfferError::MissingDescription) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn bolt12_signature_vector() {
+        // The invoice request from the BOLT 12 signature test vectors, paying an offer from
+        // `[0x41; 32]` with `[0x42; 32]` as the payer key.
+        let secp = Secp256k1::new();
+        let (payer_secret, payer_id) = test_key(&secp, 0x42);
+        let encoded = "lnr1qqyqqqqqqqqqqqqqqcp4256ypqqkgzshgysy6ct5dpjk6ct5d93kzmpq23ex2ct5d9ek293pqthvwfzadd7jejes8q9lhc4rvjxd022zv5l44g6qah82ru5rdpnpjkppqvjx204vgdzgsqpvcp4mldl3plscny0rt707gvpdh6ndydfacz43euzqhrurageg3n7kafgsek6gz3e9w52parv8gs2hlxzk95tzeswywffxlkeyhml0hh46kndmwf4m6xma3tkq2lu04qz3slje2rfthc89vss";
+        let invoice_request = unwrap!(InvoiceRequest::decode(&secp, encoded));
+        assert_eq!(invoice_request.metadata, vec![0; 8]);
+        assert_eq!(invoice_request.offer.currency, Some("USD".to_owned()));
+        assert_eq!(invoice_request.offer.amount, Some(100));
+        assert_eq!(invoice_request.offer.description, Some("A Mathematical Treatise".to_owned()));
+        assert_eq!(invoice_request.offer.issuer_id, Some(test_key(&secp, 0x41).1));
+        assert_eq!(invoice_request.payer_id, payer_id);
+
+        let (_, bytes) = unwrap!(bech32_decode_unchecked(encoded));
+        let records = unwrap!(ReadCursor::new(&bytes).read_tlv_stream());
+        assert_eq!(bolt12_merkle_root(&records), hex!("608407c18ad9a94d9ea2bcdbe170b6c20c462a7833a197621c916f78cf18e624"));
+        // The vector was signed with all-zero auxiliary randomness.
+        let msg = signature_message("invoice_request", &records);
+        let signature = schnorr_sign(&secp, &payer_secret, &msg, &[0; 32]);
+        assert_eq!(&signature[..], &bytes[(bytes.len() - 64)..]);
+    }
+
+    #[test]
+    fn offer_request_invoice_round_trip() {
+        let secp = Secp256k1::new();
+        let (issuer_secret, issuer_id) = test_key(&secp, 0x11);
+        let (payer_secret, payer_id) = test_key(&secp, 0x12);
+
+        let offer = Offer {
+            amount: Some(50_000),
+            description: Some("coffee".to_owned()),
+            absolute_expiry: Some(NOW + 3600),
+            quantity_max: Some(5),
+            issuer_id: Some(issuer_id),
+            unknown_records: vec![(41, vec![1, 2, 3])],
+            ..Offer::default()
+        };
+        let encoded = offer.encode();
+        assert!(encoded.starts_with("lno1"));
+        assert_eq!(unwrap!(Offer::decode(&encoded)), offer);
+        let split = format!("{}+\n  {}", &encoded[..20], &encoded[20..]);
+        assert_eq!(unwrap!(Offer::decode(&split.to_uppercase())), offer);
+
+        let mut invoice_request = InvoiceRequest::for_offer(&offer, vec![7; 16], payer_id);
+        match invoice_request.check_offer(NOW) {
+            Err(OfferError::InvalidQuantity) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        invoice_request.quantity = Some(2);
+        invoice_request.amount_msat = Some(99_999);
+        match invoice_request.check_offer(NOW) {
+            Err(OfferError::InvalidAmount) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        invoice_request.amount_msat = None;
+        unwrap!(invoice_request.check_offer(NOW));
+        match invoice_request.check_offer(NOW + 3600) {
+            Err(OfferError::Expired) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let encoded = unwrap!(invoice_request.encode(&secp, &payer_secret));
+        assert_eq!(unwrap!(InvoiceRequest::decode(&secp, &encoded)), invoice_request);
+        assert!(invoice_request.encode(&secp, &issuer_secret).is_err());
+
+        let path = unwrap!(BlindedPath::new(&secp, &test_key(&secp, 0x13).0, &[(issuer_id, vec![0; 8])]));
+        let payinfo = BlindedPayInfo {
+            fee_base_msat: 0,
+            fee_proportional_millionths: 0,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1,
+            htlc_maximum_msat: 1_000_000,
+            features: Vec::new(),
+        };
+        let invoice = unwrap!(Bolt12Invoice::for_request(&invoice_request, vec![(path, payinfo)], NOW, [9; 32], issuer_id));
+        assert_eq!(invoice.amount_msat, 100_000);
+        assert!(!invoice.is_expired(NOW + 7199));
+        let mut bytes = unwrap!(invoice.to_bytes(&secp, &issuer_secret));
+        let decoded = unwrap!(Bolt12Invoice::from_bytes(&secp, &bytes));
+        assert_eq!(decoded, invoice);
+        unwrap!(decoded.check_request(&invoice_request));
+
+        // The signature covers every record. Change the last byte of the amount, which comes just
+        // before the node id and signature records.
+        let amount_index = bytes.len() - 66 - 35 - 1;
+        bytes[amount_index] ^= 1;
+        match Bolt12Invoice::from_bytes(&secp, &bytes) {
+            Err(OfferError::InvalidSignature) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unknown_required_features() {
+        let secp = Secp256k1::new();
+        let (issuer_secret, issuer_id) = test_key(&secp, 0x11);
+        let (payer_secret, payer_id) = test_key(&secp, 0x12);
+
+        // Odd bits are optional. Nothing is defined for offers, so any even bit is unknown.
+        let mut offer = Offer { description: Some("coffee".to_owned()), issuer_id: Some(issuer_id), ..Offer::default() };
+        offer.features = vec![0x02, 0x00];
+        assert_eq!(unwrap!(Offer::decode(&offer.encode())), offer);
+        offer.features = vec![0x01, 0x02];
+        match Offer::decode(&offer.encode()) {
+            Err(OfferError::UnknownRequiredFeature { field: "offer_features", bit: 8 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        offer.features = Vec::new();
+
+        let mut invoice_request = InvoiceRequest::for_offer(&offer, vec![7; 16], payer_id);
+        invoice_request.amount_msat = Some(1000);
+        invoice_request.features = vec![0x08];
+        let encoded = unwrap!(invoice_request.encode(&secp, &payer_secret));
+        assert_eq!(unwrap!(InvoiceRequest::decode(&secp, &encoded)), invoice_request);
+        invoice_request.features = vec![0x04];
+        let encoded = unwrap!(invoice_request.encode(&secp, &payer_secret));
+        match InvoiceRequest::decode(&secp, &encoded) {
+            Err(OfferError::UnknownRequiredFeature { field: "invreq_features", bit: 2 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        invoice_request.features = Vec::new();
+
+        let path = unwrap!(BlindedPath::new(&secp, &test_key(&secp, 0x13).0, &[(issuer_id, vec![0; 8])]));
+        let payinfo = BlindedPayInfo {
+            fee_base_msat: 0,
+            fee_proportional_millionths: 0,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1,
+            htlc_maximum_msat: 1_000_000,
+            features: Vec::new(),
+        };
+        let mut invoice = unwrap!(Bolt12Invoice::for_request(&invoice_request, vec![(path, payinfo)], NOW, [9; 32], issuer_id));
+        // basic_mpp, required.
+        invoice.features = vec![0x01, 0x00, 0x00];
+        let bytes = unwrap!(invoice.to_bytes(&secp, &issuer_secret));
+        assert_eq!(unwrap!(Bolt12Invoice::from_bytes(&secp, &bytes)), invoice);
+        invoice.features = vec![0x05, 0x00, 0x00];
+        let bytes = unwrap!(invoice.to_bytes(&secp, &issuer_secret));
+        match Bolt12Invoice::from_bytes(&secp, &bytes) {
+            Err(OfferError::UnknownRequiredFeature { field: "invoice_features", bit: 18 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
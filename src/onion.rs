@@ -0,0 +1,540 @@
+use super::*;
+
+/// The length of the encrypted hop payloads in an onion packet.
+pub const ONION_ROUTING_INFO_LEN: usize = 1300;
+pub const ONION_VERSION: u8 = 0;
+
+const ONION_HMAC_LEN: usize = 32;
+const ZERO_NONCE: [u8; 12] = [0; 12];
+
+#[derive(Debug, Fail)]
+pub enum OnionError {
+    #[fail(display = "onion packet is {} bytes long", len)]
+    InvalidPacketLength {
+        len: usize,
+    },
+    #[fail(display = "unknown onion version {}", version)]
+    UnknownVersion {
+        version: u8,
+    },
+    #[fail(display = "invalid ephemeral public key in onion")]
+    InvalidPublicKey,
+    #[fail(display = "onion hmac does not match")]
+    InvalidHmac,
+    #[fail(display = "invalid onion hop payload: {}", _0)]
+    InvalidPayload(TlvStreamError),
+    #[fail(display = "onion hop payload is missing required tlv record type {}", type_tag)]
+    MissingPayloadField {
+        type_tag: u64,
+    },
+    #[fail(display = "hop payloads take {} bytes, more than fits in an onion", len)]
+    PayloadsTooLong {
+        len: usize,
+    },
+    #[fail(display = "session key can't be used to build an onion")]
+    InvalidSessionKey,
+}
+
+impl From<TlvStreamError> for OnionError {
+    fn from(err: TlvStreamError) -> OnionError {
+        OnionError::InvalidPayload(err)
+    }
+}
+
+impl From<MsgTooShortError> for OnionError {
+    fn from(err: MsgTooShortError) -> OnionError {
+        OnionError::InvalidPayload(TlvStreamError::MsgTooShort(err))
+    }
+}
+
+/// The `payment_data` record of the final hop's payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentData {
+    pub payment_secret: [u8; 32],
+    pub total_msat: u64,
+}
+
+/// What a node along the route is told to do with an HTLC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnionHopPayload {
+    pub amt_to_forward: u64,
+    pub outgoing_cltv_value: u32,
+    /// The channel to forward over. `None` for the final hop.
+    pub short_channel_id: Option<ShortChannelId>,
+    pub payment_data: Option<PaymentData>,
+}
+
+impl OnionHopPayload {
+    pub fn from_payload(payload: &[u8]) -> Result<OnionHopPayload, OnionError> {
+        let mut cursor = ReadCursor::new(payload);
+        let mut amt_to_forward = None;
+        let mut outgoing_cltv_value = None;
+        let mut short_channel_id = None;
+        let mut payment_data = None;
+        for record in cursor.read_tlv_stream()? {
+            match record.type_tag {
+                2 => amt_to_forward = Some(record.read_tu64()?),
+                4 => {
+                    let val = record.read_tu64()?;
+                    if val > u32::max_value() as u64 {
+                        return Err(record.malformed().into());
+                    }
+                    outgoing_cltv_value = Some(val as u32);
+                },
+                6 => {
+                    if record.value.len() != 8 {
+                        return Err(record.malformed().into());
+                    }
+                    short_channel_id = Some(ShortChannelId::from_u64(u64::from_be_bytes(slice_to_array!(record.value, 8))));
+                },
+                8 => {
+                    if record.value.len() < 32 {
+                        return Err(record.malformed().into());
+                    }
+                    let total_msat = read_tu64(&record.value[32..]).ok_or_else(|| record.malformed())?;
+                    payment_data = Some(PaymentData {
+                        payment_secret: slice_to_array!(&record.value[..32], 32),
+                        total_msat,
+                    });
+                },
+                _ => record.ignore_if_odd()?,
+            }
+        }
+        Ok(OnionHopPayload {
+            amt_to_forward: amt_to_forward.ok_or(OnionError::MissingPayloadField { type_tag: 2 })?,
+            outgoing_cltv_value: outgoing_cltv_value.ok_or(OnionError::MissingPayloadField { type_tag: 4 })?,
+            short_channel_id,
+            payment_data,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_tlv_tu64(2, self.amt_to_forward);
+        cursor.write_tlv_tu64(4, self.outgoing_cltv_value as u64);
+        if let Some(short_channel_id) = self.short_channel_id {
+            cursor.write_tlv_record(6, &u64::to_be_bytes(short_channel_id.to_u64())[..]);
+        }
+        if let Some(ref payment_data) = self.payment_data {
+            let total_msat = u64::to_be_bytes(payment_data.total_msat);
+            let skip = total_msat.iter().take_while(|b| **b == 0).count();
+            let mut value = payment_data.payment_secret.to_vec();
+            value.extend_from_slice(&total_msat[skip..]);
+            cursor.write_tlv_record(8, &value);
+        }
+    }
+
+    /// The payload as it's laid out in the onion, ie. prefixed with its length.
+    fn to_length_prefixed_bytes(&self) -> Vec<u8> {
+        let mut payload = WriteCursor::new();
+        self.write_to_cursor(&mut payload);
+        let payload = payload.into_bytes();
+        let mut cursor = WriteCursor::new();
+        cursor.write_bigsize(payload.len() as u64);
+        cursor.write_slice(&payload);
+        cursor.into_bytes().to_vec()
+    }
+}
+
+/// A node on the route of an onion, and what it should be told.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnionHop {
+    pub node_id: secp256k1::PublicKey,
+    pub payload: OnionHopPayload,
+}
+
+/// The `onion_routing_packet` of an `update_add_htlc`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnionPacket {
+    pub version: u8,
+    pub public_key: secp256k1::PublicKey,
+    /// Always `ONION_ROUTING_INFO_LEN` bytes.
+    pub hop_payloads: Vec<u8>,
+    pub hmac: [u8; 32],
+}
+
+/// What a node learns from its layer of an onion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeeledOnion {
+    /// Needed to encrypt errors sent back to the origin of the payment.
+    pub shared_secret: [u8; 32],
+    /// The tlv stream of the node's payload. Parse it with `OnionHopPayload::from_payload`.
+    pub payload: Vec<u8>,
+    /// The packet to forward to the next node, or `None` if we're the final hop.
+    pub next_packet: Option<OnionPacket>,
+}
+
+/// Derives one of the keys (`rho`, `mu`, `um`, `ammag` or `pad`) used to process an onion
+/// from a shared secret.
+pub fn generate_onion_key(key_type: &[u8], shared_secret: &[u8; 32]) -> [u8; 32] {
+    hmac_sha256(key_type, &[&shared_secret[..]])
+}
+
+fn onion_stream(key: &[u8; 32], len: usize) -> Vec<u8> {
+    let mut stream = vec![0; len];
+    chacha20(key, &ZERO_NONCE, 0, &mut stream);
+    stream
+}
+
+fn xor_in_place(data: &mut [u8], stream: &[u8]) {
+    for (byte, stream_byte) in data.iter_mut().zip(stream) {
+        *byte ^= stream_byte;
+    }
+}
+
+fn ecdh(
+    secp: &Secp256k1<secp256k1::All>,
+    public_key: &secp256k1::PublicKey,
+    secret_key: &secp256k1::SecretKey,
+) -> [u8; 32] {
+    let shared_secret = secp256k1::ecdh::SharedSecret::new(secp, public_key, secret_key);
+    slice_to_array!(&shared_secret[..], 32)
+}
+
+fn blinding_factor(
+    secp: &Secp256k1<secp256k1::All>,
+    ephemeral_public_key: &secp256k1::PublicKey,
+    shared_secret: &[u8; 32],
+) -> Result<secp256k1::SecretKey, secp256k1::Error> {
+    let hash = sha256(&[&ephemeral_public_key.serialize()[..], &shared_secret[..]]);
+    secp256k1::SecretKey::from_slice(secp, &hash[..])
+}
+
+/// The ephemeral public key each hop sees and the secret it shares with us.
+fn onion_shared_secrets(
+    secp: &Secp256k1<secp256k1::All>,
+    session_key: &secp256k1::SecretKey,
+    node_ids: &[secp256k1::PublicKey],
+) -> Result<Vec<(secp256k1::PublicKey, [u8; 32])>, secp256k1::Error> {
+    let mut ephemeral_key = session_key.clone();
+    let mut secrets = Vec::with_capacity(node_ids.len());
+    for node_id in node_ids {
+        let ephemeral_public_key = secp256k1::PublicKey::from_secret_key(secp, &ephemeral_key);
+        let shared_secret = ecdh(secp, node_id, &ephemeral_key);
+        let blinding_factor = blinding_factor(secp, &ephemeral_public_key, &shared_secret)?;
+        ephemeral_key.mul_assign(secp, &blinding_factor)?;
+        secrets.push((ephemeral_public_key, shared_secret));
+    }
+    Ok(secrets)
+}
+
+/// The bytes which each hop's decryption shifts in at the end of the routing info, encrypted
+/// with the streams of all the hops before the last so that the last hop sees them as we wrote
+/// them.
+fn generate_filler(shared_secrets: &[[u8; 32]], hop_sizes: &[usize]) -> Vec<u8> {
+    let mut filler = Vec::new();
+    for (shared_secret, hop_size) in shared_secrets.iter().zip(hop_sizes) {
+        let stream = onion_stream(&generate_onion_key(b"rho", shared_secret), 2 * ONION_ROUTING_INFO_LEN);
+        let start = ONION_ROUTING_INFO_LEN - filler.len();
+        filler.resize(filler.len() + hop_size, 0);
+        xor_in_place(&mut filler, &stream[start..]);
+    }
+    filler
+}
+
+impl OnionPacket {
+    /// Builds the onion for a payment along `hops`. Also returns the secret shared with each hop,
+    /// which is needed to decrypt any error the payment fails with.
+    pub fn build(
+        secp: &Secp256k1<secp256k1::All>,
+        session_key: &secp256k1::SecretKey,
+        hops: &[OnionHop],
+        associated_data: &[u8],
+    ) -> Result<(OnionPacket, Vec<[u8; 32]>), OnionError> {
+        let node_ids: Vec<secp256k1::PublicKey> = hops.iter().map(|hop| hop.node_id).collect();
+        let payloads: Vec<Vec<u8>> = hops.iter().map(|hop| hop.payload.to_length_prefixed_bytes()).collect();
+        OnionPacket::build_from_payloads(secp, session_key, &node_ids, &payloads, associated_data)
+    }
+
+    /// Like `build`, but with each hop's payload already encoded and prefixed with its length.
+    fn build_from_payloads(
+        secp: &Secp256k1<secp256k1::All>,
+        session_key: &secp256k1::SecretKey,
+        node_ids: &[secp256k1::PublicKey],
+        payloads: &[Vec<u8>],
+        associated_data: &[u8],
+    ) -> Result<(OnionPacket, Vec<[u8; 32]>), OnionError> {
+        let secrets = match onion_shared_secrets(secp, session_key, node_ids) {
+            Ok(secrets) => secrets,
+            Err(..) => return Err(OnionError::InvalidSessionKey),
+        };
+        let shared_secrets: Vec<[u8; 32]> = secrets.iter().map(|(_, shared_secret)| *shared_secret).collect();
+        let hop_sizes: Vec<usize> = payloads.iter().map(|payload| payload.len() + ONION_HMAC_LEN).collect();
+        let len = hop_sizes.iter().sum();
+        if len > ONION_ROUTING_INFO_LEN {
+            return Err(OnionError::PayloadsTooLong { len });
+        }
+        let filler = match payloads.len() {
+            0 => Vec::new(),
+            num_hops => generate_filler(&shared_secrets[..(num_hops - 1)], &hop_sizes[..(num_hops - 1)]),
+        };
+
+        let pad_key = generate_onion_key(b"pad", &slice_to_array!(&session_key[..], 32));
+        let mut hop_payloads = onion_stream(&pad_key, ONION_ROUTING_INFO_LEN);
+        let mut hmac = [0u8; 32];
+        for (i, payload) in payloads.iter().enumerate().rev() {
+            let mut shifted = Vec::with_capacity(ONION_ROUTING_INFO_LEN);
+            shifted.extend_from_slice(payload);
+            shifted.extend_from_slice(&hmac[..]);
+            shifted.extend_from_slice(&hop_payloads[..(ONION_ROUTING_INFO_LEN - hop_sizes[i])]);
+            hop_payloads = shifted;
+
+            let rho = generate_onion_key(b"rho", &shared_secrets[i]);
+            xor_in_place(&mut hop_payloads, &onion_stream(&rho, ONION_ROUTING_INFO_LEN));
+            if i == payloads.len() - 1 {
+                let filler_start = ONION_ROUTING_INFO_LEN - filler.len();
+                hop_payloads[filler_start..].copy_from_slice(&filler);
+            }
+            let mu = generate_onion_key(b"mu", &shared_secrets[i]);
+            hmac = hmac_sha256(&mu, &[&hop_payloads, associated_data]);
+        }
+
+        let public_key = match secrets.first() {
+            Some((public_key, _)) => *public_key,
+            None => secp256k1::PublicKey::from_secret_key(secp, session_key),
+        };
+        let packet = OnionPacket {
+            version: ONION_VERSION,
+            public_key,
+            hop_payloads,
+            hmac,
+        };
+        Ok((packet, shared_secrets))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<OnionPacket, OnionError> {
+        if bytes.len() != ONION_PACKET_LEN {
+            return Err(OnionError::InvalidPacketLength { len: bytes.len() });
+        }
+        let version = bytes[0];
+        if version != ONION_VERSION {
+            return Err(OnionError::UnknownVersion { version });
+        }
+        let secp = Secp256k1::without_caps();
+        let public_key = match secp256k1::PublicKey::from_slice(&secp, &bytes[1..34]) {
+            Ok(public_key) => public_key,
+            Err(..) => return Err(OnionError::InvalidPublicKey),
+        };
+        let hmac_start = 34 + ONION_ROUTING_INFO_LEN;
+        Ok(OnionPacket {
+            version,
+            public_key,
+            hop_payloads: bytes[34..hmac_start].to_vec(),
+            hmac: slice_to_array!(&bytes[hmac_start..], 32),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ONION_PACKET_LEN);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.public_key.serialize()[..]);
+        bytes.extend_from_slice(&self.hop_payloads);
+        bytes.extend_from_slice(&self.hmac[..]);
+        bytes
+    }
+
+    /// Decrypts our layer of the onion. `associated_data` is the payment hash of the HTLC which
+    /// carried it.
+    pub fn peel(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        node_secret: &secp256k1::SecretKey,
+        associated_data: &[u8],
+    ) -> Result<PeeledOnion, OnionError> {
+        if self.version != ONION_VERSION {
+            return Err(OnionError::UnknownVersion { version: self.version });
+        }
+        let shared_secret = ecdh(secp, &self.public_key, node_secret);
+        let mu = generate_onion_key(b"mu", &shared_secret);
+        let hmac = hmac_sha256(&mu, &[&self.hop_payloads, associated_data]);
+        if !constant_time_eq(&hmac[..], &self.hmac[..]) {
+            return Err(OnionError::InvalidHmac);
+        }
+
+        let rho = generate_onion_key(b"rho", &shared_secret);
+        let mut decrypted = self.hop_payloads.clone();
+        decrypted.resize(2 * ONION_ROUTING_INFO_LEN, 0);
+        xor_in_place(&mut decrypted, &onion_stream(&rho, 2 * ONION_ROUTING_INFO_LEN));
+
+        let mut cursor = ReadCursor::new(&decrypted);
+        let payload_len = cursor.read_bigsize()?;
+        if payload_len == 0 {
+            return Err(OnionError::MissingPayloadField { type_tag: 2 });
+        }
+        if payload_len > (ONION_ROUTING_INFO_LEN - ONION_HMAC_LEN) as u64 {
+            return Err(OnionError::PayloadsTooLong { len: payload_len as usize });
+        }
+        let payload = cursor.read_slice(payload_len as usize)?.to_vec();
+        let next_hmac = cursor.read_bytes32()?;
+        let next_hop_payloads = cursor.read_slice(ONION_ROUTING_INFO_LEN)?.to_vec();
+
+        let next_packet = if next_hmac == [0; 32] {
+            None
+        } else {
+            let mut public_key = self.public_key;
+            let tweaked = {
+                blinding_factor(secp, &self.public_key, &shared_secret)
+                .and_then(|blinding_factor| public_key.mul_assign(secp, &blinding_factor))
+            };
+            if tweaked.is_err() {
+                return Err(OnionError::InvalidPublicKey);
+            }
+            Some(OnionPacket {
+                version: ONION_VERSION,
+                public_key,
+                hop_payloads: next_hop_payloads,
+                hmac: next_hmac,
+            })
+        };
+        Ok(PeeledOnion { shared_secret, payload, next_packet })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    // The keys of the BOLT 4 test vectors: the session key is all 0x41 and the hops' keys are
+    // all 0x41, all 0x42 and so on.
+    fn hop_secret(secp: &Secp256k1<secp256k1::All>, i: usize) -> secp256k1::SecretKey {
+        unwrap!(secp256k1::SecretKey::from_slice(secp, &[0x41 + i as u8; 32]))
+    }
+
+    /// The hops of the BOLT 4 onion test vector. The second and last payloads also carry unknown
+    /// odd records, so only `bolt4_payloads` encodes them exactly.
+    fn hops(secp: &Secp256k1<secp256k1::All>) -> Vec<OnionHop> {
+        let forwards = [(15_000, 1500), (14_000, 1400), (12_500, 1250), (10_000, 1000)];
+        let mut hops: Vec<OnionHop> = {
+            forwards
+            .iter()
+            .enumerate()
+            .map(|(i, &(amt_to_forward, outgoing_cltv_value))| OnionHop {
+                node_id: secp256k1::PublicKey::from_secret_key(secp, &hop_secret(secp, i)),
+                payload: OnionHopPayload {
+                    amt_to_forward,
+                    outgoing_cltv_value,
+                    short_channel_id: Some(ShortChannelId::from_u64(i as u64 + 1)),
+                    payment_data: None,
+                },
+            })
+            .collect()
+        };
+        hops.push(OnionHop {
+            node_id: secp256k1::PublicKey::from_secret_key(secp, &hop_secret(secp, 4)),
+            payload: OnionHopPayload {
+                amt_to_forward: 10_000,
+                outgoing_cltv_value: 1000,
+                short_channel_id: None,
+                payment_data: Some(PaymentData {
+                    payment_secret: hex!("24a33562c54507a9334e79f0dc4f17d407e6d7c61f0e2f3d0d38599502f61704"),
+                    total_msat: 10_000,
+                }),
+            },
+        });
+        hops
+    }
+
+    /// The length-prefixed hop payloads of the BOLT 4 onion test vector.
+    fn bolt4_payloads() -> Vec<Vec<u8>> {
+        let mut final_payload = hex!("fd011002022710040203e8082224a33562c54507a9334e79f0dc4f17d407e6d7c61f0e2f3d0d38599502f617042710fd012de0").to_vec();
+        final_payload.extend_from_slice(&[0x2a; 224]);
+        vec![
+            hex!("1202023a98040205dc06080000000000000001").to_vec(),
+            hex!("52020236b00402057806080000000000000002fd02013c0102030405060708090a0b0c0d0e0f0102030405060708090a0b0c0d0e0f0102030405060708090a0b0c0d0e0f0102030405060708090a0b0c0d0e0f").to_vec(),
+            hex!("12020230d4040204e206080000000000000003").to_vec(),
+            hex!("1202022710040203e806080000000000000004").to_vec(),
+            final_payload,
+        ]
+    }
+
+    const BOLT4_SHARED_SECRETS: [[u8; 32]; 5] = [
+        hex!("53eb63ea8a3fec3b3cd433b85cd62a4b145e1dda09391b348c4e1cd36a03ea66"),
+        hex!("a6519e98832a0b179f62123b3567c106db99ee37bef036e783263602f3488fae"),
+        hex!("3a6b412548762f0dbccce5c7ae7bb8147d1caf9b5471c34120b30bc9c04891cc"),
+        hex!("21e13c2d7cfe7e18836df50872466117a295783ab8aab0e7ecc8c725503ad02d"),
+        hex!("b5756b9b542727dbafc6765a49488b023a725d631af688fc031217e90770c328"),
+    ];
+
+    #[test]
+    fn bolt4_shared_secrets() {
+        let secp = Secp256k1::new();
+        let session_key = hop_secret(&secp, 0);
+        let node_ids: Vec<secp256k1::PublicKey> = hops(&secp).iter().map(|hop| hop.node_id).collect();
+        let secrets = unwrap!(onion_shared_secrets(&secp, &session_key, &node_ids));
+        for ((_, shared_secret), expected) in secrets.iter().zip(BOLT4_SHARED_SECRETS.iter()) {
+            assert_eq!(shared_secret, expected);
+        }
+        assert_eq!(
+            &secrets[1].0.serialize()[..],
+            &hex!("028f9438bfbf7feac2e108d677e3a82da596be706cc1cf342b75c7b7e22bf4e6e2")[..],
+        );
+        assert_eq!(
+            generate_onion_key(b"rho", &secrets[0].1),
+            hex!("ce496ec94def95aadd4bec15cdb41a740c9f2b62347c4917325fcc6fb0453986"),
+        );
+        assert_eq!(
+            generate_onion_key(b"mu", &secrets[0].1),
+            hex!("b57061dc6d0a2b9f261ac410c8b26d64ac5506cbba30267a649c28c179400eba"),
+        );
+    }
+
+    #[test]
+    fn bolt4_onion_vector() {
+        let secp = Secp256k1::new();
+        let hops = hops(&secp);
+        let node_ids: Vec<secp256k1::PublicKey> = hops.iter().map(|hop| hop.node_id).collect();
+        let payloads = bolt4_payloads();
+        let associated_data = [0x42; 32];
+        let session_key = hop_secret(&secp, 0);
+        let (packet, shared_secrets) = unwrap!(OnionPacket::build_from_payloads(&secp, &session_key, &node_ids, &payloads, &associated_data));
+        assert_eq!(&shared_secrets[..], &BOLT4_SHARED_SECRETS[..]);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), ONION_PACKET_LEN);
+        assert_eq!(&bytes[..], &hex!("0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619f7f3416a5aa36dc7eeb3ec6d421e9615471ab870a33ac07fa5d5a51df0a8823aabe3fea3f90d387529d4f72837f9e687230371ccd8d263072206dbed0234f6505e21e282abd8c0e4f5b9ff8042800bbab065036eadd0149b37f27dde664725a49866e052e809d2b0198ab9610faa656bbf4ec516763a59f8f42c171b179166ba38958d4f51b39b3e98706e2d14a2dafd6a5df808093abfca5aeaaca16eded5db7d21fb0294dd1a163edf0fb445d5c8d7d688d6dd9c541762bf5a5123bf9939d957fe648416e88f1b0928bfa034982b22548e1a4d922690eecf546275afb233acf4323974680779f1a964cfe687456035cc0fba8a5428430b390f0057b6d1fe9a8875bfa89693eeb838ce59f09d207a503ee6f6299c92d6361bc335fcbf9b5cd44747aadce2ce6069cfdc3d671daef9f8ae590cf93d957c9e873e9a1bc62d9640dc8fc39c14902d49a1c80239b6c5b7fd91d05878cbf5ffc7db2569f47c43d6c0d27c438abff276e87364deb8858a37e5a62c446af95d8b786eaf0b5fcf78d98b41496794f8dcaac4eef34b2acfb94c7e8c32a9e9866a8fa0b6f2a06f00a1ccde569f97eec05c803ba7500acc96691d8898d73d8e6a47b8f43c3d5de74458d20eda61474c426359677001fbd75a74d7d5db6cb4feb83122f133206203e4e2d293f838bf8c8b3a29acb321315100b87e80e0edb272ee80fda944e3fb6084ed4d7f7c7d21c69d9da43d31a90b70693f9b0cc3eac74c11ab8ff655905688916cfa4ef0bd04135f2e50b7c689a21d04e8e981e74c6058188b9b1f9dfc3eec6838e9ffbcf22ce738d8a177c19318dffef090cee67e12de1a3e2a39f61247547ba5257489cbc11d7d91ed34617fcc42f7a9da2e3cf31a94a210a1018143173913c38f60e62b24bf0d7518f38b5bab3e6a1f8aeb35e31d6442c8abb5178efc892d2e787d79c6ad9e2fc271792983fa9955ac4d1d84a36c024071bc6e431b625519d556af38185601f70e29035ea6a09c8b676c9d88cf7e05e0f17098b584c4168735940263f940033a220f40be4c85344128b14beb9e75696db37014107801a59b13e89cd9d2258c169d523be6d31552c44c82ff4bb18ec9f099f3bf0e5b1bb2ba9a87d7e26f98d294927b600b5529c47e04d98956677cbcee8fa2b60f49776d8b8c367465b7c626da53700684fb6c918ead0eab8360e4f60edd25b4f43816a75ecf70f909301825b512469f8389d79402311d8aecb7b3ef8599e79485a4388d87744d899f7c47ee644361e17040a7958c8911be6f463ab6a9b2afacd688ec55ef517b38f1339efc54487232798bb25522ff4572ff68567fe830f92f7b8113efce3e98c3fffbaedce4fd8b50e41da97c0c08e423a72689cc68e68f752a5e3a9003e64e35c957ca2e1c48bb6f64b05f56b70b575ad2f278d57850a7ad568c24a4d32a3d74b29f03dc125488bc7c637da582357f40b0a52d16b3b40bb2c2315d03360bc24209e20972c200566bcf3bbe5c5b0aedd83132a8a4d5b4242ba370b6d67d9b67eb01052d132c7866b9cb502e44796d9d356e4e3cb47cc527322cd24976fe7c9257a2864151a38e568ef7a79f10d6ef27cc04ce382347a2488b1f404fdbf407fe1ca1c9d0d5649e34800e25e18951c98cae9f43555eef65fee1ea8f15828807366c3b612cd5753bf9fb8fced08855f742cddd6f765f74254f03186683d646e6f09ac2805586c7cf11998357cafc5df3f285329366f475130c928b2dceba4aa383758e7a9d20705c4bb9db619e2992f608a1ba65db254bb389468741d0502e2588aeb54390ac600c19af5c8e61383fc1bebe0029e4474051e4ef908828db9cca13277ef65db3fd47ccc2179126aaefb627719f421e20")[..]);
+
+        let mut packet = unwrap!(OnionPacket::from_bytes(&bytes));
+        for (i, (hop, payload)) in hops.iter().zip(payloads.iter()).enumerate() {
+            let peeled = unwrap!(packet.peel(&secp, &hop_secret(&secp, i), &associated_data));
+            assert_eq!(peeled.shared_secret, BOLT4_SHARED_SECRETS[i]);
+            let prefix_len = payload.len() - peeled.payload.len();
+            assert_eq!(&peeled.payload[..], &payload[prefix_len..]);
+            // The unknown odd records are skipped when parsing.
+            assert_eq!(unwrap!(OnionHopPayload::from_payload(&peeled.payload)), hop.payload);
+            match peeled.next_packet {
+                Some(next_packet) => {
+                    assert!(i < hops.len() - 1);
+                    packet = next_packet;
+                },
+                None => assert_eq!(i, hops.len() - 1),
+            }
+        }
+    }
+
+    #[test]
+    fn build_and_peel() {
+        let secp = Secp256k1::new();
+        let hops = hops(&secp);
+        let associated_data = [0x42; 32];
+        let (packet, _) = unwrap!(OnionPacket::build(&secp, &hop_secret(&secp, 0), &hops, &associated_data));
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), ONION_PACKET_LEN);
+
+        let mut packet = unwrap!(OnionPacket::from_bytes(&bytes));
+        match packet.peel(&secp, &hop_secret(&secp, 0), &[0x43; 32]) {
+            Err(OnionError::InvalidHmac) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        for (i, hop) in hops.iter().enumerate() {
+            let peeled = unwrap!(packet.peel(&secp, &hop_secret(&secp, i), &associated_data));
+            assert_eq!(unwrap!(OnionHopPayload::from_payload(&peeled.payload)), hop.payload);
+            match peeled.next_packet {
+                Some(next_packet) => packet = next_packet,
+                None => {
+                    assert_eq!(i, hops.len() - 1);
+                    return;
+                },
+            }
+        }
+        panic!("final hop was not recognised");
+    }
+}
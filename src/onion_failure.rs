@@ -0,0 +1,437 @@
+use super::*;
+
+pub const FAILURE_FLAG_BADONION: u16 = 0x8000;
+pub const FAILURE_FLAG_PERM: u16 = 0x4000;
+pub const FAILURE_FLAG_NODE: u16 = 0x2000;
+pub const FAILURE_FLAG_UPDATE: u16 = 0x1000;
+
+/// Failure messages are padded to at least this length so that they don't leak what went wrong.
+const FAILURE_MESSAGE_PADDED_LEN: usize = 256;
+
+/// Why an HTLC failed, as reported by the node it failed at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnionFailure {
+    InvalidRealm,
+    TemporaryNodeFailure,
+    PermanentNodeFailure,
+    RequiredNodeFeatureMissing,
+    InvalidOnionVersion {
+        sha256_of_onion: [u8; 32],
+    },
+    InvalidOnionHmac {
+        sha256_of_onion: [u8; 32],
+    },
+    InvalidOnionKey {
+        sha256_of_onion: [u8; 32],
+    },
+    TemporaryChannelFailure {
+        channel_update: ChannelUpdateMsg,
+    },
+    PermanentChannelFailure,
+    RequiredChannelFeatureMissing,
+    UnknownNextPeer,
+    AmountBelowMinimum {
+        htlc_msat: u64,
+        channel_update: ChannelUpdateMsg,
+    },
+    FeeInsufficient {
+        htlc_msat: u64,
+        channel_update: ChannelUpdateMsg,
+    },
+    IncorrectCltvExpiry {
+        cltv_expiry: u32,
+        channel_update: ChannelUpdateMsg,
+    },
+    ExpiryTooSoon {
+        channel_update: ChannelUpdateMsg,
+    },
+    IncorrectOrUnknownPaymentDetails {
+        htlc_msat: u64,
+        height: u32,
+    },
+    FinalIncorrectCltvExpiry {
+        cltv_expiry: u32,
+    },
+    FinalIncorrectHtlcAmount {
+        incoming_htlc_amt: u64,
+    },
+    ChannelDisabled {
+        disabled_flags: u16,
+        channel_update: ChannelUpdateMsg,
+    },
+    ExpiryTooFar,
+    InvalidOnionPayload {
+        type_tag: u64,
+        offset: u16,
+    },
+    MppTimeout,
+    /// A failure code we don't know, along with whatever data followed it.
+    Unknown {
+        failure_code: u16,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Fail)]
+pub enum OnionFailureError {
+    #[fail(display = "failure packet is too short")]
+    PacketTooShort,
+    #[fail(display = "failure packet was not created by any node on the route")]
+    Unattributable,
+    #[fail(display = "malformed failure message from hop {}: {}", hop_index, err)]
+    Malformed {
+        hop_index: usize,
+        err: PayloadError,
+    },
+}
+
+/// A failure we've traced back to the node which reported it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFailure {
+    /// Which node of the route the failure came from, counting from 0 for the first hop.
+    pub hop_index: usize,
+    pub failure: OnionFailure,
+}
+
+fn read_channel_update(cursor: &mut ReadCursor) -> Result<ChannelUpdateMsg, PayloadError> {
+    let bytes = cursor.read_u16_len_slice()?;
+    // Older nodes leave out the message type.
+    let payload = if bytes.len() >= 2 && bytes[..2] == u16::to_be_bytes(MsgType::ChannelUpdate as u16)[..] {
+        &bytes[2..]
+    } else {
+        bytes
+    };
+    ChannelUpdateMsg::from_payload(payload)
+}
+
+fn write_channel_update(cursor: &mut WriteCursor, channel_update: &ChannelUpdateMsg) {
+    let mut msg = WriteCursor::new();
+    msg.write_u16(MsgType::ChannelUpdate as u16);
+    channel_update.write_to_cursor(&mut msg);
+    cursor.write_u16_len_slice(&msg.into_bytes());
+}
+
+impl OnionFailure {
+    pub fn failure_code(&self) -> u16 {
+        match self {
+            OnionFailure::InvalidRealm => FAILURE_FLAG_PERM | 1,
+            OnionFailure::TemporaryNodeFailure => FAILURE_FLAG_NODE | 2,
+            OnionFailure::PermanentNodeFailure => FAILURE_FLAG_PERM | FAILURE_FLAG_NODE | 2,
+            OnionFailure::RequiredNodeFeatureMissing => FAILURE_FLAG_PERM | FAILURE_FLAG_NODE | 3,
+            OnionFailure::InvalidOnionVersion { .. } => FAILURE_FLAG_BADONION | FAILURE_FLAG_PERM | 4,
+            OnionFailure::InvalidOnionHmac { .. } => FAILURE_FLAG_BADONION | FAILURE_FLAG_PERM | 5,
+            OnionFailure::InvalidOnionKey { .. } => FAILURE_FLAG_BADONION | FAILURE_FLAG_PERM | 6,
+            OnionFailure::TemporaryChannelFailure { .. } => FAILURE_FLAG_UPDATE | 7,
+            OnionFailure::PermanentChannelFailure => FAILURE_FLAG_PERM | 8,
+            OnionFailure::RequiredChannelFeatureMissing => FAILURE_FLAG_PERM | 9,
+            OnionFailure::UnknownNextPeer => FAILURE_FLAG_PERM | 10,
+            OnionFailure::AmountBelowMinimum { .. } => FAILURE_FLAG_UPDATE | 11,
+            OnionFailure::FeeInsufficient { .. } => FAILURE_FLAG_UPDATE | 12,
+            OnionFailure::IncorrectCltvExpiry { .. } => FAILURE_FLAG_UPDATE | 13,
+            OnionFailure::ExpiryTooSoon { .. } => FAILURE_FLAG_UPDATE | 14,
+            OnionFailure::IncorrectOrUnknownPaymentDetails { .. } => FAILURE_FLAG_PERM | 15,
+            OnionFailure::FinalIncorrectCltvExpiry { .. } => 18,
+            OnionFailure::FinalIncorrectHtlcAmount { .. } => 19,
+            OnionFailure::ChannelDisabled { .. } => FAILURE_FLAG_UPDATE | 20,
+            OnionFailure::ExpiryTooFar => 21,
+            OnionFailure::InvalidOnionPayload { .. } => FAILURE_FLAG_PERM | 22,
+            OnionFailure::MppTimeout => 23,
+            OnionFailure::Unknown { failure_code, .. } => *failure_code,
+        }
+    }
+
+    /// Whether retrying along the same route is pointless.
+    pub fn is_permanent(&self) -> bool {
+        self.failure_code() & FAILURE_FLAG_PERM != 0
+    }
+
+    /// Whether the failure is about the node rather than the channel it should have forwarded
+    /// over.
+    pub fn is_node_failure(&self) -> bool {
+        self.failure_code() & FAILURE_FLAG_NODE != 0
+    }
+
+    /// The latest policy of the channel the HTLC should have been forwarded over, if the failing
+    /// node sent it. It should be applied to the graph before retrying.
+    pub fn channel_update(&self) -> Option<&ChannelUpdateMsg> {
+        match self {
+            OnionFailure::TemporaryChannelFailure { channel_update }
+            | OnionFailure::AmountBelowMinimum { channel_update, .. }
+            | OnionFailure::FeeInsufficient { channel_update, .. }
+            | OnionFailure::IncorrectCltvExpiry { channel_update, .. }
+            | OnionFailure::ExpiryTooSoon { channel_update }
+            | OnionFailure::ChannelDisabled { channel_update, .. } => Some(channel_update),
+            _ => None,
+        }
+    }
+
+    /// The failure to report for an onion we couldn't peel. `onion` is the packet as received.
+    /// `BADONION` failures must be sent back in an `update_fail_malformed_htlc` since we can't
+    /// encrypt them for the origin.
+    pub fn for_onion_error(err: &OnionError, onion: &[u8]) -> OnionFailure {
+        let sha256_of_onion = sha256(&[onion]);
+        match err {
+            OnionError::InvalidPacketLength { .. }
+            | OnionError::UnknownVersion { .. } => OnionFailure::InvalidOnionVersion { sha256_of_onion },
+            OnionError::InvalidHmac => OnionFailure::InvalidOnionHmac { sha256_of_onion },
+            OnionError::InvalidPublicKey => OnionFailure::InvalidOnionKey { sha256_of_onion },
+            OnionError::InvalidPayload(TlvStreamError::MalformedValue { type_tag })
+            | OnionError::InvalidPayload(TlvStreamError::UnknownEvenType { type_tag })
+            | OnionError::MissingPayloadField { type_tag } => {
+                OnionFailure::InvalidOnionPayload { type_tag: *type_tag, offset: 0 }
+            },
+            OnionError::InvalidPayload(..)
+            | OnionError::PayloadsTooLong { .. }
+            | OnionError::InvalidSessionKey => OnionFailure::InvalidOnionPayload { type_tag: 0, offset: 0 },
+        }
+    }
+
+    pub fn from_failure_msg(failure_msg: &[u8]) -> Result<OnionFailure, PayloadError> {
+        let mut cursor = ReadCursor::new(failure_msg);
+        let failure_code = cursor.read_u16()?;
+        let failure = match failure_code {
+            0x4001 => OnionFailure::InvalidRealm,
+            0x2002 => OnionFailure::TemporaryNodeFailure,
+            0x6002 => OnionFailure::PermanentNodeFailure,
+            0x6003 => OnionFailure::RequiredNodeFeatureMissing,
+            0xc004 => OnionFailure::InvalidOnionVersion { sha256_of_onion: cursor.read_bytes32()? },
+            0xc005 => OnionFailure::InvalidOnionHmac { sha256_of_onion: cursor.read_bytes32()? },
+            0xc006 => OnionFailure::InvalidOnionKey { sha256_of_onion: cursor.read_bytes32()? },
+            0x1007 => OnionFailure::TemporaryChannelFailure {
+                channel_update: read_channel_update(&mut cursor)?,
+            },
+            0x4008 => OnionFailure::PermanentChannelFailure,
+            0x4009 => OnionFailure::RequiredChannelFeatureMissing,
+            0x400a => OnionFailure::UnknownNextPeer,
+            0x100b => OnionFailure::AmountBelowMinimum {
+                htlc_msat: cursor.read_u64()?,
+                channel_update: read_channel_update(&mut cursor)?,
+            },
+            0x100c => OnionFailure::FeeInsufficient {
+                htlc_msat: cursor.read_u64()?,
+                channel_update: read_channel_update(&mut cursor)?,
+            },
+            0x100d => OnionFailure::IncorrectCltvExpiry {
+                cltv_expiry: cursor.read_u32()?,
+                channel_update: read_channel_update(&mut cursor)?,
+            },
+            0x100e => OnionFailure::ExpiryTooSoon {
+                channel_update: read_channel_update(&mut cursor)?,
+            },
+            0x400f => {
+                // Older nodes send neither field.
+                let htlc_msat = if cursor.is_empty() { 0 } else { cursor.read_u64()? };
+                let height = if cursor.is_empty() { 0 } else { cursor.read_u32()? };
+                OnionFailure::IncorrectOrUnknownPaymentDetails { htlc_msat, height }
+            },
+            0x0012 => OnionFailure::FinalIncorrectCltvExpiry { cltv_expiry: cursor.read_u32()? },
+            0x0013 => OnionFailure::FinalIncorrectHtlcAmount { incoming_htlc_amt: cursor.read_u64()? },
+            0x1014 => OnionFailure::ChannelDisabled {
+                disabled_flags: cursor.read_u16()?,
+                channel_update: read_channel_update(&mut cursor)?,
+            },
+            0x0015 => OnionFailure::ExpiryTooFar,
+            0x4016 => {
+                let type_tag = cursor.read_bigsize().map_err(PayloadError::Tlv)?;
+                let offset = cursor.read_u16()?;
+                OnionFailure::InvalidOnionPayload { type_tag, offset }
+            },
+            0x0017 => OnionFailure::MppTimeout,
+            _ => OnionFailure::Unknown {
+                failure_code,
+                data: cursor.read_to_end().to_vec(),
+            },
+        };
+        Ok(failure)
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u16(self.failure_code());
+        match self {
+            OnionFailure::InvalidOnionVersion { sha256_of_onion }
+            | OnionFailure::InvalidOnionHmac { sha256_of_onion }
+            | OnionFailure::InvalidOnionKey { sha256_of_onion } => cursor.write_slice(&sha256_of_onion[..]),
+            OnionFailure::TemporaryChannelFailure { channel_update }
+            | OnionFailure::ExpiryTooSoon { channel_update } => write_channel_update(cursor, channel_update),
+            OnionFailure::AmountBelowMinimum { htlc_msat, channel_update }
+            | OnionFailure::FeeInsufficient { htlc_msat, channel_update } => {
+                cursor.write_u64(*htlc_msat);
+                write_channel_update(cursor, channel_update);
+            },
+            OnionFailure::IncorrectCltvExpiry { cltv_expiry, channel_update } => {
+                cursor.write_u32(*cltv_expiry);
+                write_channel_update(cursor, channel_update);
+            },
+            OnionFailure::IncorrectOrUnknownPaymentDetails { htlc_msat, height } => {
+                cursor.write_u64(*htlc_msat);
+                cursor.write_u32(*height);
+            },
+            OnionFailure::FinalIncorrectCltvExpiry { cltv_expiry } => cursor.write_u32(*cltv_expiry),
+            OnionFailure::FinalIncorrectHtlcAmount { incoming_htlc_amt } => cursor.write_u64(*incoming_htlc_amt),
+            OnionFailure::ChannelDisabled { disabled_flags, channel_update } => {
+                cursor.write_u16(*disabled_flags);
+                write_channel_update(cursor, channel_update);
+            },
+            OnionFailure::InvalidOnionPayload { type_tag, offset } => {
+                cursor.write_bigsize(*type_tag);
+                cursor.write_u16(*offset);
+            },
+            OnionFailure::Unknown { data, .. } => cursor.write_slice(data),
+            OnionFailure::InvalidRealm
+            | OnionFailure::TemporaryNodeFailure
+            | OnionFailure::PermanentNodeFailure
+            | OnionFailure::RequiredNodeFeatureMissing
+            | OnionFailure::PermanentChannelFailure
+            | OnionFailure::RequiredChannelFeatureMissing
+            | OnionFailure::UnknownNextPeer
+            | OnionFailure::ExpiryTooFar
+            | OnionFailure::MppTimeout => (),
+        }
+    }
+}
+
+/// Adds (or removes) one layer of obfuscation. Every node between the failing node and the
+/// origin, including the failing node itself, does this once.
+pub fn wrap_failure_packet(shared_secret: &[u8; 32], packet: &mut [u8]) {
+    let ammag = generate_onion_key(b"ammag", shared_secret);
+    let mut stream = vec![0; packet.len()];
+    chacha20(&ammag, &[0; 12], 0, &mut stream);
+    for (byte, stream_byte) in packet.iter_mut().zip(stream) {
+        *byte ^= stream_byte;
+    }
+}
+
+/// Builds the `reason` of an `update_fail_htlc` for an HTLC which failed at our node, given
+/// the secret from peeling its onion.
+pub fn build_failure_packet(shared_secret: &[u8; 32], failure: &OnionFailure) -> Vec<u8> {
+    let mut failure_msg = WriteCursor::new();
+    failure.write_to_cursor(&mut failure_msg);
+    let failure_msg = failure_msg.into_bytes();
+    let pad_len = FAILURE_MESSAGE_PADDED_LEN.saturating_sub(failure_msg.len());
+
+    let mut cursor = WriteCursor::new();
+    cursor.write_u16_len_slice(&failure_msg);
+    cursor.write_u16_len_slice(&vec![0; pad_len]);
+    let body = cursor.into_bytes();
+
+    let um = generate_onion_key(b"um", shared_secret);
+    let mut packet = hmac_sha256(&um, &[&body]).to_vec();
+    packet.extend_from_slice(&body);
+    wrap_failure_packet(shared_secret, &mut packet);
+    packet
+}
+
+/// Works out which hop of our payment failed and why, given the secrets `OnionPacket::build`
+/// returned for its onion.
+pub fn decode_failure_packet(
+    shared_secrets: &[[u8; 32]],
+    packet: &[u8],
+) -> Result<DecodedFailure, OnionFailureError> {
+    if packet.len() < 32 + 4 {
+        return Err(OnionFailureError::PacketTooShort);
+    }
+    let mut packet = packet.to_vec();
+    for (hop_index, shared_secret) in shared_secrets.iter().enumerate() {
+        wrap_failure_packet(shared_secret, &mut packet);
+        let um = generate_onion_key(b"um", shared_secret);
+        let hmac = hmac_sha256(&um, &[&packet[32..]]);
+        if !constant_time_eq(&hmac[..], &packet[..32]) {
+            continue;
+        }
+        let mut cursor = ReadCursor::new(&packet[32..]);
+        let failure = {
+            cursor
+            .read_u16_len_slice()
+            .map_err(PayloadError::from)
+            .and_then(OnionFailure::from_failure_msg)
+        };
+        return match failure {
+            Ok(failure) => Ok(DecodedFailure { hop_index, failure }),
+            Err(err) => Err(OnionFailureError::Malformed { hop_index, err }),
+        };
+    }
+    Err(OnionFailureError::Unattributable)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    /// The shared secrets of the BOLT 4 test vectors.
+    fn shared_secrets() -> [[u8; 32]; 5] {
+        [
+            hex!("53eb63ea8a3fec3b3cd433b85cd62a4b145e1dda09391b348c4e1cd36a03ea66"),
+            hex!("a6519e98832a0b179f62123b3567c106db99ee37bef036e783263602f3488fae"),
+            hex!("3a6b412548762f0dbccce5c7ae7bb8147d1caf9b5471c34120b30bc9c04891cc"),
+            hex!("21e13c2d7cfe7e18836df50872466117a295783ab8aab0e7ecc8c725503ad02d"),
+            hex!("b5756b9b542727dbafc6765a49488b023a725d631af688fc031217e90770c328"),
+        ]
+    }
+
+    fn fail_at(hop_index: usize, failure: &OnionFailure) -> Vec<u8> {
+        let shared_secrets = shared_secrets();
+        let mut packet = build_failure_packet(&shared_secrets[hop_index], failure);
+        for shared_secret in shared_secrets[..hop_index].iter().rev() {
+            wrap_failure_packet(shared_secret, &mut packet);
+        }
+        packet
+    }
+
+    #[test]
+    fn bolt4_failure_vector() {
+        let shared_secrets = shared_secrets();
+        let mut packet = build_failure_packet(&shared_secrets[4], &OnionFailure::TemporaryNodeFailure);
+
+        // Before its own layer of obfuscation, the failing node's packet is the hmac, the failure
+        // message and the padding.
+        let mut plaintext = packet.clone();
+        wrap_failure_packet(&shared_secrets[4], &mut plaintext);
+        let mut expected_plaintext = hex!("4c2fc8bc08510334b6833ad9c3e79cd1b52ae59dfe5c2a4b23ead50f09f7ee0b0002200200fe").to_vec();
+        expected_plaintext.resize(292, 0);
+        assert_eq!(plaintext, expected_plaintext);
+
+        // The packet as it leaves each hop, from the failing node back to the first hop.
+        let hop_packets = [
+            (4, &hex!("a5e6bd0c74cb347f10cce367f949098f2457d14c046fd8a22cb96efb30b0fdcda8cb9168b50f2fd45edd73c1b0c8b33002df376801ff58aaa94000bf8a86f92620f343baef38a580102395ae3abf9128d1047a0736ff9b83d456740ebbb4aeb3aa9737f18fb4afb4aa074fb26c4d702f42968888550a3bded8c05247e045b866baef0499f079fdaeef6538f31d44deafffdfd3afa2fb4ca9082b8f1c465371a9894dd8c243fb4847e004f5256b3e90e2edde4c9fb3082ddfe4d1e734cacd96ef0706bf63c9984e22dc98851bcccd1c3494351feb458c9c6af41c0044bea3c47552b1d992ae542b17a2d0bba1a096c78d169034ecb55b6e3a7263c26017f033031228833c1daefc0dedb8cf7c3e37c9c37ebfe42f3225c326e8bcfd338804c145b16e34e4")[..]),
+            (3, &hex!("c49a1ce81680f78f5f2000cda36268de34a3f0a0662f55b4e837c83a8773c22aa081bab1616a0011585323930fa5b9fae0c85770a2279ff59ec427ad1bbff9001c0cd1497004bd2a0f68b50704cf6d6a4bf3c8b6a0833399a24b3456961ba00736785112594f65b6b2d44d9f5ea4e49b5e1ec2af978cbe31c67114440ac51a62081df0ed46d4a3df295da0b0fe25c0115019f03f15ec86fabb4c852f83449e812f141a9395b3f70b766ebbd4ec2fae2b6955bd8f32684c15abfe8fd3a6261e52650e8807a92158d9f1463261a925e4bfba44bd20b166d532f0017185c3a6ac7957adefe45559e3072c8dc35abeba835a8cb01a71a15c736911126f27d46a36168ca5ef7dccd4e2886212602b181463e0dd30185c96348f9743a02aca8ec27c0b90dca270")[..]),
+            (2, &hex!("a5d3e8634cfe78b2307d87c6d90be6fe7855b4f2cc9b1dfb19e92e4b79103f61ff9ac25f412ddfb7466e74f81b3e545563cdd8f5524dae873de61d7bdfccd496af2584930d2b566b4f8d3881f8c043df92224f38cf094cfc09d92655989531524593ec6d6caec1863bdfaa79229b5020acc034cd6deeea1021c50586947b9b8e6faa83b81fbfa6133c0af5d6b07c017f7158fa94f0d206baf12dda6b68f785b773b360fd0497e16cc402d779c8d48d0fa6315536ef0660f3f4e1865f5b38ea49c7da4fd959de4e83ff3ab686f059a45c65ba2af4a6a79166aa0f496bf04d06987b6d2ea205bdb0d347718b9aeff5b61dfff344993a275b79717cd815b6ad4c0beb568c4ac9c36ff1c315ec1119a1993c4b61e6eaa0375e0aaf738ac691abd3263bf937e3")[..]),
+            (1, &hex!("aac3200c4968f56b21f53e5e374e3a2383ad2b1b6501bbcc45abc31e59b26881b7dfadbb56ec8dae8857add94e6702fb4c3a4de22e2e669e1ed926b04447fc73034bb730f4932acd62727b75348a648a1128744657ca6a4e713b9b646c3ca66cac02cdab44dd3439890ef3aaf61708714f7375349b8da541b2548d452d84de7084bb95b3ac2345201d624d31f4d52078aa0fa05a88b4e20202bd2b86ac5b52919ea305a8949de95e935eed0319cf3cf19ebea61d76ba92532497fcdc9411d06bcd4275094d0a4a3c5d3a945e43305a5a9256e333e1f64dbca5fcd4e03a39b9012d197506e06f29339dfee3331995b21615337ae060233d39befea925cc262873e0530408e6990f1cbd233a150ef7b004ff6166c70c68d9f8c853c1abca640b8660db2921")[..]),
+            (0, &hex!("9c5add3963fc7f6ed7f148623c84134b5647e1306419dbe2174e523fa9e2fbed3a06a19f899145610741c83ad40b7712aefaddec8c6baf7325d92ea4ca4d1df8bce517f7e54554608bf2bd8071a4f52a7a2f7ffbb1413edad81eeea5785aa9d990f2865dc23b4bc3c301a94eec4eabebca66be5cf638f693ec256aec514620cc28ee4a94bd9565bc4d4962b9d3641d4278fb319ed2b84de5b665f307a2db0f7fbb757366067d88c50f7e829138fde4f78d39b5b5802f1b92a8a820865af5cc79f9f30bc3f461c66af95d13e5e1f0381c184572a91dee1c849048a647a1158cf884064deddbf1b0b88dfe2f791428d0ba0f6fb2f04e14081f69165ae66d9297c118f0907705c9c4954a199bae0bb96fad763d690e7daa6cfda59ba7f2c8d11448b604d12d")[..]),
+        ];
+        for &(hop_index, expected) in &hop_packets {
+            if hop_index < 4 {
+                wrap_failure_packet(&shared_secrets[hop_index], &mut packet);
+            }
+            assert_eq!(&packet[..], expected, "packet leaving hop {}", hop_index);
+        }
+        assert_eq!(packet, fail_at(4, &OnionFailure::TemporaryNodeFailure));
+
+        let decoded = unwrap!(decode_failure_packet(&shared_secrets, &packet));
+        assert_eq!(decoded, DecodedFailure { hop_index: 4, failure: OnionFailure::TemporaryNodeFailure });
+        assert!(decoded.failure.is_node_failure());
+        assert!(!decoded.failure.is_permanent());
+    }
+
+    #[test]
+    fn attributes_failures_with_channel_updates() {
+        let secp = Secp256k1::new();
+        let announcement = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(500));
+        let channel_update = signed_channel_update(&secp, &announcement, 2, 1_600_000_000, |_| ());
+        let failure = OnionFailure::FeeInsufficient { htlc_msat: 100_000, channel_update };
+
+        let packet = fail_at(1, &failure);
+        let decoded = unwrap!(decode_failure_packet(&shared_secrets(), &packet));
+        assert_eq!(decoded.hop_index, 1);
+        let short_channel_id = decoded.failure.channel_update().map(|update| update.short_channel_id);
+        assert_eq!(short_channel_id, Some(announcement.short_channel_id));
+        assert_eq!(decoded.failure, failure);
+
+        let mut tampered = packet.clone();
+        tampered[100] ^= 1;
+        match decode_failure_packet(&shared_secrets(), &tampered) {
+            Err(OnionFailureError::Unattributable) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -1,6 +1,14 @@
 use super::*;
 use std::io;
+use std::time::{Duration, Instant};
 use failure::Fail;
+use futures::future::{Either, Loop};
+use tokio::timer::Delay;
+
+/// How many messages of a gossip dump we send before pausing.
+const GOSSIP_DUMP_BATCH_SIZE: usize = 100;
+/// How long we pause between batches of a gossip dump.
+const GOSSIP_DUMP_BATCH_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 pub struct Peer {
@@ -83,6 +91,33 @@ impl Peer {
         })
     }
 
+    /// Streams all our gossip to the peer, for when it set `initial_routing_sync` in its `init`.
+    /// Each message waits for the previous write to finish, and we pause between batches so the
+    /// dump doesn't hog the connection. If the peer sent a `gossip_timestamp_filter`, only the
+    /// gossip passing it is sent.
+    pub fn send_gossip_dump<S>(self, source: Arc<S>, timestamp_filter: Option<GossipTimestampFilterMsg>)
+        -> impl Future<Item = Peer, Error = io::Error> + Send + 'static
+    where
+        S: GossipQuerySource + Send + Sync + 'static,
+    {
+        let dump = GossipDump::new(&*source, timestamp_filter);
+        future::loop_fn((self, dump), move |(peer, mut dump)| {
+            let batch = dump.next_batch(&*source, GOSSIP_DUMP_BATCH_SIZE);
+            stream::iter_ok(batch)
+            .fold(peer, |peer, msg| peer.send_msg(msg))
+            .and_then(move |peer| {
+                if dump.is_done() {
+                    return Either::A(future::ok(Loop::Break(peer)));
+                }
+                Either::B({
+                    Delay::new(Instant::now() + GOSSIP_DUMP_BATCH_INTERVAL)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .map(move |()| Loop::Continue((peer, dump)))
+                })
+            })
+        })
+    }
+
     /*
     pub fn open_channel(
         self,
@@ -137,5 +172,105 @@ mod test {
             })
         })).never_err()
     }
+
+    /// Dumps `graph` over a loopback connection and returns the first `count` messages received.
+    fn receive_gossip_dump(
+        graph: NetworkGraph,
+        timestamp_filter: Option<GossipTimestampFilterMsg>,
+        count: usize,
+    ) -> Vec<Bytes> {
+        let mut runtime = unwrap!(Runtime::new());
+        let listener = unwrap!(TcpListener::bind(&addr!("127.0.0.1:0")));
+        let addr = unwrap!(listener.local_addr());
+        let accept = {
+            listener
+            .incoming()
+            .into_future()
+            .map(|(stream, _)| unwrap!(stream))
+            .map_err(|(e, _)| e)
+        };
+        let (sender, receiver) = unwrap!(runtime.block_on(accept.join(TcpStream::connect(&addr))));
+        let sender = Peer { stream: sender, sk: [1; 32], rk: [2; 32], sn: 0, rn: 0 };
+        let receiver = Peer { stream: receiver, sk: [2; 32], rk: [1; 32], sn: 0, rn: 0 };
+
+        let send = {
+            sender
+            .send_gossip_dump(Arc::new(graph), timestamp_filter)
+            .map_err(|e| panic!("error sending gossip dump: {}", e))
+        };
+        let recv = {
+            stream::iter_ok(0..count)
+            .fold((receiver, Vec::new()), |(receiver, mut msgs), _| {
+                receiver
+                .recv_msg()
+                .map(move |(receiver, msg)| {
+                    msgs.push(msg);
+                    (receiver, msgs)
+                })
+            })
+            .map_err(|e: handshake::RecvMsgError| panic!("error receiving gossip dump: {}", e))
+        };
+        let (_, (_, msgs)) = unwrap!(runtime.block_on(send.join(recv)));
+        msgs
+    }
+
+    #[test]
+    fn gossip_dump_order_and_filter() {
+        let secp = Secp256k1::new();
+        // Ordered by node id, the nodes are 2, 3, 1.
+        let channel_a = signed_channel_announcement(&secp, 1, 2, test_short_channel_id(100));
+        let channel_b = signed_channel_announcement(&secp, 2, 3, test_short_channel_id(200));
+        let update_a_2 = signed_channel_update(&secp, &channel_a, 2, 1000, |_| ());
+        let update_a_1 = signed_channel_update(&secp, &channel_a, 1, 2000, |_| ());
+        let update_b_2 = signed_channel_update(&secp, &channel_b, 2, 3000, |_| ());
+        let update_b_3 = signed_channel_update(&secp, &channel_b, 3, 4000, |_| ());
+        let node_1 = signed_node_announcement(&secp, 1, 1000);
+        let node_2 = signed_node_announcement(&secp, 2, 3000);
+        let node_3 = signed_node_announcement(&secp, 3, 4000);
+        let graph = || {
+            let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+            for announcement in &[&channel_a, &channel_b] {
+                unwrap!(graph.handle_channel_announcement(announcement, 5000));
+            }
+            for update in &[&update_a_2, &update_a_1, &update_b_2, &update_b_3] {
+                unwrap!(graph.handle_channel_update(update, 5000));
+            }
+            for announcement in &[&node_1, &node_2, &node_3] {
+                unwrap!(graph.handle_node_announcement(announcement));
+            }
+            graph
+        };
+        let to_bytes = |msgs: Vec<Msg>| -> Vec<Bytes> {
+            msgs.iter().map(Msg::to_bytes).collect()
+        };
+
+        let expected = to_bytes(vec![
+            Msg::ChannelAnnouncement(channel_a.clone()),
+            Msg::ChannelUpdate(update_a_2.clone()),
+            Msg::ChannelUpdate(update_a_1.clone()),
+            Msg::NodeAnnouncement(node_2.clone()),
+            Msg::NodeAnnouncement(node_1.clone()),
+            Msg::ChannelAnnouncement(channel_b.clone()),
+            Msg::ChannelUpdate(update_b_2.clone()),
+            Msg::ChannelUpdate(update_b_3.clone()),
+            Msg::NodeAnnouncement(node_3.clone()),
+        ]);
+        assert_eq!(receive_gossip_dump(graph(), None, expected.len()), expected);
+
+        // Channel a's updates are both too old, so its announcement is skipped too.
+        let filter = GossipTimestampFilterMsg {
+            chain_hash: ChainHash::BITCOIN,
+            first_timestamp: 2500,
+            timestamp_range: 10_000,
+        };
+        let expected = to_bytes(vec![
+            Msg::NodeAnnouncement(node_2.clone()),
+            Msg::ChannelAnnouncement(channel_b.clone()),
+            Msg::ChannelUpdate(update_b_2.clone()),
+            Msg::ChannelUpdate(update_b_3.clone()),
+            Msg::NodeAnnouncement(node_3.clone()),
+        ]);
+        assert_eq!(receive_gossip_dump(graph(), Some(filter), expected.len()), expected);
+    }
 }
 
@@ -0,0 +1,321 @@
+use super::*;
+
+/// What we need to resend after a `channel_reestablish`, in order to get the peer back in sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retransmission {
+    /// The peer never received our last `commitment_signed` so it has forgotten these updates.
+    /// They must be resent, in this order, before the `commitment_signed`.
+    pub updates: Vec<CommitmentUpdate>,
+    pub commitment_signed: bool,
+    pub revoke_and_ack: bool,
+    /// If both `commitment_signed` and `revoke_and_ack` are retransmitted, whether the
+    /// `revoke_and_ack` must go first. They have to be resent in their original order.
+    pub revoke_and_ack_first: bool,
+    /// Neither side has sent a `commitment_signed` since the channel opened, so the peer may not
+    /// have seen our `channel_ready`.
+    pub channel_ready: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReestablishOutcome {
+    /// We're in sync (possibly after retransmitting) and can resume normal operation.
+    Resume(Retransmission),
+    /// The peer is ahead of us, so we've lost state. We must not broadcast our commitment
+    /// transaction since it has been revoked, and should ask the peer to close the channel
+    /// instead. Their per-commitment point lets us sweep our output from their commitment once it
+    /// confirms.
+    DataLoss {
+        remote_per_commitment_point: Option<secp256k1::PublicKey>,
+    },
+}
+
+#[derive(Debug, Fail)]
+pub enum ReestablishError {
+    #[fail(display = "message is for a different channel")]
+    WrongChannelId,
+    #[fail(display = "peer expects commitment {} but we have only signed up to {}", next_commitment_number, remote_commitment_number)]
+    UnexpectedNextCommitmentNumber {
+        next_commitment_number: u64,
+        remote_commitment_number: u64,
+    },
+    #[fail(display = "peer expects revocation {} but we have only sent {}", next_revocation_number, revocations_sent)]
+    UnexpectedNextRevocationNumber {
+        next_revocation_number: u64,
+        revocations_sent: u64,
+    },
+    #[fail(display = "peer sent the wrong last per-commitment secret")]
+    InvalidLastPerCommitmentSecret,
+}
+
+impl UpdateQueue {
+    /// Builds the `channel_reestablish` to send on reconnection. `data_loss_protect` should be
+    /// set if `option_data_loss_protect` was negotiated. The secret is the last one the peer
+    /// revealed to us (or all zeroes), and the point is the one for our current commitment.
+    pub fn channel_reestablish(&self, data_loss_protect: Option<DataLossProtect>) -> ChannelReestablishMsg {
+        ChannelReestablishMsg {
+            channel_id: *self.channel_id(),
+            next_commitment_number: self.local_commitment_number() + 1,
+            next_revocation_number: self.revocations_received(),
+            data_loss_protect,
+        }
+    }
+
+    /// Works out what to retransmit after receiving the peer's `channel_reestablish`. This must
+    /// be called after `disconnect`.
+    ///
+    /// `local_per_commitment_secret` returns our per-commitment secret for a given commitment
+    /// number. It's used to check the peer's claim about which revocations it has received.
+    pub fn handle_channel_reestablish<F>(
+        &self,
+        msg: &ChannelReestablishMsg,
+        local_per_commitment_secret: F,
+    ) -> Result<ReestablishOutcome, ReestablishError>
+    where
+        F: Fn(u64) -> [u8; 32],
+    {
+        if msg.channel_id != *self.channel_id() {
+            return Err(ReestablishError::WrongChannelId);
+        }
+
+        let revocations_sent = self.revocations_sent();
+        let next_revocation_number = msg.next_revocation_number;
+        let expected_secret = |revocation_number: u64| {
+            if revocation_number == 0 {
+                [0u8; 32]
+            } else {
+                local_per_commitment_secret(revocation_number - 1)
+            }
+        };
+
+        if next_revocation_number > revocations_sent {
+            return match msg.data_loss_protect {
+                Some(ref data_loss_protect) => {
+                    let claimed = data_loss_protect.your_last_per_commitment_secret;
+                    if claimed != expected_secret(next_revocation_number) {
+                        return Err(ReestablishError::InvalidLastPerCommitmentSecret);
+                    }
+                    Ok(ReestablishOutcome::DataLoss {
+                        remote_per_commitment_point: Some(data_loss_protect.my_current_per_commitment_point),
+                    })
+                },
+                None => Err(ReestablishError::UnexpectedNextRevocationNumber {
+                    next_revocation_number,
+                    revocations_sent,
+                }),
+            };
+        }
+        if let Some(ref data_loss_protect) = msg.data_loss_protect {
+            if data_loss_protect.your_last_per_commitment_secret != expected_secret(next_revocation_number) {
+                return Err(ReestablishError::InvalidLastPerCommitmentSecret);
+            }
+        }
+
+        let revoke_and_ack = if next_revocation_number == revocations_sent {
+            false
+        } else if revocations_sent > 0 && next_revocation_number == revocations_sent - 1 {
+            true
+        } else {
+            return Err(ReestablishError::UnexpectedNextRevocationNumber {
+                next_revocation_number,
+                revocations_sent,
+            });
+        };
+
+        let remote_commitment_number = self.remote_commitment_number();
+        let next_commitment_number = msg.next_commitment_number;
+        let lost_commitment_signed = {
+            remote_commitment_number > 0
+            && next_commitment_number == remote_commitment_number
+            && self.awaiting_remote_revocation()
+        };
+        let commitment_signed = if next_commitment_number == remote_commitment_number + 1 {
+            false
+        } else if lost_commitment_signed {
+            true
+        } else if next_commitment_number > remote_commitment_number + 1 {
+            // The peer has a commitment we don't remember signing, so we've lost state.
+            return Ok(ReestablishOutcome::DataLoss {
+                remote_per_commitment_point: {
+                    msg.data_loss_protect.as_ref().map(|data_loss_protect| data_loss_protect.my_current_per_commitment_point)
+                },
+            });
+        } else {
+            return Err(ReestablishError::UnexpectedNextCommitmentNumber {
+                next_commitment_number,
+                remote_commitment_number,
+            });
+        };
+
+        let updates = if commitment_signed {
+            self.updates()
+            .iter()
+            .filter(|queued| queued.origin == UpdateOrigin::Local && queued.state == UpdateState::SentCommit)
+            .map(|queued| queued.update.clone())
+            .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(ReestablishOutcome::Resume(Retransmission {
+            updates,
+            commitment_signed,
+            revoke_and_ack,
+            revoke_and_ack_first: !self.revocation_sent_last(),
+            channel_ready: self.local_commitment_number() == 0 && next_commitment_number == 1,
+        }))
+    }
+}
+
+/// Derives the private key for our `to_remote` output in the peer's commitment transaction,
+/// given the `my_current_per_commitment_point` they sent when we discovered we'd lost state.
+/// With `option_static_remotekey` the output pays our payment basepoint, so the point isn't
+/// needed.
+pub fn derive_data_loss_recovery_key(
+    secp: &Secp256k1<secp256k1::All>,
+    payment_basepoint_secret: &secp256k1::SecretKey,
+    remote_per_commitment_point: &secp256k1::PublicKey,
+    channel_type: ChannelType,
+) -> Result<secp256k1::SecretKey, secp256k1::Error> {
+    if channel_type.static_remotekey() {
+        return Ok(payment_basepoint_secret.clone());
+    }
+    derive_private_key(secp, payment_basepoint_secret, remote_per_commitment_point)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL_ID: ChannelId = ChannelId([4; 32]);
+
+    fn secret(commitment_number: u64) -> [u8; 32] {
+        let mut secret = [0x80; 32];
+        secret[..8].copy_from_slice(&u64::to_be_bytes(commitment_number)[..]);
+        secret
+    }
+
+    fn point() -> secp256k1::PublicKey {
+        let secp = Secp256k1::new();
+        let sk = unwrap!(secp256k1::SecretKey::from_slice(&secp, &[0x33; 32]));
+        secp256k1::PublicKey::from_secret_key(&secp, &sk)
+    }
+
+    fn add_htlc(id: u64) -> CommitmentUpdate {
+        CommitmentUpdate::AddHtlc(UpdateAddHtlcMsg {
+            channel_id: CHANNEL_ID,
+            id,
+            amount_msat: 5000,
+            payment_hash: [id as u8; 32],
+            cltv_expiry: 100,
+            onion_routing_packet: vec![0; ONION_PACKET_LEN],
+            blinding_point: None,
+        })
+    }
+
+    fn reestablish(next_commitment_number: u64, next_revocation_number: u64, last_secret: [u8; 32])
+        -> ChannelReestablishMsg
+    {
+        ChannelReestablishMsg {
+            channel_id: CHANNEL_ID,
+            next_commitment_number,
+            next_revocation_number,
+            data_loss_protect: Some(DataLossProtect {
+                your_last_per_commitment_secret: last_secret,
+                my_current_per_commitment_point: point(),
+            }),
+        }
+    }
+
+    #[test]
+    fn retransmits_unacknowledged_commitment() {
+        let mut alice = UpdateQueue::new(CHANNEL_ID, true, 253);
+        unwrap!(alice.send_update(add_htlc(0)));
+        unwrap!(alice.send_commitment_signed());
+        unwrap!(alice.send_update(add_htlc(1)));
+        alice.disconnect();
+        assert_eq!(alice.next_local_htlc_id(), 1);
+
+        let msg = reestablish(1, 0, [0; 32]);
+        let outcome = unwrap!(alice.handle_channel_reestablish(&msg, secret));
+        match outcome {
+            ReestablishOutcome::Resume(retransmission) => {
+                assert_eq!(retransmission.updates, vec![add_htlc(0)]);
+                assert!(retransmission.commitment_signed);
+                assert!(!retransmission.revoke_and_ack);
+            },
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn retransmits_lost_revocation() {
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        unwrap!(bob.receive_update(add_htlc(0)));
+        unwrap!(bob.receive_commitment_signed());
+        unwrap!(bob.send_revoke_and_ack());
+        bob.disconnect();
+
+        let msg = reestablish(1, 0, [0; 32]);
+        match unwrap!(bob.handle_channel_reestablish(&msg, secret)) {
+            ReestablishOutcome::Resume(retransmission) => {
+                assert!(retransmission.revoke_and_ack);
+                assert!(!retransmission.commitment_signed);
+            },
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn detects_data_loss() {
+        let bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        let msg = reestablish(6, 5, secret(4));
+        match unwrap!(bob.handle_channel_reestablish(&msg, secret)) {
+            ReestablishOutcome::DataLoss { remote_per_commitment_point } => {
+                assert_eq!(remote_per_commitment_point, Some(point()));
+            },
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn detects_data_loss_from_commitment_number() {
+        let mut alice = UpdateQueue::new(CHANNEL_ID, true, 253);
+        unwrap!(alice.send_update(add_htlc(0)));
+        unwrap!(alice.send_commitment_signed());
+        alice.disconnect();
+
+        // We've only signed commitment 1 for the peer, but it expects commitment 3 next.
+        let msg = reestablish(3, 0, [0; 32]);
+        match unwrap!(alice.handle_channel_reestablish(&msg, secret)) {
+            ReestablishOutcome::DataLoss { remote_per_commitment_point } => {
+                assert_eq!(remote_per_commitment_point, Some(point()));
+            },
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn recovery_key_follows_channel_type() {
+        let secp = Secp256k1::new();
+        let secrets = test_channel_secrets(&secp, 0x10);
+        let basepoints = secrets.basepoints(&secp);
+        let remote = test_channel_secrets(&secp, 0x20).basepoints(&secp);
+        let point = point();
+        for channel_type in &[ChannelType::Legacy, ChannelType::StaticRemoteKey, ChannelType::AnchorsZeroFeeHtlcTx] {
+            // Our to_remote key in the peer's commitment, where we're the remote party.
+            let keys = unwrap!(CommitmentKeys::derive(&secp, &point, &remote, &basepoints, *channel_type));
+            let key = unwrap!(derive_data_loss_recovery_key(&secp, &secrets.payment_basepoint_secret, &point, *channel_type));
+            assert_eq!(secp256k1::PublicKey::from_secret_key(&secp, &key), keys.remote_pubkey);
+        }
+    }
+
+    #[test]
+    fn rejects_bogus_data_loss_claim() {
+        let bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        let msg = reestablish(6, 5, [0x12; 32]);
+        match bob.handle_channel_reestablish(&msg, secret) {
+            Err(ReestablishError::InvalidLastPerCommitmentSecret) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
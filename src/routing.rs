@@ -0,0 +1,411 @@
+use super::*;
+
+/// The most hops an onion can take us through.
+pub const MAX_ROUTE_HOPS: usize = 20;
+/// The default limit on how long a payment's funds can be locked up, in blocks.
+pub const DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA: u32 = 1008;
+/// How much locking up one msat for one block counts against a route, in billionths of a msat.
+/// Lets a cheap route lose to a slightly dearer one that's much faster to time out.
+const CLTV_RISK_FACTOR_PER_BILLION: u64 = 15;
+
+/// A hop of a private route to the payee, as found in invoices. `node_id` forwards over
+/// `short_channel_id` to the next hop of the hint, or to the payee if it's the last hop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteHintHop {
+    pub node_id: NodeId,
+    pub short_channel_id: ShortChannelId,
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    pub cltv_expiry_delta: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteParams {
+    pub source: NodeId,
+    pub target: NodeId,
+    pub amount_msat: u64,
+    pub final_cltv_expiry_delta: u16,
+    pub current_block_height: u32,
+    /// The longest the payment is allowed to take to time out, counted from
+    /// `current_block_height`.
+    pub max_total_cltv_expiry_delta: u32,
+    pub route_hints: Vec<Vec<RouteHintHop>>,
+}
+
+impl RouteParams {
+    pub fn new(
+        source: NodeId,
+        target: NodeId,
+        amount_msat: u64,
+        final_cltv_expiry_delta: u16,
+        current_block_height: u32,
+    ) -> RouteParams {
+        RouteParams {
+            source,
+            target,
+            amount_msat,
+            final_cltv_expiry_delta,
+            current_block_height,
+            max_total_cltv_expiry_delta: DEFAULT_MAX_TOTAL_CLTV_EXPIRY_DELTA,
+            route_hints: Vec::new(),
+        }
+    }
+}
+
+/// One channel of a route, and what the HTLC sent over it should look like.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteHop {
+    /// The node at the far end of the channel.
+    pub node_id: NodeId,
+    pub short_channel_id: ShortChannelId,
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub hops: Vec<RouteHop>,
+}
+
+impl Route {
+    /// What the first HTLC has to carry, ie. the amount paid plus all fees.
+    pub fn total_amount_msat(&self) -> u64 {
+        self.hops.first().map(|hop| hop.amount_msat).unwrap_or(0)
+    }
+
+    pub fn total_fee_msat(&self) -> u64 {
+        let received = self.hops.last().map(|hop| hop.amount_msat).unwrap_or(0);
+        self.total_amount_msat() - received
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum RouteError {
+    #[fail(display = "can't route a payment to ourselves")]
+    SourceIsTarget,
+    #[fail(display = "final cltv expiry delta {} exceeds the limit of {}", final_cltv_expiry_delta, max_total_cltv_expiry_delta)]
+    FinalCltvExpiryTooFar {
+        final_cltv_expiry_delta: u16,
+        max_total_cltv_expiry_delta: u32,
+    },
+    #[fail(display = "no route to {}", target)]
+    NoRoute {
+        target: NodeId,
+    },
+}
+
+/// A channel payments can be forwarded over, from either the graph or a route hint.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: NodeId,
+    short_channel_id: ShortChannelId,
+    fee_base_msat: u32,
+    fee_proportional_millionths: u32,
+    cltv_expiry_delta: u16,
+    htlc_minimum_msat: u64,
+    htlc_maximum_msat: Option<u64>,
+}
+
+impl Edge {
+    fn from_policy(from: NodeId, short_channel_id: ShortChannelId, policy: &ChannelPolicy) -> Edge {
+        Edge {
+            from,
+            short_channel_id,
+            fee_base_msat: policy.fee_base_msat,
+            fee_proportional_millionths: policy.fee_proportional_millionths,
+            cltv_expiry_delta: policy.cltv_expiry_delta,
+            htlc_minimum_msat: policy.htlc_minimum_msat,
+            htlc_maximum_msat: policy.htlc_maximum_msat,
+        }
+    }
+
+    fn from_hint(hop: &RouteHintHop) -> Edge {
+        Edge {
+            from: hop.node_id,
+            short_channel_id: hop.short_channel_id,
+            fee_base_msat: hop.fee_base_msat,
+            fee_proportional_millionths: hop.fee_proportional_millionths,
+            cltv_expiry_delta: hop.cltv_expiry_delta,
+            htlc_minimum_msat: 0,
+            htlc_maximum_msat: None,
+        }
+    }
+
+    fn can_carry(&self, amount_msat: u64) -> bool {
+        amount_msat >= self.htlc_minimum_msat
+        && self.htlc_maximum_msat.map(|max| amount_msat <= max).unwrap_or(true)
+    }
+
+    fn fee_msat(&self, amount_msat: u64) -> u64 {
+        let proportional = amount_msat as u128 * self.fee_proportional_millionths as u128 / 1_000_000;
+        self.fee_base_msat as u64 + proportional as u64
+    }
+}
+
+/// The cheapest way found so far to get from a node to the target.
+#[derive(Clone, Copy)]
+struct Label {
+    cost: u64,
+    /// What the HTLC arriving at the node has to carry.
+    amount_msat: u64,
+    /// How far in the future the HTLC arriving at the node has to expire.
+    cltv_expiry_delta: u32,
+    num_hops: usize,
+    next: Option<(NodeId, ShortChannelId)>,
+}
+
+/// The enabled channels over which `node_id` can be paid.
+fn edges_to(
+    graph: &NetworkGraph,
+    hint_edges: &HashMap<NodeId, Vec<Edge>>,
+    node_id: &NodeId,
+) -> Vec<Edge> {
+    let mut edges = Vec::new();
+    for channel in graph.node_channels(node_id) {
+        let from = if channel.node_id_1 == *node_id { channel.node_id_2 } else { channel.node_id_1 };
+        let direction = unwrap!(channel.direction_from(&from));
+        match channel.policy(direction) {
+            Some(policy) if !policy.disabled => {
+                edges.push(Edge::from_policy(from, channel.short_channel_id, policy));
+            },
+            _ => (),
+        }
+    }
+    if let Some(hints) = hint_edges.get(node_id) {
+        edges.extend(hints.iter().cloned());
+    }
+    edges
+}
+
+/// Finds the cheapest route for a payment, counting both fees and how long the funds may be
+/// locked up for.
+pub fn find_route(graph: &NetworkGraph, params: &RouteParams) -> Result<Route, RouteError> {
+    find_route_with_scorer(graph, params, &ZeroPenaltyScorer)
+}
+
+/// Like `find_route`, with the scorer's penalties added to the cost of each hop. The search runs
+/// backwards from the target so that the amount and expiry each hop needs are known as soon as
+/// the hop is reached.
+pub fn find_route_with_scorer<S>(
+    graph: &NetworkGraph,
+    params: &RouteParams,
+    scorer: &S,
+) -> Result<Route, RouteError>
+where
+    S: Scorer + ?Sized,
+{
+    if params.source == params.target {
+        return Err(RouteError::SourceIsTarget);
+    }
+    if params.final_cltv_expiry_delta as u32 > params.max_total_cltv_expiry_delta {
+        return Err(RouteError::FinalCltvExpiryTooFar {
+            final_cltv_expiry_delta: params.final_cltv_expiry_delta,
+            max_total_cltv_expiry_delta: params.max_total_cltv_expiry_delta,
+        });
+    }
+
+    let mut hint_edges: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+    for hint in &params.route_hints {
+        for (i, hop) in hint.iter().enumerate() {
+            let to = hint.get(i + 1).map(|next| next.node_id).unwrap_or(params.target);
+            hint_edges.entry(to).or_insert_with(Vec::new).push(Edge::from_hint(hop));
+        }
+    }
+
+    let mut labels = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    labels.insert(params.target, Label {
+        cost: 0,
+        amount_msat: params.amount_msat,
+        cltv_expiry_delta: params.final_cltv_expiry_delta as u32,
+        num_hops: 0,
+        next: None,
+    });
+    queue.push((cmp::Reverse(0), params.target));
+
+    while let Some((cmp::Reverse(cost), node_id)) = queue.pop() {
+        let label = labels[&node_id];
+        if cost > label.cost {
+            continue;
+        }
+        if node_id == params.source {
+            return Ok(build_route(&labels, params));
+        }
+        if label.num_hops == MAX_ROUTE_HOPS {
+            continue;
+        }
+        for edge in edges_to(graph, &hint_edges, &node_id) {
+            if !edge.can_carry(label.amount_msat) {
+                continue;
+            }
+            let (amount_msat, cltv_expiry_delta, cost) = if edge.from == params.source {
+                (label.amount_msat, label.cltv_expiry_delta, label.cost)
+            } else {
+                let penalty_msat = scorer.channel_penalty_msat(
+                    edge.short_channel_id,
+                    &edge.from,
+                    &node_id,
+                    label.amount_msat,
+                    edge.htlc_maximum_msat,
+                );
+                if penalty_msat == u64::max_value() {
+                    continue;
+                }
+                let fee_msat = edge.fee_msat(label.amount_msat);
+                let risk = {
+                    label.amount_msat as u128
+                    * edge.cltv_expiry_delta as u128
+                    * CLTV_RISK_FACTOR_PER_BILLION as u128
+                    / 1_000_000_000
+                };
+                (
+                    label.amount_msat.saturating_add(fee_msat),
+                    label.cltv_expiry_delta + edge.cltv_expiry_delta as u32,
+                    label.cost
+                    .saturating_add(fee_msat)
+                    .saturating_add(risk as u64)
+                    .saturating_add(penalty_msat),
+                )
+            };
+            if cltv_expiry_delta > params.max_total_cltv_expiry_delta {
+                continue;
+            }
+            let improves = labels.get(&edge.from).map(|existing| cost < existing.cost).unwrap_or(true);
+            if improves {
+                labels.insert(edge.from, Label {
+                    cost,
+                    amount_msat,
+                    cltv_expiry_delta,
+                    num_hops: label.num_hops + 1,
+                    next: Some((node_id, edge.short_channel_id)),
+                });
+                queue.push((cmp::Reverse(cost), edge.from));
+            }
+        }
+    }
+    Err(RouteError::NoRoute { target: params.target })
+}
+
+fn build_route(labels: &HashMap<NodeId, Label>, params: &RouteParams) -> Route {
+    let mut hops = Vec::new();
+    let mut label = labels[&params.source];
+    while let Some((node_id, short_channel_id)) = label.next {
+        label = labels[&node_id];
+        hops.push(RouteHop {
+            node_id,
+            short_channel_id,
+            amount_msat: label.amount_msat,
+            cltv_expiry: params.current_block_height + label.cltv_expiry_delta,
+        });
+    }
+    Route { hops }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u32 = 1_600_000_000;
+    const BLOCK_HEIGHT: u32 = 700_000;
+
+    /// Two ways from node 1 to node 4: through node 2 or through node 3.
+    fn diamond(secp: &Secp256k1<secp256k1::All>) -> (NetworkGraph, Vec<ChannelAnnouncementMsg>) {
+        let mut graph = NetworkGraph::new(ChainHash::BITCOIN);
+        let mut announcements = Vec::new();
+        for (i, &(a, b)) in [(1, 2), (2, 4), (1, 3), (3, 4)].iter().enumerate() {
+            let announcement = signed_channel_announcement(secp, a, b, test_short_channel_id(500 + i as u32));
+            unwrap!(graph.handle_channel_announcement(&announcement, NOW));
+            for &node in &[a, b] {
+                let update = signed_channel_update(secp, &announcement, node, NOW, |update| {
+                    if node == 3 {
+                        update.fee_base_msat = 5000;
+                    }
+                });
+                unwrap!(graph.handle_channel_update(&update, NOW));
+            }
+            announcements.push(announcement);
+        }
+        (graph, announcements)
+    }
+
+    #[test]
+    fn finds_cheapest_route() {
+        let secp = Secp256k1::new();
+        let (mut graph, announcements) = diamond(&secp);
+        let params = RouteParams::new(test_node_id(1), test_node_id(4), 100_000, 18, BLOCK_HEIGHT);
+
+        let route = unwrap!(find_route(&graph, &params));
+        assert_eq!(route.hops, vec![
+            RouteHop {
+                node_id: test_node_id(2),
+                short_channel_id: test_short_channel_id(500),
+                amount_msat: 101_010,
+                cltv_expiry: BLOCK_HEIGHT + 18 + 40,
+            },
+            RouteHop {
+                node_id: test_node_id(4),
+                short_channel_id: test_short_channel_id(501),
+                amount_msat: 100_000,
+                cltv_expiry: BLOCK_HEIGHT + 18,
+            },
+        ]);
+        assert_eq!(route.total_fee_msat(), 1010);
+
+        let disable = signed_channel_update(&secp, &announcements[1], 2, NOW + 1, |update| {
+            update.channel_flags = CHANNEL_UPDATE_CHANNEL_FLAG_DISABLE;
+        });
+        unwrap!(graph.handle_channel_update(&disable, NOW));
+        let route = unwrap!(find_route(&graph, &params));
+        assert_eq!(route.hops[0].node_id, test_node_id(3));
+        assert_eq!(route.total_fee_msat(), 5010);
+    }
+
+    #[test]
+    fn honours_htlc_limits_cltv_limit_and_route_hints() {
+        let secp = Secp256k1::new();
+        let (mut graph, announcements) = diamond(&secp);
+        let too_small = signed_channel_update(&secp, &announcements[1], 2, NOW + 1, |update| {
+            update.htlc_maximum_msat = Some(50_000);
+        });
+        unwrap!(graph.handle_channel_update(&too_small, NOW));
+
+        let mut params = RouteParams::new(test_node_id(1), test_node_id(9), 100_000, 18, BLOCK_HEIGHT);
+        match find_route(&graph, &params) {
+            Err(RouteError::NoRoute { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        params.route_hints.push(vec![RouteHintHop {
+            node_id: test_node_id(4),
+            short_channel_id: test_short_channel_id(900),
+            fee_base_msat: 0,
+            fee_proportional_millionths: 0,
+            cltv_expiry_delta: 100,
+        }]);
+        let route = unwrap!(find_route(&graph, &params));
+        let nodes: Vec<NodeId> = route.hops.iter().map(|hop| hop.node_id).collect();
+        assert_eq!(nodes, vec![test_node_id(3), test_node_id(4), test_node_id(9)]);
+        assert_eq!(route.hops[0].cltv_expiry, BLOCK_HEIGHT + 18 + 100 + 40);
+
+        params.max_total_cltv_expiry_delta = 150;
+        match find_route(&graph, &params) {
+            Err(RouteError::NoRoute { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn scorer_steers_around_failed_channels() {
+        let secp = Secp256k1::new();
+        let (graph, _) = diamond(&secp);
+        let params = RouteParams::new(test_node_id(1), test_node_id(4), 100_000, 18, BLOCK_HEIGHT);
+        let mut scorer = ProbabilisticScorer::new(ProbabilisticScoringParams::default());
+
+        let route = unwrap!(find_route_with_scorer(&graph, &params, &scorer));
+        assert_eq!(route.hops[0].node_id, test_node_id(2));
+
+        scorer.payment_path_failed(&params.source, &route.hops, route.hops[1].short_channel_id, NOW);
+        let route = unwrap!(find_route_with_scorer(&graph, &params, &scorer));
+        assert_eq!(route.hops[0].node_id, test_node_id(3));
+    }
+}
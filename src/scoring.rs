@@ -0,0 +1,388 @@
+use super::*;
+
+/// Lets the router weigh channels by how likely they are to carry a payment, and learn from how
+/// payments over them went.
+pub trait Scorer {
+    /// The extra cost, in msat, of sending `amount_msat` from `from` to `to` over the channel.
+    /// `htlc_maximum_msat` is the most the channel's policy allows, if known. Hops penalised
+    /// with `u64::max_value()` are not used at all.
+    fn channel_penalty_msat(
+        &self,
+        short_channel_id: ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        amount_msat: u64,
+        htlc_maximum_msat: Option<u64>,
+    ) -> u64;
+
+    /// A payment sent by `source` along `path` failed because the channel `failed_short_channel_id`
+    /// couldn't forward it.
+    fn payment_path_failed(
+        &mut self,
+        source: &NodeId,
+        path: &[RouteHop],
+        failed_short_channel_id: ShortChannelId,
+        now: u32,
+    );
+
+    /// A payment sent by `source` along `path` was claimed.
+    fn payment_path_successful(&mut self, source: &NodeId, path: &[RouteHop], now: u32);
+}
+
+/// A scorer which doesn't penalise anything, ie. routes purely on fees and expiry.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZeroPenaltyScorer;
+
+impl Scorer for ZeroPenaltyScorer {
+    fn channel_penalty_msat(&self, _: ShortChannelId, _: &NodeId, _: &NodeId, _: u64, _: Option<u64>) -> u64 {
+        0
+    }
+
+    fn payment_path_failed(&mut self, _: &NodeId, _: &[RouteHop], _: ShortChannelId, _: u32) {}
+
+    fn payment_path_successful(&mut self, _: &NodeId, _: &[RouteHop], _: u32) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbabilisticScoringParams {
+    /// Charged for every hop, so that shorter routes win when nothing else tells them apart.
+    pub base_penalty_msat: u64,
+    /// Multiplied by `-log10` of the probability that a hop can carry the amount. A hop with a 10%
+    /// chance of success costs this much, one with a 1% chance twice as much.
+    pub liquidity_penalty_multiplier_msat: u64,
+    /// How long it takes for half of what we've learnt about a channel to be forgotten, as its
+    /// liquidity moves around with other payments.
+    pub liquidity_half_life_secs: u32,
+}
+
+impl Default for ProbabilisticScoringParams {
+    fn default() -> ProbabilisticScoringParams {
+        ProbabilisticScoringParams {
+            base_penalty_msat: 500,
+            liquidity_penalty_multiplier_msat: 40_000,
+            liquidity_half_life_secs: 6 * 60 * 60,
+        }
+    }
+}
+
+/// What we know about the liquidity available in one direction of a channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LiquidityBounds {
+    /// The channel has carried this much before.
+    min_liquidity_msat: u64,
+    /// The channel failed to carry more than this, if it ever failed.
+    max_liquidity_msat: Option<u64>,
+    last_updated: u32,
+}
+
+impl LiquidityBounds {
+    fn new(now: u32) -> LiquidityBounds {
+        LiquidityBounds {
+            min_liquidity_msat: 0,
+            max_liquidity_msat: None,
+            last_updated: now,
+        }
+    }
+
+    /// Forgets some of what we know: the lower bound shrinks and the upper bound grows by the same
+    /// factor, halving and doubling respectively every half life.
+    fn decay(&mut self, now: u32, half_life_secs: u32) {
+        let elapsed = now.saturating_sub(self.last_updated);
+        if elapsed == 0 || half_life_secs == 0 {
+            return;
+        }
+        let factor = 0.5f64.powf(elapsed as f64 / half_life_secs as f64);
+        self.min_liquidity_msat = (self.min_liquidity_msat as f64 * factor) as u64;
+        self.max_liquidity_msat = self.max_liquidity_msat.and_then(|max_liquidity_msat| {
+            let max_liquidity_msat = max_liquidity_msat as f64 / factor;
+            if max_liquidity_msat >= u64::max_value() as f64 {
+                None
+            } else {
+                Some(max_liquidity_msat as u64)
+            }
+        });
+        self.last_updated = now;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min_liquidity_msat == 0 && self.max_liquidity_msat.is_none()
+    }
+
+    /// The probability that `amount_msat` can be sent, assuming the liquidity is anywhere between
+    /// the bounds with equal likelihood. `None` if we have no upper bound to go by.
+    fn success_probability(&self, amount_msat: u64, htlc_maximum_msat: Option<u64>) -> Option<f64> {
+        if amount_msat <= self.min_liquidity_msat {
+            return Some(1.0);
+        }
+        let max_liquidity_msat = match (self.max_liquidity_msat, htlc_maximum_msat) {
+            (Some(a), Some(b)) => cmp::min(a, b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => return None,
+        };
+        if amount_msat > max_liquidity_msat {
+            return Some(0.0);
+        }
+        let min_liquidity_msat = cmp::min(self.min_liquidity_msat, max_liquidity_msat);
+        let possible = (max_liquidity_msat - min_liquidity_msat + 1) as f64;
+        let successful = (max_liquidity_msat - amount_msat + 1) as f64;
+        Some(successful / possible)
+    }
+}
+
+/// A scorer which learns bounds on the liquidity of each channel direction from payments made
+/// over it, and penalises hops by how unlikely they are to succeed.
+#[derive(Debug, Clone)]
+pub struct ProbabilisticScorer {
+    params: ProbabilisticScoringParams,
+    liquidity: HashMap<(ShortChannelId, u8), LiquidityBounds>,
+}
+
+/// The direction of payments from `from` to `to`, as in the channel's `channel_update`s.
+fn channel_direction(from: &NodeId, to: &NodeId) -> u8 {
+    if from < to {
+        0
+    } else {
+        1
+    }
+}
+
+impl ProbabilisticScorer {
+    pub fn new(params: ProbabilisticScoringParams) -> ProbabilisticScorer {
+        ProbabilisticScorer {
+            params,
+            liquidity: HashMap::new(),
+        }
+    }
+
+    /// Forgets what has become stale. Should be called before finding routes, since penalties
+    /// are worked out from the bounds as of the last decay.
+    pub fn decay(&mut self, now: u32) {
+        let half_life_secs = self.params.liquidity_half_life_secs;
+        for bounds in self.liquidity.values_mut() {
+            bounds.decay(now, half_life_secs);
+        }
+        self.liquidity.retain(|_, bounds| !bounds.is_empty());
+    }
+
+    fn bounds_mut(
+        &mut self,
+        short_channel_id: ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        now: u32,
+    ) -> &mut LiquidityBounds {
+        let half_life_secs = self.params.liquidity_half_life_secs;
+        let key = (short_channel_id, channel_direction(from, to));
+        let bounds = self.liquidity.entry(key).or_insert_with(|| LiquidityBounds::new(now));
+        bounds.decay(now, half_life_secs);
+        bounds
+    }
+
+    /// Each hop of `path` along with the node which forwarded over it.
+    fn hops<'a>(source: &'a NodeId, path: &'a [RouteHop]) -> impl Iterator<Item = (&'a NodeId, &'a RouteHop)> + 'a {
+        iter::once(source)
+        .chain(path.iter().map(|hop| &hop.node_id))
+        .zip(path.iter())
+    }
+}
+
+impl Scorer for ProbabilisticScorer {
+    fn channel_penalty_msat(
+        &self,
+        short_channel_id: ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        amount_msat: u64,
+        htlc_maximum_msat: Option<u64>,
+    ) -> u64 {
+        let key = (short_channel_id, channel_direction(from, to));
+        let bounds = self.liquidity.get(&key).cloned().unwrap_or_else(|| LiquidityBounds::new(0));
+        let liquidity_penalty_msat = match bounds.success_probability(amount_msat, htlc_maximum_msat) {
+            None => 0,
+            Some(probability) if probability <= 0.0 => u64::max_value(),
+            Some(probability) => {
+                (-probability.log10() * self.params.liquidity_penalty_multiplier_msat as f64) as u64
+            },
+        };
+        self.params.base_penalty_msat.saturating_add(liquidity_penalty_msat)
+    }
+
+    fn payment_path_failed(
+        &mut self,
+        source: &NodeId,
+        path: &[RouteHop],
+        failed_short_channel_id: ShortChannelId,
+        now: u32,
+    ) {
+        for (from, hop) in ProbabilisticScorer::hops(source, path) {
+            let bounds = self.bounds_mut(hop.short_channel_id, from, &hop.node_id, now);
+            if hop.short_channel_id == failed_short_channel_id {
+                let max_liquidity_msat = hop.amount_msat.saturating_sub(1);
+                bounds.max_liquidity_msat = Some(match bounds.max_liquidity_msat {
+                    Some(current) => cmp::min(current, max_liquidity_msat),
+                    None => max_liquidity_msat,
+                });
+                if bounds.min_liquidity_msat > max_liquidity_msat {
+                    bounds.min_liquidity_msat = 0;
+                }
+                break;
+            }
+            bounds.min_liquidity_msat = cmp::max(bounds.min_liquidity_msat, hop.amount_msat);
+        }
+    }
+
+    fn payment_path_successful(&mut self, source: &NodeId, path: &[RouteHop], now: u32) {
+        for (from, hop) in ProbabilisticScorer::hops(source, path) {
+            let bounds = self.bounds_mut(hop.short_channel_id, from, &hop.node_id, now);
+            bounds.min_liquidity_msat = cmp::max(bounds.min_liquidity_msat, hop.amount_msat);
+            if bounds.max_liquidity_msat.map(|max| max < hop.amount_msat).unwrap_or(false) {
+                bounds.max_liquidity_msat = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u32 = 1_600_000_000;
+
+    fn hop(node: u8, block_height: u32, amount_msat: u64) -> RouteHop {
+        RouteHop {
+            node_id: test_node_id(node),
+            short_channel_id: test_short_channel_id(block_height),
+            amount_msat,
+            cltv_expiry: 0,
+        }
+    }
+
+    fn learnt_bounds(scorer: &ProbabilisticScorer, block_height: u32, from: u8, to: u8) -> Option<LiquidityBounds> {
+        let direction = channel_direction(&test_node_id(from), &test_node_id(to));
+        scorer.liquidity.get(&(test_short_channel_id(block_height), direction)).cloned()
+    }
+
+    #[test]
+    fn failures_and_successes_move_bounds() {
+        let mut scorer = ProbabilisticScorer::new(ProbabilisticScoringParams::default());
+        let source = test_node_id(1);
+        let path = [hop(2, 500, 101_000), hop(3, 501, 100_000), hop(4, 502, 99_000)];
+        scorer.payment_path_failed(&source, &path, test_short_channel_id(501), NOW);
+        let bounds_500 = unwrap!(learnt_bounds(&scorer, 500, 1, 2));
+        assert_eq!((bounds_500.min_liquidity_msat, bounds_500.max_liquidity_msat), (101_000, None));
+        let bounds_501 = unwrap!(learnt_bounds(&scorer, 501, 2, 3));
+        assert_eq!((bounds_501.min_liquidity_msat, bounds_501.max_liquidity_msat), (0, Some(99_999)));
+        // The payment never got past the failing hop.
+        assert!(learnt_bounds(&scorer, 502, 3, 4).is_none());
+
+        // A failure of a larger amount tells us nothing new, a smaller one lowers the upper bound.
+        let path = [hop(3, 501, 200_000)];
+        scorer.payment_path_failed(&test_node_id(2), &path, test_short_channel_id(501), NOW);
+        assert_eq!(unwrap!(learnt_bounds(&scorer, 501, 2, 3)).max_liquidity_msat, Some(99_999));
+
+        let path = [hop(3, 501, 50_000)];
+        scorer.payment_path_successful(&test_node_id(2), &path, NOW);
+        let bounds_501 = unwrap!(learnt_bounds(&scorer, 501, 2, 3));
+        assert_eq!((bounds_501.min_liquidity_msat, bounds_501.max_liquidity_msat), (50_000, Some(99_999)));
+
+        // Bounds which contradict each other are dropped in favour of the newest.
+        let path = [hop(3, 501, 40_000)];
+        scorer.payment_path_failed(&test_node_id(2), &path, test_short_channel_id(501), NOW);
+        let bounds_501 = unwrap!(learnt_bounds(&scorer, 501, 2, 3));
+        assert_eq!((bounds_501.min_liquidity_msat, bounds_501.max_liquidity_msat), (0, Some(39_999)));
+        let path = [hop(3, 501, 120_000)];
+        scorer.payment_path_successful(&test_node_id(2), &path, NOW);
+        let bounds_501 = unwrap!(learnt_bounds(&scorer, 501, 2, 3));
+        assert_eq!((bounds_501.min_liquidity_msat, bounds_501.max_liquidity_msat), (120_000, None));
+    }
+
+    #[test]
+    fn bounds_decay_over_time() {
+        let half_life_secs = 3600;
+        let mut bounds = LiquidityBounds { min_liquidity_msat: 80_000, max_liquidity_msat: Some(100_000), last_updated: NOW };
+        bounds.decay(NOW - 10, half_life_secs);
+        assert_eq!(bounds.last_updated, NOW);
+        assert_eq!((bounds.min_liquidity_msat, bounds.max_liquidity_msat), (80_000, Some(100_000)));
+        bounds.decay(NOW + half_life_secs, half_life_secs);
+        assert_eq!((bounds.min_liquidity_msat, bounds.max_liquidity_msat), (40_000, Some(200_000)));
+        bounds.decay(NOW + 3 * half_life_secs, half_life_secs);
+        assert_eq!((bounds.min_liquidity_msat, bounds.max_liquidity_msat), (10_000, Some(800_000)));
+        assert_eq!(bounds.last_updated, NOW + 3 * half_life_secs);
+        // An upper bound which grows past what fits is dropped.
+        bounds.decay(NOW + 70 * half_life_secs, half_life_secs);
+        assert_eq!((bounds.min_liquidity_msat, bounds.max_liquidity_msat), (0, None));
+        assert!(bounds.is_empty());
+
+        let params = ProbabilisticScoringParams { liquidity_half_life_secs: half_life_secs, ..ProbabilisticScoringParams::default() };
+        let mut scorer = ProbabilisticScorer::new(params);
+        let path = [hop(2, 500, 1000)];
+        scorer.payment_path_successful(&test_node_id(1), &path, NOW);
+        scorer.decay(NOW + half_life_secs);
+        assert_eq!(unwrap!(learnt_bounds(&scorer, 500, 1, 2)).min_liquidity_msat, 500);
+        // Bounds are forgotten once there's nothing left of them.
+        scorer.decay(NOW + 20 * half_life_secs);
+        assert!(scorer.liquidity.is_empty());
+    }
+
+    #[test]
+    fn penalties_ordered_by_amount() {
+        let params = ProbabilisticScoringParams::default();
+        let mut scorer = ProbabilisticScorer::new(params);
+        let (a, b) = (test_node_id(1), test_node_id(2));
+        let penalty = |scorer: &ProbabilisticScorer, amount_msat, htlc_maximum_msat| {
+            scorer.channel_penalty_msat(test_short_channel_id(500), &a, &b, amount_msat, htlc_maximum_msat)
+        };
+
+        // With nothing learnt, the policy's htlc_maximum_msat is the upper bound. A 10% chance
+        // costs the multiplier.
+        assert_eq!(penalty(&scorer, 90_000, None), params.base_penalty_msat);
+        let ten_percent = penalty(&scorer, 90_000, Some(99_999));
+        let expected = params.base_penalty_msat + params.liquidity_penalty_multiplier_msat;
+        assert!(ten_percent + 1 >= expected && ten_percent <= expected);
+
+        scorer.payment_path_successful(&a, &[hop(2, 500, 20_000)], NOW);
+        scorer.payment_path_failed(&a, &[hop(2, 500, 100_001)], test_short_channel_id(500), NOW);
+        let amounts = [0, 20_000, 40_000, 60_000, 80_000, 99_000, 100_000];
+        let penalties: Vec<_> = amounts.iter().map(|&amount_msat| penalty(&scorer, amount_msat, None)).collect();
+        assert_eq!(penalties[0], params.base_penalty_msat);
+        assert_eq!(penalties[1], params.base_penalty_msat);
+        for pair in penalties[1..].windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", penalties);
+        }
+        assert!(penalties[6] < u64::max_value());
+        assert_eq!(penalty(&scorer, 100_001, None), u64::max_value());
+        // A lower htlc_maximum_msat tightens the upper bound.
+        assert!(penalty(&scorer, 60_000, Some(70_000)) > penalties[3]);
+        assert_eq!(penalty(&scorer, 70_001, Some(70_000)), u64::max_value());
+    }
+
+    #[test]
+    fn learns_and_forgets_liquidity_bounds() {
+        let mut scorer = ProbabilisticScorer::new(ProbabilisticScoringParams::default());
+        let (a, b, c) = (test_node_id(1), test_node_id(2), test_node_id(3));
+        let penalty = |scorer: &ProbabilisticScorer, from: &NodeId, to: &NodeId, block_height, amount_msat| {
+            scorer.channel_penalty_msat(test_short_channel_id(block_height), from, to, amount_msat, None)
+        };
+        let base = ProbabilisticScoringParams::default().base_penalty_msat;
+        assert_eq!(penalty(&scorer, &b, &c, 501, 100_000), base);
+
+        let path = [hop(2, 500, 101_000), hop(3, 501, 100_000)];
+        scorer.payment_path_failed(&a, &path, test_short_channel_id(501), NOW);
+
+        // The first hop carried the payment, the second couldn't.
+        assert_eq!(penalty(&scorer, &a, &b, 500, 101_000), base);
+        assert_eq!(penalty(&scorer, &b, &c, 501, 100_000), u64::max_value());
+        let small = penalty(&scorer, &b, &c, 501, 10_000);
+        let large = penalty(&scorer, &b, &c, 501, 90_000);
+        assert!(base < small && small < large);
+        // Only the failed direction is affected.
+        assert_eq!(penalty(&scorer, &c, &b, 501, 100_000), base);
+
+        scorer.decay(NOW + ProbabilisticScoringParams::default().liquidity_half_life_secs);
+        assert!(penalty(&scorer, &b, &c, 501, 100_000) < u64::max_value());
+        assert!(penalty(&scorer, &b, &c, 501, 90_000) < large);
+
+        scorer.payment_path_successful(&a, &path, NOW + 100);
+        assert_eq!(penalty(&scorer, &b, &c, 501, 100_000), base);
+    }
+}
@@ -0,0 +1,281 @@
+use super::*;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_2: u8 = 0x52;
+pub const OP_16: u8 = 0x60;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_IFDUP: u8 = 0x73;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+
+#[derive(Debug, Fail)]
+pub enum SegwitAddressError {
+    #[fail(display = "invalid bech32 string")]
+    InvalidBech32,
+    #[fail(display = "address is for network {}, expected {}", hrp, expected)]
+    WrongNetwork {
+        hrp: String,
+        expected: String,
+    },
+    #[fail(display = "address uses the wrong checksum for witness version {}", version)]
+    WrongChecksum {
+        version: u8,
+    },
+    #[fail(display = "invalid witness version {}", version)]
+    InvalidVersion {
+        version: u8,
+    },
+    #[fail(display = "invalid witness program length {} for version {}", len, version)]
+    InvalidProgramLength {
+        version: u8,
+        len: usize,
+    },
+}
+
+/// Builds a script one opcode or push at a time, always using the minimal push encoding so that
+/// scripts come out byte-for-byte as the BOLTs specify them.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptBuilder {
+    script: Vec<u8>,
+}
+
+impl ScriptBuilder {
+    pub fn new() -> ScriptBuilder {
+        ScriptBuilder::default()
+    }
+
+    pub fn push_opcode(mut self, opcode: u8) -> ScriptBuilder {
+        self.script.push(opcode);
+        self
+    }
+
+    pub fn push_slice(mut self, data: &[u8]) -> ScriptBuilder {
+        match data.len() {
+            0 => return self.push_opcode(OP_0),
+            1 if data[0] >= 1 && data[0] <= 16 => return self.push_opcode(OP_1 + data[0] - 1),
+            1 if data[0] == 0x81 => return self.push_opcode(OP_1NEGATE),
+            len if len < OP_PUSHDATA1 as usize => self.script.push(len as u8),
+            len if len <= 0xff => {
+                self.script.push(OP_PUSHDATA1);
+                self.script.push(len as u8);
+            },
+            len if len <= 0xffff => {
+                self.script.push(OP_PUSHDATA2);
+                self.script.extend_from_slice(&(len as u16).to_le_bytes());
+            },
+            len => {
+                self.script.push(OP_PUSHDATA4);
+                self.script.extend_from_slice(&(len as u32).to_le_bytes());
+            },
+        }
+        self.script.extend_from_slice(data);
+        self
+    }
+
+    /// Pushes a number in script number encoding: little-endian, with the sign in the top bit.
+    pub fn push_int(self, n: i64) -> ScriptBuilder {
+        if n == 0 {
+            return self.push_opcode(OP_0);
+        }
+        let negative = n < 0;
+        let mut abs = n.wrapping_abs() as u64;
+        let mut bytes = Vec::with_capacity(9);
+        while abs > 0 {
+            bytes.push(abs as u8);
+            abs >>= 8;
+        }
+        if bytes[bytes.len() - 1] & 0x80 != 0 {
+            bytes.push(if negative { 0x80 } else { 0x00 });
+        } else if negative {
+            let last = bytes.len() - 1;
+            bytes[last] |= 0x80;
+        }
+        self.push_slice(&bytes)
+    }
+
+    pub fn push_pubkey(self, pubkey: &secp256k1::PublicKey) -> ScriptBuilder {
+        self.push_slice(&pubkey.serialize()[..])
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.script
+    }
+}
+
+/// `version || push(program)`, the script_pubkey of a native segwit output.
+pub fn witness_program_script_pubkey(version: u8, program: &[u8]) -> Vec<u8> {
+    let version_opcode = if version == 0 { OP_0 } else { OP_1 + version - 1 };
+    let mut script = Vec::with_capacity(2 + program.len());
+    script.push(version_opcode);
+    script.push(program.len() as u8);
+    script.extend_from_slice(program);
+    script
+}
+
+pub fn p2wpkh_script_pubkey(pubkey: &secp256k1::PublicKey) -> Vec<u8> {
+    witness_program_script_pubkey(0, &hash160(&pubkey.serialize()[..])[..])
+}
+
+pub fn p2wsh_script_pubkey(witness_script: &[u8]) -> Vec<u8> {
+    witness_program_script_pubkey(0, &sha256(&[witness_script])[..])
+}
+
+/// `output_key` is the x-only, already tweaked, taproot output key.
+pub fn p2tr_script_pubkey(output_key: &[u8; 32]) -> Vec<u8> {
+    witness_program_script_pubkey(1, &output_key[..])
+}
+
+/// The BIP 143 script code for spending a P2WPKH output: the equivalent P2PKH script.
+pub fn p2wpkh_script_code(pubkey: &secp256k1::PublicKey) -> Vec<u8> {
+    ScriptBuilder::new()
+    .push_opcode(OP_DUP)
+    .push_opcode(OP_HASH160)
+    .push_slice(&hash160(&pubkey.serialize()[..])[..])
+    .push_opcode(OP_EQUALVERIFY)
+    .push_opcode(OP_CHECKSIG)
+    .into_bytes()
+}
+
+/// Splits a native segwit script_pubkey into its witness version and program.
+pub fn witness_program(script_pubkey: &[u8]) -> Option<(u8, &[u8])> {
+    if script_pubkey.len() < 4 || script_pubkey.len() > 42 || script_pubkey[1] as usize != script_pubkey.len() - 2 {
+        return None;
+    }
+    let version = match script_pubkey[0] {
+        OP_0 => 0,
+        opcode if opcode >= OP_1 && opcode <= OP_16 => opcode - OP_1 + 1,
+        _ => return None,
+    };
+    Some((version, &script_pubkey[2..]))
+}
+
+fn check_witness_program(version: u8, program: &[u8]) -> Result<(), SegwitAddressError> {
+    if version > 16 {
+        return Err(SegwitAddressError::InvalidVersion { version });
+    }
+    let len_ok = match version {
+        0 => program.len() == 20 || program.len() == 32,
+        _ => program.len() >= 2 && program.len() <= 40,
+    };
+    if !len_ok {
+        return Err(SegwitAddressError::InvalidProgramLength { version, len: program.len() });
+    }
+    Ok(())
+}
+
+/// Encodes a segwit address (BIP 173, or BIP 350 for versions above 0). `hrp` is "bc" for
+/// mainnet, "tb" for testnet and "bcrt" for regtest.
+pub fn segwit_address(hrp: &str, version: u8, program: &[u8]) -> Result<String, SegwitAddressError> {
+    check_witness_program(version, program)?;
+    let mut groups = vec![version];
+    groups.extend(bytes_to_groups(program));
+    let checksum_const = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    Ok(bech32_encode(hrp, &groups, checksum_const))
+}
+
+/// Decodes a segwit address for the network with the given `hrp` into its witness version and
+/// program.
+pub fn decode_segwit_address(hrp: &str, address: &str) -> Result<(u8, Vec<u8>), SegwitAddressError> {
+    let (address_hrp, groups, checksum_const) = match bech32_decode(address) {
+        Some(decoded) => decoded,
+        None => return Err(SegwitAddressError::InvalidBech32),
+    };
+    if address_hrp != hrp {
+        return Err(SegwitAddressError::WrongNetwork { hrp: address_hrp, expected: hrp.to_owned() });
+    }
+    let version = match groups.first() {
+        Some(version) => *version,
+        None => return Err(SegwitAddressError::InvalidBech32),
+    };
+    let expected_const = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    if checksum_const != expected_const {
+        return Err(SegwitAddressError::WrongChecksum { version });
+    }
+    let program = groups_to_bytes(&groups[1..], false);
+    // Padding must be under 5 bits and all zeros, which is the case exactly when re-encoding
+    // gives back the same groups.
+    if bytes_to_groups(&program)[..] != groups[1..] {
+        return Err(SegwitAddressError::InvalidBech32);
+    }
+    check_witness_program(version, &program)?;
+    Ok((version, program))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn bip173_and_bip350_addresses() {
+        let secp = Secp256k1::new();
+        let pubkey = hex!("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+        let pubkey = unwrap!(secp256k1::PublicKey::from_slice(&secp, &pubkey[..]));
+        let witness_script = ScriptBuilder::new().push_pubkey(&pubkey).push_opcode(OP_CHECKSIG).into_bytes();
+        let output_key = hex!("a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c");
+        let cases = [
+            (p2wpkh_script_pubkey(&pubkey), "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            (p2wsh_script_pubkey(&witness_script), "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"),
+            (p2tr_script_pubkey(&output_key), "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"),
+        ];
+        for (script_pubkey, address) in cases.iter() {
+            let (version, program) = unwrap!(witness_program(script_pubkey));
+            assert_eq!(unwrap!(segwit_address("bc", version, program)), *address);
+            assert_eq!(unwrap!(decode_segwit_address("bc", address)), (version, program.to_vec()));
+        }
+
+        match decode_segwit_address("tb", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4") {
+            Err(SegwitAddressError::WrongNetwork { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // A version 1 program with a bech32 rather than bech32m checksum.
+        let wrong_checksum = {
+            let mut groups = vec![1];
+            groups.extend(bytes_to_groups(&output_key[..]));
+            bech32_encode("bc", &groups, BECH32_CONST)
+        };
+        match decode_segwit_address("bc", &wrong_checksum) {
+            Err(SegwitAddressError::WrongChecksum { version: 1 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn minimal_pushes() {
+        let script = {
+            ScriptBuilder::new()
+            .push_int(0)
+            .push_int(16)
+            .push_int(-1)
+            .push_int(144)
+            .push_int(-255)
+            .push_int(500_000)
+            .push_slice(&[0x42; 76])
+            .into_bytes()
+        };
+        let mut expected = vec![OP_0, OP_16, OP_1NEGATE, 0x02, 0x90, 0x00, 0x02, 0xff, 0x80, 0x03, 0x20, 0xa1, 0x07];
+        expected.extend_from_slice(&[OP_PUSHDATA1, 76]);
+        expected.extend_from_slice(&[0x42; 76]);
+        assert_eq!(script, expected);
+    }
+}
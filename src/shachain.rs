@@ -0,0 +1,316 @@
+use super::*;
+
+/// Commitment numbers count up from 0, but per-commitment secrets are indexed counting down from
+/// this.
+pub const FIRST_PER_COMMITMENT_INDEX: u64 = (1 << 48) - 1;
+
+const SHACHAIN_BUCKETS: usize = 49;
+
+#[derive(Debug, Fail)]
+pub enum ShachainError {
+    #[fail(display = "per-commitment secret index {} is out of range", index)]
+    IndexOutOfRange {
+        index: u64,
+    },
+    #[fail(display = "expected per-commitment secret {}, got {}", expected, index)]
+    UnexpectedIndex {
+        expected: u64,
+        index: u64,
+    },
+    #[fail(display = "per-commitment secret {} does not derive earlier secrets", index)]
+    InconsistentSecret {
+        index: u64,
+    },
+    #[fail(display = "commitment number {} is out of range", commitment_number)]
+    CommitmentNumberOutOfRange {
+        commitment_number: u64,
+    },
+    #[fail(display = "stored per-commitment secret indexes are inconsistent")]
+    InconsistentStore,
+    #[fail(display = "{}", _0)]
+    MsgTooShort(MsgTooShortError),
+}
+
+impl From<MsgTooShortError> for ShachainError {
+    fn from(err: MsgTooShortError) -> ShachainError {
+        ShachainError::MsgTooShort(err)
+    }
+}
+
+/// Hashes `secret` down the tree, flipping each bit below `bits` which is set in `index`.
+fn derive_secret(secret: &[u8; 32], bits: u32, index: u64) -> [u8; 32] {
+    let mut secret = *secret;
+    for bit in (0..bits).rev() {
+        if index & (1 << bit) != 0 {
+            secret[(bit / 8) as usize] ^= 1 << (bit % 8);
+            secret = sha256(&[&secret[..]]);
+        }
+    }
+    secret
+}
+
+/// The BOLT 3 per-commitment secret at `index`, from the channel's commitment seed.
+pub fn per_commitment_secret(seed: &[u8; 32], index: u64) -> [u8; 32] {
+    derive_secret(seed, 48, index)
+}
+
+/// The per-commitment secret index of a commitment number. There are only 2^48 of them.
+pub fn per_commitment_index(commitment_number: u64) -> Result<u64, ShachainError> {
+    if commitment_number > FIRST_PER_COMMITMENT_INDEX {
+        return Err(ShachainError::CommitmentNumberOutOfRange { commitment_number });
+    }
+    Ok(FIRST_PER_COMMITMENT_INDEX - commitment_number)
+}
+
+/// Which of a store's entries the secret for `index` goes in.
+fn bucket(index: u64) -> u32 {
+    cmp::min(index.trailing_zeros(), 48)
+}
+
+/// Stores the counterparty's revealed per-commitment secrets in O(log n) space. A secret at an
+/// index with `b` trailing zeros can derive every secret which differs from it only in those
+/// bits, so one secret per trailing-zero count is enough to derive all those seen so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShachainStore {
+    known: Vec<Option<([u8; 32], u64)>>,
+}
+
+impl Default for ShachainStore {
+    fn default() -> ShachainStore {
+        ShachainStore::new()
+    }
+}
+
+impl ShachainStore {
+    pub fn new() -> ShachainStore {
+        ShachainStore {
+            known: vec![None; SHACHAIN_BUCKETS],
+        }
+    }
+
+    /// The index of the most recently inserted secret.
+    pub fn min_index(&self) -> Option<u64> {
+        self.known.iter().filter_map(|entry| entry.map(|(_, index)| index)).min()
+    }
+
+    /// Stores the secret for `index`, which must be the one after the last secret stored.
+    pub fn insert_secret(&mut self, secret: [u8; 32], index: u64) -> Result<(), ShachainError> {
+        if index > FIRST_PER_COMMITMENT_INDEX {
+            return Err(ShachainError::IndexOutOfRange { index });
+        }
+        let expected = self.min_index().map(|min_index| min_index.wrapping_sub(1)).unwrap_or(FIRST_PER_COMMITMENT_INDEX);
+        if index != expected {
+            return Err(ShachainError::UnexpectedIndex { expected, index });
+        }
+        let bucket = bucket(index);
+        for entry in self.known.iter().take(bucket as usize) {
+            if let Some((known_secret, known_index)) = *entry {
+                if derive_secret(&secret, bucket, known_index) != known_secret {
+                    return Err(ShachainError::InconsistentSecret { index });
+                }
+            }
+        }
+        self.known[bucket as usize] = Some((secret, index));
+        Ok(())
+    }
+
+    /// Derives the secret for `index`, if it has been revealed.
+    pub fn get_secret(&self, index: u64) -> Option<[u8; 32]> {
+        for (b, entry) in self.known.iter().enumerate() {
+            if let Some((secret, known_index)) = *entry {
+                let mask = !((1u64 << b) - 1);
+                if index & mask == known_index & mask {
+                    return Some(derive_secret(&secret, b as u32, index));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        for entry in &self.known {
+            match *entry {
+                Some((secret, index)) => {
+                    cursor.write_u64(index);
+                    cursor.write_slice(&secret[..]);
+                },
+                None => {
+                    cursor.write_u64(u64::max_value());
+                    cursor.write_slice(&[0; 32]);
+                },
+            }
+        }
+    }
+
+    /// Reads a store written by `write_to_cursor`. Its indexes must be the ones inserting
+    /// secrets in order would have left.
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<ShachainStore, ShachainError> {
+        let mut known = Vec::with_capacity(SHACHAIN_BUCKETS);
+        for _ in 0..SHACHAIN_BUCKETS {
+            let index = cursor.read_u64()?;
+            let secret = cursor.read_bytes32()?;
+            known.push(if index == u64::max_value() { None } else { Some((secret, index)) });
+        }
+        let store = ShachainStore { known };
+        if let Some(min_index) = store.min_index() {
+            if min_index > FIRST_PER_COMMITMENT_INDEX {
+                return Err(ShachainError::InconsistentStore);
+            }
+            for (b, entry) in store.known.iter().enumerate() {
+                if entry.map(|(_, index)| index) != ShachainStore::bucket_index(min_index, b as u32) {
+                    return Err(ShachainError::InconsistentStore);
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    /// The index whose secret bucket `b` holds once every secret down to `min_index` has been
+    /// inserted: the lowest index at or above `min_index` which belongs in the bucket.
+    fn bucket_index(min_index: u64, b: u32) -> Option<u64> {
+        let step = 1u64 << b;
+        let mut index = (min_index + step - 1) & !(step - 1);
+        if bucket(index) != b {
+            index += step;
+        }
+        if index <= FIRST_PER_COMMITMENT_INDEX && bucket(index) == b {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn generate_from_seed_vectors() {
+        let cases = [
+            ([0x00; 32], FIRST_PER_COMMITMENT_INDEX, hex!("02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148")),
+            ([0xff; 32], FIRST_PER_COMMITMENT_INDEX, hex!("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc")),
+            ([0xff; 32], 0xaaa_aaaa_aaaa, hex!("56f4008fb007ca9acf0e15b054d5c9fd12ee06cea347914ddbaed70d1c13a528")),
+            ([0xff; 32], 0x5555_5555_5555, hex!("9015daaeb06dba4ccc05b91b2f73bd54405f2be9f217fbacd3c5ac2e62327d31")),
+            ([0x01; 32], 1, hex!("915c75942a26bb3a433a8ce2cb0427c29ec6c1775cfc78328b57f6ba7bfeaa9c")),
+        ];
+        for (seed, index, expected) in cases.iter() {
+            assert_eq!(per_commitment_secret(seed, *index), *expected);
+        }
+    }
+
+    #[test]
+    fn stores_and_derives_secrets() {
+        let seed = [0xff; 32];
+        let mut store = ShachainStore::new();
+        for commitment_number in 0..1001 {
+            let index = unwrap!(per_commitment_index(commitment_number));
+            unwrap!(store.insert_secret(per_commitment_secret(&seed, index), index));
+        }
+        for commitment_number in 0..1001 {
+            let index = unwrap!(per_commitment_index(commitment_number));
+            assert_eq!(store.get_secret(index), Some(per_commitment_secret(&seed, index)));
+        }
+        assert_eq!(store.get_secret(unwrap!(per_commitment_index(1001))), None);
+
+        let mut cursor = WriteCursor::new();
+        store.write_to_cursor(&mut cursor);
+        let bytes = cursor.into_bytes();
+        assert_eq!(unwrap!(ShachainStore::read_from_cursor(&mut ReadCursor::new(&bytes))), store);
+
+        // A secret from another seed is caught once it should derive one we already have.
+        let index = unwrap!(per_commitment_index(1001));
+        let bogus = per_commitment_secret(&[0x01; 32], index);
+        match store.insert_secret(bogus, index) {
+            Err(ShachainError::InconsistentSecret { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match store.insert_secret(per_commitment_secret(&seed, index - 1), index - 1) {
+            Err(ShachainError::UnexpectedIndex { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        assert_eq!(unwrap!(per_commitment_index(FIRST_PER_COMMITMENT_INDEX)), 0);
+        match per_commitment_index(FIRST_PER_COMMITMENT_INDEX + 1) {
+            Err(ShachainError::CommitmentNumberOutOfRange { .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Stored indexes which secrets inserted in order couldn't have left.
+        let mut bytes = bytes.to_vec();
+        bytes[7] ^= 2;
+        match ShachainStore::read_from_cursor(&mut ReadCursor::new(&bytes)) {
+            Err(ShachainError::InconsistentStore) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        bytes[7] ^= 2;
+        bytes[40..48].copy_from_slice(&[0xff; 8]);
+        match ShachainStore::read_from_cursor(&mut ReadCursor::new(&bytes)) {
+            Err(ShachainError::InconsistentStore) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match ShachainStore::read_from_cursor(&mut ReadCursor::new(&bytes[..100])) {
+            Err(ShachainError::MsgTooShort(..)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn bolt3_storage_vectors() {
+        // The secrets are those of seeds `[0xff; 32]` and `[0x00; 32]`, for the indexes counting
+        // down from `FIRST_PER_COMMITMENT_INDEX`.
+        let correct = [
+            hex!("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc"),
+            hex!("c7518c8ae4660ed02894df8976fa1a3659c1a8b4b5bec0c4b872abeba4cb8964"),
+            hex!("2273e227a5b7449b6e70f1fb4652864038b1cbf9cd7c043a7d6456b7fc275ad8"),
+            hex!("27cddaa5624534cb6cb9d7da077cf2b22ab21e9b506fd4998a51d54502e99116"),
+            hex!("c65716add7aa98ba7acb236352d665cab17345fe45b55fb879ff80e6bd0c41dd"),
+            hex!("969660042a28f32d9be17344e09374b379962d03db1574df5a8a5a47e19ce3f2"),
+            hex!("a5a64476122ca0925fb344bdc1854c1c0a59fc614298e50a33e331980a220f32"),
+            hex!("05cde6323d949933f7f7b78776bcc1ea6d9b31447732e3802e1f7ac44b650e17"),
+        ];
+        let incorrect = [
+            hex!("02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148"),
+            hex!("dddc3a8d14fddf2b68fa8c7fbad2748274937479dd0f8930d5ebb4ab6bd866a3"),
+            hex!("c51a18b13e8527e579ec56365482c62f180b7d5760b46e9477dae59e87ed423a"),
+            hex!("ba65d7b0ef55a3ba300d4e87af29868f394f8f138d78a7011669c79b37b936f4"),
+            hex!("631373ad5f9ef654bb3dade742d09504c567edd24320d2fcd68e3cc47e2ff6a6"),
+            hex!("b7e76a83668bde38b373970155c868a653304308f9896692f904a23731224bb1"),
+            hex!("e7971de736e01da8ed58b94c2fc216cb1dca9e326f3a96e7194fe8ea8af6c0a3"),
+            hex!("a7efbc61aac46d34f77778bac22c8a20c6a46ca460addc49009bda875ec88fa4"),
+        ];
+
+        let mut store = ShachainStore::new();
+        for (i, secret) in correct.iter().enumerate() {
+            unwrap!(store.insert_secret(*secret, FIRST_PER_COMMITMENT_INDEX - i as u64));
+        }
+        for (i, secret) in correct.iter().enumerate() {
+            assert_eq!(store.get_secret(FIRST_PER_COMMITMENT_INDEX - i as u64), Some(*secret));
+        }
+
+        // Incorrect cases #1 to #8: the secrets which are wrong, and the one which reveals it.
+        let cases: [(&[usize], usize); 8] = [
+            (&[0], 1),
+            (&[0, 1], 3),
+            (&[2], 3),
+            (&[0, 1, 2, 3], 7),
+            (&[4], 5),
+            (&[4, 5], 7),
+            (&[6], 7),
+            (&[7], 7),
+        ];
+        for (wrong, caught) in cases.iter() {
+            let mut store = ShachainStore::new();
+            for i in 0..correct.len() {
+                let secret = if wrong.contains(&i) { incorrect[i] } else { correct[i] };
+                let index = FIRST_PER_COMMITMENT_INDEX - i as u64;
+                match store.insert_secret(secret, index) {
+                    Ok(()) if i < *caught => (),
+                    Err(ShachainError::InconsistentSecret { .. }) if i == *caught => break,
+                    res => panic!("unexpected result for secret {}: {:?}", i, res),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use super::*;
+
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+#[derive(Debug, Fail)]
+pub enum TransactionError {
+    #[fail(display = "{}", _0)]
+    MsgTooShort(MsgTooShortError),
+    #[fail(display = "non-canonical compact size")]
+    NonCanonicalCompactSize,
+    #[fail(display = "unsupported segwit flag {}", flag)]
+    UnsupportedFlag {
+        flag: u8,
+    },
+    #[fail(display = "transaction has the segwit flag but no witnesses")]
+    SuperfluousWitness,
+    #[fail(display = "trailing bytes after transaction")]
+    TrailingBytes,
+}
+
+impl From<MsgTooShortError> for TransactionError {
+    fn from(err: MsgTooShortError) -> TransactionError {
+        TransactionError::MsgTooShort(err)
+    }
+}
+
+/// A reference to a transaction output. `txid` is in internal byte order, the reverse of how
+/// block explorers display it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    /// The witness stack. Not covered by the txid.
+    pub witness: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxOut {
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub version: u32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+// Bitcoin's own serialization is little-endian and length-prefixes with compact sizes, unlike
+// the lightning wire format.
+
+impl<'a> ReadCursor<'a> {
+    pub fn read_u32_le(&mut self) -> Result<u32, MsgTooShortError> {
+        Ok(u32::from_le_bytes(slice_to_array!(self.read_slice(4)?, 4)))
+    }
+
+    pub fn read_u64_le(&mut self) -> Result<u64, MsgTooShortError> {
+        Ok(u64::from_le_bytes(slice_to_array!(self.read_slice(8)?, 8)))
+    }
+
+    pub fn read_compact_size(&mut self) -> Result<u64, TransactionError> {
+        let (val, min) = match self.read_u8()? {
+            0xfd => (u16::from_le_bytes(slice_to_array!(self.read_slice(2)?, 2)) as u64, 0xfd),
+            0xfe => (self.read_u32_le()? as u64, 0x1_0000),
+            0xff => (self.read_u64_le()?, 0x1_0000_0000),
+            val => return Ok(val as u64),
+        };
+        if val < min {
+            return Err(TransactionError::NonCanonicalCompactSize);
+        }
+        Ok(val)
+    }
+
+    pub fn read_compact_size_slice(&mut self) -> Result<&'a [u8], TransactionError> {
+        let len = self.read_compact_size()?;
+        if len > self.remaining().len() as u64 {
+            return Err(TransactionError::MsgTooShort(MsgTooShortError));
+        }
+        Ok(self.read_slice(len as usize)?)
+    }
+}
+
+impl WriteCursor {
+    pub fn write_u32_le(&mut self, val: u32) {
+        self.write_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64_le(&mut self, val: u64) {
+        self.write_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_compact_size(&mut self, val: u64) {
+        if val < 0xfd {
+            self.write_u8(val as u8);
+        } else if val <= 0xffff {
+            self.write_u8(0xfd);
+            self.write_slice(&(val as u16).to_le_bytes());
+        } else if val <= 0xffff_ffff {
+            self.write_u8(0xfe);
+            self.write_u32_le(val as u32);
+        } else {
+            self.write_u8(0xff);
+            self.write_u64_le(val);
+        }
+    }
+
+    pub fn write_compact_size_slice(&mut self, slice: &[u8]) {
+        self.write_compact_size(slice.len() as u64);
+        self.write_slice(slice);
+    }
+}
+
+impl OutPoint {
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<OutPoint, MsgTooShortError> {
+        Ok(OutPoint {
+            txid: cursor.read_bytes32()?,
+            vout: cursor.read_u32_le()?,
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_slice(&self.txid[..]);
+        cursor.write_u32_le(self.vout);
+    }
+}
+
+impl TxOut {
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<TxOut, TransactionError> {
+        Ok(TxOut {
+            value: cursor.read_u64_le()?,
+            script_pubkey: cursor.read_compact_size_slice()?.to_vec(),
+        })
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_u64_le(self.value);
+        cursor.write_compact_size_slice(&self.script_pubkey);
+    }
+}
+
+impl Transaction {
+    /// Whether any input has a witness, in which case the transaction is serialized in the BIP
+    /// 144 format.
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    fn write_to_cursor_inner(&self, cursor: &mut WriteCursor, with_witness: bool) {
+        let with_witness = with_witness && self.has_witness();
+        cursor.write_u32_le(self.version);
+        if with_witness {
+            cursor.write_u8(0x00);
+            cursor.write_u8(0x01);
+        }
+        cursor.write_compact_size(self.inputs.len() as u64);
+        for input in &self.inputs {
+            input.previous_output.write_to_cursor(cursor);
+            cursor.write_compact_size_slice(&input.script_sig);
+            cursor.write_u32_le(input.sequence);
+        }
+        cursor.write_compact_size(self.outputs.len() as u64);
+        for output in &self.outputs {
+            output.write_to_cursor(cursor);
+        }
+        if with_witness {
+            for input in &self.inputs {
+                cursor.write_compact_size(input.witness.len() as u64);
+                for item in &input.witness {
+                    cursor.write_compact_size_slice(item);
+                }
+            }
+        }
+        cursor.write_u32_le(self.lock_time);
+    }
+
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        self.write_to_cursor_inner(cursor, true)
+    }
+
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<Transaction, TransactionError> {
+        let version = cursor.read_u32_le()?;
+        // A transaction with no inputs would look the same, but those can't be valid anyway.
+        let with_witness = cursor.remaining().first() == Some(&0x00);
+        if with_witness {
+            cursor.read_u8()?;
+            let flag = cursor.read_u8()?;
+            if flag != 0x01 {
+                return Err(TransactionError::UnsupportedFlag { flag });
+            }
+        }
+
+        let num_inputs = cursor.read_compact_size()?;
+        let mut inputs = Vec::with_capacity(cmp::min(num_inputs, 1024) as usize);
+        for _ in 0..num_inputs {
+            inputs.push(TxIn {
+                previous_output: OutPoint::read_from_cursor(cursor)?,
+                script_sig: cursor.read_compact_size_slice()?.to_vec(),
+                sequence: cursor.read_u32_le()?,
+                witness: Vec::new(),
+            });
+        }
+        let num_outputs = cursor.read_compact_size()?;
+        let mut outputs = Vec::with_capacity(cmp::min(num_outputs, 1024) as usize);
+        for _ in 0..num_outputs {
+            outputs.push(TxOut::read_from_cursor(cursor)?);
+        }
+        if with_witness {
+            for input in &mut inputs {
+                let num_items = cursor.read_compact_size()?;
+                for _ in 0..num_items {
+                    input.witness.push(cursor.read_compact_size_slice()?.to_vec());
+                }
+            }
+        }
+        let lock_time = cursor.read_u32_le()?;
+
+        let tx = Transaction { version, inputs, outputs, lock_time };
+        if with_witness && !tx.has_witness() {
+            return Err(TransactionError::SuperfluousWitness);
+        }
+        Ok(tx)
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut cursor = WriteCursor::new();
+        self.write_to_cursor(&mut cursor);
+        cursor.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Transaction, TransactionError> {
+        let mut cursor = ReadCursor::new(bytes);
+        let tx = Transaction::read_from_cursor(&mut cursor)?;
+        if !cursor.is_empty() {
+            return Err(TransactionError::TrailingBytes);
+        }
+        Ok(tx)
+    }
+
+    /// The serialization without witnesses, which the txid commits to.
+    pub fn to_bytes_without_witness(&self) -> Bytes {
+        let mut cursor = WriteCursor::new();
+        self.write_to_cursor_inner(&mut cursor, false);
+        cursor.into_bytes()
+    }
+
+    /// In internal byte order.
+    pub fn txid(&self) -> [u8; 32] {
+        double_sha256(&[&self.to_bytes_without_witness()[..]])
+    }
+
+    /// In internal byte order. The same as the txid if there are no witnesses.
+    pub fn wtxid(&self) -> [u8; 32] {
+        double_sha256(&[&self.to_bytes()[..]])
+    }
+
+    /// BIP 141 weight: witness bytes count once, everything else four times.
+    pub fn weight(&self) -> u64 {
+        let base_size = self.to_bytes_without_witness().len() as u64;
+        let total_size = self.to_bytes().len() as u64;
+        base_size * 3 + total_size
+    }
+
+    /// The BIP 143 signature hash for spending a segwit v0 output of `value` satoshis with input
+    /// `input_index`. `script_code` is the witness script, or for P2WPKH the output of
+    /// `p2wpkh_script_code`. Panics if `input_index` is out of range.
+    pub fn bip143_sighash(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: u32,
+    ) -> [u8; 32] {
+        let input = &self.inputs[input_index];
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+        let base_type = sighash_type & 0x1f;
+
+        let hash_prevouts = if anyone_can_pay {
+            [0; 32]
+        } else {
+            let mut cursor = WriteCursor::new();
+            for input in &self.inputs {
+                input.previous_output.write_to_cursor(&mut cursor);
+            }
+            double_sha256(&[&cursor.into_bytes()[..]])
+        };
+        let hash_sequence = if anyone_can_pay || base_type == SIGHASH_SINGLE || base_type == SIGHASH_NONE {
+            [0; 32]
+        } else {
+            let mut cursor = WriteCursor::new();
+            for input in &self.inputs {
+                cursor.write_u32_le(input.sequence);
+            }
+            double_sha256(&[&cursor.into_bytes()[..]])
+        };
+        let hash_outputs = if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+            let mut cursor = WriteCursor::new();
+            for output in &self.outputs {
+                output.write_to_cursor(&mut cursor);
+            }
+            double_sha256(&[&cursor.into_bytes()[..]])
+        } else if base_type == SIGHASH_SINGLE && input_index < self.outputs.len() {
+            let mut cursor = WriteCursor::new();
+            self.outputs[input_index].write_to_cursor(&mut cursor);
+            double_sha256(&[&cursor.into_bytes()[..]])
+        } else {
+            [0; 32]
+        };
+
+        let mut cursor = WriteCursor::new();
+        cursor.write_u32_le(self.version);
+        cursor.write_slice(&hash_prevouts[..]);
+        cursor.write_slice(&hash_sequence[..]);
+        input.previous_output.write_to_cursor(&mut cursor);
+        cursor.write_compact_size_slice(script_code);
+        cursor.write_u64_le(value);
+        cursor.write_u32_le(input.sequence);
+        cursor.write_slice(&hash_outputs[..]);
+        cursor.write_u32_le(self.lock_time);
+        cursor.write_u32_le(sighash_type);
+        double_sha256(&[&cursor.into_bytes()[..]])
+    }
+
+    /// Signs input `input_index` per BIP 143, returning the signature as it goes in the witness:
+    /// DER-encoded with the sighash type appended.
+    pub fn sign_input(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        input_index: usize,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: u32,
+        secret_key: &secp256k1::SecretKey,
+    ) -> Vec<u8> {
+        let sighash = self.bip143_sighash(input_index, script_code, value, sighash_type);
+        let msg = unwrap!(secp256k1::Message::from_slice(&sighash[..]));
+        let signature = secp.sign(&msg, secret_key);
+        witness_signature(secp, &signature, sighash_type)
+    }
+}
+
+/// A signature as it goes in a witness: DER-encoded with the sighash type appended.
+pub fn witness_signature(
+    secp: &Secp256k1<secp256k1::All>,
+    signature: &secp256k1::Signature,
+    sighash_type: u32,
+) -> Vec<u8> {
+    let mut encoded = signature.serialize_der(secp);
+    encoded.push(sighash_type as u8);
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::*;
+
+    #[test]
+    fn genesis_coinbase_txid() {
+        let bytes = hex!("
+            01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d
+            0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66
+            207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe55
+            48271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba
+            0b8d578a4c702b6bf11d5fac00000000
+        ");
+        let tx = unwrap!(Transaction::from_bytes(&bytes[..]));
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.outputs[0].value, 50 * 100_000_000);
+        assert_eq!(&tx.to_bytes()[..], &bytes[..]);
+
+        let mut txid = tx.txid();
+        txid.reverse();
+        assert_eq!(txid, hex!("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"));
+        assert_eq!(tx.wtxid(), tx.txid());
+        assert_eq!(tx.weight(), bytes.len() as u64 * 4);
+    }
+
+    #[test]
+    fn bip143_p2wpkh_sighash_and_witness_round_trip() {
+        let unsigned = hex!("
+            0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffff
+            ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206
+            000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42db
+            ee7e4dbe6a21b2d50ce2f0167faa815988ac11000000
+        ");
+        let mut tx = unwrap!(Transaction::from_bytes(&unsigned[..]));
+        let script_code = hex!("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac");
+        assert_eq!(
+            tx.bip143_sighash(1, &script_code[..], 600_000_000, SIGHASH_ALL),
+            hex!("c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"),
+        );
+
+        let txid = tx.txid();
+        tx.inputs[1].witness = vec![vec![0x30; 71], vec![0x02; 33]];
+        let bytes = tx.to_bytes();
+        assert_eq!(&bytes[4..6], &[0x00, 0x01][..]);
+        let decoded = unwrap!(Transaction::from_bytes(&bytes));
+        assert_eq!(decoded, tx);
+        assert_eq!(decoded.txid(), txid);
+        assert!(decoded.wtxid() != txid);
+        assert_eq!(decoded.weight(), unsigned.len() as u64 * 4 + 2 + 1 + 1 + 1 + 71 + 1 + 33);
+    }
+}
@@ -0,0 +1,812 @@
+use super::*;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UpdateOrigin {
+    Local,
+    Remote,
+}
+
+/// A channel update, as sent by either side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommitmentUpdate {
+    AddHtlc(UpdateAddHtlcMsg),
+    FulfillHtlc(UpdateFulfillHtlcMsg),
+    FailHtlc(UpdateFailHtlcMsg),
+    FailMalformedHtlc(UpdateFailMalformedHtlcMsg),
+    Fee(UpdateFeeMsg),
+}
+
+impl CommitmentUpdate {
+    pub fn from_msg(msg: Msg) -> Result<CommitmentUpdate, Msg> {
+        match msg {
+            Msg::UpdateAddHtlc(msg) => Ok(CommitmentUpdate::AddHtlc(msg)),
+            Msg::UpdateFulfillHtlc(msg) => Ok(CommitmentUpdate::FulfillHtlc(msg)),
+            Msg::UpdateFailHtlc(msg) => Ok(CommitmentUpdate::FailHtlc(msg)),
+            Msg::UpdateFailMalformedHtlc(msg) => Ok(CommitmentUpdate::FailMalformedHtlc(msg)),
+            Msg::UpdateFee(msg) => Ok(CommitmentUpdate::Fee(msg)),
+            msg => Err(msg),
+        }
+    }
+
+    pub fn to_msg(&self) -> Msg {
+        match self {
+            CommitmentUpdate::AddHtlc(msg) => Msg::UpdateAddHtlc(msg.clone()),
+            CommitmentUpdate::FulfillHtlc(msg) => Msg::UpdateFulfillHtlc(msg.clone()),
+            CommitmentUpdate::FailHtlc(msg) => Msg::UpdateFailHtlc(msg.clone()),
+            CommitmentUpdate::FailMalformedHtlc(msg) => Msg::UpdateFailMalformedHtlc(msg.clone()),
+            CommitmentUpdate::Fee(msg) => Msg::UpdateFee(msg.clone()),
+        }
+    }
+
+    pub fn channel_id(&self) -> &ChannelId {
+        match self {
+            CommitmentUpdate::AddHtlc(msg) => &msg.channel_id,
+            CommitmentUpdate::FulfillHtlc(msg) => &msg.channel_id,
+            CommitmentUpdate::FailHtlc(msg) => &msg.channel_id,
+            CommitmentUpdate::FailMalformedHtlc(msg) => &msg.channel_id,
+            CommitmentUpdate::Fee(msg) => &msg.channel_id,
+        }
+    }
+
+    /// The id of the HTLC this update removes, if it's a fulfill or fail.
+    pub fn removed_htlc_id(&self) -> Option<u64> {
+        match self {
+            CommitmentUpdate::FulfillHtlc(msg) => Some(msg.id),
+            CommitmentUpdate::FailHtlc(msg) => Some(msg.id),
+            CommitmentUpdate::FailMalformedHtlc(msg) => Some(msg.id),
+            CommitmentUpdate::AddHtlc(..) | CommitmentUpdate::Fee(..) => None,
+        }
+    }
+}
+
+/// Where an update is in the commitment dance.
+///
+/// An update proposed by one side goes into the other side's commitment first. Once that side
+/// revokes its previous commitment the update is irrevocably committed there, and it then gets
+/// signed into the proposer's commitment. It's fully settled once the proposer revokes as well.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UpdateState {
+    /// We sent the update but haven't signed a remote commitment including it.
+    SentUpdate,
+    /// Included in the remote commitment we signed. Waiting on their `revoke_and_ack`.
+    SentCommit,
+    /// Irrevocably committed in the remote commitment. Waiting on their `commitment_signed`.
+    ReceivedRevocation,
+    /// Included in our commitment. Waiting for us to send `revoke_and_ack`.
+    ReceivedAckCommit,
+    /// The peer sent the update but hasn't signed a local commitment including it.
+    ReceivedUpdate,
+    /// Included in the local commitment the peer signed. Waiting for us to `revoke_and_ack`.
+    ReceivedCommit,
+    /// Irrevocably committed in our commitment. Waiting for us to send `commitment_signed`.
+    SentRevocation,
+    /// Included in the remote commitment we signed. Waiting on their `revoke_and_ack`.
+    SentAckCommit,
+    /// Irrevocably committed in both commitments.
+    IrrevocablyCommitted,
+}
+
+impl UpdateState {
+    pub fn in_local_commitment(self) -> bool {
+        match self {
+            UpdateState::ReceivedAckCommit
+            | UpdateState::ReceivedCommit
+            | UpdateState::SentRevocation
+            | UpdateState::SentAckCommit
+            | UpdateState::IrrevocablyCommitted => true,
+            _ => false,
+        }
+    }
+
+    pub fn in_remote_commitment(self) -> bool {
+        match self {
+            UpdateState::SentCommit
+            | UpdateState::ReceivedRevocation
+            | UpdateState::ReceivedAckCommit
+            | UpdateState::SentAckCommit
+            | UpdateState::IrrevocablyCommitted => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the update goes into the next local commitment the peer signs.
+    pub fn in_next_local_commitment(self) -> bool {
+        self.in_local_commitment()
+        || self == UpdateState::ReceivedUpdate
+        || self == UpdateState::ReceivedRevocation
+    }
+
+    /// Whether the update goes into the next remote commitment we sign.
+    pub fn in_next_remote_commitment(self) -> bool {
+        self.in_remote_commitment()
+        || self == UpdateState::SentUpdate
+        || self == UpdateState::SentRevocation
+    }
+
+    pub fn irrevocably_in_local_commitment(self) -> bool {
+        match self {
+            UpdateState::SentRevocation
+            | UpdateState::SentAckCommit
+            | UpdateState::IrrevocablyCommitted => true,
+            _ => false,
+        }
+    }
+
+    pub fn irrevocably_in_remote_commitment(self) -> bool {
+        match self {
+            UpdateState::ReceivedRevocation
+            | UpdateState::ReceivedAckCommit
+            | UpdateState::IrrevocablyCommitted => true,
+            _ => false,
+        }
+    }
+
+    fn from_u8(val: u8) -> Option<UpdateState> {
+        let state = match val {
+            0 => UpdateState::SentUpdate,
+            1 => UpdateState::SentCommit,
+            2 => UpdateState::ReceivedRevocation,
+            3 => UpdateState::ReceivedAckCommit,
+            4 => UpdateState::ReceivedUpdate,
+            5 => UpdateState::ReceivedCommit,
+            6 => UpdateState::SentRevocation,
+            7 => UpdateState::SentAckCommit,
+            8 => UpdateState::IrrevocablyCommitted,
+            _ => return None,
+        };
+        Some(state)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedUpdate {
+    pub origin: UpdateOrigin,
+    pub state: UpdateState,
+    pub update: CommitmentUpdate,
+}
+
+#[derive(Debug, Fail)]
+pub enum UpdateQueueError {
+    #[fail(display = "update is for a different channel")]
+    WrongChannelId,
+    #[fail(display = "htlc id {} is out of sequence, expected {}", id, expected)]
+    HtlcIdOutOfSequence {
+        id: u64,
+        expected: u64,
+    },
+    #[fail(display = "no htlc with id {} can be removed", id)]
+    UnknownHtlc {
+        id: u64,
+    },
+    #[fail(display = "htlc {} is not yet irrevocably committed", id)]
+    HtlcNotCommitted {
+        id: u64,
+    },
+    #[fail(display = "htlc {} is already being removed", id)]
+    HtlcAlreadyRemoved {
+        id: u64,
+    },
+    #[fail(display = "payment preimage does not match the payment hash of htlc {}", id)]
+    PreimageMismatch {
+        id: u64,
+    },
+    #[fail(display = "only the funder can send update_fee")]
+    UpdateFeeFromNonFunder,
+    #[fail(display = "commitment_signed must include at least one update")]
+    EmptyCommitment,
+    #[fail(display = "still waiting for revoke_and_ack on the previous commitment")]
+    AwaitingRevocation,
+    #[fail(display = "received revoke_and_ack without an outstanding commitment_signed")]
+    UnexpectedRevocation,
+    #[fail(display = "no commitment_signed to revoke_and_ack")]
+    NothingToRevoke,
+    #[fail(display = "received commitment_signed before we revoked the previous commitment")]
+    UnrevokedCommitment,
+}
+
+/// Tracks the updates of a single channel as they move through the commitment exchange.
+pub struct UpdateQueue {
+    channel_id: ChannelId,
+    is_funder: bool,
+    updates: Vec<QueuedUpdate>,
+    next_local_htlc_id: u64,
+    next_remote_htlc_id: u64,
+    awaiting_remote_revocation: bool,
+    owe_local_revocation: bool,
+    feerate_per_kw: u32,
+    local_commitment_number: u64,
+    remote_commitment_number: u64,
+    revocation_sent_last: bool,
+}
+
+impl UpdateQueue {
+    pub fn new(channel_id: ChannelId, is_funder: bool, feerate_per_kw: u32) -> UpdateQueue {
+        UpdateQueue {
+            channel_id,
+            is_funder,
+            updates: Vec::new(),
+            next_local_htlc_id: 0,
+            next_remote_htlc_id: 0,
+            awaiting_remote_revocation: false,
+            owe_local_revocation: false,
+            feerate_per_kw,
+            local_commitment_number: 0,
+            remote_commitment_number: 0,
+            revocation_sent_last: false,
+        }
+    }
+
+    pub fn channel_id(&self) -> &ChannelId {
+        &self.channel_id
+    }
+
+    pub fn is_funder(&self) -> bool {
+        self.is_funder
+    }
+
+    /// All updates which haven't been fully resolved, in the order they were sent or received.
+    pub fn updates(&self) -> &[QueuedUpdate] {
+        &self.updates
+    }
+
+    pub fn next_local_htlc_id(&self) -> u64 {
+        self.next_local_htlc_id
+    }
+
+    pub fn next_remote_htlc_id(&self) -> u64 {
+        self.next_remote_htlc_id
+    }
+
+    pub fn awaiting_remote_revocation(&self) -> bool {
+        self.awaiting_remote_revocation
+    }
+
+    pub fn owe_local_revocation(&self) -> bool {
+        self.owe_local_revocation
+    }
+
+    /// The number of the latest commitment the peer has signed for us.
+    pub fn local_commitment_number(&self) -> u64 {
+        self.local_commitment_number
+    }
+
+    /// The number of the latest commitment we've signed for the peer.
+    pub fn remote_commitment_number(&self) -> u64 {
+        self.remote_commitment_number
+    }
+
+    /// How many of our commitments we've revoked.
+    pub fn revocations_sent(&self) -> u64 {
+        self.local_commitment_number - self.owe_local_revocation as u64
+    }
+
+    /// How many of the peer's commitments they've revoked.
+    pub fn revocations_received(&self) -> u64 {
+        self.remote_commitment_number - self.awaiting_remote_revocation as u64
+    }
+
+    /// Whether the most recent of our `commitment_signed` and `revoke_and_ack` messages was the
+    /// `revoke_and_ack`.
+    pub fn revocation_sent_last(&self) -> bool {
+        self.revocation_sent_last
+    }
+
+    /// Whether there's anything for us to sign into a new remote commitment.
+    pub fn has_updates_to_sign(&self) -> bool {
+        self.updates.iter().any(|queued| match queued.state {
+            UpdateState::SentUpdate | UpdateState::SentRevocation => true,
+            _ => false,
+        })
+    }
+
+    /// The HTLCs in the latest local commitment, along with which side offered them.
+    pub fn htlcs_in_local_commitment(&self) -> Vec<(UpdateOrigin, &UpdateAddHtlcMsg)> {
+        self.htlcs_in_commitment(UpdateState::in_local_commitment)
+    }
+
+    /// The HTLCs in the latest remote commitment, along with which side offered them.
+    pub fn htlcs_in_remote_commitment(&self) -> Vec<(UpdateOrigin, &UpdateAddHtlcMsg)> {
+        self.htlcs_in_commitment(UpdateState::in_remote_commitment)
+    }
+
+    /// The HTLCs that would go into the next remote commitment if we signed one now.
+    pub fn htlcs_in_next_remote_commitment(&self) -> Vec<(UpdateOrigin, &UpdateAddHtlcMsg)> {
+        self.htlcs_in_commitment(UpdateState::in_next_remote_commitment)
+    }
+
+    /// The HTLCs that would go into the next local commitment if the peer signed one now.
+    pub fn htlcs_in_next_local_commitment(&self) -> Vec<(UpdateOrigin, &UpdateAddHtlcMsg)> {
+        self.htlcs_in_commitment(UpdateState::in_next_local_commitment)
+    }
+
+    pub fn feerate_in_local_commitment(&self) -> u32 {
+        self.feerate_in_commitment(UpdateState::in_local_commitment)
+    }
+
+    pub fn feerate_in_remote_commitment(&self) -> u32 {
+        self.feerate_in_commitment(UpdateState::in_remote_commitment)
+    }
+
+    pub fn feerate_in_next_local_commitment(&self) -> u32 {
+        self.feerate_in_commitment(UpdateState::in_next_local_commitment)
+    }
+
+    pub fn feerate_in_next_remote_commitment(&self) -> u32 {
+        self.feerate_in_commitment(UpdateState::in_next_remote_commitment)
+    }
+
+    pub fn send_update(&mut self, update: CommitmentUpdate) -> Result<(), UpdateQueueError> {
+        self.check_update(UpdateOrigin::Local, &update)?;
+        if let CommitmentUpdate::AddHtlc(..) = update {
+            self.next_local_htlc_id += 1;
+        }
+        self.updates.push(QueuedUpdate {
+            origin: UpdateOrigin::Local,
+            state: UpdateState::SentUpdate,
+            update,
+        });
+        Ok(())
+    }
+
+    pub fn receive_update(&mut self, update: CommitmentUpdate) -> Result<(), UpdateQueueError> {
+        self.check_update(UpdateOrigin::Remote, &update)?;
+        if let CommitmentUpdate::AddHtlc(..) = update {
+            self.next_remote_htlc_id += 1;
+        }
+        self.updates.push(QueuedUpdate {
+            origin: UpdateOrigin::Remote,
+            state: UpdateState::ReceivedUpdate,
+            update,
+        });
+        Ok(())
+    }
+
+    pub fn send_commitment_signed(&mut self) -> Result<(), UpdateQueueError> {
+        if self.awaiting_remote_revocation {
+            return Err(UpdateQueueError::AwaitingRevocation);
+        }
+        if !self.has_updates_to_sign() {
+            return Err(UpdateQueueError::EmptyCommitment);
+        }
+        for queued in &mut self.updates {
+            queued.state = match queued.state {
+                UpdateState::SentUpdate => UpdateState::SentCommit,
+                UpdateState::SentRevocation => UpdateState::SentAckCommit,
+                state => state,
+            };
+        }
+        self.awaiting_remote_revocation = true;
+        self.remote_commitment_number += 1;
+        self.revocation_sent_last = false;
+        Ok(())
+    }
+
+    pub fn receive_revoke_and_ack(&mut self) -> Result<(), UpdateQueueError> {
+        if !self.awaiting_remote_revocation {
+            return Err(UpdateQueueError::UnexpectedRevocation);
+        }
+        for queued in &mut self.updates {
+            queued.state = match queued.state {
+                UpdateState::SentCommit => UpdateState::ReceivedRevocation,
+                UpdateState::SentAckCommit => UpdateState::IrrevocablyCommitted,
+                state => state,
+            };
+        }
+        self.awaiting_remote_revocation = false;
+        self.settle();
+        Ok(())
+    }
+
+    pub fn receive_commitment_signed(&mut self) -> Result<(), UpdateQueueError> {
+        if self.owe_local_revocation {
+            return Err(UpdateQueueError::UnrevokedCommitment);
+        }
+        let mut changed = false;
+        for queued in &mut self.updates {
+            queued.state = match queued.state {
+                UpdateState::ReceivedUpdate => {
+                    changed = true;
+                    UpdateState::ReceivedCommit
+                },
+                UpdateState::ReceivedRevocation => {
+                    changed = true;
+                    UpdateState::ReceivedAckCommit
+                },
+                state => state,
+            };
+        }
+        if !changed {
+            return Err(UpdateQueueError::EmptyCommitment);
+        }
+        self.owe_local_revocation = true;
+        self.local_commitment_number += 1;
+        Ok(())
+    }
+
+    pub fn send_revoke_and_ack(&mut self) -> Result<(), UpdateQueueError> {
+        if !self.owe_local_revocation {
+            return Err(UpdateQueueError::NothingToRevoke);
+        }
+        for queued in &mut self.updates {
+            queued.state = match queued.state {
+                UpdateState::ReceivedCommit => UpdateState::SentRevocation,
+                UpdateState::ReceivedAckCommit => UpdateState::IrrevocablyCommitted,
+                state => state,
+            };
+        }
+        self.owe_local_revocation = false;
+        self.revocation_sent_last = true;
+        self.settle();
+        Ok(())
+    }
+
+    /// Forgets updates which weren't covered by a `commitment_signed` before the connection
+    /// dropped. The peer forgets them too, so HTLC ids get reused.
+    pub fn disconnect(&mut self) {
+        let mut dropped_local_adds = 0;
+        let mut dropped_remote_adds = 0;
+        self.updates.retain(|queued| {
+            let uncommitted = match queued.state {
+                UpdateState::SentUpdate | UpdateState::ReceivedUpdate => true,
+                _ => false,
+            };
+            if uncommitted {
+                if let CommitmentUpdate::AddHtlc(..) = queued.update {
+                    match queued.origin {
+                        UpdateOrigin::Local => dropped_local_adds += 1,
+                        UpdateOrigin::Remote => dropped_remote_adds += 1,
+                    }
+                }
+            }
+            !uncommitted
+        });
+        self.next_local_htlc_id -= dropped_local_adds;
+        self.next_remote_htlc_id -= dropped_remote_adds;
+    }
+
+    /// Writes the queue as part of a channel's persisted state. Updates are stored as the wire
+    /// messages they came from.
+    pub fn write_to_cursor(&self, cursor: &mut WriteCursor) {
+        cursor.write_channel_id(&self.channel_id);
+        cursor.write_u8(self.is_funder as u8);
+        cursor.write_u32(self.feerate_per_kw);
+        cursor.write_u64(self.next_local_htlc_id);
+        cursor.write_u64(self.next_remote_htlc_id);
+        cursor.write_u64(self.local_commitment_number);
+        cursor.write_u64(self.remote_commitment_number);
+        cursor.write_u8(self.awaiting_remote_revocation as u8);
+        cursor.write_u8(self.owe_local_revocation as u8);
+        cursor.write_u8(self.revocation_sent_last as u8);
+        cursor.write_u32(self.updates.len() as u32);
+        for queued in &self.updates {
+            cursor.write_u8(match queued.origin {
+                UpdateOrigin::Local => 0,
+                UpdateOrigin::Remote => 1,
+            });
+            cursor.write_u8(queued.state as u8);
+            cursor.write_u16_len_slice(&queued.update.to_msg().to_bytes());
+        }
+    }
+
+    pub fn read_from_cursor(cursor: &mut ReadCursor) -> Result<UpdateQueue, ChannelStateError> {
+        let channel_id = cursor.read_channel_id()?;
+        let is_funder = cursor.read_u8()? != 0;
+        let feerate_per_kw = cursor.read_u32()?;
+        let mut queue = UpdateQueue::new(channel_id, is_funder, feerate_per_kw);
+        queue.next_local_htlc_id = cursor.read_u64()?;
+        queue.next_remote_htlc_id = cursor.read_u64()?;
+        queue.local_commitment_number = cursor.read_u64()?;
+        queue.remote_commitment_number = cursor.read_u64()?;
+        queue.awaiting_remote_revocation = cursor.read_u8()? != 0;
+        queue.owe_local_revocation = cursor.read_u8()? != 0;
+        queue.revocation_sent_last = cursor.read_u8()? != 0;
+        let num_updates = cursor.read_u32()?;
+        for _ in 0..num_updates {
+            let origin = match cursor.read_u8()? {
+                0 => UpdateOrigin::Local,
+                1 => UpdateOrigin::Remote,
+                _ => return Err(ChannelStateError::Malformed),
+            };
+            let state = match UpdateState::from_u8(cursor.read_u8()?) {
+                Some(state) => state,
+                None => return Err(ChannelStateError::Malformed),
+            };
+            let msg = Msg::from_bytes(cursor.read_u16_len_slice()?).map_err(|_| ChannelStateError::Malformed)?;
+            let update = CommitmentUpdate::from_msg(msg).map_err(|_| ChannelStateError::Malformed)?;
+            if *update.channel_id() != channel_id {
+                return Err(ChannelStateError::Malformed);
+            }
+            queue.updates.push(QueuedUpdate { origin, state, update });
+        }
+        Ok(queue)
+    }
+
+    fn check_update(&self, origin: UpdateOrigin, update: &CommitmentUpdate)
+        -> Result<(), UpdateQueueError>
+    {
+        if *update.channel_id() != self.channel_id {
+            return Err(UpdateQueueError::WrongChannelId);
+        }
+        match update {
+            CommitmentUpdate::AddHtlc(msg) => {
+                let expected = match origin {
+                    UpdateOrigin::Local => self.next_local_htlc_id,
+                    UpdateOrigin::Remote => self.next_remote_htlc_id,
+                };
+                if msg.id != expected {
+                    return Err(UpdateQueueError::HtlcIdOutOfSequence { id: msg.id, expected });
+                }
+            },
+            CommitmentUpdate::Fee(..) => {
+                let from_funder = match origin {
+                    UpdateOrigin::Local => self.is_funder,
+                    UpdateOrigin::Remote => !self.is_funder,
+                };
+                if !from_funder {
+                    return Err(UpdateQueueError::UpdateFeeFromNonFunder);
+                }
+            },
+            _ => {
+                let id = unwrap!(update.removed_htlc_id());
+                let offerer = match origin {
+                    UpdateOrigin::Local => UpdateOrigin::Remote,
+                    UpdateOrigin::Remote => UpdateOrigin::Local,
+                };
+                let add = match self.find_htlc(offerer, id) {
+                    Some(add) => add,
+                    None => return Err(UpdateQueueError::UnknownHtlc { id }),
+                };
+                if add.state != UpdateState::IrrevocablyCommitted {
+                    return Err(UpdateQueueError::HtlcNotCommitted { id });
+                }
+                let already_removed = self.updates.iter().any(|queued| {
+                    queued.origin == origin && queued.update.removed_htlc_id() == Some(id)
+                });
+                if already_removed {
+                    return Err(UpdateQueueError::HtlcAlreadyRemoved { id });
+                }
+                if let CommitmentUpdate::FulfillHtlc(fulfill) = update {
+                    let payment_hash = match add.update {
+                        CommitmentUpdate::AddHtlc(ref add_msg) => add_msg.payment_hash,
+                        _ => unreachable!(),
+                    };
+                    if sha256(&[&fulfill.payment_preimage[..]]) != payment_hash {
+                        return Err(UpdateQueueError::PreimageMismatch { id });
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn find_htlc(&self, offerer: UpdateOrigin, id: u64) -> Option<&QueuedUpdate> {
+        self.updates.iter().find(|queued| {
+            queued.origin == offerer && match queued.update {
+                CommitmentUpdate::AddHtlc(ref msg) => msg.id == id,
+                _ => false,
+            }
+        })
+    }
+
+    /// The HTLCs in the commitment made up of the updates whose state passes `included`, such as
+    /// `UpdateState::in_local_commitment`.
+    pub fn htlcs_in_commitment<F>(&self, included: F) -> Vec<(UpdateOrigin, &UpdateAddHtlcMsg)>
+    where
+        F: Fn(UpdateState) -> bool,
+    {
+        let mut htlcs = Vec::new();
+        for queued in &self.updates {
+            let msg = match queued.update {
+                CommitmentUpdate::AddHtlc(ref msg) => msg,
+                _ => continue,
+            };
+            if !included(queued.state) {
+                continue;
+            }
+            let removed = self.updates.iter().any(|removal| {
+                removal.origin != queued.origin
+                && removal.update.removed_htlc_id() == Some(msg.id)
+                && included(removal.state)
+            });
+            if !removed {
+                htlcs.push((queued.origin, msg));
+            }
+        }
+        htlcs
+    }
+
+    pub fn feerate_in_commitment<F>(&self, included: F) -> u32
+    where
+        F: Fn(UpdateState) -> bool,
+    {
+        let mut feerate_per_kw = self.feerate_per_kw;
+        for queued in &self.updates {
+            if let CommitmentUpdate::Fee(ref msg) = queued.update {
+                if included(queued.state) {
+                    feerate_per_kw = msg.feerate_per_kw;
+                }
+            }
+        }
+        feerate_per_kw
+    }
+
+    /// Drops fee updates and removed HTLCs once they're irrevocably committed on both sides.
+    fn settle(&mut self) {
+        let mut removed_local = Vec::new();
+        let mut removed_remote = Vec::new();
+        for queued in &self.updates {
+            if queued.state != UpdateState::IrrevocablyCommitted {
+                continue;
+            }
+            if let CommitmentUpdate::Fee(ref msg) = queued.update {
+                self.feerate_per_kw = msg.feerate_per_kw;
+            }
+            if let Some(id) = queued.update.removed_htlc_id() {
+                match queued.origin {
+                    UpdateOrigin::Local => removed_remote.push(id),
+                    UpdateOrigin::Remote => removed_local.push(id),
+                }
+            }
+        }
+
+        self.updates.retain(|queued| {
+            if queued.state != UpdateState::IrrevocablyCommitted {
+                return true;
+            }
+            match queued.update {
+                CommitmentUpdate::AddHtlc(ref msg) => {
+                    let removed = match queued.origin {
+                        UpdateOrigin::Local => &removed_local,
+                        UpdateOrigin::Remote => &removed_remote,
+                    };
+                    !removed.contains(&msg.id)
+                },
+                _ => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL_ID: ChannelId = ChannelId([9; 32]);
+
+    fn add_htlc(id: u64, payment_preimage: &[u8; 32]) -> CommitmentUpdate {
+        CommitmentUpdate::AddHtlc(UpdateAddHtlcMsg {
+            channel_id: CHANNEL_ID,
+            id,
+            amount_msat: 1_000_000,
+            payment_hash: sha256(&[&payment_preimage[..]]),
+            cltv_expiry: 500_000,
+            onion_routing_packet: vec![0; ONION_PACKET_LEN],
+            blinding_point: None,
+        })
+    }
+
+    fn fulfill_htlc(id: u64, payment_preimage: [u8; 32]) -> CommitmentUpdate {
+        CommitmentUpdate::FulfillHtlc(UpdateFulfillHtlcMsg {
+            channel_id: CHANNEL_ID,
+            id,
+            payment_preimage,
+        })
+    }
+
+    /// Runs a full commitment dance where `sender` signs first.
+    fn dance(sender: &mut UpdateQueue, receiver: &mut UpdateQueue) {
+        unwrap!(sender.send_commitment_signed());
+        unwrap!(receiver.receive_commitment_signed());
+        unwrap!(receiver.send_revoke_and_ack());
+        unwrap!(sender.receive_revoke_and_ack());
+        unwrap!(receiver.send_commitment_signed());
+        unwrap!(sender.receive_commitment_signed());
+        unwrap!(sender.send_revoke_and_ack());
+        unwrap!(receiver.receive_revoke_and_ack());
+    }
+
+    #[test]
+    fn htlc_moves_through_commitment_dance() {
+        let mut alice = UpdateQueue::new(CHANNEL_ID, true, 253);
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        let preimage = [0x55; 32];
+
+        unwrap!(alice.send_update(add_htlc(0, &preimage)));
+        unwrap!(bob.receive_update(add_htlc(0, &preimage)));
+        assert!(alice.htlcs_in_remote_commitment().is_empty());
+        assert_eq!(alice.htlcs_in_next_remote_commitment().len(), 1);
+
+        unwrap!(alice.send_commitment_signed());
+        unwrap!(bob.receive_commitment_signed());
+        assert_eq!(alice.htlcs_in_remote_commitment().len(), 1);
+        assert_eq!(bob.htlcs_in_local_commitment().len(), 1);
+        assert!(bob.htlcs_in_remote_commitment().is_empty());
+
+        unwrap!(bob.send_revoke_and_ack());
+        unwrap!(alice.receive_revoke_and_ack());
+        assert_eq!(alice.updates()[0].state, UpdateState::ReceivedRevocation);
+        assert_eq!(bob.updates()[0].state, UpdateState::SentRevocation);
+
+        unwrap!(bob.send_commitment_signed());
+        unwrap!(alice.receive_commitment_signed());
+        unwrap!(alice.send_revoke_and_ack());
+        unwrap!(bob.receive_revoke_and_ack());
+        assert_eq!(alice.updates()[0].state, UpdateState::IrrevocablyCommitted);
+        assert_eq!(bob.updates()[0].state, UpdateState::IrrevocablyCommitted);
+
+        unwrap!(bob.send_update(fulfill_htlc(0, preimage)));
+        unwrap!(alice.receive_update(fulfill_htlc(0, preimage)));
+        dance(&mut bob, &mut alice);
+        assert!(alice.updates().is_empty());
+        assert!(bob.updates().is_empty());
+    }
+
+    #[test]
+    fn cannot_fulfill_uncommitted_htlc() {
+        let mut alice = UpdateQueue::new(CHANNEL_ID, true, 253);
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        let preimage = [0x55; 32];
+
+        unwrap!(alice.send_update(add_htlc(0, &preimage)));
+        unwrap!(bob.receive_update(add_htlc(0, &preimage)));
+        match bob.send_update(fulfill_htlc(0, preimage)) {
+            Err(UpdateQueueError::HtlcNotCommitted { id: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_preimage() {
+        let mut alice = UpdateQueue::new(CHANNEL_ID, true, 253);
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        let preimage = [0x55; 32];
+
+        unwrap!(alice.send_update(add_htlc(0, &preimage)));
+        unwrap!(bob.receive_update(add_htlc(0, &preimage)));
+        dance(&mut alice, &mut bob);
+        match alice.receive_update(fulfill_htlc(0, [0x66; 32])) {
+            Err(UpdateQueueError::PreimageMismatch { id: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_sequence_htlc_ids() {
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        match bob.receive_update(add_htlc(1, &[0; 32])) {
+            Err(UpdateQueueError::HtlcIdOutOfSequence { id: 1, expected: 0 }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn update_fee_only_from_funder() {
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+        let update = CommitmentUpdate::Fee(UpdateFeeMsg { channel_id: CHANNEL_ID, feerate_per_kw: 1000 });
+        match bob.send_update(update.clone()) {
+            Err(UpdateQueueError::UpdateFeeFromNonFunder) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        unwrap!(bob.receive_update(update));
+        assert_eq!(bob.feerate_in_local_commitment(), 253);
+    }
+
+    #[test]
+    fn rejects_commitment_signed_before_revocation() {
+        let mut alice = UpdateQueue::new(CHANNEL_ID, true, 253);
+        let mut bob = UpdateQueue::new(CHANNEL_ID, false, 253);
+
+        unwrap!(alice.send_update(add_htlc(0, &[0x55; 32])));
+        unwrap!(bob.receive_update(add_htlc(0, &[0x55; 32])));
+        unwrap!(alice.send_commitment_signed());
+        unwrap!(bob.receive_commitment_signed());
+        unwrap!(alice.send_update(add_htlc(1, &[0x56; 32])));
+        unwrap!(bob.receive_update(add_htlc(1, &[0x56; 32])));
+        match bob.receive_commitment_signed() {
+            Err(UpdateQueueError::UnrevokedCommitment) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(bob.local_commitment_number(), 1);
+        assert_eq!(bob.updates()[1].state, UpdateState::ReceivedUpdate);
+    }
+}